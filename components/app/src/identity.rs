@@ -3,7 +3,7 @@ use std::path::Path;
 
 use identity::{create_identity, IdentityClient};
 
-use proto::file::identity::load_identity_from_file;
+use proto::file::identity::{identity_passphrase_from_env, load_identity_from_file};
 
 #[derive(Debug)]
 pub enum IdentityFromFileError {
@@ -11,6 +11,9 @@ pub enum IdentityFromFileError {
     CreateIdentityError,
 }
 
+/// Load an identity from a file and spawn an identity service for it.
+/// The passphrase of an encrypted identity file is read from the environment
+/// (See `IDENTITY_PASSPHRASE_ENV`).
 pub fn identity_from_file<S>(
     idfile_path: &Path,
     mut spawner: S,
@@ -18,8 +21,10 @@ pub fn identity_from_file<S>(
 where
     S: Spawn,
 {
+    let opt_passphrase = identity_passphrase_from_env();
     let identity =
-        load_identity_from_file(idfile_path).map_err(|_| IdentityFromFileError::LoadFileError)?;
+        load_identity_from_file(idfile_path, opt_passphrase.as_ref().map(String::as_str))
+            .map_err(|_| IdentityFromFileError::LoadFileError)?;
 
     // Spawn identity service:
     let (sender, identity_loop) = create_identity(identity);
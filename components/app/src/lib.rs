@@ -52,6 +52,13 @@ pub mod report {
     };
 }
 
+pub mod signature_buff {
+    pub use proto::funder::signature_buff::{
+        create_failure_signature_buffer, create_response_signature_buffer,
+        move_token_signature_buff,
+    };
+}
+
 pub mod invoice {
     pub use crypto::invoice_id::{InvoiceId, INVOICE_ID_LEN};
 }
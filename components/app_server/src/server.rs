@@ -9,11 +9,12 @@ use futures::{future, stream, Sink, SinkExt, Stream, StreamExt};
 use common::conn::ConnPair;
 use common::select_streams::{select_streams, BoxStream};
 // use common::mutable_state::MutableState;
+use crypto::identity::PublicKey;
 use crypto::uid::Uid;
 
 use proto::funder::messages::{
-    FriendStatus, FunderControl, FunderIncomingControl, FunderOutgoingControl, RemoveFriend,
-    RequestsStatus, SetFriendStatus, SetRequestsStatus,
+    FriendStatus, FunderControl, FunderIncomingControl, FunderOutgoingControl, RequestsStatus,
+    SetFriendStatus, SetRequestsStatus,
 };
 use proto::report::convert::funder_report_mutation_to_index_mutation;
 
@@ -56,6 +57,8 @@ pub struct App<B: Clone> {
     opt_sender: Option<mpsc::Sender<AppServerToApp<B>>>,
     open_route_requests: HashSet<Uid>,
     open_send_funds_requests: HashSet<Uid>,
+    /// Friends for which this app queried the token counters.
+    open_token_counters_queries: HashSet<PublicKey>,
 }
 
 impl<B> App<B>
@@ -68,6 +71,7 @@ where
             opt_sender: Some(sender),
             open_route_requests: HashSet::new(),
             open_send_funds_requests: HashSet::new(),
+            open_token_counters_queries: HashSet::new(),
         }
     }
 
@@ -114,6 +118,7 @@ fn check_permissions<B>(app_permissions: &AppPermissions, app_request: &AppReque
         AppRequest::RequestRoutes(_) => app_permissions.routes,
         AppRequest::AddIndexServer(_) => app_permissions.config,
         AppRequest::RemoveIndexServer(_) => app_permissions.config,
+        AppRequest::QueryTokenCounters(_) => app_permissions.config,
     }
 }
 
@@ -249,6 +254,40 @@ where
 
                 await!(self.broadcast_node_report_mutations(report_mutations));
             }
+            FunderOutgoingControl::FriendQueryResult(friend_query_result) => {
+                // We never send QueryFriend to the funder, so we don't expect this message.
+                warn!(
+                    "handle_from_funder(): Unexpected friend query result: {:?}",
+                    friend_query_result.friend_public_key
+                );
+            }
+            FunderOutgoingControl::ReceiptEvicted(evicted_receipt) => {
+                // TODO: Forward evicted receipts to the apps, once the app protocol supports it.
+                warn!(
+                    "handle_from_funder(): Ready receipt evicted: {:?}",
+                    evicted_receipt
+                );
+            }
+            FunderOutgoingControl::MetricsQueryResult(funder_metrics) => {
+                // We never send QueryMetrics to the funder, so we don't expect this message.
+                warn!(
+                    "handle_from_funder(): Unexpected metrics query result: {:?}",
+                    funder_metrics
+                );
+            }
+            FunderOutgoingControl::TokenCountersQueryResult(token_counters_query_result) => {
+                // Forward the result to all the apps that queried this friend:
+                for app in self.apps.values_mut() {
+                    if app
+                        .open_token_counters_queries
+                        .remove(&token_counters_query_result.friend_public_key)
+                    {
+                        await!(app.send(AppServerToApp::TokenCountersQueryResult(
+                            token_counters_query_result.clone()
+                        )));
+                    }
+                }
+            }
         }
         Ok(())
     }
@@ -359,8 +398,7 @@ where
                 )))
                 .map_err(|_| AppServerError::SendToFunderError)
             }
-            AppRequest::RemoveFriend(friend_public_key) => {
-                let remove_friend = RemoveFriend { friend_public_key };
+            AppRequest::RemoveFriend(remove_friend) => {
                 await!(self.to_funder.send(FunderIncomingControl::new(
                     app_request_id,
                     FunderControl::RemoveFriend(remove_friend)
@@ -450,6 +488,16 @@ where
                     IndexClientRequest::RemoveIndexServer(index_server_address)
                 ))))
             .map_err(|_| AppServerError::SendToIndexClientError),
+            AppRequest::QueryTokenCounters(friend_public_key) => {
+                // Keep track of which application issued this query:
+                app.open_token_counters_queries
+                    .insert(friend_public_key.clone());
+                await!(self.to_funder.send(FunderIncomingControl::new(
+                    app_request_id,
+                    FunderControl::QueryTokenCounters(friend_public_key)
+                )))
+                .map_err(|_| AppServerError::SendToFunderError)
+            }
         }
     }
 
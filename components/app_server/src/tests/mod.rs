@@ -1,6 +1,7 @@
 mod all_apps_closed;
 mod funder_command;
 mod index_client_command;
+mod query_token_counters;
 mod request_routes;
 mod request_send_funds;
 mod two_apps;
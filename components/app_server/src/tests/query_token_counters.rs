@@ -0,0 +1,136 @@
+use futures::channel::mpsc;
+use futures::executor::ThreadPool;
+use futures::task::Spawn;
+use futures::{SinkExt, StreamExt};
+
+use crypto::identity::{PublicKey, PUBLIC_KEY_LEN};
+use crypto::uid::{Uid, UID_LEN};
+
+use proto::app_server::messages::{AppPermissions, AppRequest, AppServerToApp, AppToAppServer};
+use proto::funder::messages::{
+    FunderControl, FunderOutgoingControl, MoveTokenCounters, TokenChannelCounters,
+    TokenCountersQueryResult,
+};
+
+use super::utils::spawn_dummy_app_server;
+
+async fn task_app_server_loop_query_token_counters<S>(spawner: S)
+where
+    S: Spawn + Clone + Send + 'static,
+{
+    let (
+        mut funder_sender,
+        mut funder_receiver,
+        _index_client_sender,
+        _index_client_receiver,
+        mut connections_sender,
+        _initial_node_report,
+    ) = spawn_dummy_app_server(spawner.clone());
+
+    // Connect two apps:
+    let (mut app_sender0, app_server_receiver) = mpsc::channel(0);
+    let (app_server_sender, mut app_receiver0) = mpsc::channel(0);
+    let app_server_conn_pair = (app_server_sender, app_server_receiver);
+    let app_permissions = AppPermissions {
+        routes: true,
+        send_funds: true,
+        config: true,
+    };
+    await!(connections_sender.send((app_permissions, app_server_conn_pair))).unwrap();
+
+    let (_app_sender1, app_server_receiver) = mpsc::channel(0);
+    let (app_server_sender, mut app_receiver1) = mpsc::channel(0);
+    let app_server_conn_pair = (app_server_sender, app_server_receiver);
+    let app_permissions = AppPermissions {
+        routes: true,
+        send_funds: true,
+        config: true,
+    };
+    await!(connections_sender.send((app_permissions, app_server_conn_pair))).unwrap();
+
+    // The apps should receive the current node report as the first message:
+    let _to_app_message = await!(app_receiver0.next()).unwrap();
+    let _to_app_message = await!(app_receiver1.next()).unwrap();
+
+    // Query token counters through app0:
+    let friend_public_key = PublicKey::from(&[0xee; PUBLIC_KEY_LEN]);
+    let to_app_server = AppToAppServer::new(
+        Uid::from(&[22; UID_LEN]),
+        AppRequest::QueryTokenCounters(friend_public_key.clone()),
+    );
+    await!(app_sender0.send(to_app_server)).unwrap();
+
+    // QueryTokenCounters command should be forwarded to the Funder:
+    let to_funder_message = await!(funder_receiver.next()).unwrap();
+    assert_eq!(to_funder_message.app_request_id, Uid::from(&[22; UID_LEN]));
+    match to_funder_message.funder_control {
+        FunderControl::QueryTokenCounters(public_key) => assert_eq!(public_key, friend_public_key),
+        _ => unreachable!(),
+    };
+
+    // Funder returns a result for a friend that was not queried.
+    // This result will be discarded:
+    let token_counters_query_result = TokenCountersQueryResult {
+        friend_public_key: PublicKey::from(&[0xdd; PUBLIC_KEY_LEN]),
+        opt_token_channel_counters: None,
+    };
+    await!(
+        funder_sender.send(FunderOutgoingControl::TokenCountersQueryResult(
+            token_counters_query_result
+        ))
+    )
+    .unwrap();
+
+    // We shouldn't get an message at any of the apps:
+    assert!(app_receiver0.try_next().is_err());
+    assert!(app_receiver1.try_next().is_err());
+
+    // Funder returns a result for the queried friend:
+    let token_counters_query_result = TokenCountersQueryResult {
+        friend_public_key: friend_public_key.clone(),
+        opt_token_channel_counters: Some(TokenChannelCounters {
+            opt_local_outgoing: Some(MoveTokenCounters {
+                move_token_counter: 5,
+                inconsistency_counter: 1,
+            }),
+            opt_last_incoming: None,
+        }),
+    };
+    await!(
+        funder_sender.send(FunderOutgoingControl::TokenCountersQueryResult(
+            token_counters_query_result.clone()
+        ))
+    )
+    .unwrap();
+
+    let to_app_message = await!(app_receiver0.next()).unwrap();
+    match to_app_message {
+        AppServerToApp::TokenCountersQueryResult(received_query_result) => {
+            assert_eq!(received_query_result, token_counters_query_result)
+        }
+        _ => unreachable!(),
+    }
+    // We shouldn't get an incoming message at app1:
+    assert!(app_receiver1.try_next().is_err());
+
+    // Funder again returns the same result.
+    // This time the result should be discarded, because the query was already answered:
+    await!(
+        funder_sender.send(FunderOutgoingControl::TokenCountersQueryResult(
+            token_counters_query_result
+        ))
+    )
+    .unwrap();
+
+    // We shouldn't get an message at any of the apps:
+    assert!(app_receiver0.try_next().is_err());
+    assert!(app_receiver1.try_next().is_err());
+}
+
+#[test]
+fn test_app_server_loop_query_token_counters() {
+    let mut thread_pool = ThreadPool::new().unwrap();
+    thread_pool.run(task_app_server_loop_query_token_counters(
+        thread_pool.clone(),
+    ));
+}
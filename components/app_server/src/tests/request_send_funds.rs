@@ -88,6 +88,8 @@ where
     let response_received = ResponseReceived {
         request_id: Uid::from(&[2; UID_LEN]),
         result: ResponseSendFundsResult::Failure(pk_e.clone()),
+        opt_elapsed_ticks: None,
+        opt_failure_reason: None,
     };
     await!(funder_sender.send(FunderOutgoingControl::ResponseReceived(response_received))).unwrap();
 
@@ -99,6 +101,8 @@ where
     let response_received = ResponseReceived {
         request_id: Uid::from(&[3; UID_LEN]),
         result: ResponseSendFundsResult::Failure(pk_e.clone()),
+        opt_elapsed_ticks: None,
+        opt_failure_reason: None,
     };
     await!(funder_sender.send(FunderOutgoingControl::ResponseReceived(
         response_received.clone()
@@ -121,6 +125,8 @@ where
     let response_received = ResponseReceived {
         request_id: Uid::from(&[3; UID_LEN]),
         result: ResponseSendFundsResult::Failure(pk_e),
+        opt_elapsed_ticks: None,
+        opt_failure_reason: None,
     };
     await!(funder_sender.send(FunderOutgoingControl::ResponseReceived(response_received))).unwrap();
 
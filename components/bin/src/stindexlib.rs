@@ -22,7 +22,7 @@ use timer::create_timer;
 
 use net::{NetConnector, TcpListener};
 
-use proto::file::identity::load_identity_from_file;
+use proto::file::identity::{identity_passphrase_from_env, load_identity_from_file};
 use proto::file::index_server::{load_trusted_servers, IndexServerDirectoryError};
 
 // TODO: Maybe take as a command line argument in the future?
@@ -71,8 +71,12 @@ pub fn stindex(st_index_cmd: StIndexCmd) -> Result<(), IndexServerBinError> {
         trusted,
     } = st_index_cmd;
 
-    let identity = load_identity_from_file(Path::new(&idfile))
-        .map_err(|_| IndexServerBinError::LoadIdentityError)?;
+    let opt_passphrase = identity_passphrase_from_env();
+    let identity = load_identity_from_file(
+        Path::new(&idfile),
+        opt_passphrase.as_ref().map(String::as_str),
+    )
+    .map_err(|_| IndexServerBinError::LoadIdentityError)?;
 
     let trusted_servers = load_trusted_servers(Path::new(&trusted))
         .map_err(IndexServerBinError::LoadTrustedServersError)?
@@ -15,7 +15,10 @@ use database::file_db::FileDb;
 use node::NodeState;
 
 use proto::file::app::{store_trusted_app_to_file, TrustedApp};
-use proto::file::identity::{load_identity_from_file, store_raw_identity_to_file};
+use proto::file::identity::{
+    identity_passphrase_from_env, load_identity_from_file, store_encrypted_raw_identity_to_file,
+    store_raw_identity_to_file,
+};
 use proto::file::index_server::store_index_server_to_file;
 use proto::file::node::store_node_to_file;
 use proto::file::relay::store_relay_to_file;
@@ -137,8 +140,9 @@ fn init_node_db(InitNodeDbCmd { idfile, output }: InitNodeDbCmd) -> Result<(), I
     }
 
     // Parse identity file:
-    let identity =
-        load_identity_from_file(&idfile).map_err(|_| InitNodeDbError::LoadIdentityError)?;
+    let opt_passphrase = identity_passphrase_from_env();
+    let identity = load_identity_from_file(&idfile, opt_passphrase.as_ref().map(String::as_str))
+        .map_err(|_| InitNodeDbError::LoadIdentityError)?;
     let local_public_key = identity.get_public_key();
 
     // Create a new database file:
@@ -164,7 +168,14 @@ fn gen_identity(GenIdentCmd { output }: GenIdentCmd) -> Result<(), GenIdentityEr
         return Err(GenIdentityError::OutputAlreadyExists);
     }
 
-    store_raw_identity_to_file(&pkcs8, &output).map_err(|_| GenIdentityError::StoreToFileError)
+    // The private key is encrypted if a passphrase was provided:
+    match identity_passphrase_from_env() {
+        Some(passphrase) => {
+            store_encrypted_raw_identity_to_file(&pkcs8, &output, &passphrase, &rng)
+        }
+        None => store_raw_identity_to_file(&pkcs8, &output),
+    }
+    .map_err(|_| GenIdentityError::StoreToFileError)
 }
 
 #[derive(Debug)]
@@ -191,8 +202,12 @@ fn app_ticket(
     }: AppTicketCmd,
 ) -> Result<(), AppTicketError> {
     // Obtain app's public key:
-    let identity = load_identity_from_file(Path::new(&idfile))
-        .map_err(|_| AppTicketError::LoadIdentityError)?;
+    let opt_passphrase = identity_passphrase_from_env();
+    let identity = load_identity_from_file(
+        Path::new(&idfile),
+        opt_passphrase.as_ref().map(String::as_str),
+    )
+    .map_err(|_| AppTicketError::LoadIdentityError)?;
     let public_key = identity.get_public_key();
 
     // Make sure that output does not exist.
@@ -244,8 +259,9 @@ fn relay_ticket(
     }
 
     // Parse identity file:
-    let identity =
-        load_identity_from_file(&idfile).map_err(|_| RelayTicketError::LoadIdentityError)?;
+    let opt_passphrase = identity_passphrase_from_env();
+    let identity = load_identity_from_file(&idfile, opt_passphrase.as_ref().map(String::as_str))
+        .map_err(|_| RelayTicketError::LoadIdentityError)?;
     let public_key = identity.get_public_key();
 
     let relay_address = RelayAddress {
@@ -285,8 +301,9 @@ fn index_ticket(
     }
 
     // Parse identity file:
-    let identity =
-        load_identity_from_file(&idfile).map_err(|_| IndexTicketError::LoadIdentityError)?;
+    let opt_passphrase = identity_passphrase_from_env();
+    let identity = load_identity_from_file(&idfile, opt_passphrase.as_ref().map(String::as_str))
+        .map_err(|_| IndexTicketError::LoadIdentityError)?;
     let public_key = identity.get_public_key();
 
     let index_address = IndexServerAddress {
@@ -327,8 +344,9 @@ fn node_ticket(
     }
 
     // Parse identity file:
-    let identity =
-        load_identity_from_file(&idfile).map_err(|_| NodeTicketError::LoadIdentityError)?;
+    let opt_passphrase = identity_passphrase_from_env();
+    let identity = load_identity_from_file(&idfile, opt_passphrase.as_ref().map(String::as_str))
+        .map_err(|_| NodeTicketError::LoadIdentityError)?;
     let public_key = identity.get_public_key();
 
     let node_address = NodeAddress {
@@ -20,7 +20,7 @@ use net::TcpListener;
 use relay::{net_relay_server, NetRelayServerError};
 use timer::create_timer;
 
-use proto::file::identity::load_identity_from_file;
+use proto::file::identity::{identity_passphrase_from_env, load_identity_from_file};
 
 // TODO: Maybe take as a command line argument in the future?
 /// Maximum amount of concurrent encrypted channel set-ups.
@@ -54,8 +54,9 @@ pub fn strelay(st_relay_cmd: StRelayCmd) -> Result<(), RelayServerBinError> {
     let StRelayCmd { idfile, laddr } = st_relay_cmd;
 
     // Parse identity file:
-    let identity =
-        load_identity_from_file(&idfile).map_err(|_| RelayServerBinError::LoadIdentityError)?;
+    let opt_passphrase = identity_passphrase_from_env();
+    let identity = load_identity_from_file(&idfile, opt_passphrase.as_ref().map(String::as_str))
+        .map_err(|_| RelayServerBinError::LoadIdentityError)?;
 
     // Create a ThreadPool:
     let mut thread_pool =
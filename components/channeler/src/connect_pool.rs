@@ -247,14 +247,18 @@ where
     pub fn handle_config_request(&mut self, config: Vec<RA>) -> Result<(), ConnectPoolError> {
         let old_addresses = self.addresses.iter().cloned().collect::<HashSet<_>>();
 
-        let new_addresses: HashSet<RA> = config.into_iter().collect::<HashSet<_>>();
+        let new_addresses: HashSet<RA> = config.iter().cloned().collect::<HashSet<_>>();
 
         for removed_address in old_addresses.difference(&new_addresses) {
             self.remove_address(removed_address.clone())?;
         }
 
-        for added_address in new_addresses.difference(&old_addresses) {
-            self.add_address(added_address.clone())?;
+        // Add new addresses according to the order in the configuration,
+        // so that they are attempted in sequence:
+        for added_address in config {
+            if !old_addresses.contains(&added_address) {
+                self.add_address(added_address)?;
+            }
         }
         Ok(())
     }
@@ -758,4 +762,97 @@ mod tests {
         let mut thread_pool = ThreadPool::new().unwrap();
         thread_pool.run(task_pool_connector_backoff_ticks(thread_pool.clone()));
     }
+
+    async fn task_pool_connector_address_failover<S>(mut spawner: S)
+    where
+        S: Spawn + Clone + Send + 'static,
+    {
+        // Create a mock time service:
+        let (mut tick_sender_receiver, mut timer_client) =
+            dummy_timer_multi_sender(spawner.clone());
+
+        let backoff_ticks = 2;
+
+        let (conn_request_sender, mut conn_request_receiver) = mpsc::channel(0);
+        let client_connector = DummyConnector::new(conn_request_sender);
+
+        // We don't need encryption for this test:
+        let encrypt_transform = FuncFutTransform::new(|(_public_key, conn_pair)| {
+            Box::pin(future::ready(Some(conn_pair)))
+        });
+
+        let timer_stream = await!(timer_client.request_timer_stream()).unwrap();
+        let mut tick_sender = await!(tick_sender_receiver.next()).unwrap();
+
+        // Used for debugging the loop:
+        let (event_sender, mut event_receiver) = mpsc::channel(0);
+
+        let (request_sender, incoming_requests) = mpsc::channel(0);
+        let (config_sender, incoming_config) = mpsc::channel(0);
+
+        let pk_b = PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]);
+
+        let loop_fut = connect_pool_loop(
+            incoming_requests,
+            incoming_config,
+            timer_stream,
+            encrypt_transform,
+            pk_b.clone(), // friend_public_key
+            backoff_ticks,
+            client_connector,
+            spawner.clone(),
+            Some(event_sender),
+        )
+        .map_err(|e| error!("connect_pool_loop() error: {:?}", e))
+        .map(|_| ());
+
+        spawner.spawn(loop_fut).unwrap();
+
+        let mut connect_client = CpConnectClient::new(request_sender);
+        let mut config_client = CpConfigClient::new(config_sender);
+
+        // Addresses should be attempted in the configured order:
+        await!(config_client.config(vec![0x5u32, 0x3u32])).unwrap();
+        await!(event_receiver.next()).unwrap();
+
+        let connect_fut = connect_client.connect();
+        let handle_connect_fut = async {
+            await!(event_receiver.next()).unwrap(); // Connection request event
+
+            // First address fails:
+            let conn_request = await!(conn_request_receiver.next()).unwrap();
+            let (address, pk) = &conn_request.address;
+            assert_eq!(address, &0x5u32);
+            assert_eq!(pk, &pk_b);
+            conn_request.reply(None);
+            await!(event_receiver.next()).unwrap(); // connection attempt done event
+
+            for _ in 0..backoff_ticks {
+                await!(tick_sender.send(TimerTick)).unwrap();
+                await!(event_receiver.next()).unwrap(); // timer tick event
+            }
+
+            // We fail over to the second address:
+            let conn_request = await!(conn_request_receiver.next()).unwrap();
+            let (address, pk) = &conn_request.address;
+            assert_eq!(address, &0x3u32);
+            assert_eq!(pk, &pk_b);
+
+            let (local_sender, remote_receiver) = mpsc::channel(0);
+            let (remote_sender, local_receiver) = mpsc::channel(0);
+            conn_request.reply(Some((local_sender, local_receiver)));
+            await!(event_receiver.next()).unwrap(); // connection attempt done event
+            (conn_request_receiver, (remote_sender, remote_receiver))
+        };
+        let (local_conn, (_new_conn_request_receiver, _remote_conn)) =
+            await!(join(connect_fut, handle_connect_fut));
+
+        drop(local_conn);
+    }
+
+    #[test]
+    fn test_pool_connector_address_failover() {
+        let mut thread_pool = ThreadPool::new().unwrap();
+        thread_pool.run(task_pool_connector_address_failover(thread_pool.clone()));
+    }
 }
@@ -9,7 +9,7 @@ use timer::TimerClient;
 
 use crypto::identity::PublicKey;
 
-use relay::{ClientConnector, ClientListener};
+use relay::{ClientConnector, ClientListener, DEFAULT_MAX_CONNS, DEFAULT_MAX_CONNS_PER_KEY};
 
 use crate::channeler::{channeler_loop, ChannelerError};
 use crate::connect_pool::PoolConnector;
@@ -135,6 +135,9 @@ where
         enc_relay_connector,
         keepalive_transform.clone(),
         conn_timeout_ticks,
+        DEFAULT_MAX_CONNS_PER_KEY,
+        DEFAULT_MAX_CONNS,
+        backoff_ticks,
         timer_client.clone(),
         spawner.clone(),
     );
@@ -17,3 +17,50 @@ where
         None => None,
     }
 }
+
+/// Collect at most `max_items` items from a Stream.
+/// Returns the collected items, and whether collection stopped because `max_items` was reached
+/// (In which case the stream may contain more items). This avoids hanging on streams that never
+/// end.
+pub async fn collect_bounded<T, M>(mut reader: M, max_items: usize) -> (Vec<T>, bool)
+where
+    M: Stream<Item = T> + Unpin,
+{
+    let mut items = Vec::new();
+    while items.len() < max_items {
+        match await!(reader.next()) {
+            Some(item) => items.push(item),
+            None => return (items, false),
+        }
+    }
+    (items, true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use futures::stream;
+
+    #[test]
+    fn test_collect_bounded() {
+        // The stream ends before the cap is reached:
+        let (items, truncated) = block_on(collect_bounded(stream::iter(0..3u32), 5));
+        assert_eq!(items, vec![0, 1, 2]);
+        assert!(!truncated);
+
+        // The stream exceeds the cap:
+        let (items, truncated) = block_on(collect_bounded(stream::iter(0..10u32), 4));
+        assert_eq!(items, vec![0, 1, 2, 3]);
+        assert!(truncated);
+
+        // A stream that never ends:
+        let (items, truncated) = block_on(collect_bounded(stream::repeat(7u32), 3));
+        assert_eq!(items, vec![7, 7, 7]);
+        assert!(truncated);
+
+        let (items, truncated) = block_on(collect_bounded(stream::repeat(7u32), 0));
+        assert!(items.is_empty());
+        assert!(truncated);
+    }
+}
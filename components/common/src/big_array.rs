@@ -7,7 +7,8 @@ use std::fmt;
 use std::marker::PhantomData;
 
 /// This code is required to be able to Serialize and Deserialize arrays of size larger than 64.
-/// This feature is required for serializing the Signature type, which is of size 64.
+/// Types defined using define_fixed_bytes! do not need it, as they implement serde directly
+/// using the same representation.
 /// In the future this might be supported automatically by Rust, or can be done using an external
 /// crate.
 pub trait BigArray<'de>: Sized {
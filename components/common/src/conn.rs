@@ -1,8 +1,12 @@
 use core::pin::Pin;
 use futures::channel::mpsc;
-use futures::Future;
+use futures::task::{Spawn, SpawnError, SpawnExt};
+use futures::{Future, SinkExt, StreamExt};
+use std::fmt::Debug;
 use std::marker::PhantomData;
 
+use crate::int_convert::{be_bytes_to_u64, u64_to_be_bytes};
+
 pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
 
 pub type ConnPair<SendItem, RecvItem> = (mpsc::Sender<SendItem>, mpsc::Receiver<RecvItem>);
@@ -126,3 +130,211 @@ where
         (self.func)(input)
     }
 }
+
+/// Layer serialization over a ConnPairVec, obtaining a ConnPair of typed messages.
+/// If a received message can not be deserialized, the connection is closed.
+pub fn framed<T, U, SF, DF, E, S>(
+    conn_pair: ConnPairVec,
+    serialize_fn: SF,
+    deserialize_fn: DF,
+    mut spawner: S,
+) -> Result<ConnPair<T, U>, SpawnError>
+where
+    T: Send + 'static,
+    U: Send + 'static,
+    SF: Fn(&T) -> Vec<u8> + Send + 'static,
+    DF: Fn(&[u8]) -> Result<U, E> + Send + 'static,
+    E: Debug,
+    S: Spawn,
+{
+    let (mut sender, mut receiver) = conn_pair;
+    let (user_sender, mut from_user_receiver) = mpsc::channel::<T>(0);
+    let (mut to_user_sender, user_receiver) = mpsc::channel::<U>(0);
+
+    // Add serialization for sender:
+    spawner.spawn(async move {
+        while let Some(message) = await!(from_user_receiver.next()) {
+            if await!(sender.send(serialize_fn(&message))).is_err() {
+                return;
+            }
+        }
+    })?;
+
+    // Add deserialization for receiver:
+    spawner.spawn(async move {
+        while let Some(data) = await!(receiver.next()) {
+            let message = match deserialize_fn(&data) {
+                Ok(message) => message,
+                Err(e) => {
+                    error!("framed(): Error deserializing message: {:?}", e);
+                    return;
+                }
+            };
+            if await!(to_user_sender.send(message)).is_err() {
+                return;
+            }
+        }
+    })?;
+
+    Ok((user_sender, user_receiver))
+}
+
+/// Length of the sequence number prefix added by `sequenced`.
+const SEQ_NUM_LEN: usize = 8;
+
+/// Tag every outgoing frame of a ConnPairVec with a sequence number, and make sure that incoming
+/// frames arrive in order. If an incoming frame is missing, duplicated or out of order, the
+/// connection is closed.
+///
+/// Both sides of the connection must use `sequenced`. This is useful for connections forwarded by
+/// a third party (For example, a relay), that could otherwise drop or reorder frames.
+pub fn sequenced<S>(conn_pair: ConnPairVec, mut spawner: S) -> Result<ConnPairVec, SpawnError>
+where
+    S: Spawn,
+{
+    let (mut sender, mut receiver) = conn_pair;
+    let (user_sender, mut from_user_receiver) = mpsc::channel::<Vec<u8>>(0);
+    let (mut to_user_sender, user_receiver) = mpsc::channel::<Vec<u8>>(0);
+
+    // Add sequence numbers for sender:
+    spawner.spawn(async move {
+        let mut seq_num: u64 = 0;
+        while let Some(message) = await!(from_user_receiver.next()) {
+            let mut frame = u64_to_be_bytes(seq_num).to_vec();
+            frame.extend_from_slice(&message);
+            if await!(sender.send(frame)).is_err() {
+                return;
+            }
+            seq_num = seq_num.wrapping_add(1);
+        }
+    })?;
+
+    // Verify sequence numbers for receiver:
+    spawner.spawn(async move {
+        let mut expected_seq_num: u64 = 0;
+        while let Some(frame) = await!(receiver.next()) {
+            if frame.len() < SEQ_NUM_LEN {
+                error!("sequenced(): Received frame is too short");
+                return;
+            }
+            let mut seq_num_bytes = [0u8; SEQ_NUM_LEN];
+            seq_num_bytes.copy_from_slice(&frame[..SEQ_NUM_LEN]);
+            let seq_num = be_bytes_to_u64(&seq_num_bytes);
+            if seq_num != expected_seq_num {
+                error!(
+                    "sequenced(): Invalid sequence number: {}. Expected: {}",
+                    seq_num, expected_seq_num
+                );
+                return;
+            }
+            if await!(to_user_sender.send(frame[SEQ_NUM_LEN..].to_vec())).is_err() {
+                return;
+            }
+            expected_seq_num = expected_seq_num.wrapping_add(1);
+        }
+    })?;
+
+    Ok((user_sender, user_receiver))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_executor::TestExecutor;
+
+    fn serialize_u8(num: &u8) -> Vec<u8> {
+        vec![*num]
+    }
+
+    fn deserialize_u8(data: &[u8]) -> Result<u8, ()> {
+        if data.len() == 1 {
+            Ok(data[0])
+        } else {
+            Err(())
+        }
+    }
+
+    async fn task_framed_basic(test_executor: TestExecutor) {
+        let (local_sender, mut remote_receiver) = mpsc::channel::<Vec<u8>>(0);
+        let (mut remote_sender, local_receiver) = mpsc::channel::<Vec<u8>>(0);
+
+        let (mut sender, mut receiver) = framed(
+            (local_sender, local_receiver),
+            serialize_u8,
+            deserialize_u8,
+            test_executor.clone(),
+        )
+        .unwrap();
+
+        await!(sender.send(5u8)).unwrap();
+        assert_eq!(await!(remote_receiver.next()).unwrap(), vec![5u8]);
+
+        await!(remote_sender.send(vec![1u8])).unwrap();
+        assert_eq!(await!(receiver.next()).unwrap(), 1u8);
+        await!(remote_sender.send(vec![2u8])).unwrap();
+        assert_eq!(await!(receiver.next()).unwrap(), 2u8);
+
+        // Invalid message should close the receive stream:
+        await!(remote_sender.send(vec![1u8, 2u8])).unwrap();
+        assert!(await!(receiver.next()).is_none());
+    }
+
+    #[test]
+    fn test_framed_basic() {
+        let test_executor = TestExecutor::new();
+        let res = test_executor.run(task_framed_basic(test_executor.clone()));
+        assert!(res.is_output());
+    }
+
+    async fn task_sequenced_basic(test_executor: TestExecutor) {
+        let (a_sender, b_receiver) = mpsc::channel::<Vec<u8>>(0);
+        let (b_sender, a_receiver) = mpsc::channel::<Vec<u8>>(0);
+
+        let (mut a_sender, mut a_receiver) =
+            sequenced((a_sender, a_receiver), test_executor.clone()).unwrap();
+        let (mut b_sender, mut b_receiver) =
+            sequenced((b_sender, b_receiver), test_executor.clone()).unwrap();
+
+        for i in 0..4u8 {
+            await!(a_sender.send(vec![i, i])).unwrap();
+            assert_eq!(await!(b_receiver.next()).unwrap(), vec![i, i]);
+        }
+        await!(b_sender.send(vec![])).unwrap();
+        assert_eq!(await!(a_receiver.next()).unwrap(), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn test_sequenced_basic() {
+        let test_executor = TestExecutor::new();
+        let res = test_executor.run(task_sequenced_basic(test_executor.clone()));
+        assert!(res.is_output());
+    }
+
+    async fn task_sequenced_out_of_order(test_executor: TestExecutor) {
+        let (local_sender, _remote_receiver) = mpsc::channel::<Vec<u8>>(0);
+        let (mut remote_sender, local_receiver) = mpsc::channel::<Vec<u8>>(0);
+
+        let (_sender, mut receiver) =
+            sequenced((local_sender, local_receiver), test_executor.clone()).unwrap();
+
+        let seq_frame = |seq_num: u64, data: &[u8]| {
+            let mut frame = u64_to_be_bytes(seq_num).to_vec();
+            frame.extend_from_slice(data);
+            frame
+        };
+
+        await!(remote_sender.send(seq_frame(0, &[1]))).unwrap();
+        assert_eq!(await!(receiver.next()).unwrap(), vec![1]);
+
+        // Frame 1 is skipped. This should close the receiver:
+        await!(remote_sender.send(seq_frame(2, &[3]))).unwrap();
+        assert!(await!(receiver.next()).is_none());
+    }
+
+    #[test]
+    fn test_sequenced_out_of_order() {
+        let test_executor = TestExecutor::new();
+        let res = test_executor.run(task_sequenced_out_of_order(test_executor.clone()));
+        assert!(res.is_output());
+    }
+}
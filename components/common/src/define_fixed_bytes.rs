@@ -1,11 +1,221 @@
+use std::fmt;
+
+use serde::de::{DeserializeSeed, Deserializer, Error, SeqAccess, Visitor};
+use serde::ser::{Serialize, SerializeTuple, Serializer};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FromHexError {
+    /// The input has an odd number of hex digits.
+    OddLength,
+    /// The input decodes into a wrong amount of bytes.
+    InvalidLength { expected: usize, found: usize },
+    /// The input contains a character that is not a hex digit.
+    InvalidHexCharacter { c: char, index: usize },
+}
+
+impl fmt::Display for FromHexError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FromHexError::OddLength => write!(f, "odd number of hex digits"),
+            FromHexError::InvalidLength { expected, found } => write!(
+                f,
+                "invalid length: expected {} bytes, found {} bytes",
+                expected, found
+            ),
+            FromHexError::InvalidHexCharacter { c, index } => {
+                write!(f, "invalid hex character {:?} at index {}", c, index)
+            }
+        }
+    }
+}
+
+/// Encode bytes as an upper case hex string.
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02X}", byte)).collect()
+}
+
+/// Decode a hex string (upper or lower case) into `buff`.
+/// The amount of hex digits must match the size of `buff` exactly.
+pub fn from_hex(hex_str: &str, buff: &mut [u8]) -> Result<(), FromHexError> {
+    let hex_bytes = hex_str.as_bytes();
+    if hex_bytes.len() % 2 != 0 {
+        return Err(FromHexError::OddLength);
+    }
+    if hex_bytes.len() / 2 != buff.len() {
+        return Err(FromHexError::InvalidLength {
+            expected: buff.len(),
+            found: hex_bytes.len() / 2,
+        });
+    }
+
+    let hex_digit = |index: usize| -> Result<u8, FromHexError> {
+        let c = hex_bytes[index];
+        match c {
+            b'0'..=b'9' => Ok(c - b'0'),
+            b'a'..=b'f' => Ok(c - b'a' + 10),
+            b'A'..=b'F' => Ok(c - b'A' + 10),
+            _ => Err(FromHexError::InvalidHexCharacter {
+                c: char::from(c),
+                index,
+            }),
+        }
+    };
+
+    for (i, byte) in buff.iter_mut().enumerate() {
+        *byte = (hex_digit(2 * i)? << 4) | hex_digit(2 * i + 1)?;
+    }
+    Ok(())
+}
+
+/// Serialize a fixed size byte array as a tuple of its bytes.
+/// This is the same representation serde derives for arrays of up to 32 bytes (and the one
+/// produced by `BigArray`), but works for any length.
+pub struct FixedBytesRef<'a>(pub &'a [u8]);
+
+impl<'a> Serialize for FixedBytesRef<'a> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut seq = serializer.serialize_tuple(self.0.len())?;
+        for elem in self.0 {
+            seq.serialize_element(elem)?;
+        }
+        seq.end()
+    }
+}
+
+/// Deserialize a tuple of bytes into a fixed size buffer.
+struct FixedBytesSeed<'a>(&'a mut [u8]);
+
+impl<'de, 'a> DeserializeSeed<'de> for FixedBytesSeed<'a> {
+    type Value = ();
+
+    fn deserialize<D>(self, deserializer: D) -> Result<(), D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let len = self.0.len();
+        deserializer.deserialize_tuple(len, self)
+    }
+}
+
+impl<'de, 'a> Visitor<'de> for FixedBytesSeed<'a> {
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "an array of length {}", self.0.len())
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> Result<(), A::Error>
+    where
+        A: SeqAccess<'de>,
+    {
+        for i in 0..self.0.len() {
+            let byte = seq
+                .next_element()?
+                .ok_or_else(|| Error::invalid_length(i, &self))?;
+            self.0[i] = byte;
+        }
+        Ok(())
+    }
+}
+
+/// Visits the newtype struct wrapping the array of bytes.
+struct FixedBytesNewtypeVisitor<'a>(&'a mut [u8]);
+
+impl<'de, 'a> Visitor<'de> for FixedBytesNewtypeVisitor<'a> {
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "a newtype struct of {} bytes", self.0.len())
+    }
+
+    fn visit_newtype_struct<D>(self, deserializer: D) -> Result<(), D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        FixedBytesSeed(self.0).deserialize(deserializer)
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> Result<(), A::Error>
+    where
+        A: SeqAccess<'de>,
+    {
+        seq.next_element_seed(FixedBytesSeed(self.0))?
+            .ok_or_else(|| Error::invalid_length(0, &"a newtype struct"))
+    }
+}
+
+/// Deserialize a newtype struct named `name` wrapping a fixed size array of bytes into `buff`.
+/// Matches the representation serde derives for `struct Name([u8; LEN])`.
+pub fn deserialize_fixed_bytes<'de, D>(
+    deserializer: D,
+    name: &'static str,
+    buff: &mut [u8],
+) -> Result<(), D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserializer.deserialize_newtype_struct(name, FixedBytesNewtypeVisitor(buff))
+}
+
 #[macro_export]
 macro_rules! define_fixed_bytes {
     ($name:ident, $len:expr) => {
-        #[derive(
-            Default, Debug, Clone, Eq, PartialEq, Hash, PartialOrd, Ord, Serialize, Deserialize,
-        )]
+        #[derive(Default, Debug, Clone, Eq, PartialEq, Hash, PartialOrd, Ord)]
         pub struct $name([u8; $len]);
 
+        $crate::impl_fixed_bytes_conversions!($name, $len);
+        $crate::impl_fixed_bytes_serde!($name, $len);
+    };
+}
+
+/// Define a fixed bytes type whose equality check runs in constant time.
+/// Use this for secrets and tokens (For example: signatures or reset tokens), where an early
+/// exit on the first differing byte could leak information through timing.
+#[macro_export]
+macro_rules! define_fixed_bytes_ct {
+    ($name:ident, $len:expr) => {
+        #[derive(Clone)]
+        pub struct $name([u8; $len]);
+
+        $crate::impl_fixed_bytes_conversions!($name, $len);
+        $crate::impl_fixed_bytes_serde!($name, $len);
+
+        impl ::std::convert::From<[u8; $len]> for $name {
+            #[inline]
+            fn from(src: [u8; $len]) -> $name {
+                $name(src)
+            }
+        }
+
+        impl PartialEq for $name {
+            /// Constant time comparison: All bytes are always visited.
+            #[inline]
+            fn eq(&self, other: &$name) -> bool {
+                let diff = self
+                    .0
+                    .iter()
+                    .zip(other.0.iter())
+                    .fold(0u8, |acc, (a, b)| acc | (a ^ b));
+                diff == 0
+            }
+        }
+
+        impl Eq for $name {}
+
+        impl ::std::fmt::Debug for $name {
+            fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+                ::std::fmt::Debug::fmt(&self.0[..], f)
+            }
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! impl_fixed_bytes_conversions {
+    ($name:ident, $len:expr) => {
         impl $name {
             #[allow(unused)]
             #[inline]
@@ -13,6 +223,35 @@ macro_rules! define_fixed_bytes {
                 &self.0
             }
         }
+        impl $name {
+            /// Encode as an upper case hex string.
+            #[allow(unused)]
+            pub fn to_hex(&self) -> String {
+                $crate::define_fixed_bytes::to_hex(&self.0)
+            }
+
+            /// Decode from a hex string. Both upper and lower case digits are accepted.
+            #[allow(unused)]
+            pub fn from_hex(
+                hex_str: &str,
+            ) -> Result<$name, $crate::define_fixed_bytes::FromHexError> {
+                let mut inner = [0x00u8; $len];
+                $crate::define_fixed_bytes::from_hex(hex_str, &mut inner)?;
+                Ok($name(inner))
+            }
+        }
+        impl ::std::fmt::Display for $name {
+            fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+                write!(f, "{}", self.to_hex())
+            }
+        }
+        impl ::std::str::FromStr for $name {
+            type Err = $crate::define_fixed_bytes::FromHexError;
+
+            fn from_str(s: &str) -> Result<$name, Self::Err> {
+                $name::from_hex(s)
+            }
+        }
         impl AsRef<[u8]> for $name {
             #[inline]
             fn as_ref(&self) -> &[u8] {
@@ -70,3 +309,37 @@ macro_rules! define_fixed_bytes {
         }
     };
 }
+
+/// Serialize and deserialize the fixed bytes type as a newtype struct over a tuple of bytes.
+/// This is the representation serde would derive, but is not limited to arrays of up to 32 bytes.
+#[macro_export]
+macro_rules! impl_fixed_bytes_serde {
+    ($name:ident, $len:expr) => {
+        impl ::serde::Serialize for $name {
+            fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+            where
+                S: ::serde::Serializer,
+            {
+                serializer.serialize_newtype_struct(
+                    stringify!($name),
+                    &$crate::define_fixed_bytes::FixedBytesRef(&self.0[..]),
+                )
+            }
+        }
+
+        impl<'de> ::serde::Deserialize<'de> for $name {
+            fn deserialize<D>(deserializer: D) -> Result<$name, D::Error>
+            where
+                D: ::serde::Deserializer<'de>,
+            {
+                let mut inner = [0x00u8; $len];
+                $crate::define_fixed_bytes::deserialize_fixed_bytes(
+                    deserializer,
+                    stringify!($name),
+                    &mut inner,
+                )?;
+                Ok($name(inner))
+            }
+        }
+    };
+}
@@ -1,6 +1,9 @@
+use std::sync::Arc;
+
 use crate::conn::{BoxFuture, FutTransform};
 use futures::channel::{mpsc, oneshot};
-use futures::SinkExt;
+use futures::task::{Spawn, SpawnExt};
+use futures::{SinkExt, StreamExt};
 
 pub struct ConnRequest<A, O> {
     pub address: A,
@@ -13,14 +16,75 @@ impl<A, O> ConnRequest<A, O> {
     }
 }
 
+type DelayFn = Arc<dyn Fn() -> BoxFuture<'static, ()> + Send + Sync>;
+
 /// A connector that contains only one pre-created connection.
 pub struct DummyConnector<A, O> {
     req_sender: mpsc::Sender<ConnRequest<A, O>>,
+    opt_delay: Option<DelayFn>,
 }
 
 impl<A, O> DummyConnector<A, O> {
     pub fn new(req_sender: mpsc::Sender<ConnRequest<A, O>>) -> Self {
-        DummyConnector { req_sender }
+        DummyConnector {
+            req_sender,
+            opt_delay: None,
+        }
+    }
+
+    /// Create a connector that waits for the future returned by `delay` before every connection
+    /// attempt is forwarded to `req_sender`. A new delay future is created for every attempt.
+    ///
+    /// See `timer::utils::dummy_connector_with_latency` for a delay driven by a timer.
+    pub fn with_delay<F>(req_sender: mpsc::Sender<ConnRequest<A, O>>, delay: F) -> Self
+    where
+        F: Fn() -> BoxFuture<'static, ()> + Send + Sync + 'static,
+    {
+        DummyConnector {
+            req_sender,
+            opt_delay: Some(Arc::new(delay)),
+        }
+    }
+}
+
+impl<A, O> DummyConnector<A, O>
+where
+    A: Send + 'static,
+    O: Send + 'static,
+{
+    /// Create a connector that replies to every connection attempt with the next scripted result,
+    /// regardless of the requested address. Useful for deterministically simulating failing
+    /// connection attempts.
+    ///
+    /// The script decides what happens once it is exhausted:
+    /// - `results.into_iter().cycle()` replays the script from its beginning.
+    /// - `results.into_iter().chain(iter::repeat_with(|| None))` returns a terminal result forever.
+    ///
+    /// If the script ends, any further connection attempt will panic.
+    pub fn with_script<I, S>(script: I, mut spawner: S) -> Self
+    where
+        I: IntoIterator<Item = O>,
+        I::IntoIter: Send + 'static,
+        S: Spawn,
+    {
+        let (req_sender, mut req_receiver) = mpsc::channel::<ConnRequest<A, O>>(0);
+        let mut script_iter = script.into_iter();
+
+        spawner
+            .spawn(async move {
+                while let Some(conn_request) = await!(req_receiver.next()) {
+                    match script_iter.next() {
+                        Some(result) => conn_request.reply(result),
+                        None => {
+                            error!("DummyConnector: Script exhausted");
+                            return;
+                        }
+                    }
+                }
+            })
+            .unwrap();
+
+        DummyConnector::new(req_sender)
     }
 }
 
@@ -33,6 +97,7 @@ impl<A, O> Clone for DummyConnector<A, O> {
     fn clone(&self) -> DummyConnector<A, O> {
         DummyConnector {
             req_sender: self.req_sender.clone(),
+            opt_delay: self.opt_delay.clone(),
         }
     }
 }
@@ -52,10 +117,60 @@ where
             response_sender,
         };
 
+        let opt_delay_fut = self.opt_delay.as_ref().map(|delay| delay());
+
         let fut_conn_pair = async move {
+            if let Some(delay_fut) = opt_delay_fut {
+                await!(delay_fut);
+            }
             await!(self.req_sender.send(conn_request)).unwrap();
             await!(response_receiver).unwrap()
         };
         Box::pin(fut_conn_pair)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_executor::TestExecutor;
+    use std::iter;
+
+    async fn task_dummy_connector_script_terminal(test_executor: TestExecutor) {
+        let script = vec![Some(1u32), None, Some(3u32)]
+            .into_iter()
+            .chain(iter::repeat_with(|| None));
+        let mut connector = DummyConnector::<u32, Option<u32>>::with_script(script, test_executor);
+
+        assert_eq!(await!(connector.transform(0)), Some(1));
+        assert_eq!(await!(connector.transform(0)), None);
+        assert_eq!(await!(connector.transform(0)), Some(3));
+        for _ in 0..8 {
+            assert_eq!(await!(connector.transform(0)), None);
+        }
+    }
+
+    #[test]
+    fn test_dummy_connector_script_terminal() {
+        let test_executor = TestExecutor::new();
+        let res = test_executor.run(task_dummy_connector_script_terminal(test_executor.clone()));
+        assert!(res.is_output());
+    }
+
+    async fn task_dummy_connector_script_loop(test_executor: TestExecutor) {
+        let script = vec![Some(1u32), None].into_iter().cycle();
+        let mut connector = DummyConnector::<u32, Option<u32>>::with_script(script, test_executor);
+
+        for _ in 0..4 {
+            assert_eq!(await!(connector.transform(0)), Some(1));
+            assert_eq!(await!(connector.transform(0)), None);
+        }
+    }
+
+    #[test]
+    fn test_dummy_connector_script_loop() {
+        let test_executor = TestExecutor::new();
+        let res = test_executor.run(task_dummy_connector_script_loop(test_executor.clone()));
+        assert!(res.is_output());
+    }
+}
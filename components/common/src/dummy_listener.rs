@@ -1,7 +1,9 @@
+use std::sync::{Arc, Mutex};
+
 use crate::conn::Listener;
 use futures::channel::mpsc;
 use futures::task::{Spawn, SpawnExt};
-use futures::SinkExt;
+use futures::{SinkExt, StreamExt};
 
 #[allow(unused)]
 pub struct ListenRequest<CONN, CONF, AR> {
@@ -10,10 +12,35 @@ pub struct ListenRequest<CONN, CONF, AR> {
     pub arg: AR,
 }
 
+/// A connection accepted by a DummyListener.
+/// Allows the DummyListener to keep track of the remote side of every accepted connection.
+pub trait AcceptedConn {
+    type Remote: Clone + Send + 'static;
+
+    /// Identity of the remote side of the connection (Usually a public key)
+    fn remote(&self) -> Self::Remote;
+}
+
+impl<K, T> AcceptedConn for (K, T)
+where
+    K: Clone + Send + 'static,
+{
+    type Remote = K;
+
+    fn remote(&self) -> K {
+        self.0.clone()
+    }
+}
+
 /// A test util: A mock Listener.
-pub struct DummyListener<S, CONN, CONF, AR> {
+pub struct DummyListener<S, CONN, CONF, AR>
+where
+    CONN: AcceptedConn,
+{
     req_sender: mpsc::Sender<ListenRequest<CONN, CONF, AR>>,
     spawner: S,
+    /// Remote sides of all connections accepted so far. Shared between all clones.
+    accepted: Arc<Mutex<Vec<CONN::Remote>>>,
 }
 
 // TODO: Why didn't the automatic #[derive(Clone)] works for DummyListener?
@@ -22,11 +49,13 @@ pub struct DummyListener<S, CONN, CONF, AR> {
 impl<S, CONN, CONF, AR> Clone for DummyListener<S, CONN, CONF, AR>
 where
     S: Clone,
+    CONN: AcceptedConn,
 {
     fn clone(&self) -> DummyListener<S, CONN, CONF, AR> {
         DummyListener {
             req_sender: self.req_sender.clone(),
             spawner: self.spawner.clone(),
+            accepted: self.accepted.clone(),
         }
     }
 }
@@ -34,7 +63,7 @@ where
 impl<S, CONN, CONF, AR> DummyListener<S, CONN, CONF, AR>
 where
     S: Spawn,
-    CONN: Send + 'static,
+    CONN: AcceptedConn + Send + 'static,
     CONF: Send + 'static,
     AR: Send + 'static,
 {
@@ -45,14 +74,26 @@ where
         DummyListener {
             req_sender,
             spawner,
+            accepted: Arc::new(Mutex::new(Vec::new())),
         }
     }
+
+    /// Remote sides of all the connections accepted so far, in order of arrival.
+    /// Includes connections accepted by clones of this listener.
+    pub fn accepted_connections(&self) -> Vec<CONN::Remote> {
+        self.accepted.lock().unwrap().clone()
+    }
+
+    /// Amount of connections accepted so far.
+    pub fn num_accepted_connections(&self) -> usize {
+        self.accepted.lock().unwrap().len()
+    }
 }
 
 impl<S, CONN, CONF, AR> Listener for DummyListener<S, CONN, CONF, AR>
 where
     S: Spawn,
-    CONN: Send + 'static,
+    CONN: AcceptedConn + Send + 'static,
     CONF: Send + 'static,
     AR: Send + 'static,
 {
@@ -61,11 +102,12 @@ where
     type Arg = AR;
 
     fn listen(self, arg: AR) -> (mpsc::Sender<CONF>, mpsc::Receiver<CONN>) {
-        let (conn_sender, conn_receiver) = mpsc::channel(0);
+        let (mut conn_sender, conn_receiver) = mpsc::channel(0);
+        let (inner_conn_sender, mut inner_conn_receiver) = mpsc::channel::<CONN>(0);
         let (config_sender, config_receiver) = mpsc::channel(0);
 
         let listen_request = ListenRequest {
-            conn_sender,
+            conn_sender: inner_conn_sender,
             config_receiver,
             arg,
         };
@@ -73,6 +115,7 @@ where
         let DummyListener {
             mut spawner,
             mut req_sender,
+            accepted,
         } = self;
 
         spawner
@@ -84,6 +127,51 @@ where
             })
             .unwrap();
 
+        // Keep track of accepted connections:
+        spawner
+            .spawn(async move {
+                while let Some(conn) = await!(inner_conn_receiver.next()) {
+                    accepted.lock().unwrap().push(conn.remote());
+                    if await!(conn_sender.send(conn)).is_err() {
+                        return;
+                    }
+                }
+            })
+            .unwrap();
+
         (config_sender, conn_receiver)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_executor::TestExecutor;
+
+    async fn task_dummy_listener_accepted_connections(test_executor: TestExecutor) {
+        let (req_sender, mut req_receiver) = mpsc::channel(0);
+        let listener =
+            DummyListener::<_, (u32, u8), (), ()>::new(req_sender, test_executor.clone());
+        let c_listener = listener.clone();
+        let (_config_sender, mut conn_receiver) = listener.listen(());
+
+        let mut listen_request = await!(req_receiver.next()).unwrap();
+        for remote in &[1u32, 2, 1] {
+            await!(listen_request.conn_sender.send((*remote, 0u8))).unwrap();
+            let (conn_remote, _) = await!(conn_receiver.next()).unwrap();
+            assert_eq!(conn_remote, *remote);
+        }
+
+        assert_eq!(c_listener.num_accepted_connections(), 3);
+        assert_eq!(c_listener.accepted_connections(), vec![1u32, 2, 1]);
+    }
+
+    #[test]
+    fn test_dummy_listener_accepted_connections() {
+        let test_executor = TestExecutor::new();
+        let res = test_executor.run(task_dummy_listener_accepted_connections(
+            test_executor.clone(),
+        ));
+        assert!(res.is_output());
+    }
+}
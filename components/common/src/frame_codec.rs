@@ -10,19 +10,26 @@
 //!
 //! - `length` (unsigned 4 bytes integer, big endian): the length (in bytes) of the remaining data
 //! - `data` (bytes, with length of `length`): the actual data
+//!
+//! The length prefix is always encoded in big endian (network order), regardless of the platform.
+//! Other implementations of this protocol must use the same byte order.
 
-use derive_more::*;
-
+use std::cmp;
 use std::io;
 use std::mem;
-use std::cmp;
-use bytes::{BytesMut};
-use tokio_io::codec::{Encoder, Decoder};
-use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use bytes::BytesMut;
+use tokio::codec::{Decoder, Encoder};
 
 const MAX_FRAME_LEN: usize = 1 << 20;
 
+/// Length of the frame length prefix, in bytes.
+const LENGTH_PREFIX_LEN: usize = 4;
+
+/// Byte order of the frame length prefix (Network order).
+type LengthPrefixOrder = BigEndian;
+
 /// Break a stream of bytes into chunks, using prefix length frames.
 /// Every frame begins with a 32 bit length prefix, after which the data follows.
 enum FrameCodecState {
@@ -35,15 +42,14 @@ enum FrameCodecState {
         length: usize,
         // Accumulated frame bytes:
         accum_frame: Vec<u8>,
-    }
+    },
 }
 
-
 pub struct FrameCodec {
     state: FrameCodecState,
 }
 
-#[derive(Debug, From)]
+#[derive(Debug)]
 pub enum FrameCodecError {
     SerializeLengthError(io::Error),
     DeserializeLengthError(io::Error),
@@ -52,6 +58,12 @@ pub enum FrameCodecError {
     SentFrameLenTooLarge,
 }
 
+impl From<io::Error> for FrameCodecError {
+    fn from(e: io::Error) -> Self {
+        FrameCodecError::IoError(e)
+    }
+}
+
 impl FrameCodec {
     pub fn new() -> Self {
         FrameCodec {
@@ -73,14 +85,14 @@ impl Decoder for FrameCodec {
                 FrameCodecState::Empty => unreachable!(),
                 FrameCodecState::CollectingLength { mut accum_length } => {
                     // Try to add as many as possible bytes to accum_length:
-                    let missing_length_bytes = 4 - accum_length.len();
+                    let missing_length_bytes = LENGTH_PREFIX_LEN - accum_length.len();
                     let bytes_to_read = cmp::min(missing_length_bytes, buf.len());
                     accum_length.extend(buf.split_to(bytes_to_read));
 
-                    if accum_length.len() == 4 {
+                    if accum_length.len() == LENGTH_PREFIX_LEN {
                         // Done reading length.
                         let mut rdr = io::Cursor::new(accum_length);
-                        let frame_length = match rdr.read_u32::<BigEndian>() {
+                        let frame_length = match rdr.read_u32::<LengthPrefixOrder>() {
                             Ok(frame_length) => frame_length,
                             Err(e) => return Err(FrameCodecError::DeserializeLengthError(e)),
                         } as usize;
@@ -89,20 +101,21 @@ impl Decoder for FrameCodec {
                             return Err(FrameCodecError::ReceivedFrameLenTooLarge);
                         }
 
+                        // May continue from here to CollectingFrame state in the next iteration of
+                        // the loop.
                         self.state = FrameCodecState::CollectingFrame {
                             length: frame_length,
                             accum_frame: Vec::new(),
                         };
-                        // May continue from here to CollectingFrame state in the next iteration of
-                        // the loop.
                     } else {
-                        self.state = FrameCodecState::CollectingLength {
-                            accum_length,
-                        };
+                        self.state = FrameCodecState::CollectingLength { accum_length };
                         return Ok(None);
                     }
-                },
-                FrameCodecState::CollectingFrame { length, mut accum_frame } => {
+                }
+                FrameCodecState::CollectingFrame {
+                    length,
+                    mut accum_frame,
+                } => {
                     let missing_frame_bytes = length - accum_frame.len();
                     let bytes_to_read = cmp::min(missing_frame_bytes, buf.len());
                     accum_frame.extend(buf.split_to(bytes_to_read));
@@ -115,7 +128,6 @@ impl Decoder for FrameCodec {
 
                         // Return a completed message
                         return Ok(Some(accum_frame));
-
                     } else {
                         self.state = FrameCodecState::CollectingFrame {
                             length,
@@ -123,7 +135,7 @@ impl Decoder for FrameCodec {
                         };
                         return Ok(None);
                     }
-                },
+                }
             };
         }
     }
@@ -140,8 +152,8 @@ impl Encoder for FrameCodec {
 
         // Encode length prefix as bytes:
         let mut wtr = vec![];
-        match wtr.write_u32::<BigEndian>(data.len() as u32) {
-            Ok(()) => {},
+        match wtr.write_u32::<LengthPrefixOrder>(data.len() as u32) {
+            Ok(()) => {}
             Err(e) => return Err(FrameCodecError::SerializeLengthError(e)),
         };
 
@@ -161,23 +173,22 @@ mod tests {
     fn test_prefix_frame_encoder_basic() {
         let mut prefix_frame_codec = FrameCodec::new();
         let mut buf = BytesMut::new();
-        match prefix_frame_codec.encode(vec![1,2,3,4,5], &mut buf) {
-            Ok(()) => {},
+        match prefix_frame_codec.encode(vec![1, 2, 3, 4, 5], &mut buf) {
+            Ok(()) => {}
             Err(_) => panic!("Error encoding data!"),
         };
-        assert_eq!(buf, vec![0,0,0,5,1,2,3,4,5]);
+        assert_eq!(buf, vec![0, 0, 0, 5, 1, 2, 3, 4, 5]);
     }
 
-
     #[test]
     fn test_prefix_frame_encoder_empty_data() {
         let mut prefix_frame_codec = FrameCodec::new();
         let mut buf = BytesMut::new();
         match prefix_frame_codec.encode(vec![], &mut buf) {
-            Ok(()) => {},
+            Ok(()) => {}
             _ => panic!("Error encoding data!"),
         };
-        assert_eq!(buf, vec![0,0,0,0]);
+        assert_eq!(buf, vec![0, 0, 0, 0]);
     }
 
     #[test]
@@ -185,7 +196,7 @@ mod tests {
         let mut prefix_frame_codec = FrameCodec::new();
         let mut buf = BytesMut::new();
         match prefix_frame_codec.encode(vec![0; MAX_FRAME_LEN], &mut buf) {
-            Ok(()) => {},
+            Ok(()) => {}
             _ => panic!("Error encoding data!"),
         };
         assert_eq!(buf.len(), 4 + MAX_FRAME_LEN);
@@ -196,7 +207,7 @@ mod tests {
         let mut prefix_frame_codec = FrameCodec::new();
         let mut buf = BytesMut::new();
         match prefix_frame_codec.encode(vec![0; MAX_FRAME_LEN + 1], &mut buf) {
-            Err(FrameCodecError::SentFrameLenTooLarge) => {},
+            Err(FrameCodecError::SentFrameLenTooLarge) => {}
             _ => panic!("Test failed"),
         };
     }
@@ -205,34 +216,34 @@ mod tests {
     fn test_prefix_frame_decoder() {
         let mut prefix_frame_codec = FrameCodec::new();
         let mut buf = BytesMut::new();
-        buf.extend(vec![0,0]);
+        buf.extend(vec![0, 0]);
         match prefix_frame_codec.decode(&mut buf) {
-            Ok(None) => {},
+            Ok(None) => {}
             _ => panic!("Test failed1!"),
         };
         buf.extend(vec![0]);
         match prefix_frame_codec.decode(&mut buf) {
-            Ok(None) => {},
+            Ok(None) => {}
             _ => panic!("Test failed2!"),
         };
         buf.extend(vec![5]);
         match prefix_frame_codec.decode(&mut buf) {
-            Ok(None) => {},
+            Ok(None) => {}
             _ => panic!("Test failed3!"),
         };
-        buf.extend(vec![1,2,3,4]);
+        buf.extend(vec![1, 2, 3, 4]);
         match prefix_frame_codec.decode(&mut buf) {
-            Ok(None) => {},
+            Ok(None) => {}
             _ => panic!("Test failed4!"),
         };
-        buf.extend(vec![5,6,7,8]);
+        buf.extend(vec![5, 6, 7, 8]);
         match prefix_frame_codec.decode(&mut buf) {
-            Ok(Some(v)) => assert_eq!(v,vec![1,2,3,4,5]),
+            Ok(Some(v)) => assert_eq!(v, vec![1, 2, 3, 4, 5]),
             _ => panic!("Test failed5!"),
         };
 
         // Make sure that we still have the remainder:
-        assert_eq!(buf, vec![6,7,8]);
+        assert_eq!(buf, vec![6, 7, 8]);
     }
 
     #[test]
@@ -242,11 +253,123 @@ mod tests {
 
         // Encode length prefix as bytes:
         let mut wtr = vec![];
-        wtr.write_u32::<BigEndian>((MAX_FRAME_LEN + 1) as u32).unwrap();
+        wtr.write_u32::<BigEndian>((MAX_FRAME_LEN + 1) as u32)
+            .unwrap();
         buf.extend(wtr);
         match prefix_frame_codec.decode(&mut buf) {
-            Err(FrameCodecError::ReceivedFrameLenTooLarge) => {},
+            Err(FrameCodecError::ReceivedFrameLenTooLarge) => {}
+            _ => panic!("Test failed1!"),
+        };
+    }
+
+    /// Decode all the frames that are currently available in buf.
+    fn decode_all(prefix_frame_codec: &mut FrameCodec, buf: &mut BytesMut) -> Vec<Vec<u8>> {
+        let mut frames = Vec::new();
+        while let Some(frame) = prefix_frame_codec.decode(buf).unwrap() {
+            frames.push(frame);
+        }
+        frames
+    }
+
+    /// Encode a few frames of different sizes, including an empty frame.
+    fn encode_frames(frames: &[Vec<u8>]) -> BytesMut {
+        let mut prefix_frame_codec = FrameCodec::new();
+        let mut buf = BytesMut::new();
+        for frame in frames {
+            prefix_frame_codec.encode(frame.clone(), &mut buf).unwrap();
+        }
+        buf
+    }
+
+    fn test_frames() -> Vec<Vec<u8>> {
+        vec![
+            vec![1, 2, 3, 4, 5],
+            vec![],
+            vec![6],
+            vec![7; 0x1000],
+            vec![],
+            vec![8, 9],
+        ]
+    }
+
+    #[test]
+    fn test_prefix_frame_decoder_split_reads() {
+        let frames = test_frames();
+        let encoded = encode_frames(&frames);
+
+        // Feed the bytes one at a time:
+        let mut prefix_frame_codec = FrameCodec::new();
+        let mut buf = BytesMut::new();
+        let mut decoded = Vec::new();
+        for byte in encoded.iter() {
+            buf.extend(vec![*byte]);
+            decoded.extend(decode_all(&mut prefix_frame_codec, &mut buf));
+        }
+
+        assert_eq!(decoded, frames);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_prefix_frame_decoder_coalesced_reads() {
+        let frames = test_frames();
+        let encoded = encode_frames(&frames);
+
+        // Feed all the bytes at once:
+        let mut prefix_frame_codec = FrameCodec::new();
+        let mut buf = encoded.clone();
+        assert_eq!(decode_all(&mut prefix_frame_codec, &mut buf), frames);
+        assert!(buf.is_empty());
+
+        // Feed the bytes in chunks that do not match the frame boundaries:
+        let mut prefix_frame_codec = FrameCodec::new();
+        let mut buf = BytesMut::new();
+        let mut decoded = Vec::new();
+        for chunk in encoded.chunks(7) {
+            buf.extend(chunk.to_vec());
+            decoded.extend(decode_all(&mut prefix_frame_codec, &mut buf));
+        }
+        assert_eq!(decoded, frames);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_prefix_frame_golden_bytes() {
+        // A frame of length 0x010203, with a length prefix in big endian:
+        let data: Vec<u8> = (0..0x010203usize).map(|i| (i % 0x100) as u8).collect();
+        let mut golden = vec![0x00, 0x01, 0x02, 0x03];
+        golden.extend(data.iter().cloned());
+
+        let mut prefix_frame_codec = FrameCodec::new();
+        let mut buf = BytesMut::new();
+        prefix_frame_codec.encode(data.clone(), &mut buf).unwrap();
+        assert_eq!(&buf[..], &golden[..]);
+
+        let mut prefix_frame_codec = FrameCodec::new();
+        let mut buf = BytesMut::new();
+        buf.extend(golden);
+        match prefix_frame_codec.decode(&mut buf) {
+            Ok(Some(v)) => assert_eq!(v, data),
+            _ => panic!("Test failed!"),
+        };
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_prefix_frame_decoder_empty_frame() {
+        let mut prefix_frame_codec = FrameCodec::new();
+        let mut buf = BytesMut::new();
+        buf.extend(vec![0, 0, 0, 0]);
+        match prefix_frame_codec.decode(&mut buf) {
+            Ok(Some(v)) => assert!(v.is_empty()),
             _ => panic!("Test failed1!"),
         };
+        assert!(buf.is_empty());
+
+        // No more frames:
+        match prefix_frame_codec.decode(&mut buf) {
+            Ok(None) => {}
+            _ => panic!("Test failed2!"),
+        };
     }
 }
@@ -1,4 +1,5 @@
 use futures::channel::mpsc;
+use futures::compat::{Compat, Compat01As03};
 use futures::sink::{Sink, SinkExt};
 use futures::{stream, Future, FutureExt, StreamExt};
 use std::marker::Unpin;
@@ -46,11 +47,31 @@ pub fn future_select<T>(
     s.into_future().map(|(opt_item, _s)| opt_item.unwrap())
 }
 
+/// Convert a futures 0.1 future into a futures 0.3 future, so that it could be awaited from new
+/// style async code.
+pub fn compat01to03<F>(fut01: F) -> impl Future<Output = Result<F::Item, F::Error>>
+where
+    F: TokioFuture,
+{
+    Compat01As03::new(fut01)
+}
+
+/// Convert a futures 0.3 future that outputs a Result into a futures 0.1 future, so that it
+/// could be used from old style code.
+pub fn compat03to01<F, T, E>(fut03: F) -> impl TokioFuture<Item = T, Error = E>
+where
+    F: Future<Output = Result<T, E>> + Unpin,
+{
+    Compat::new(fut03)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use futures::executor::LocalPool;
+    use futures::executor::{block_on, LocalPool};
+    use futures::future;
     use futures::stream::StreamExt;
+    use tokio::prelude::future as future01;
 
     #[test]
     fn test_create_interval() {
@@ -60,4 +81,35 @@ mod tests {
         let mut local_pool = LocalPool::new();
         local_pool.run_until(my_fut);
     }
+
+    async fn add_one01<F>(fut01: F) -> Result<u32, ()>
+    where
+        F: TokioFuture<Item = u32, Error = ()>,
+    {
+        let x = await!(compat01to03(fut01))?;
+        Ok(x + 1)
+    }
+
+    #[test]
+    fn test_compat01to03() {
+        assert_eq!(block_on(add_one01(future01::ok::<u32, ()>(5))), Ok(6));
+        assert_eq!(block_on(add_one01(future01::err::<u32, ()>(()))), Err(()));
+
+        // A lazy 0.1 future is only evaluated when polled from the 0.3 context:
+        let fut01 = future01::lazy(|| future01::ok::<u32, ()>(7));
+        assert_eq!(block_on(add_one01(fut01)), Ok(8));
+    }
+
+    #[test]
+    fn test_compat03to01_roundtrip() {
+        let fut01 = compat03to01(future::ready(Ok::<u32, ()>(3)));
+        assert_eq!(block_on(add_one01(fut01)), Ok(4));
+
+        let fut01 = compat03to01(future::ready(Err::<u32, ()>(())));
+        assert_eq!(block_on(add_one01(fut01)), Err(()));
+
+        // Wait on the converted future from old style code:
+        let fut01 = compat03to01(future::ready(Ok::<u32, ()>(9)));
+        assert_eq!(fut01.wait(), Ok(9));
+    }
 }
@@ -35,6 +35,36 @@ pub fn u32_to_usize(num: u32) -> Option<usize> {
     Some(num as usize)
 }
 
+/// Big endian representation of a u64
+pub fn u64_to_be_bytes(num: u64) -> [u8; 8] {
+    num.to_be_bytes()
+}
+
+/// Parse a big endian representation of a u64
+pub fn be_bytes_to_u64(bytes: &[u8; 8]) -> u64 {
+    u64::from_be_bytes(*bytes)
+}
+
+/// Big endian representation of a u128
+pub fn u128_to_be_bytes(num: u128) -> [u8; 16] {
+    num.to_be_bytes()
+}
+
+/// Parse a big endian representation of a u128
+pub fn be_bytes_to_u128(bytes: &[u8; 16]) -> u128 {
+    u128::from_be_bytes(*bytes)
+}
+
+/// Big endian (Two's complement) representation of an i128
+pub fn i128_to_be_bytes(num: i128) -> [u8; 16] {
+    num.to_be_bytes()
+}
+
+/// Parse a big endian (Two's complement) representation of an i128
+pub fn be_bytes_to_i128(bytes: &[u8; 16]) -> i128 {
+    i128::from_be_bytes(*bytes)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -63,4 +93,44 @@ mod tests {
         assert_eq!(u32_to_usize(1u32), Some(1usize));
         assert_eq!(u32_to_usize(0xffff_ffff_u32), Some(0xffff_ffff_usize));
     }
+
+    #[test]
+    fn test_u64_be_bytes() {
+        assert_eq!(u64_to_be_bytes(0), [0u8; 8]);
+        assert_eq!(u64_to_be_bytes(1), [0, 0, 0, 0, 0, 0, 0, 1]);
+        assert_eq!(u64_to_be_bytes(std::u64::MAX), [0xffu8; 8]);
+
+        for &num in &[0u64, 1, 0x1234_5678_9abc_def0, std::u64::MAX] {
+            assert_eq!(be_bytes_to_u64(&u64_to_be_bytes(num)), num);
+        }
+    }
+
+    #[test]
+    fn test_u128_be_bytes() {
+        assert_eq!(u128_to_be_bytes(0), [0u8; 16]);
+        let mut expected = [0u8; 16];
+        expected[15] = 1;
+        assert_eq!(u128_to_be_bytes(1), expected);
+        assert_eq!(u128_to_be_bytes(std::u128::MAX), [0xffu8; 16]);
+
+        for &num in &[0u128, 1, 0x1234_5678_9abc_def0, std::u128::MAX] {
+            assert_eq!(be_bytes_to_u128(&u128_to_be_bytes(num)), num);
+        }
+    }
+
+    #[test]
+    fn test_i128_be_bytes() {
+        assert_eq!(i128_to_be_bytes(0), [0u8; 16]);
+        assert_eq!(i128_to_be_bytes(-1), [0xffu8; 16]);
+        let mut expected = [0u8; 16];
+        expected[0] = 0x80;
+        assert_eq!(i128_to_be_bytes(std::i128::MIN), expected);
+        let mut expected = [0xffu8; 16];
+        expected[0] = 0x7f;
+        assert_eq!(i128_to_be_bytes(std::i128::MAX), expected);
+
+        for &num in &[0i128, 1, -1, std::i128::MIN, std::i128::MAX] {
+            assert_eq!(be_bytes_to_i128(&i128_to_be_bytes(num)), num);
+        }
+    }
 }
@@ -21,7 +21,6 @@ pub mod big_array;
 #[macro_use]
 pub mod define_fixed_bytes;
 // pub mod async_adapter;
-// pub mod frame_codec;
 pub mod access_control;
 pub mod async_test_utils;
 pub mod caller_info;
@@ -29,6 +28,7 @@ pub mod canonical_serialize;
 pub mod conn;
 pub mod dummy_connector;
 pub mod dummy_listener;
+pub mod frame_codec;
 pub mod futures_compat;
 pub mod multi_consumer;
 pub mod mutable_state;
@@ -1,5 +1,7 @@
 #![allow(trivial_numeric_casts)]
 
+use std::cmp::Ordering;
+
 pub trait SafeSignedArithmetic: Copy {
     type Unsigned;
 
@@ -117,6 +119,92 @@ impl_safe_unsigned_arithmetic!(u32, i32);
 impl_safe_unsigned_arithmetic!(u64, i64);
 impl_safe_unsigned_arithmetic!(u128, i128);
 
+/// Multiply two u128 numbers.
+/// The 256 bit result is returned as a (high, low) pair.
+fn mul_wide_u128(a: u128, b: u128) -> (u128, u128) {
+    let mask = u128::from(u64::max_value());
+    let (a_hi, a_lo) = (a >> 64, a & mask);
+    let (b_hi, b_lo) = (b >> 64, b & mask);
+
+    let lo_lo = a_lo * b_lo;
+    let hi_lo = a_hi * b_lo;
+    let lo_hi = a_lo * b_hi;
+    let hi_hi = a_hi * b_hi;
+
+    // Sum of three numbers smaller than 2^64, can not overflow:
+    let mid = (lo_lo >> 64) + (hi_lo & mask) + (lo_hi & mask);
+
+    let low = (mid << 64) | (lo_lo & mask);
+    let high = hi_hi + (hi_lo >> 64) + (lo_hi >> 64) + (mid >> 64);
+    (high, low)
+}
+
+/// A ratio in the range [0, 1].
+/// `Numerator(n)` represents the ratio `n / 2^64`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ratio {
+    One,
+    Numerator(u64),
+}
+
+impl Ratio {
+    /// Create the largest ratio of the form `n / 2^64` that is not larger than `num / denom`.
+    /// Returns `Ratio::One` if `num >= denom`.
+    ///
+    /// Two nodes that compute a ratio from the same fraction always get the same result.
+    /// Returns None if `denom` is zero.
+    pub fn from_fraction(num: u128, denom: u128) -> Option<Ratio> {
+        if denom == 0 {
+            return None;
+        }
+        if num >= denom {
+            return Some(Ratio::One);
+        }
+
+        // Long division of (num * 2^64) by denom. We know that num < denom,
+        // hence the result fits in a u64:
+        let mut rem = num;
+        let mut quot = 0u64;
+        for _ in 0..64 {
+            let carry = rem >> 127 == 1;
+            rem <<= 1;
+            quot <<= 1;
+            if carry || rem >= denom {
+                rem = rem.wrapping_sub(denom);
+                quot |= 1;
+            }
+        }
+        Some(Ratio::Numerator(quot))
+    }
+
+    /// Exactly compare this ratio with the fraction `num / denom`.
+    /// Returns None if `denom` is zero.
+    pub fn cmp_fraction(&self, num: u128, denom: u128) -> Option<Ordering> {
+        if denom == 0 {
+            return None;
+        }
+        Some(match self {
+            Ratio::One => denom.cmp(&num),
+            // Compare n * denom with num * 2^64:
+            Ratio::Numerator(n) => {
+                mul_wide_u128(u128::from(*n), denom).cmp(&(num >> 64, num << 64))
+            }
+        })
+    }
+
+    /// Calculate `floor(value * ratio)`.
+    pub fn mul_floor(&self, value: u128) -> u128 {
+        match self {
+            Ratio::One => value,
+            Ratio::Numerator(n) => {
+                // value * n < 2^192, hence the result of the division by 2^64 fits in a u128:
+                let (high, low) = mul_wide_u128(value, u128::from(*n));
+                (high << 64) | (low >> 64)
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -181,4 +269,120 @@ mod tests {
         assert_eq!((254_u8).saturating_sub_signed(-1_i8), 255_u8);
         assert_eq!((254_u8).saturating_sub_signed(-3_i8), 255_u8);
     }
+
+    /// A small deterministic pseudo random generator (xorshift), for property tests.
+    struct XorShift(u64);
+
+    impl XorShift {
+        fn next_u64(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn next_u128(&mut self) -> u128 {
+            (u128::from(self.next_u64()) << 64) | u128::from(self.next_u64())
+        }
+    }
+
+    #[test]
+    fn test_mul_wide_u128() {
+        let max = u128::max_value();
+        assert_eq!(mul_wide_u128(0, max), (0, 0));
+        assert_eq!(mul_wide_u128(1, max), (0, max));
+        assert_eq!(mul_wide_u128(1 << 64, 1 << 64), (1, 0));
+        // (2^128 - 1)^2 = 2^256 - 2^129 + 1
+        assert_eq!(mul_wide_u128(max, max), (max - 1, 1));
+    }
+
+    #[test]
+    fn test_ratio_basic() {
+        let half = Ratio::Numerator(1 << 63);
+        assert_eq!(Ratio::from_fraction(1, 2), Some(half));
+        assert_eq!(Ratio::from_fraction(3, 3), Some(Ratio::One));
+        assert_eq!(Ratio::from_fraction(5, 3), Some(Ratio::One));
+        assert_eq!(Ratio::from_fraction(0, 3), Some(Ratio::Numerator(0)));
+        assert_eq!(
+            Ratio::from_fraction(u128::max_value(), u128::max_value()),
+            Some(Ratio::One)
+        );
+        assert_eq!(Ratio::from_fraction(u128::max_value(), 1), Some(Ratio::One));
+        assert_eq!(
+            Ratio::from_fraction(u128::max_value() - 1, u128::max_value()),
+            Some(Ratio::Numerator(u64::max_value()))
+        );
+
+        assert_eq!(half.cmp_fraction(1, 2), Some(Ordering::Equal));
+        assert_eq!(half.cmp_fraction(1, 3), Some(Ordering::Greater));
+        assert_eq!(half.cmp_fraction(2, 3), Some(Ordering::Less));
+        assert_eq!(Ratio::One.cmp_fraction(7, 7), Some(Ordering::Equal));
+        assert_eq!(Ratio::One.cmp_fraction(6, 7), Some(Ordering::Greater));
+        assert_eq!(Ratio::One.cmp_fraction(8, 7), Some(Ordering::Less));
+
+        assert_eq!(half.mul_floor(9), 4);
+        assert_eq!(Ratio::One.mul_floor(9), 9);
+        assert_eq!(half.mul_floor(u128::max_value()), u128::max_value() >> 1);
+    }
+
+    #[test]
+    fn test_ratio_zero_denominator() {
+        assert_eq!(Ratio::from_fraction(0, 0), None);
+        assert_eq!(Ratio::from_fraction(1, 0), None);
+        assert_eq!(Ratio::One.cmp_fraction(1, 0), None);
+        assert_eq!(Ratio::Numerator(1 << 63).cmp_fraction(0, 0), None);
+    }
+
+    #[test]
+    fn test_ratio_cmp_fraction_matches_exact() {
+        let mut rng = XorShift(0x1234_5678_9abc_def0);
+        for _ in 0..10_000 {
+            // Numerator(k) represents k / 2^64. With num, denom < 2^64, both sides of
+            // k * denom <=> num * 2^64 fit in a u128, so we can compute the exact answer:
+            let k = rng.next_u64();
+            let num = rng.next_u64() >> (rng.next_u64() % 64);
+            let denom = (rng.next_u64() >> (rng.next_u64() % 64)).max(1);
+
+            let ratio = Ratio::Numerator(k);
+            let expected = (u128::from(k) * u128::from(denom)).cmp(&(u128::from(num) << 64));
+            assert_eq!(
+                ratio.cmp_fraction(u128::from(num), u128::from(denom)),
+                Some(expected)
+            );
+
+            let expected_one = u128::from(denom).cmp(&u128::from(num));
+            assert_eq!(
+                Ratio::One.cmp_fraction(u128::from(num), u128::from(denom)),
+                Some(expected_one)
+            );
+        }
+    }
+
+    #[test]
+    fn test_ratio_from_fraction_consistent() {
+        let mut rng = XorShift(0x0fed_cba9_8765_4321);
+        for _ in 0..10_000 {
+            let a = rng.next_u128();
+            let b = rng.next_u128().max(1);
+            let (num, denom) = if a < b { (a, b) } else { (b, a.max(1)) };
+
+            // from_fraction rounds down, so the ratio is never above num / denom,
+            // and adding one unit to the numerator takes it above num / denom:
+            match Ratio::from_fraction(num, denom).unwrap() {
+                Ratio::One => assert_eq!(num, denom),
+                Ratio::Numerator(n) => {
+                    assert_ne!(
+                        Ratio::Numerator(n).cmp_fraction(num, denom),
+                        Some(Ordering::Greater)
+                    );
+                    if let Some(n_next) = n.checked_add(1) {
+                        assert_eq!(
+                            Ratio::Numerator(n_next).cmp_fraction(num, denom),
+                            Some(Ordering::Greater)
+                        );
+                    }
+                }
+            }
+        }
+    }
 }
@@ -94,5 +94,29 @@ mod tests {
         assert_eq!(result.len(), 4 + 5 + 4);
     }
 
+    #[test]
+    fn test_select_stream_hot_stream() {
+        // A stream that always has an item ready:
+        let s_hot = stream::repeat(0u8);
+        let s1 = stream::iter(vec![1u8; 4]);
+        let s2 = stream::iter(vec![2u8; 4]);
+
+        let selected = select_streams![s_hot, s1, s2];
+
+        // The hot stream should not starve the other streams:
+        let result = block_on(selected.take(12).collect::<Vec<u8>>());
+        assert_eq!(result.iter().filter(|&&x| x == 0).count(), 4);
+        assert_eq!(result.iter().filter(|&&x| x == 1).count(), 4);
+        assert_eq!(result.iter().filter(|&&x| x == 2).count(), 4);
+    }
+
+    #[test]
+    fn test_select_stream_empty() {
+        let streams: Vec<BoxStream<'static, u8>> = Vec::new();
+        let selected = select_streams(streams);
+        let result = block_on(selected.collect::<Vec<u8>>());
+        assert!(result.is_empty());
+    }
+
     // TODO: Add more tests here.
 }
@@ -0,0 +1,33 @@
+#![feature(test)]
+
+extern crate test;
+
+use test::{black_box, Bencher};
+
+use offst_crypto::identity::{Signature, SIGNATURE_LEN};
+
+// Signature comparison should take the same time regardless of the position of the first
+// differing byte. The three benchmarks below are expected to report similar timings.
+
+#[bench]
+fn bench_signature_eq_equal(b: &mut Bencher) {
+    let sig_a = Signature::from(&[0x55; SIGNATURE_LEN]);
+    let sig_b = Signature::from(&[0x55; SIGNATURE_LEN]);
+    b.iter(|| black_box(&sig_a) == black_box(&sig_b));
+}
+
+#[bench]
+fn bench_signature_eq_differ_first(b: &mut Bencher) {
+    let sig_a = Signature::from(&[0x55; SIGNATURE_LEN]);
+    let mut sig_b = sig_a.clone();
+    sig_b[0] ^= 0x01;
+    b.iter(|| black_box(&sig_a) == black_box(&sig_b));
+}
+
+#[bench]
+fn bench_signature_eq_differ_last(b: &mut Bencher) {
+    let sig_a = Signature::from(&[0x55; SIGNATURE_LEN]);
+    let mut sig_b = sig_a.clone();
+    sig_b[SIGNATURE_LEN - 1] ^= 0x01;
+    b.iter(|| black_box(&sig_a) == black_box(&sig_b));
+}
@@ -1,19 +1,16 @@
-use derive_more::*;
 use ring::signature;
 use std::cmp::Ordering;
 
 use super::CryptoError;
 use crate::crypto_rand::CryptoRandom;
 use crate::hash::sha_512_256;
-use common::big_array::BigArray;
 
 pub const PUBLIC_KEY_LEN: usize = 32;
 pub const SIGNATURE_LEN: usize = 64;
 
 define_fixed_bytes!(PublicKey, PUBLIC_KEY_LEN);
 
-#[derive(Clone, Serialize, Deserialize, From)]
-pub struct Signature(#[serde(with = "BigArray")] [u8; SIGNATURE_LEN]);
+define_fixed_bytes_ct!(Signature, SIGNATURE_LEN);
 
 /// Check if one public key is "lower" than another.
 /// This is used to decide which side begins the token channel.
@@ -82,94 +79,11 @@ impl Identity for SoftwareEd25519Identity {
     }
 }
 
-// ==================== Convenience for Signature ====================
-// Cuz SIGNATURE_LEN > 32, these trait can't be derived automatically.
-// ===================================================================
-
-impl AsRef<[u8]> for Signature {
-    #[inline]
-    fn as_ref(&self) -> &[u8] {
-        &self.0
-    }
-}
-
-impl ::std::ops::Deref for Signature {
-    type Target = [u8];
-    #[inline]
-    fn deref(&self) -> &[u8] {
-        &self.0
-    }
-}
-
-impl ::std::ops::DerefMut for Signature {
-    #[inline]
-    fn deref_mut(&mut self) -> &mut [u8] {
-        &mut self.0
-    }
-}
-
-impl<'a> ::std::convert::From<&'a [u8; SIGNATURE_LEN]> for Signature {
-    #[inline]
-    fn from(src: &'a [u8; SIGNATURE_LEN]) -> Signature {
-        let mut inner = [0x00u8; SIGNATURE_LEN];
-        inner.copy_from_slice(&src[..SIGNATURE_LEN]);
-        Signature(inner)
-    }
-}
-
-impl<'a> ::std::convert::TryFrom<&'a [u8]> for Signature {
-    type Error = ();
-
-    #[inline]
-    fn try_from(src: &'a [u8]) -> Result<Signature, ()> {
-        if src.len() < SIGNATURE_LEN {
-            Err(())
-        } else {
-            let mut inner = [0x00u8; SIGNATURE_LEN];
-            inner.copy_from_slice(&src[..SIGNATURE_LEN]);
-            Ok(Signature(inner))
-        }
-    }
-}
-
-impl<'a> ::std::convert::TryFrom<&'a ::bytes::Bytes> for Signature {
-    type Error = ();
-
-    #[inline]
-    fn try_from(src: &'a ::bytes::Bytes) -> Result<Signature, ()> {
-        if src.len() < SIGNATURE_LEN {
-            Err(())
-        } else {
-            let mut inner = [0x00u8; SIGNATURE_LEN];
-            inner.copy_from_slice(&src[..SIGNATURE_LEN]);
-            Ok(Signature(inner))
-        }
-    }
-}
-
-impl PartialEq for Signature {
-    #[inline]
-    fn eq(&self, other: &Signature) -> bool {
-        for i in 0..SIGNATURE_LEN {
-            if self.0[i] != other.0[i] {
-                return false;
-            }
-        }
-        true
-    }
-}
-
-impl Eq for Signature {}
-
-impl ::std::fmt::Debug for Signature {
-    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
-        ::std::fmt::Debug::fmt(&self.0[..], f)
-    }
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
+    use common::big_array::BigArray;
+    use common::define_fixed_bytes::FromHexError;
     use ring::test::rand::FixedByteRandom;
 
     #[test]
@@ -214,4 +128,107 @@ mod tests {
 
         assert!(!verify_signature(message, &public_key2, &signature1));
     }
+
+    #[test]
+    fn test_signature_eq() {
+        let sig_a = Signature::from(&[0x11; SIGNATURE_LEN]);
+        let sig_b = Signature::from(&[0x11; SIGNATURE_LEN]);
+        assert_eq!(sig_a, sig_b);
+        assert_eq!(sig_a, sig_a.clone());
+
+        for &i in &[0, SIGNATURE_LEN / 2, SIGNATURE_LEN - 1] {
+            let mut sig_c = sig_a.clone();
+            sig_c[i] ^= 0x80;
+            assert_ne!(sig_a, sig_c);
+            assert_ne!(sig_c, sig_a);
+        }
+
+        assert_ne!(Signature::zero(), sig_a);
+        assert_eq!(Signature::zero(), Signature::from([0x00; SIGNATURE_LEN]));
+    }
+
+    // The representations that were previously derived for PublicKey and Signature:
+    #[derive(Serialize, Deserialize)]
+    struct OldPublicKey([u8; PUBLIC_KEY_LEN]);
+
+    #[derive(Serialize, Deserialize)]
+    struct OldSignature(#[serde(with = "BigArray")] [u8; SIGNATURE_LEN]);
+
+    #[test]
+    fn test_fixed_bytes_serde_compat() {
+        let mut pk_array = [0u8; PUBLIC_KEY_LEN];
+        for (i, byte) in pk_array.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        let public_key = PublicKey::from(&pk_array);
+        let ser_public_key = serde_json::to_string(&public_key).unwrap();
+        assert_eq!(
+            ser_public_key,
+            serde_json::to_string(&OldPublicKey(pk_array)).unwrap()
+        );
+        let public_key2: PublicKey = serde_json::from_str(&ser_public_key).unwrap();
+        assert_eq!(public_key, public_key2);
+
+        let mut sig_array = [0u8; SIGNATURE_LEN];
+        for (i, byte) in sig_array.iter_mut().enumerate() {
+            *byte = 0xff - i as u8;
+        }
+        let signature = Signature::from(&sig_array);
+        let ser_signature = serde_json::to_string(&signature).unwrap();
+        assert_eq!(
+            ser_signature,
+            serde_json::to_string(&OldSignature(sig_array)).unwrap()
+        );
+        let signature2: Signature = serde_json::from_str(&ser_signature).unwrap();
+        assert_eq!(signature, signature2);
+
+        // Data serialized by the old representation still deserializes:
+        let old_ser = serde_json::to_string(&OldSignature(sig_array)).unwrap();
+        let signature3: Signature = serde_json::from_str(&old_ser).unwrap();
+        assert_eq!(signature, signature3);
+    }
+
+    #[test]
+    fn test_fixed_bytes_deser_wrong_length() {
+        let short = serde_json::to_string(&[1u8; PUBLIC_KEY_LEN - 1]).unwrap();
+        assert!(serde_json::from_str::<PublicKey>(&short).is_err());
+    }
+
+    #[test]
+    fn test_fixed_bytes_hex() {
+        let public_key = PublicKey::from(&[0xab; PUBLIC_KEY_LEN]);
+        let hex_str = public_key.to_hex();
+        assert_eq!(hex_str, "AB".repeat(PUBLIC_KEY_LEN));
+        assert_eq!(format!("{}", public_key), hex_str);
+        assert_eq!(PublicKey::from_hex(&hex_str).unwrap(), public_key);
+        assert_eq!(
+            PublicKey::from_hex(&hex_str.to_lowercase()).unwrap(),
+            public_key
+        );
+        assert_eq!(hex_str.parse::<PublicKey>().unwrap(), public_key);
+
+        let signature = Signature::from(&[0x01; SIGNATURE_LEN]);
+        assert_eq!(
+            Signature::from_hex(&signature.to_string()).unwrap(),
+            signature
+        );
+    }
+
+    #[test]
+    fn test_fixed_bytes_from_hex_errors() {
+        assert_eq!(PublicKey::from_hex("abc"), Err(FromHexError::OddLength));
+        assert_eq!(
+            PublicKey::from_hex("abcd"),
+            Err(FromHexError::InvalidLength {
+                expected: PUBLIC_KEY_LEN,
+                found: 2
+            })
+        );
+        let mut bad_str = "00".repeat(PUBLIC_KEY_LEN);
+        bad_str.replace_range(5..6, "g");
+        assert_eq!(
+            PublicKey::from_hex(&bad_str),
+            Err(FromHexError::InvalidHexCharacter { c: 'g', index: 5 })
+        );
+    }
 }
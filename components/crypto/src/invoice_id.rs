@@ -1,5 +1,4 @@
 use ring::rand::SecureRandom;
-use std::fmt;
 
 pub const INVOICE_ID_LEN: usize = 32;
 
@@ -13,21 +12,4 @@ impl InvoiceId {
         rng.fill(&mut invoice_id.0).unwrap();
         invoice_id
     }
-
-    /// Formatting for `Debug` and `Display`.
-    fn format(&self) -> String {
-        let upper_hex = self
-            .as_ref()
-            .iter()
-            .map(|byte| format!("{:02X}", byte))
-            .collect::<Vec<_>>();
-
-        upper_hex.join("")
-    }
-}
-
-impl fmt::Display for InvoiceId {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.format())
-    }
 }
@@ -2,10 +2,14 @@ use std::iter;
 
 use ring;
 use ring::aead::{open_in_place, seal_in_place, OpeningKey, SealingKey, CHACHA20_POLY1305};
+use ring::{digest, pbkdf2};
 
+use super::dh::Salt;
 use super::{increase_nonce, CryptoError};
 
 pub const SYMMETRIC_KEY_LEN: usize = 32;
+// Amount of PBKDF2 iterations used for deriving a symmetric key from a password
+const PASSWORD_KDF_ITERATIONS: u32 = 100_000;
 // Length of tag for CHACHA20_POLY1305
 const TAG_LEN: usize = 16;
 // Length of nonce for CHACHA20_POLY1305
@@ -47,6 +51,20 @@ impl Default for EncryptNonceCounter {
     }
 }
 
+/// Derive a symmetric key from a password (Using PBKDF2).
+/// The same password and salt always result in the same symmetric key.
+pub fn derive_symmetric_key_from_password(password: &[u8], salt: &Salt) -> SymmetricKey {
+    let mut symmetric_key = [0x00u8; SYMMETRIC_KEY_LEN];
+    pbkdf2::derive(
+        &digest::SHA512_256,
+        PASSWORD_KDF_ITERATIONS,
+        salt,
+        password,
+        &mut symmetric_key,
+    );
+    SymmetricKey::from(&symmetric_key)
+}
+
 /// A structure used for encrypting messages with a given symmetric key.
 /// Maintains internal state of an increasing nonce counter.
 pub struct Encryptor {
@@ -103,6 +121,9 @@ impl Decryptor {
 
     /// Decrypt and authenticate a message.
     pub fn decrypt(&mut self, cipher_msg: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        if cipher_msg.len() < ENC_NONCE_LEN {
+            return Err(CryptoError);
+        }
         let enc_nonce = &cipher_msg[..ENC_NONCE_LEN];
         if enc_nonce != self.nonce_counter.as_ref() {
             // Nonce doesn't match!
@@ -125,6 +146,7 @@ impl Decryptor {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::dh::SALT_LEN;
 
     #[test]
     fn increase_nonce_basic() {
@@ -160,4 +182,31 @@ mod tests {
 
         assert_eq!(plain_msg, &decrypted_msg[..]);
     }
+
+    #[test]
+    fn test_derive_symmetric_key_from_password() {
+        let salt1 = Salt::from(&[1; SALT_LEN]);
+        let salt2 = Salt::from(&[2; SALT_LEN]);
+
+        let key1 = derive_symmetric_key_from_password(b"password", &salt1);
+        assert_eq!(
+            key1,
+            derive_symmetric_key_from_password(b"password", &salt1)
+        );
+        assert_ne!(
+            key1,
+            derive_symmetric_key_from_password(b"password", &salt2)
+        );
+        assert_ne!(
+            key1,
+            derive_symmetric_key_from_password(b"passw0rd", &salt1)
+        );
+
+        let mut encryptor = Encryptor::new(&key1).unwrap();
+        let cipher_msg = encryptor.encrypt(b"Hello world!").unwrap();
+
+        let wrong_key = derive_symmetric_key_from_password(b"passw0rd", &salt1);
+        let mut decryptor = Decryptor::new(&wrong_key).unwrap();
+        assert!(decryptor.decrypt(&cipher_msg).is_err());
+    }
 }
@@ -44,3 +44,31 @@ impl SecureRandom for DummyRandom {
 }
 
 impl CryptoRandom for DummyRandom {}
+
+/// A deterministic random generator for tests that need to predict exact random values.
+/// Fills buffers with a running byte counter: The first generated byte is `start`,
+/// and every following byte is larger by one (wrapping around after 0xff).
+pub struct CounterRandom {
+    counter: Mutex<u8>,
+}
+
+impl CounterRandom {
+    pub fn new(start: u8) -> Self {
+        CounterRandom {
+            counter: Mutex::new(start),
+        }
+    }
+}
+
+impl SecureRandom for CounterRandom {
+    fn fill(&self, dest: &mut [u8]) -> Result<(), Unspecified> {
+        let mut counter = self.counter.lock().unwrap();
+        for byte in dest.iter_mut() {
+            *byte = *counter;
+            *counter = counter.wrapping_add(1);
+        }
+        Ok(())
+    }
+}
+
+impl CryptoRandom for CounterRandom {}
@@ -1,5 +1,4 @@
 use ring::rand::SecureRandom;
-use std::fmt;
 
 pub const UID_LEN: usize = 16;
 
@@ -15,21 +14,4 @@ impl Uid {
         rng.fill(&mut uuid.0).unwrap();
         uuid
     }
-
-    /// Formatting for `Debug` and `Display`.
-    fn format(&self) -> String {
-        let upper_hex = self
-            .as_ref()
-            .iter()
-            .map(|byte| format!("{:02X}", byte))
-            .collect::<Vec<_>>();
-
-        upper_hex.join("")
-    }
-}
-
-impl fmt::Display for Uid {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.format())
-    }
 }
@@ -30,6 +30,14 @@ pub fn credits_on_failure() -> Option<u128> {
     Some(0)
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CreditCalcError {
+    /// The node index is not a valid index of a receiving node on the route.
+    InvalidNodeIndex,
+    /// The amount of credits does not fit in a u128.
+    Overflow,
+}
+
 /// Compute the amount of credits we need to freeze when sending a request to a node which is
 /// nodes_to_dest nodes from the destination.
 ///
@@ -44,8 +52,24 @@ pub fn credits_on_failure() -> Option<u128> {
 /// we should have node_index = 2 in order to calculate the amount of credits C should freeze.
 /// ```
 ///
-pub fn credits_to_freeze(node_index: u32, route_len: u32, dest_payment: u128) -> Option<u128> {
-    credits_on_success(node_index, route_len, dest_payment)
+/// Returns CreditCalcError::Overflow if the amount does not fit in a u128. This may happen for
+/// adversarial routes, and must not cause a panic.
+///
+pub fn credits_to_freeze(
+    node_index: u32,
+    route_len: u32,
+    dest_payment: u128,
+) -> Result<u128, CreditCalcError> {
+    if node_index == 0 {
+        return Err(CreditCalcError::InvalidNodeIndex);
+    }
+    let dist = route_len
+        .checked_sub(node_index)
+        .and_then(|dist| dist.checked_sub(1))
+        .ok_or(CreditCalcError::InvalidNodeIndex)?;
+    u128::from(dist)
+        .checked_add(dest_payment)
+        .ok_or(CreditCalcError::Overflow)
 }
 
 /// A credit calculator object that is wired to work with a specific request.
@@ -62,10 +86,16 @@ impl CreditCalculator {
         }
     }
 
+    /// A credit calculator for a direct payment to a friend (A route of two nodes).
+    /// There are no intermediate nodes on such a route, hence the payment carries no fees.
+    pub fn new_free(dest_payment: u128) -> Self {
+        CreditCalculator::new(2, dest_payment)
+    }
+
     /// Amount of credits node <index-1> should freeze when sending
     /// a request message to node <index>
     /// Source node has index 0. Destination node has index route_len - 1.
-    pub fn credits_to_freeze(&self, node_index: u32) -> Option<u128> {
+    pub fn credits_to_freeze(&self, node_index: u32) -> Result<u128, CreditCalcError> {
         credits_to_freeze(node_index, self.route_len, self.dest_payment)
     }
 
@@ -82,6 +112,59 @@ impl CreditCalculator {
     pub fn credits_on_failure(&self, _node_index: u32, _reporting_node_index: u32) -> Option<u128> {
         credits_on_failure()
     }
+
+    /// Total amount of credits the source node pays on top of dest_payment.
+    /// This is the sum of the fees earned by all the intermediate nodes along the route.
+    /// Upon any overflow (u128) this function will return None.
+    pub fn total_fee(&self) -> Option<u128> {
+        if self.route_len < 2 {
+            return None;
+        }
+        let mut total_fee = 0u128;
+        // Every intermediate node is paid by the previous node, and pays the next node:
+        for node_index in 1..self.route_len.checked_sub(1)? {
+            let node_fee = self
+                .credits_on_success(node_index)?
+                .checked_sub(self.credits_on_success(node_index.checked_add(1)?)?)?;
+            total_fee = total_fee.checked_add(node_fee)?;
+        }
+        Some(total_fee)
+    }
+
+    /// Find the largest dest_payment that can be sent along a route, given the amount of credits
+    /// that can be frozen along every hop of the route.
+    /// hop_capacities[i] is the capacity from node <i> to node <i+1>, hence the route length is
+    /// hop_capacities.len() + 1.
+    ///
+    /// For the returned dest_payment, credits_to_freeze(i + 1) <= hop_capacities[i] holds for
+    /// every hop. If no payment is possible, 0 is returned.
+    pub fn max_payable(hop_capacities: &[u128]) -> u128 {
+        let route_len = match hop_capacities
+            .len()
+            .checked_add(1)
+            .and_then(|route_len| u32::try_from(route_len).ok())
+        {
+            Some(route_len) => route_len,
+            None => return 0,
+        };
+        if hop_capacities.is_empty() {
+            return 0;
+        }
+
+        let mut max_payable = u128::max_value();
+        for (hop_index, capacity) in hop_capacities.iter().enumerate() {
+            // credits_to_freeze(node_index) = dest_payment + (route_len - node_index - 1),
+            // where node_index = hop_index + 1:
+            let hop_index = u32::try_from(hop_index).unwrap();
+            let dist = route_len - hop_index - 2;
+            let hop_max = match capacity.checked_sub(u128::from(dist)) {
+                Some(hop_max) => hop_max,
+                None => return 0,
+            };
+            max_payable = max_payable.min(hop_max);
+        }
+        max_payable
+    }
 }
 
 #[cfg(test)]
@@ -90,7 +173,7 @@ mod tests {
     // use num_traits::PrimInt;
     // use std::cmp;
 
-    // TODO: Add tests for CreditCalculator.
+    // TODO: Add more tests for CreditCalculator.
 
     /*
     fn is_linear<F,N,M>(f: F, begin: N, end: N) -> bool
@@ -143,8 +226,106 @@ mod tests {
         // telescopic:
         for node_index in 1..route_len {
             let success_credits = credits_on_success(node_index, route_len, dest_payment);
-            let freeze_credits = credits_to_freeze(node_index, route_len, dest_payment);
+            let freeze_credits = credits_to_freeze(node_index, route_len, dest_payment).ok();
             assert!(freeze_credits >= success_credits);
         }
     }
+
+    #[test]
+    fn test_credits_to_freeze_errors() {
+        // Nothing is frozen for the source node, or past the destination:
+        assert_eq!(
+            credits_to_freeze(0, 5, 100),
+            Err(CreditCalcError::InvalidNodeIndex)
+        );
+        assert_eq!(
+            credits_to_freeze(5, 5, 100),
+            Err(CreditCalcError::InvalidNodeIndex)
+        );
+
+        // The destination node can be paid the full u128 range, but the fees of intermediate
+        // nodes do not fit:
+        assert_eq!(
+            credits_to_freeze(4, 5, u128::max_value()),
+            Ok(u128::max_value())
+        );
+        assert_eq!(
+            credits_to_freeze(1, 5, u128::max_value()),
+            Err(CreditCalcError::Overflow)
+        );
+        let credit_calc = CreditCalculator::new(u32::max_value(), u128::max_value() - 10);
+        assert_eq!(
+            credit_calc.credits_to_freeze(1),
+            Err(CreditCalcError::Overflow)
+        );
+    }
+
+    #[test]
+    fn test_credit_calculator_total_fee() {
+        // B -- C -- D -- E -- F
+        // C receives 103, D receives 102, E receives 101 and F receives 100.
+        // Every intermediate node earns 1 credit.
+        let credit_calc = CreditCalculator::new(5, 100);
+        assert_eq!(credit_calc.total_fee(), Some(3));
+        assert_eq!(
+            credit_calc.credits_on_success(1).unwrap(),
+            100 + credit_calc.total_fee().unwrap()
+        );
+
+        // Direct payment to a friend has no fees:
+        let credit_calc = CreditCalculator::new(2, 100);
+        assert_eq!(credit_calc.total_fee(), Some(0));
+
+        // Not a valid route:
+        let credit_calc = CreditCalculator::new(1, 100);
+        assert_eq!(credit_calc.total_fee(), None);
+
+        // Overflow:
+        let credit_calc = CreditCalculator::new(5, u128::max_value());
+        assert_eq!(credit_calc.total_fee(), None);
+    }
+
+    #[test]
+    fn test_credit_calculator_free() {
+        // B -- C
+        // B pays C directly, without any fees:
+        let dest_payment = 100;
+        let credit_calc = CreditCalculator::new_free(dest_payment);
+        assert_eq!(credit_calc.total_fee(), Some(0));
+        assert_eq!(credit_calc.credits_to_freeze(1), Ok(dest_payment));
+        assert_eq!(credit_calc.credits_on_success(1), Some(dest_payment));
+        assert_eq!(credit_calc.credits_on_failure(1, 1), Some(0));
+
+        // The full u128 range can be paid directly:
+        let credit_calc = CreditCalculator::new_free(u128::max_value());
+        assert_eq!(credit_calc.total_fee(), Some(0));
+        assert_eq!(credit_calc.credits_to_freeze(1), Ok(u128::max_value()));
+    }
+
+    #[test]
+    fn test_credit_calculator_max_payable() {
+        // The middle hop is the bottleneck:
+        let hop_capacities = [100, 20, 100];
+        let max_payable = CreditCalculator::max_payable(&hop_capacities);
+        assert_eq!(max_payable, 19);
+
+        let check_payable = |dest_payment| {
+            let credit_calc = CreditCalculator::new(4, dest_payment);
+            hop_capacities
+                .iter()
+                .enumerate()
+                .all(|(hop_index, capacity)| {
+                    credit_calc.credits_to_freeze(hop_index as u32 + 1).unwrap() <= *capacity
+                })
+        };
+        assert!(check_payable(max_payable));
+        assert!(!check_payable(max_payable + 1));
+
+        // Direct friend:
+        assert_eq!(CreditCalculator::max_payable(&[50]), 50);
+        // Capacity too small for the fees:
+        assert_eq!(CreditCalculator::max_payable(&[100, 0, 100]), 0);
+        // Empty route:
+        assert_eq!(CreditCalculator::max_payable(&[]), 0);
+    }
 }
@@ -1,19 +1,65 @@
+use im::hashmap::HashMap as ImHashMap;
+
+use crypto::identity::PublicKey;
+use crypto::uid::Uid;
+
+use proto::funder::messages::FunderMetrics;
+
 use super::liveness::{Liveness, LivenessMutation};
 
 #[derive(Clone, Default)]
 pub struct Ephemeral {
     pub liveness: Liveness,
+    /// Amount of timer ticks received since the funder was started.
+    pub ticks: u64,
+    /// The tick in which every in flight user request was received.
+    /// Used to report the elapsed time when a response arrives.
+    pub request_start_ticks: ImHashMap<Uid, u64>,
+    /// The tick in which we last processed an inconsistency error from every friend.
+    /// Used to rate limit the processing of inconsistency errors.
+    pub inconsistency_error_ticks: ImHashMap<PublicKey, u64>,
+    /// The tick since which every friend has pending outgoing operations.
+    /// Used to flush pending operations of idle friends.
+    pub flush_pending_ticks: ImHashMap<PublicKey, u64>,
+    /// The move token counter of our last outgoing move token, and the tick since which we are
+    /// waiting for the token from every friend while having pending outgoing operations.
+    /// Used to detect stalled token channels.
+    pub token_wait_ticks: ImHashMap<PublicKey, (u128, u64)>,
+    /// The tick since which every request received from a friend is pending at our node.
+    /// Used to fail requests that hold frozen credits for too long.
+    pub pending_request_ticks: ImHashMap<Uid, u64>,
+    /// Counters of events processed since the funder was started.
+    pub metrics: FunderMetrics,
 }
 
 #[derive(Debug)]
 pub enum EphemeralMutation {
     LivenessMutation(LivenessMutation),
+    TimerTick,
+    SetRequestStartTick((Uid, u64)),
+    RemoveRequestStartTick(Uid),
+    SetInconsistencyErrorTick((PublicKey, u64)),
+    SetFlushPendingTick((PublicKey, u64)),
+    RemoveFlushPendingTick(PublicKey),
+    SetTokenWaitTick((PublicKey, u128, u64)),
+    RemoveTokenWaitTick(PublicKey),
+    SetPendingRequestTick((Uid, u64)),
+    RemovePendingRequestTick(Uid),
+    /// Add the given amounts to the event counters.
+    AddMetrics(FunderMetrics),
 }
 
 impl Ephemeral {
     pub fn new() -> Ephemeral {
         Ephemeral {
             liveness: Liveness::new(),
+            ticks: 0,
+            request_start_ticks: ImHashMap::new(),
+            inconsistency_error_ticks: ImHashMap::new(),
+            flush_pending_ticks: ImHashMap::new(),
+            token_wait_ticks: ImHashMap::new(),
+            pending_request_ticks: ImHashMap::new(),
+            metrics: FunderMetrics::default(),
         }
     }
 
@@ -22,6 +68,58 @@ impl Ephemeral {
             EphemeralMutation::LivenessMutation(liveness_mutation) => {
                 self.liveness.mutate(liveness_mutation)
             }
+            EphemeralMutation::TimerTick => {
+                self.ticks = self.ticks.saturating_add(1);
+            }
+            EphemeralMutation::SetRequestStartTick((request_id, start_tick)) => {
+                self.request_start_ticks.insert(*request_id, *start_tick);
+            }
+            EphemeralMutation::RemoveRequestStartTick(request_id) => {
+                let _ = self.request_start_ticks.remove(request_id);
+            }
+            EphemeralMutation::SetInconsistencyErrorTick((friend_public_key, tick)) => {
+                self.inconsistency_error_ticks
+                    .insert(friend_public_key.clone(), *tick);
+            }
+            EphemeralMutation::SetFlushPendingTick((friend_public_key, tick)) => {
+                self.flush_pending_ticks
+                    .insert(friend_public_key.clone(), *tick);
+            }
+            EphemeralMutation::RemoveFlushPendingTick(friend_public_key) => {
+                let _ = self.flush_pending_ticks.remove(friend_public_key);
+            }
+            EphemeralMutation::SetTokenWaitTick((friend_public_key, move_token_counter, tick)) => {
+                self.token_wait_ticks
+                    .insert(friend_public_key.clone(), (*move_token_counter, *tick));
+            }
+            EphemeralMutation::RemoveTokenWaitTick(friend_public_key) => {
+                let _ = self.token_wait_ticks.remove(friend_public_key);
+            }
+            EphemeralMutation::SetPendingRequestTick((request_id, tick)) => {
+                self.pending_request_ticks.insert(*request_id, *tick);
+            }
+            EphemeralMutation::RemovePendingRequestTick(request_id) => {
+                let _ = self.pending_request_ticks.remove(request_id);
+            }
+            EphemeralMutation::AddMetrics(metrics) => {
+                let m = &mut self.metrics;
+                m.requests_forwarded = m
+                    .requests_forwarded
+                    .saturating_add(metrics.requests_forwarded);
+                m.responses_delivered = m
+                    .responses_delivered
+                    .saturating_add(metrics.responses_delivered);
+                m.failures_generated = m
+                    .failures_generated
+                    .saturating_add(metrics.failures_generated);
+                m.inconsistencies_entered = m
+                    .inconsistencies_entered
+                    .saturating_add(metrics.inconsistencies_entered);
+                m.move_tokens_sent = m.move_tokens_sent.saturating_add(metrics.move_tokens_sent);
+                m.move_tokens_received = m
+                    .move_tokens_received
+                    .saturating_add(metrics.move_tokens_received);
+            }
         }
     }
 }
@@ -1,14 +1,13 @@
 use im::hashmap::HashMap as ImHashMap;
 
-use num_bigint::BigUint;
-
 use crypto::identity::PublicKey;
 use crypto::hash::{HashResult, sha_512_256};
 
 use common::int_convert::usize_to_u32;
 use common::canonical_serialize::CanonicalSerialize;
+use common::safe_arithmetic::Ratio;
 
-use proto::funder::messages::{Ratio, FriendsRoute, FreezeLink};
+use proto::funder::messages::{FriendsRoute, FreezeLink};
 
 use crate::credit_calc::CreditCalculator;
 use crate::state::FunderState;
@@ -237,23 +236,16 @@ impl FreezeGuard {
         let route_len = usize_to_u32(route.len()).unwrap();
         let credit_calc = CreditCalculator::new(route_len, dest_payment);
 
-        let two_pow_128 = BigUint::new(vec![0x0u32, 0x0u32, 0x0u32, 0x0u32, 0x1u32]);
-        assert_eq!(two_pow_128, BigUint::from(0xffff_ffff_ffff_ffff_ffff_ffff_ffff_ffffu128) + BigUint::from(1u128));
-
         // Verify previous freezing links
         for node_findex in 0 .. freeze_links.len() {
             let first_freeze_link = &freeze_links[node_findex];
-            let mut allowed_credits: BigUint = first_freeze_link.shared_credits.into();
+            let mut allowed_credits: u128 = first_freeze_link.shared_credits;
             for freeze_link in &freeze_links[
                 node_findex .. freeze_links.len()] {
 
-                allowed_credits = match freeze_link.usable_ratio {
-                    Ratio::One => allowed_credits,
-                    Ratio::Numerator(num) => {
-                        let b_num = BigUint::from(num);
-                        allowed_credits * b_num / &two_pow_128
-                    },
-                };
+                // The freezing node should create the usable ratio using Ratio::from_fraction,
+                // so that both sides agree on the usable ratio:
+                allowed_credits = freeze_link.usable_ratio.mul_floor(allowed_credits);
             }
 
             let subroute = &route
@@ -262,9 +254,9 @@ impl FreezeGuard {
 
             let node_findex = usize_to_u32(node_findex).unwrap();
             let next_node_findex = node_findex.checked_add(1).unwrap();
-            let new_frozen = credit_calc.credits_to_freeze(next_node_findex)?
+            let new_frozen = credit_calc.credits_to_freeze(next_node_findex).ok()?
                 .checked_add(old_frozen).unwrap();
-            if allowed_credits < new_frozen.into() {
+            if allowed_credits < new_frozen {
                 return None;
             }
         }
@@ -455,7 +447,7 @@ mod tests {
 
         let frozen_b = freeze_guard.get_frozen(&[pk_b.clone(), pk_c.clone(), pk_d.clone()]);
         let frozen_c = freeze_guard.get_frozen(&[pk_c.clone(), pk_d.clone()]);
-        let half = Ratio::Numerator(0x8000_0000_0000_0000_0000_0000_0000_0000);
+        let half = Ratio::Numerator(0x8000_0000_0000_0000);
 
 
         // -- Freezing not allowed, c -- d
@@ -2,14 +2,15 @@ use im::vector::Vector as ImVec;
 use std::fmt::Debug;
 
 use crypto::identity::PublicKey;
+use crypto::uid::Uid;
 
 use common::canonical_serialize::CanonicalSerialize;
 use common::safe_arithmetic::SafeUnsignedArithmetic;
 
 use proto::app_server::messages::{NamedRelayAddress, RelayAddress};
 use proto::funder::messages::{
-    FailureSendFunds, FriendStatus, PendingRequest, RequestSendFunds, RequestsStatus, ResetTerms,
-    ResponseSendFunds,
+    FailureReason, FailureSendFunds, FriendStatus, PendingRequest, RequestSendFunds,
+    RequestsStatus, ResetTerms, ResponseSendFunds,
 };
 
 use crate::token_channel::{TcMutation, TokenChannel};
@@ -20,7 +21,19 @@ pub enum ResponseOp {
     Response(ResponseSendFunds),
     UnsignedResponse(PendingRequest),
     Failure(FailureSendFunds),
-    UnsignedFailure(PendingRequest),
+    UnsignedFailure((PendingRequest, FailureReason)),
+}
+
+impl ResponseOp {
+    /// The request_id of the request this response (or failure) belongs to.
+    pub fn request_id(&self) -> &Uid {
+        match self {
+            ResponseOp::Response(response_send_funds) => &response_send_funds.request_id,
+            ResponseOp::UnsignedResponse(pending_request) => &pending_request.request_id,
+            ResponseOp::Failure(failure_send_funds) => &failure_send_funds.request_id,
+            ResponseOp::UnsignedFailure((pending_request, _)) => &pending_request.request_id,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -70,15 +83,21 @@ pub enum FriendMutation<B: Clone> {
     SetInconsistent(ChannelInconsistent),
     SetConsistent(TokenChannel<B>),
     SetWantedRemoteMaxDebt(u128),
+    SetAckedRemoteMaxDebt(u128),
     SetWantedLocalRequestsStatus(RequestsStatus),
     PushBackPendingRequest(RequestSendFunds),
     PopFrontPendingRequest,
+    RemovePendingRequest(Uid),
+    PushBackPendingCancel(Uid),
+    PopFrontPendingCancel,
     PushBackPendingResponse(ResponseOp),
     PopFrontPendingResponse,
     PushBackPendingUserRequest(RequestSendFunds),
     PopFrontPendingUserRequest,
+    RemovePendingUserRequest(Uid),
     SetStatus(FriendStatus),
     SetRemoteRelays(Vec<RelayAddress<B>>),
+    SetOptPrevRemoteRelays(Option<Vec<RelayAddress<B>>>),
     SetName(String),
     SetSentLocalRelays(SentLocalRelays<B>),
 }
@@ -90,8 +109,18 @@ pub struct ChannelInconsistent {
     pub opt_remote_reset_terms: Option<ResetTerms>,
 }
 
+impl ChannelInconsistent {
+    /// The inconsistency counter of the token channel before it became inconsistent.
+    /// Our local reset terms carry the next inconsistency counter.
+    pub fn get_inconsistency_counter(&self) -> u64 {
+        self.local_reset_terms
+            .inconsistency_counter
+            .saturating_sub(1)
+    }
+}
+
 #[allow(clippy::large_enum_variant)]
-#[derive(Clone, Serialize, Deserialize, Debug)]
+#[derive(Eq, PartialEq, Clone, Serialize, Deserialize, Debug)]
 pub enum ChannelStatus<B> {
     Inconsistent(ChannelInconsistent),
     Consistent(TokenChannel<B>),
@@ -118,18 +147,29 @@ pub struct FriendState<B: Clone> {
     pub local_public_key: PublicKey,
     pub remote_public_key: PublicKey,
     pub remote_relays: Vec<RelayAddress<B>>,
+    pub opt_prev_remote_relays: Option<Vec<RelayAddress<B>>>,
+    // The last confirmed remote relays, kept while an update of the remote relays (received
+    // through a move token) is not yet confirmed by a following move token. Used to roll back the
+    // update if the channel becomes inconsistent.
     pub sent_local_relays: SentLocalRelays<B>,
     pub name: String,
     pub channel_status: ChannelStatus<B>,
     pub wanted_remote_max_debt: u128,
+    #[serde(default)]
+    pub acked_remote_max_debt: u128,
+    // The last remote_max_debt the remote side is known to have received.
+    // May lag behind wanted_remote_max_debt until the remote side sends us a move token.
     pub wanted_local_requests_status: RequestsStatus,
     pub pending_requests: ImVec<RequestSendFunds>,
     pub pending_responses: ImVec<ResponseOp>,
     // Pending operations to be sent to the token channel.
+    pub pending_cancels: ImVec<Uid>,
+    // Requests we have sent to this friend, and we want to cancel.
     pub status: FriendStatus,
     pub pending_user_requests: ImVec<RequestSendFunds>,
     // Request that the user has sent to this neighbor,
-    // but have not been processed yet. Bounded in size.
+    // but have not been processed yet. Bounded in size by max_pending_user_requests.
+    // When full, new user requests are rejected with a failure response.
 }
 
 impl<B> FriendState<B>
@@ -149,6 +189,7 @@ where
             local_public_key: local_public_key.clone(),
             remote_public_key: remote_public_key.clone(),
             remote_relays,
+            opt_prev_remote_relays: None,
             sent_local_relays: SentLocalRelays::NeverSent,
             name,
             channel_status: ChannelStatus::Consistent(token_channel),
@@ -156,17 +197,19 @@ where
             // The remote_max_debt we want to have. When possible, this will be sent to the remote
             // side.
             wanted_remote_max_debt: 0,
+            // The remote_max_debt that was acknowledged by the remote side.
+            acked_remote_max_debt: 0,
             wanted_local_requests_status: RequestsStatus::Closed,
             // The local_send_price we want to have (Or possibly close requests, by having an empty
             // send price). When possible, this will be updated with the TokenChannel.
             pending_requests: ImVec::new(),
             pending_responses: ImVec::new(),
+            pending_cancels: ImVec::new(),
             status: FriendStatus::Disabled,
             pending_user_requests: ImVec::new(),
         }
     }
 
-    // TODO: Do we use this function somewhere?
     /// Find the shared credits we have with this friend.
     /// This value is used for freeze guard calculations.
     /// This value is the capacity shared between the rest of the friends.
@@ -182,15 +225,15 @@ where
     /// B, C and D.
     ///
     pub fn get_shared_credits(&self) -> u128 {
-        let balance = match &self.channel_status {
-            ChannelStatus::Consistent(token_channel) => {
-                &token_channel.get_mutual_credit().state().balance
-            }
+        let mutual_credit = match &self.channel_status {
+            ChannelStatus::Consistent(token_channel) => token_channel.get_mutual_credit(),
             ChannelStatus::Inconsistent(_channel_inconsistent) => return 0,
         };
-        balance
+        mutual_credit
+            .state()
+            .balance
             .local_max_debt
-            .saturating_add_signed(balance.balance)
+            .saturating_add_signed(mutual_credit.net_balance())
     }
 
     pub fn mutate(&mut self, friend_mutation: &FriendMutation<B>) {
@@ -210,6 +253,9 @@ where
             FriendMutation::SetWantedRemoteMaxDebt(wanted_remote_max_debt) => {
                 self.wanted_remote_max_debt = *wanted_remote_max_debt;
             }
+            FriendMutation::SetAckedRemoteMaxDebt(acked_remote_max_debt) => {
+                self.acked_remote_max_debt = *acked_remote_max_debt;
+            }
             FriendMutation::SetWantedLocalRequestsStatus(wanted_local_requests_status) => {
                 self.wanted_local_requests_status = wanted_local_requests_status.clone();
             }
@@ -219,6 +265,16 @@ where
             FriendMutation::PopFrontPendingRequest => {
                 let _ = self.pending_requests.pop_front();
             }
+            FriendMutation::RemovePendingRequest(request_id) => {
+                self.pending_requests
+                    .retain(|request_send_funds| &request_send_funds.request_id != request_id);
+            }
+            FriendMutation::PushBackPendingCancel(request_id) => {
+                self.pending_cancels.push_back(*request_id);
+            }
+            FriendMutation::PopFrontPendingCancel => {
+                let _ = self.pending_cancels.pop_front();
+            }
             FriendMutation::PushBackPendingResponse(response_op) => {
                 self.pending_responses.push_back(response_op.clone());
             }
@@ -232,12 +288,19 @@ where
             FriendMutation::PopFrontPendingUserRequest => {
                 let _ = self.pending_user_requests.pop_front();
             }
+            FriendMutation::RemovePendingUserRequest(request_id) => {
+                self.pending_user_requests
+                    .retain(|request_send_funds| &request_send_funds.request_id != request_id);
+            }
             FriendMutation::SetStatus(friend_status) => {
                 self.status = friend_status.clone();
             }
             FriendMutation::SetRemoteRelays(remote_relays) => {
                 self.remote_relays = remote_relays.clone();
             }
+            FriendMutation::SetOptPrevRemoteRelays(opt_prev_remote_relays) => {
+                self.opt_prev_remote_relays = opt_prev_remote_relays.clone();
+            }
             FriendMutation::SetName(friend_name) => {
                 self.name = friend_name.clone();
             }
@@ -1,8 +1,9 @@
 use std::fmt::Debug;
+use std::marker::Unpin;
 
 use futures::channel::mpsc;
 use futures::stream::select;
-use futures::{future, stream, SinkExt, StreamExt};
+use futures::{future, stream, SinkExt, Stream, StreamExt};
 
 use common::canonical_serialize::CanonicalSerialize;
 
@@ -17,12 +18,18 @@ use proto::funder::messages::{FunderIncomingControl, FunderOutgoingControl};
 use crate::ephemeral::Ephemeral;
 use crate::handler::funder_handle_message;
 use crate::state::{FunderMutation, FunderState};
-use crate::types::{FunderIncoming, FunderIncomingComm, FunderOutgoingComm};
+use crate::types::{FunderConfig, FunderIncoming, FunderIncomingComm, FunderOutgoingComm};
 
+/// A fatal error of the funder loop. When returned, the funder loop has stopped.
+///
+/// Errors that concern a single incoming message (For example, a message from a friend that does
+/// not exist) are not fatal: They are logged, the message is dropped, and the funder loop
+/// continues. See `FunderHandlerError`.
 #[derive(Debug)]
 pub enum FunderError {
     IncomingControlClosed,
     IncomingCommClosed,
+    TimerClosed,
     IncomingMessagesError,
     DbError,
     SendControlError,
@@ -34,30 +41,34 @@ pub enum FunderEvent<B> {
     FunderIncoming(FunderIncoming<B>),
     IncomingControlClosed,
     IncomingCommClosed,
+    TimerClosed,
 }
 
-pub async fn inner_funder_loop<B, R>(
+pub async fn inner_funder_loop<B, R, TS>(
     mut identity_client: IdentityClient,
     rng: R,
     incoming_control: mpsc::Receiver<FunderIncomingControl<B>>,
     incoming_comm: mpsc::Receiver<FunderIncomingComm<B>>,
+    timer_stream: TS,
     control_sender: mpsc::Sender<FunderOutgoingControl<B>>,
     comm_sender: mpsc::Sender<FunderOutgoingComm<B>>,
     mut funder_state: FunderState<B>,
     mut db_client: DatabaseClient<FunderMutation<B>>,
-    max_operations_in_batch: usize,
-    max_node_relays: usize,
-    max_pending_user_requests: usize,
+    funder_config: FunderConfig,
     mut opt_event_sender: Option<mpsc::Sender<FunderEvent<B>>>,
 ) -> Result<(), FunderError>
 where
     B: Clone + PartialEq + Eq + CanonicalSerialize + Debug,
     R: CryptoRandom + 'static,
+    TS: Stream + Unpin,
 {
     // Transform error type:
     let mut comm_sender = comm_sender.sink_map_err(|_| ());
     let mut control_sender = control_sender.sink_map_err(|_| ());
 
+    // States saved by older versions are loaded without the ready receipts indices:
+    funder_state.rebuild_receipt_indices();
+
     // let mut db_runner = DbRunner::new(atomic_db);
     let mut ephemeral = Ephemeral::new();
 
@@ -74,11 +85,17 @@ where
             FunderEvent::FunderIncoming(FunderIncoming::Comm(incoming_comm_msg))
         })
         .chain(stream::once(future::ready(FunderEvent::IncomingCommClosed)));
+    let timer_stream = timer_stream
+        .map(|_| FunderEvent::FunderIncoming(FunderIncoming::TimerTick))
+        .chain(stream::once(future::ready(FunderEvent::TimerClosed)));
     // Chain the Init message first:
     let mut incoming_messages = stream::once(future::ready(FunderEvent::FunderIncoming(
         FunderIncoming::Init,
     )))
-    .chain(select(incoming_control, incoming_comm));
+    .chain(select(
+        incoming_control,
+        select(incoming_comm, timer_stream),
+    ));
 
     while let Some(funder_event) = await!(incoming_messages.next()) {
         // For testing:
@@ -86,6 +103,7 @@ where
         let funder_incoming = match funder_event.clone() {
             FunderEvent::IncomingControlClosed => return Err(FunderError::IncomingControlClosed),
             FunderEvent::IncomingCommClosed => return Err(FunderError::IncomingCommClosed),
+            FunderEvent::TimerClosed => return Err(FunderError::TimerClosed),
             FunderEvent::FunderIncoming(funder_incoming) => funder_incoming,
         };
 
@@ -94,17 +112,19 @@ where
             &rng,
             funder_state.clone(),
             ephemeral.clone(),
-            max_node_relays,
-            max_operations_in_batch,
-            max_pending_user_requests,
+            &funder_config,
             funder_incoming
         ));
 
         let handler_output = match res {
             Ok(handler_output) => handler_output,
             Err(handler_error) => {
-                // Reporting a recoverable error:
+                // Reporting a recoverable error. The message is dropped, and none of its mutations
+                // are applied:
                 error!("Funder handler error: {:?}", handler_error);
+                if let Some(ref mut event_sender) = opt_event_sender {
+                    await!(event_sender.send(funder_event)).unwrap();
+                }
                 continue;
             }
         };
@@ -141,35 +161,34 @@ where
     Ok(())
 }
 
-pub async fn funder_loop<B, R>(
+pub async fn funder_loop<B, R, TS>(
     identity_client: IdentityClient,
     rng: R,
     incoming_control: mpsc::Receiver<FunderIncomingControl<B>>,
     incoming_comm: mpsc::Receiver<FunderIncomingComm<B>>,
+    timer_stream: TS,
     control_sender: mpsc::Sender<FunderOutgoingControl<B>>,
     comm_sender: mpsc::Sender<FunderOutgoingComm<B>>,
-    max_operations_in_batch: usize,
-    max_node_relays: usize,
-    max_pending_user_requests: usize,
+    funder_config: FunderConfig,
     funder_state: FunderState<B>,
     db_client: DatabaseClient<FunderMutation<B>>,
 ) -> Result<(), FunderError>
 where
     B: Clone + PartialEq + Eq + CanonicalSerialize + Debug,
     R: CryptoRandom + 'static,
+    TS: Stream + Unpin,
 {
     await!(inner_funder_loop(
         identity_client,
         rng,
         incoming_control,
         incoming_comm,
+        timer_stream,
         control_sender,
         comm_sender,
         funder_state,
         db_client,
-        max_operations_in_batch,
-        max_node_relays,
-        max_pending_user_requests,
+        funder_config,
         None
     ))
 }
@@ -1,12 +1,14 @@
 use common::canonical_serialize::CanonicalSerialize;
 use crypto::identity::PublicKey;
+use crypto::uid::Uid;
 use std::fmt::Debug;
 
 use proto::funder::messages::{
-    FunderOutgoingControl, RequestSendFunds, ResponseReceived, ResponseSendFundsResult,
+    FailureReason, FunderOutgoingControl, PendingRequest, RequestSendFunds, ResponseReceived,
+    ResponseSendFundsResult,
 };
 
-use crate::handler::handler::{find_request_origin, MutableFunderState};
+use crate::handler::handler::MutableFunderState;
 use crate::handler::sender::SendCommands;
 
 use crate::friend::{ChannelStatus, FriendMutation, ResponseOp};
@@ -44,11 +46,12 @@ pub fn reply_with_failure<B>(
     send_commands: &mut SendCommands,
     remote_public_key: &PublicKey,
     request_send_funds: &RequestSendFunds,
+    failure_reason: FailureReason,
 ) where
     B: Clone + CanonicalSerialize + PartialEq + Eq + Debug,
 {
     let pending_request = create_pending_request(request_send_funds);
-    let u_failure_op = ResponseOp::UnsignedFailure(pending_request);
+    let u_failure_op = ResponseOp::UnsignedFailure((pending_request, failure_reason));
     let friend_mutation = FriendMutation::PushBackPendingResponse(u_failure_op);
     let funder_mutation =
         FunderMutation::FriendMutation((remote_public_key.clone(), friend_mutation));
@@ -85,13 +88,16 @@ pub fn cancel_local_pending_requests<B>(
 
     // Prepare a list of all remote requests that we need to cancel:
     for (local_request_id, pending_local_request) in pending_local_requests {
-        let opt_origin_public_key =
-            find_request_origin(m_state.state(), &local_request_id).cloned();
+        let opt_origin_public_key = m_state
+            .state()
+            .find_request_origin(&local_request_id)
+            .cloned();
         match opt_origin_public_key {
             Some(origin_public_key) => {
                 // We have found the friend that is the origin of this request.
                 // We send him a failure message.
-                let u_failure_op = ResponseOp::UnsignedFailure(pending_local_request);
+                let u_failure_op =
+                    ResponseOp::UnsignedFailure((pending_local_request, FailureReason::Canceled));
                 let friend_mutation = FriendMutation::PushBackPendingResponse(u_failure_op);
                 let funder_mutation =
                     FunderMutation::FriendMutation((origin_public_key.clone(), friend_mutation));
@@ -106,6 +112,8 @@ pub fn cancel_local_pending_requests<B>(
                     result: ResponseSendFundsResult::Failure(
                         m_state.state().local_public_key.clone(),
                     ),
+                    opt_elapsed_ticks: None,
+                    opt_failure_reason: Some(FailureReason::Canceled),
                 };
                 outgoing_control.push(FunderOutgoingControl::ResponseReceived(response_received));
             }
@@ -113,11 +121,14 @@ pub fn cancel_local_pending_requests<B>(
     }
 }
 
+/// Cancel requests that are waiting to be sent to a friend.
+/// failure_reason is reported to the origin of every canceled request.
 pub fn cancel_pending_requests<B>(
     m_state: &mut MutableFunderState<B>,
     send_commands: &mut SendCommands,
     outgoing_control: &mut Vec<FunderOutgoingControl<B>>,
     friend_public_key: &PublicKey,
+    failure_reason: FailureReason,
 ) where
     B: Clone + CanonicalSerialize + PartialEq + Eq + Debug,
 {
@@ -130,12 +141,15 @@ pub fn cancel_pending_requests<B>(
             FunderMutation::FriendMutation((friend_public_key.clone(), friend_mutation));
         m_state.mutate(funder_mutation);
 
-        let opt_origin_public_key =
-            find_request_origin(m_state.state(), &pending_request.request_id).cloned();
+        let opt_origin_public_key = m_state
+            .state()
+            .find_request_origin(&pending_request.request_id)
+            .cloned();
         match opt_origin_public_key {
             Some(origin_public_key) => {
                 let local_pending_request = create_pending_request(&pending_request);
-                let u_failure_op = ResponseOp::UnsignedFailure(local_pending_request);
+                let u_failure_op =
+                    ResponseOp::UnsignedFailure((local_pending_request, failure_reason.clone()));
                 let friend_mutation = FriendMutation::PushBackPendingResponse(u_failure_op);
                 let funder_mutation =
                     FunderMutation::FriendMutation((origin_public_key.clone(), friend_mutation));
@@ -149,6 +163,8 @@ pub fn cancel_pending_requests<B>(
                     result: ResponseSendFundsResult::Failure(
                         m_state.state().local_public_key.clone(),
                     ),
+                    opt_elapsed_ticks: None,
+                    opt_failure_reason: Some(failure_reason.clone()),
                 };
                 outgoing_control.push(FunderOutgoingControl::ResponseReceived(response_received));
             }
@@ -156,10 +172,12 @@ pub fn cancel_pending_requests<B>(
     }
 }
 
+/// Cancel user requests that are waiting to be sent to a friend.
 pub fn cancel_pending_user_requests<B>(
     m_state: &mut MutableFunderState<B>,
     outgoing_control: &mut Vec<FunderOutgoingControl<B>>,
     friend_public_key: &PublicKey,
+    failure_reason: FailureReason,
 ) where
     B: Clone + CanonicalSerialize + PartialEq + Eq + Debug,
 {
@@ -176,7 +194,107 @@ pub fn cancel_pending_user_requests<B>(
         let response_received = ResponseReceived {
             request_id: pending_user_request.request_id,
             result: ResponseSendFundsResult::Failure(m_state.state().local_public_key.clone()),
+            opt_elapsed_ticks: None,
+            opt_failure_reason: Some(failure_reason.clone()),
         };
         outgoing_control.push(FunderOutgoingControl::ResponseReceived(response_received));
     }
 }
+
+/// Ask a friend to cancel a request we have sent it, unless we have already asked.
+/// The failure for the request will arrive back from the friend through the usual path.
+pub fn push_pending_cancel<B>(
+    m_state: &mut MutableFunderState<B>,
+    send_commands: &mut SendCommands,
+    friend_public_key: &PublicKey,
+    request_id: Uid,
+) where
+    B: Clone + CanonicalSerialize + PartialEq + Eq + Debug,
+{
+    let friend = m_state.state().friends.get(friend_public_key).unwrap();
+    if friend
+        .pending_cancels
+        .iter()
+        .any(|pending_cancel| pending_cancel == &request_id)
+    {
+        return;
+    }
+
+    let friend_mutation = FriendMutation::PushBackPendingCancel(request_id);
+    let funder_mutation =
+        FunderMutation::FriendMutation((friend_public_key.clone(), friend_mutation));
+    m_state.mutate(funder_mutation);
+    send_commands.set_try_send(friend_public_key);
+}
+
+/// Cancel a request we have received from the friend `remote_public_key`.
+/// If the request was not yet forwarded, we fail it back to the remote side right away with
+/// `failure_reason`. If it was already forwarded, we pass the cancellation on to the next node on
+/// the route. The failure will then arrive back through the usual path, releasing the frozen
+/// credits along the route. We can not fail the request ourselves in this case, as the next node
+/// might have already fulfilled it.
+pub fn cancel_remote_request<B>(
+    m_state: &mut MutableFunderState<B>,
+    send_commands: &mut SendCommands,
+    remote_public_key: &PublicKey,
+    pending_request: PendingRequest,
+    failure_reason: FailureReason,
+) where
+    B: Clone + CanonicalSerialize + PartialEq + Eq + Debug,
+{
+    let request_id = pending_request.request_id;
+
+    // A response or a failure for this request is already on its way to the remote side:
+    let friend = m_state.state().friends.get(remote_public_key).unwrap();
+    if friend
+        .pending_responses
+        .iter()
+        .any(|response_op| response_op.request_id() == &request_id)
+    {
+        return;
+    }
+
+    let opt_next_public_key = pending_request
+        .route
+        .find_pk_pair(&remote_public_key, &m_state.state().local_public_key)
+        .and_then(|remote_index| remote_index.checked_add(2))
+        .and_then(|next_index| pending_request.route.index_to_pk(next_index))
+        .cloned();
+
+    let next_public_key = match opt_next_public_key {
+        Some(next_public_key) => next_public_key,
+        // We are the destination. Our response was already sent:
+        None => return,
+    };
+
+    if let Some(next_friend) = m_state.state().friends.get(&next_public_key) {
+        let is_forwarded = match &next_friend.channel_status {
+            ChannelStatus::Consistent(token_channel) => token_channel
+                .get_mutual_credit()
+                .state()
+                .pending_requests
+                .pending_local_requests
+                .contains_key(&request_id),
+            ChannelStatus::Inconsistent(_) => false,
+        };
+
+        if is_forwarded {
+            push_pending_cancel(m_state, send_commands, &next_public_key, request_id);
+            return;
+        }
+
+        // The request might still be waiting to be forwarded:
+        let friend_mutation = FriendMutation::RemovePendingRequest(request_id);
+        let funder_mutation =
+            FunderMutation::FriendMutation((next_public_key.clone(), friend_mutation));
+        m_state.mutate(funder_mutation);
+    }
+
+    // The request is not held by any node further along the route. We fail it right away:
+    let u_failure_op = ResponseOp::UnsignedFailure((pending_request, failure_reason));
+    let friend_mutation = FriendMutation::PushBackPendingResponse(u_failure_op);
+    let funder_mutation =
+        FunderMutation::FriendMutation((remote_public_key.clone(), friend_mutation));
+    m_state.mutate(funder_mutation);
+    send_commands.set_try_send(remote_public_key);
+}
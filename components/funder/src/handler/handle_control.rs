@@ -1,27 +1,35 @@
+use std::convert::TryFrom;
 use std::fmt::Debug;
 
 use common::canonical_serialize::CanonicalSerialize;
 
 use crypto::identity::PublicKey;
+use crypto::uid::Uid;
 
-use crate::friend::{ChannelStatus, FriendMutation};
+use crate::credit_calc::CreditCalculator;
+use crate::friend::{ChannelStatus, FriendMutation, FriendState};
 use crate::state::FunderMutation;
 
 use proto::app_server::messages::{NamedRelayAddress, RelayAddress};
 use proto::funder::messages::{
-    AddFriend, ChannelerUpdateFriend, FriendStatus, FunderControl, FunderOutgoingControl,
-    ReceiptAck, RemoveFriend, ResetFriendChannel, ResponseReceived, ResponseSendFundsResult,
-    SetFriendName, SetFriendRelays, SetFriendRemoteMaxDebt, SetFriendStatus, SetRequestsStatus,
+    AddFriend, AddFriendWithTrust, ChannelerUpdateFriend, FailureReason, FriendQueryResult,
+    FriendStatus, FunderControl, FunderOutgoingControl, MoveTokenCounters, ReceiptAck,
+    RemoveFriend, ResetFriendChannel, ResponseReceived, ResponseSendFundsResult,
+    SetFriendLivenessInterval, SetFriendName, SetFriendRelays, SetFriendRemoteMaxDebt,
+    SetFriendStatus, SetRequestsStatus, TokenChannelCounters, TokenCountersQueryResult,
     UserRequestSendFunds,
 };
 
-use crate::ephemeral::Ephemeral;
+use crate::ephemeral::{Ephemeral, EphemeralMutation};
 use crate::handler::canceler::{
     cancel_local_pending_requests, cancel_pending_requests, cancel_pending_user_requests,
+    cancel_remote_request, push_pending_cancel,
 };
 use crate::handler::handler::{is_friend_ready, MutableEphemeral, MutableFunderState};
 use crate::handler::sender::SendCommands;
+use crate::liveness::LivenessMutation;
 
+use crate::report::create_single_friend_report;
 use crate::types::ChannelerConfig;
 
 #[derive(Debug)]
@@ -38,6 +46,10 @@ pub enum HandleControlError {
     UserRequestInvalid,
     FriendNotReady,
     MaxNodeRelaysReached,
+    RequestDoesNotExist,
+    FriendAlreadyExists,
+    FriendBalanceNotZero,
+    FriendHasPendingOperations,
 }
 
 fn control_set_friend_remote_max_debt<B>(
@@ -75,6 +87,9 @@ where
     Ok(())
 }
 
+/// Accept the reset terms of the remote side, for a channel in inconsistent state.
+/// The given reset token must match the remote reset terms. Fails with NotInvitedToReset if
+/// the remote reset terms are not known yet.
 fn control_reset_friend_channel<B>(
     m_state: &mut MutableFunderState<B>,
     send_commands: &mut SendCommands,
@@ -142,9 +157,20 @@ fn disable_friend<B>(
     B: Clone + PartialEq + Eq + CanonicalSerialize + Debug,
 {
     // Cancel all pending requests to this friend:
-    cancel_pending_requests(m_state, send_commands, outgoing_control, friend_public_key);
+    cancel_pending_requests(
+        m_state,
+        send_commands,
+        outgoing_control,
+        friend_public_key,
+        FailureReason::Canceled,
+    );
 
-    cancel_pending_user_requests(m_state, outgoing_control, friend_public_key);
+    cancel_pending_user_requests(
+        m_state,
+        outgoing_control,
+        friend_public_key,
+        FailureReason::Canceled,
+    );
 
     // Notify Channeler:
     let channeler_config = ChannelerConfig::RemoveFriend(friend_public_key.clone());
@@ -229,8 +255,78 @@ where
     m_state.mutate(funder_mutation);
 }
 
+/// Add a friend and set its wanted remote max debt in one step.
+/// The friend is created disabled, so the initial trust is in place before any move token is
+/// exchanged.
+fn control_add_friend_with_trust<B>(
+    m_state: &mut MutableFunderState<B>,
+    add_friend_with_trust: AddFriendWithTrust<B>,
+) -> Result<(), HandleControlError>
+where
+    B: Clone + PartialEq + Eq + CanonicalSerialize + Debug,
+{
+    let AddFriendWithTrust {
+        add_friend,
+        initial_remote_max_debt,
+    } = add_friend_with_trust;
+
+    if m_state
+        .state()
+        .friends
+        .contains_key(&add_friend.friend_public_key)
+    {
+        return Err(HandleControlError::FriendAlreadyExists);
+    }
+
+    let friend_public_key = add_friend.friend_public_key.clone();
+    control_add_friend(m_state, add_friend);
+
+    let friend_mutation = FriendMutation::SetWantedRemoteMaxDebt(initial_remote_max_debt);
+    let funder_mutation = FunderMutation::FriendMutation((friend_public_key, friend_mutation));
+    m_state.mutate(funder_mutation);
+    Ok(())
+}
+
+/// Make sure that removing a friend will not strand any funds: The balance with the friend must
+/// be zero, and there must be no requests or responses in flight.
+fn check_friend_settled<B>(friend: &FriendState<B>) -> Result<(), HandleControlError>
+where
+    B: Clone,
+{
+    let is_balance_settled = match &friend.channel_status {
+        ChannelStatus::Consistent(token_channel) => {
+            let mutual_credit = token_channel.get_mutual_credit();
+            let mc_state = mutual_credit.state();
+            if !mc_state.pending_requests.pending_local_requests.is_empty()
+                || !mc_state.pending_requests.pending_remote_requests.is_empty()
+            {
+                return Err(HandleControlError::FriendHasPendingOperations);
+            }
+            // Neither side may owe credits to the other:
+            mutual_credit.we_owe() == 0 && mutual_credit.owed_to_us() == 0
+        }
+        ChannelStatus::Inconsistent(channel_inconsistent) => {
+            channel_inconsistent.local_reset_terms.balance_for_reset == 0
+        }
+    };
+
+    if !friend.pending_requests.is_empty()
+        || !friend.pending_responses.is_empty()
+        || !friend.pending_user_requests.is_empty()
+    {
+        return Err(HandleControlError::FriendHasPendingOperations);
+    }
+
+    if !is_balance_settled {
+        return Err(HandleControlError::FriendBalanceNotZero);
+    }
+
+    Ok(())
+}
+
 /// This is a violent operation, as it removes all the known state with the remote friend.
 /// An inconsistency will occur if the friend is added again.
+/// Unless `force` is set, the friend is only removed if it is settled (See `check_friend_settled`).
 fn control_remove_friend<B>(
     m_state: &mut MutableFunderState<B>,
     send_commands: &mut SendCommands,
@@ -242,12 +338,16 @@ where
     B: Clone + PartialEq + Eq + CanonicalSerialize + Debug,
 {
     // Make sure that friend exists:
-    let _friend = m_state
+    let friend = m_state
         .state()
         .friends
         .get(&remove_friend.friend_public_key)
         .ok_or(HandleControlError::FriendDoesNotExist)?;
 
+    if !remove_friend.force {
+        check_friend_settled(friend)?;
+    }
+
     disable_friend(
         m_state,
         send_commands,
@@ -378,6 +478,14 @@ where
     ));
     m_state.mutate(funder_mutation);
 
+    // The relays set by the user replace any unconfirmed update from the remote side:
+    let friend_mutation = FriendMutation::SetOptPrevRemoteRelays(None);
+    let funder_mutation = FunderMutation::FriendMutation((
+        set_friend_relays.friend_public_key.clone(),
+        friend_mutation,
+    ));
+    m_state.mutate(funder_mutation);
+
     if let FriendStatus::Enabled = friend_status {
         // Notify Channeler to change the friend's address:
         let update_friend = ChannelerUpdateFriend {
@@ -421,10 +529,44 @@ where
     Ok(())
 }
 
+/// Set the offline grace period of a single friend, overriding the global offline_grace_ticks.
+fn control_set_friend_liveness_interval<B>(
+    m_state: &MutableFunderState<B>,
+    m_ephemeral: &mut MutableEphemeral,
+    set_friend_liveness_interval: SetFriendLivenessInterval,
+) -> Result<(), HandleControlError>
+where
+    B: Clone + PartialEq + Eq + CanonicalSerialize + Debug,
+{
+    // Make sure that friend exists:
+    if !m_state
+        .state()
+        .friends
+        .contains_key(&set_friend_liveness_interval.friend_public_key)
+    {
+        return Err(HandleControlError::FriendDoesNotExist);
+    }
+
+    let liveness_mutation = LivenessMutation::SetFriendInterval((
+        set_friend_liveness_interval.friend_public_key,
+        set_friend_liveness_interval.interval_ticks,
+    ));
+    m_ephemeral.mutate(EphemeralMutation::LivenessMutation(liveness_mutation));
+
+    Ok(())
+}
+
 fn check_user_request_valid(user_request_send_funds: &UserRequestSendFunds) -> Option<()> {
     if !user_request_send_funds.route.is_valid() {
         return None;
     }
+    // We freeze the payment together with the fees of the whole route. Make sure that this amount
+    // does not overflow:
+    let route_len = u32::try_from(user_request_send_funds.route.len()).ok()?;
+    let dest_payment = user_request_send_funds.dest_payment;
+    CreditCalculator::new(route_len, dest_payment)
+        .total_fee()?
+        .checked_add(dest_payment)?;
     Some(())
 }
 
@@ -453,6 +595,8 @@ where
         let response_received = ResponseReceived {
             request_id: user_request_send_funds.request_id,
             result: ResponseSendFundsResult::Success(receipt.clone()),
+            opt_elapsed_ticks: None,
+            opt_failure_reason: None,
         };
         outgoing_control.push(FunderOutgoingControl::ResponseReceived(response_received));
         return Ok(());
@@ -482,26 +626,11 @@ where
         return Err(HandleControlError::FriendNotReady);
     }
 
-    // If request is already in progress, we do nothing:
-    // Check if there is already a pending user request with the same request_id:
-    for user_request in &friend.pending_user_requests {
-        if user_request_send_funds.request_id == user_request.request_id {
-            return Err(HandleControlError::RequestAlreadyInProgress);
-        }
-    }
-
-    let token_channel = match &friend.channel_status {
-        ChannelStatus::Inconsistent(_) => unreachable!(),
-        ChannelStatus::Consistent(token_channel) => token_channel,
-    };
-
-    // Check if there is an ongoing request with the same request_id with this specific friend:
-    if token_channel
-        .get_mutual_credit()
+    // Make sure that the request_id does not collide with any request in flight, through any
+    // friend. Otherwise responses and receipts could be attributed to the wrong request.
+    if m_state
         .state()
-        .pending_requests
-        .pending_local_requests
-        .contains_key(&user_request_send_funds.request_id)
+        .is_request_in_flight(&user_request_send_funds.request_id)
     {
         return Err(HandleControlError::RequestAlreadyInProgress);
     }
@@ -546,6 +675,8 @@ where
         let response_received = ResponseReceived {
             request_id: user_request_send_funds.request_id,
             result: ResponseSendFundsResult::Failure(m_state.state().local_public_key.clone()),
+            opt_elapsed_ticks: None,
+            opt_failure_reason: None,
         };
 
         outgoing_control.push(FunderOutgoingControl::ResponseReceived(response_received));
@@ -556,6 +687,220 @@ where
     Ok(())
 }
 
+/// Cancel a request we have originated or forwarded.
+/// A user request that is still waiting to be sent to a friend is failed right away. For a request
+/// that was already sent inside a move token, we ask the next node on the route to cancel it. The
+/// failure then arrives back through the usual path.
+fn control_cancel_request<B>(
+    m_state: &mut MutableFunderState<B>,
+    outgoing_control: &mut Vec<FunderOutgoingControl<B>>,
+    send_commands: &mut SendCommands,
+    request_id: Uid,
+) -> Result<(), HandleControlError>
+where
+    B: Clone + PartialEq + Eq + CanonicalSerialize + Debug,
+{
+    // Find the friend that has this request in its pending user requests queue:
+    let opt_friend_public_key = m_state
+        .state()
+        .friends
+        .iter()
+        .find(|(_friend_public_key, friend)| {
+            friend
+                .pending_user_requests
+                .iter()
+                .any(|request_send_funds| request_send_funds.request_id == request_id)
+        })
+        .map(|(friend_public_key, _friend)| friend_public_key.clone());
+
+    let friend_public_key = match opt_friend_public_key {
+        Some(friend_public_key) => friend_public_key,
+        None => return control_cancel_sent_request(m_state, send_commands, request_id),
+    };
+
+    let friend_mutation = FriendMutation::RemovePendingUserRequest(request_id);
+    let funder_mutation = FunderMutation::FriendMutation((friend_public_key, friend_mutation));
+    m_state.mutate(funder_mutation);
+
+    // Every user request must have a matching response:
+    let response_received = ResponseReceived {
+        request_id,
+        result: ResponseSendFundsResult::Failure(m_state.state().local_public_key.clone()),
+        opt_elapsed_ticks: None,
+        opt_failure_reason: Some(FailureReason::Canceled),
+    };
+    outgoing_control.push(FunderOutgoingControl::ResponseReceived(response_received));
+
+    Ok(())
+}
+
+/// Cancel a request that was already sent to a friend inside a move token.
+fn control_cancel_sent_request<B>(
+    m_state: &mut MutableFunderState<B>,
+    send_commands: &mut SendCommands,
+    request_id: Uid,
+) -> Result<(), HandleControlError>
+where
+    B: Clone + PartialEq + Eq + CanonicalSerialize + Debug,
+{
+    // A request we have forwarded:
+    if let Some(origin_public_key) = m_state.state().find_request_origin(&request_id).cloned() {
+        let friend = m_state
+            .state()
+            .friends
+            .get(&origin_public_key)
+            .ok_or(HandleControlError::FriendDoesNotExist)?;
+        let pending_request = match &friend.channel_status {
+            ChannelStatus::Consistent(token_channel) => token_channel
+                .get_mutual_credit()
+                .state()
+                .pending_requests
+                .pending_remote_requests
+                .get(&request_id)
+                .cloned()
+                .ok_or(HandleControlError::RequestDoesNotExist)?,
+            ChannelStatus::Inconsistent(_) => return Err(HandleControlError::FriendNotReady),
+        };
+        cancel_remote_request(
+            m_state,
+            send_commands,
+            &origin_public_key,
+            pending_request,
+            FailureReason::Canceled,
+        );
+        return Ok(());
+    }
+
+    // A request we have originated:
+    let opt_friend_public_key = m_state
+        .state()
+        .friends
+        .iter()
+        .find(
+            |(_friend_public_key, friend)| match &friend.channel_status {
+                ChannelStatus::Consistent(token_channel) => token_channel
+                    .get_mutual_credit()
+                    .state()
+                    .pending_requests
+                    .pending_local_requests
+                    .contains_key(&request_id),
+                ChannelStatus::Inconsistent(_) => false,
+            },
+        )
+        .map(|(friend_public_key, _friend)| friend_public_key.clone());
+
+    let friend_public_key = opt_friend_public_key.ok_or(HandleControlError::RequestDoesNotExist)?;
+    push_pending_cancel(m_state, send_commands, &friend_public_key, request_id);
+    Ok(())
+}
+
+/// Resend our last outgoing move token to a friend.
+/// This is useful for recovering a stuck channel, for example after a relay glitch.
+fn control_resend_token<B>(
+    m_state: &MutableFunderState<B>,
+    send_commands: &mut SendCommands,
+    friend_public_key: PublicKey,
+) -> Result<(), HandleControlError>
+where
+    B: Clone + PartialEq + Eq + CanonicalSerialize + Debug,
+{
+    let friend = m_state
+        .state()
+        .friends
+        .get(&friend_public_key)
+        .ok_or(HandleControlError::FriendDoesNotExist)?;
+
+    match &friend.channel_status {
+        ChannelStatus::Consistent(token_channel) if token_channel.is_outgoing() => {
+            send_commands.set_resend_outgoing(&friend_public_key);
+        }
+        ChannelStatus::Consistent(_) => warn!(
+            "control_resend_token(): No outgoing move token for friend {:?}",
+            friend_public_key
+        ),
+        ChannelStatus::Inconsistent(_) => warn!(
+            "control_resend_token(): Channel with friend {:?} is inconsistent",
+            friend_public_key
+        ),
+    }
+    Ok(())
+}
+
+/// Send the user a report of the full state of a single friend.
+/// If the friend does not exist, the report is empty.
+fn control_query_friend<B>(
+    m_state: &MutableFunderState<B>,
+    ephemeral: &Ephemeral,
+    outgoing_control: &mut Vec<FunderOutgoingControl<B>>,
+    pending_queue_threshold: usize,
+    friend_public_key: PublicKey,
+) where
+    B: Clone + PartialEq + Eq + CanonicalSerialize + Debug,
+{
+    let opt_friend_report = create_single_friend_report(
+        m_state.state(),
+        ephemeral,
+        &friend_public_key,
+        pending_queue_threshold,
+    );
+    let friend_query_result = FriendQueryResult {
+        friend_public_key,
+        opt_friend_report,
+    };
+    outgoing_control.push(FunderOutgoingControl::FriendQueryResult(
+        friend_query_result,
+    ));
+}
+
+/// Send the user the move token counters of the token channel with a friend.
+/// If the friend does not exist, or the channel is inconsistent, no counters are reported.
+fn control_query_token_counters<B>(
+    m_state: &MutableFunderState<B>,
+    outgoing_control: &mut Vec<FunderOutgoingControl<B>>,
+    friend_public_key: PublicKey,
+) where
+    B: Clone + PartialEq + Eq + CanonicalSerialize + Debug,
+{
+    let opt_token_channel = m_state
+        .state()
+        .friends
+        .get(&friend_public_key)
+        .and_then(|friend| match &friend.channel_status {
+            ChannelStatus::Consistent(token_channel) => Some(token_channel),
+            ChannelStatus::Inconsistent(_) => None,
+        });
+
+    let opt_token_channel_counters = opt_token_channel.map(|token_channel| {
+        // If we hold the token, the current counters are the counters of our outgoing move token:
+        let opt_local_outgoing = if token_channel.is_outgoing() {
+            Some(MoveTokenCounters {
+                move_token_counter: token_channel.get_move_token_counter(),
+                inconsistency_counter: token_channel.get_inconsistency_counter(),
+            })
+        } else {
+            None
+        };
+        let opt_last_incoming =
+            token_channel
+                .get_last_incoming_move_token_hashed()
+                .map(|move_token_hashed| MoveTokenCounters {
+                    move_token_counter: move_token_hashed.move_token_counter,
+                    inconsistency_counter: move_token_hashed.inconsistency_counter,
+                });
+        TokenChannelCounters {
+            opt_local_outgoing,
+            opt_last_incoming,
+        }
+    });
+
+    outgoing_control.push(FunderOutgoingControl::TokenCountersQueryResult(
+        TokenCountersQueryResult {
+            friend_public_key,
+            opt_token_channel_counters,
+        },
+    ));
+}
+
 /// Handle an incoming receipt ack message
 fn control_receipt_ack<B>(
     m_state: &mut MutableFunderState<B>,
@@ -583,6 +928,32 @@ where
     Ok(())
 }
 
+/// Send the user a ready receipt that was not yet acknowledged.
+/// Note that the receipt is not removed. This will only be done when a receipt ack is received.
+fn control_fetch_receipt<B>(
+    m_state: &MutableFunderState<B>,
+    outgoing_control: &mut Vec<FunderOutgoingControl<B>>,
+    request_id: Uid,
+) -> Result<(), HandleControlError>
+where
+    B: Clone + PartialEq + Eq + CanonicalSerialize + Debug,
+{
+    let receipt = m_state
+        .state()
+        .ready_receipts
+        .get(&request_id)
+        .ok_or(HandleControlError::ReceiptDoesNotExist)?;
+
+    let response_received = ResponseReceived {
+        request_id,
+        result: ResponseSendFundsResult::Success(receipt.clone()),
+        opt_elapsed_ticks: None,
+        opt_failure_reason: None,
+    };
+    outgoing_control.push(FunderOutgoingControl::ResponseReceived(response_received));
+    Ok(())
+}
+
 pub fn handle_control_message<B>(
     m_state: &mut MutableFunderState<B>,
     m_ephemeral: &mut MutableEphemeral,
@@ -591,6 +962,7 @@ pub fn handle_control_message<B>(
     outgoing_channeler_config: &mut Vec<ChannelerConfig<RelayAddress<B>>>,
     max_node_relays: usize,
     max_pending_user_requests: usize,
+    pending_queue_threshold: usize,
     incoming_control: FunderControl<B>,
 ) -> Result<(), HandleControlError>
 where
@@ -628,6 +1000,10 @@ where
             Ok(())
         }
 
+        FunderControl::AddFriendWithTrust(add_friend_with_trust) => {
+            control_add_friend_with_trust(m_state, add_friend_with_trust)
+        }
+
         FunderControl::RemoveFriend(remove_friend) => control_remove_friend(
             m_state,
             send_commands,
@@ -656,6 +1032,10 @@ where
             control_set_friend_name(m_state, set_friend_name)
         }
 
+        FunderControl::SetFriendLivenessInterval(set_friend_liveness_interval) => {
+            control_set_friend_liveness_interval(m_state, m_ephemeral, set_friend_liveness_interval)
+        }
+
         FunderControl::RequestSendFunds(user_request_send_funds) => control_request_send_funds(
             m_state,
             m_ephemeral.ephemeral(),
@@ -665,6 +1045,1150 @@ where
             user_request_send_funds,
         ),
 
+        FunderControl::CancelRequest(request_id) => {
+            control_cancel_request(m_state, outgoing_control, send_commands, request_id)
+        }
+
+        FunderControl::ResendToken(friend_public_key) => {
+            control_resend_token(m_state, send_commands, friend_public_key)
+        }
+
+        FunderControl::QueryFriend(friend_public_key) => {
+            control_query_friend(
+                m_state,
+                m_ephemeral.ephemeral(),
+                outgoing_control,
+                pending_queue_threshold,
+                friend_public_key,
+            );
+            Ok(())
+        }
+
         FunderControl::ReceiptAck(receipt_ack) => control_receipt_ack(m_state, receipt_ack),
+
+        FunderControl::FetchReceipt(request_id) => {
+            control_fetch_receipt(m_state, outgoing_control, request_id)
+        }
+
+        FunderControl::QueryMetrics => {
+            outgoing_control.push(FunderOutgoingControl::MetricsQueryResult(
+                m_ephemeral.ephemeral().metrics.clone(),
+            ));
+            Ok(())
+        }
+
+        FunderControl::QueryTokenCounters(friend_public_key) => {
+            control_query_token_counters(m_state, outgoing_control, friend_public_key);
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crypto::crypto_rand::{RandValue, RAND_VALUE_LEN};
+    use crypto::hash::{HashResult, HASH_RESULT_LEN};
+    use crypto::identity::{Signature, PUBLIC_KEY_LEN, SIGNATURE_LEN};
+    use crypto::invoice_id::{InvoiceId, INVOICE_ID_LEN};
+    use crypto::uid::UID_LEN;
+    use proto::funder::messages::{FriendsRoute, MoveToken, Receipt, RequestsStatus, ResetTerms};
+    use proto::report::messages::FriendLivenessReport;
+
+    use crate::friend::ChannelInconsistent;
+
+    use crate::mutual_credit::types::McMutation;
+    use crate::state::FunderState;
+    use crate::tests::utils::{dummy_named_relay_address, dummy_relay_address};
+    use crate::token_channel::{SetDirection, TcMutation, TokenChannel};
+    use crate::types::create_hashed;
+
+    /// Add a friend that is online and open for requests.
+    fn add_ready_friend(
+        state: &mut FunderState<u32>,
+        ephemeral: &mut Ephemeral,
+        friend_public_key: &PublicKey,
+        index: u8,
+    ) {
+        let add_friend = AddFriend {
+            friend_public_key: friend_public_key.clone(),
+            relays: vec![dummy_relay_address(index)],
+            name: format!("friend{}", index),
+            balance: 0i128,
+        };
+        state.mutate(&FunderMutation::AddFriend(add_friend));
+
+        // The remote side is open for requests:
+        let mc_mutation = McMutation::SetRemoteRequestsStatus(RequestsStatus::Open);
+        let friend_mutation = FriendMutation::TcMutation(TcMutation::McMutation(mc_mutation));
+        state.mutate(&FunderMutation::FriendMutation((
+            friend_public_key.clone(),
+            friend_mutation,
+        )));
+
+        // The remote side is online:
+        let liveness_mutation = LivenessMutation::SetOnline(friend_public_key.clone());
+        ephemeral.mutate(&EphemeralMutation::LivenessMutation(liveness_mutation));
+    }
+
+    #[test]
+    fn test_control_fetch_receipt() {
+        let local_pk = PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]);
+        let state = FunderState::<u32>::new(local_pk.clone(), vec![dummy_named_relay_address(0)]);
+        let mut m_state = MutableFunderState::new(state);
+        let mut outgoing_control = Vec::new();
+
+        let request_id = Uid::from(&[1; UID_LEN]);
+        let receipt = Receipt {
+            response_hash: HashResult::from(&[2; HASH_RESULT_LEN]),
+            invoice_id: InvoiceId::from(&[3; INVOICE_ID_LEN]),
+            dest_payment: 10,
+            signature: Signature::from(&[4; SIGNATURE_LEN]),
+        };
+
+        // The receipt is kept in case the user crashes before receiving it:
+        m_state.mutate(FunderMutation::AddReceipt((request_id, receipt.clone())));
+        let pending_receipts = m_state.state().pending_receipts().collect::<Vec<_>>();
+        assert_eq!(pending_receipts, vec![(&request_id, &receipt)]);
+
+        // The user reclaims the receipt:
+        control_fetch_receipt(&m_state, &mut outgoing_control, request_id).unwrap();
+        assert_eq!(outgoing_control.len(), 1);
+        match outgoing_control.pop().unwrap() {
+            FunderOutgoingControl::ResponseReceived(response_received) => {
+                assert_eq!(response_received.request_id, request_id);
+                assert_eq!(
+                    response_received.result,
+                    ResponseSendFundsResult::Success(receipt.clone())
+                );
+            }
+            _ => unreachable!(),
+        };
+        // Fetching does not remove the receipt:
+        assert_eq!(m_state.state().pending_receipts().count(), 1);
+
+        // Acknowledging the receipt removes it:
+        let receipt_ack = ReceiptAck {
+            request_id,
+            receipt_signature: receipt.signature.clone(),
+        };
+        control_receipt_ack(&mut m_state, receipt_ack).unwrap();
+        assert_eq!(m_state.state().pending_receipts().count(), 0);
+
+        match control_fetch_receipt(&m_state, &mut outgoing_control, request_id) {
+            Err(HandleControlError::ReceiptDoesNotExist) => {}
+            _ => unreachable!(),
+        };
+        assert!(outgoing_control.is_empty());
+    }
+
+    #[test]
+    fn test_control_request_send_funds_pending_user_requests_full() {
+        let max_pending_user_requests = 2;
+
+        let local_pk = PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]);
+        let remote_pk = PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]);
+
+        let mut state =
+            FunderState::<u32>::new(local_pk.clone(), vec![dummy_named_relay_address(0)]);
+        let mut ephemeral = Ephemeral::new();
+        add_ready_friend(&mut state, &mut ephemeral, &remote_pk, 1);
+
+        let mut m_state = MutableFunderState::new(state);
+        let mut send_commands = SendCommands::new();
+        let mut outgoing_control = Vec::new();
+
+        let user_request = |i: u8| UserRequestSendFunds {
+            request_id: Uid::from(&[i; UID_LEN]),
+            route: FriendsRoute {
+                public_keys: vec![local_pk.clone(), remote_pk.clone()],
+            },
+            invoice_id: InvoiceId::from(&[i; INVOICE_ID_LEN]),
+            dest_payment: 10,
+        };
+
+        // Fill the pending user requests queue:
+        for i in 0..max_pending_user_requests {
+            control_request_send_funds(
+                &mut m_state,
+                &ephemeral,
+                &mut outgoing_control,
+                &mut send_commands,
+                max_pending_user_requests,
+                user_request(i as u8),
+            )
+            .unwrap();
+        }
+        assert!(outgoing_control.is_empty());
+        let friend = m_state.state().friends.get(&remote_pk).unwrap();
+        assert_eq!(
+            friend.pending_user_requests.len(),
+            max_pending_user_requests
+        );
+
+        // The next request should be rejected immediately:
+        let rejected_request = user_request(max_pending_user_requests as u8);
+        control_request_send_funds(
+            &mut m_state,
+            &ephemeral,
+            &mut outgoing_control,
+            &mut send_commands,
+            max_pending_user_requests,
+            rejected_request.clone(),
+        )
+        .unwrap();
+
+        let friend = m_state.state().friends.get(&remote_pk).unwrap();
+        assert_eq!(
+            friend.pending_user_requests.len(),
+            max_pending_user_requests
+        );
+
+        assert_eq!(outgoing_control.len(), 1);
+        match &outgoing_control[0] {
+            FunderOutgoingControl::ResponseReceived(response_received) => {
+                assert_eq!(response_received.request_id, rejected_request.request_id);
+                assert_eq!(
+                    response_received.result,
+                    ResponseSendFundsResult::Failure(local_pk.clone())
+                );
+            }
+            _ => unreachable!(),
+        };
+    }
+
+    #[test]
+    fn test_control_request_send_funds_request_id_collision() {
+        let local_pk = PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]);
+        let pk_b = PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]);
+        let pk_c = PublicKey::from(&[0xcc; PUBLIC_KEY_LEN]);
+
+        let mut state =
+            FunderState::<u32>::new(local_pk.clone(), vec![dummy_named_relay_address(0)]);
+        let mut ephemeral = Ephemeral::new();
+        add_ready_friend(&mut state, &mut ephemeral, &pk_b, 1);
+        add_ready_friend(&mut state, &mut ephemeral, &pk_c, 2);
+
+        let mut m_state = MutableFunderState::new(state);
+        let mut send_commands = SendCommands::new();
+        let mut outgoing_control = Vec::new();
+
+        let request_id = Uid::from(&[1; UID_LEN]);
+        let request_b = UserRequestSendFunds {
+            request_id,
+            route: FriendsRoute {
+                public_keys: vec![local_pk.clone(), pk_b.clone()],
+            },
+            invoice_id: InvoiceId::from(&[1; INVOICE_ID_LEN]),
+            dest_payment: 10,
+        };
+        control_request_send_funds(
+            &mut m_state,
+            &ephemeral,
+            &mut outgoing_control,
+            &mut send_commands,
+            16,
+            request_b,
+        )
+        .unwrap();
+        assert!(outgoing_control.is_empty());
+
+        // A request with the same request_id through a different friend:
+        let request_c = UserRequestSendFunds {
+            request_id,
+            route: FriendsRoute {
+                public_keys: vec![local_pk.clone(), pk_c.clone()],
+            },
+            invoice_id: InvoiceId::from(&[2; INVOICE_ID_LEN]),
+            dest_payment: 20,
+        };
+        control_request_send_funds(
+            &mut m_state,
+            &ephemeral,
+            &mut outgoing_control,
+            &mut send_commands,
+            16,
+            request_c.clone(),
+        )
+        .unwrap();
+
+        // The colliding request should be rejected:
+        assert!(m_state
+            .state()
+            .friends
+            .get(&pk_c)
+            .unwrap()
+            .pending_user_requests
+            .is_empty());
+        assert_eq!(
+            m_state
+                .state()
+                .friends
+                .get(&pk_b)
+                .unwrap()
+                .pending_user_requests
+                .len(),
+            1
+        );
+
+        // The intake path reports a request id collision:
+        match control_request_send_funds_inner(
+            &mut m_state,
+            &ephemeral,
+            &mut outgoing_control,
+            &mut send_commands,
+            16,
+            request_c,
+        ) {
+            Err(HandleControlError::RequestAlreadyInProgress) => {}
+            _ => unreachable!(),
+        };
+
+        assert_eq!(outgoing_control.len(), 1);
+        match &outgoing_control[0] {
+            FunderOutgoingControl::ResponseReceived(response_received) => {
+                assert_eq!(response_received.request_id, request_id);
+                assert_eq!(
+                    response_received.result,
+                    ResponseSendFundsResult::Failure(local_pk.clone())
+                );
+            }
+            _ => unreachable!(),
+        };
+    }
+
+    #[test]
+    fn test_control_request_send_funds_fee_overflow() {
+        let local_pk = PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]);
+        let pk_b = PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]);
+        let pk_c = PublicKey::from(&[0xcc; PUBLIC_KEY_LEN]);
+
+        let mut state =
+            FunderState::<u32>::new(local_pk.clone(), vec![dummy_named_relay_address(0)]);
+        let mut ephemeral = Ephemeral::new();
+        add_ready_friend(&mut state, &mut ephemeral, &pk_b, 1);
+
+        let mut m_state = MutableFunderState::new(state);
+        let mut send_commands = SendCommands::new();
+        let mut outgoing_control = Vec::new();
+
+        // pk_b earns a fee of one credit, which can not be added to the payment:
+        let user_request = UserRequestSendFunds {
+            request_id: Uid::from(&[1; UID_LEN]),
+            route: FriendsRoute {
+                public_keys: vec![local_pk.clone(), pk_b.clone(), pk_c.clone()],
+            },
+            invoice_id: InvoiceId::from(&[1; INVOICE_ID_LEN]),
+            dest_payment: u128::max_value(),
+        };
+        match control_request_send_funds_inner(
+            &mut m_state,
+            &ephemeral,
+            &mut outgoing_control,
+            &mut send_commands,
+            16,
+            user_request.clone(),
+        ) {
+            Err(HandleControlError::UserRequestInvalid) => {}
+            _ => unreachable!(),
+        };
+        assert!(m_state
+            .state()
+            .friends
+            .get(&pk_b)
+            .unwrap()
+            .pending_user_requests
+            .is_empty());
+
+        // Without intermediate nodes there are no fees:
+        let user_request = UserRequestSendFunds {
+            route: FriendsRoute {
+                public_keys: vec![local_pk.clone(), pk_b.clone()],
+            },
+            ..user_request
+        };
+        control_request_send_funds_inner(
+            &mut m_state,
+            &ephemeral,
+            &mut outgoing_control,
+            &mut send_commands,
+            16,
+            user_request,
+        )
+        .unwrap();
+        assert!(outgoing_control.is_empty());
+    }
+
+    #[test]
+    fn test_control_cancel_request() {
+        let local_pk = PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]);
+        let remote_pk = PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]);
+
+        let mut state =
+            FunderState::<u32>::new(local_pk.clone(), vec![dummy_named_relay_address(0)]);
+        let mut ephemeral = Ephemeral::new();
+        add_ready_friend(&mut state, &mut ephemeral, &remote_pk, 1);
+
+        // A request that was already sent to the remote friend inside a move token:
+        let sent_request = UserRequestSendFunds {
+            request_id: Uid::from(&[1; UID_LEN]),
+            route: FriendsRoute {
+                public_keys: vec![local_pk.clone(), remote_pk.clone()],
+            },
+            invoice_id: InvoiceId::from(&[1; INVOICE_ID_LEN]),
+            dest_payment: 10,
+        };
+        let mc_mutation =
+            McMutation::InsertLocalPendingRequest(sent_request.create_pending_request());
+        let friend_mutation = FriendMutation::TcMutation(TcMutation::McMutation(mc_mutation));
+        state.mutate(&FunderMutation::FriendMutation((
+            remote_pk.clone(),
+            friend_mutation,
+        )));
+
+        let mut m_state = MutableFunderState::new(state);
+        let mut send_commands = SendCommands::new();
+        let mut outgoing_control = Vec::new();
+
+        // A request that is still waiting to be sent:
+        let queued_request = UserRequestSendFunds {
+            request_id: Uid::from(&[2; UID_LEN]),
+            route: FriendsRoute {
+                public_keys: vec![local_pk.clone(), remote_pk.clone()],
+            },
+            invoice_id: InvoiceId::from(&[2; INVOICE_ID_LEN]),
+            dest_payment: 10,
+        };
+        control_request_send_funds(
+            &mut m_state,
+            &ephemeral,
+            &mut outgoing_control,
+            &mut send_commands,
+            16,
+            queued_request.clone(),
+        )
+        .unwrap();
+        assert!(outgoing_control.is_empty());
+
+        // The queued request can be canceled:
+        control_cancel_request(
+            &mut m_state,
+            &mut outgoing_control,
+            &mut send_commands,
+            queued_request.request_id,
+        )
+        .unwrap();
+
+        let friend = m_state.state().friends.get(&remote_pk).unwrap();
+        assert!(friend.pending_user_requests.is_empty());
+
+        assert_eq!(outgoing_control.len(), 1);
+        match outgoing_control.pop().unwrap() {
+            FunderOutgoingControl::ResponseReceived(response_received) => {
+                assert_eq!(response_received.request_id, queued_request.request_id);
+                assert_eq!(
+                    response_received.result,
+                    ResponseSendFundsResult::Failure(local_pk.clone())
+                );
+            }
+            _ => unreachable!(),
+        };
+
+        // For the request that was already sent, we ask the remote friend to cancel it. The
+        // failure will arrive from the remote friend:
+        let mut send_commands = SendCommands::new();
+        control_cancel_request(
+            &mut m_state,
+            &mut outgoing_control,
+            &mut send_commands,
+            sent_request.request_id,
+        )
+        .unwrap();
+        assert!(send_commands.send_commands.contains_key(&remote_pk));
+        assert!(outgoing_control.is_empty());
+
+        // Canceling again does not ask the remote friend twice:
+        let mut send_commands = SendCommands::new();
+        control_cancel_request(
+            &mut m_state,
+            &mut outgoing_control,
+            &mut send_commands,
+            sent_request.request_id,
+        )
+        .unwrap();
+        assert!(send_commands.send_commands.is_empty());
+        assert!(outgoing_control.is_empty());
+
+        let friend = m_state.state().friends.get(&remote_pk).unwrap();
+        assert_eq!(
+            friend.pending_cancels.iter().cloned().collect::<Vec<_>>(),
+            vec![sent_request.request_id]
+        );
+
+        // A request that does not exist:
+        match control_cancel_request(
+            &mut m_state,
+            &mut outgoing_control,
+            &mut send_commands,
+            Uid::from(&[3; UID_LEN]),
+        ) {
+            Err(HandleControlError::RequestDoesNotExist) => {}
+            _ => unreachable!(),
+        };
+        assert!(outgoing_control.is_empty());
+    }
+
+    #[test]
+    fn test_control_set_friend_status_disable() {
+        let local_pk = PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]);
+        let remote_pk = PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]);
+
+        let mut state =
+            FunderState::<u32>::new(local_pk.clone(), vec![dummy_named_relay_address(0)]);
+        let mut ephemeral = Ephemeral::new();
+        add_ready_friend(&mut state, &mut ephemeral, &remote_pk, 1);
+
+        let mut m_state = MutableFunderState::new(state);
+        let mut send_commands = SendCommands::new();
+        let mut outgoing_control = Vec::new();
+        let mut outgoing_channeler_config = Vec::new();
+
+        control_set_friend_status(
+            &mut m_state,
+            &mut send_commands,
+            &mut outgoing_control,
+            &mut outgoing_channeler_config,
+            SetFriendStatus {
+                friend_public_key: remote_pk.clone(),
+                status: FriendStatus::Enabled,
+            },
+        )
+        .unwrap();
+        outgoing_channeler_config.clear();
+
+        // Two user requests are in flight:
+        for i in 0..2u8 {
+            let user_request = UserRequestSendFunds {
+                request_id: Uid::from(&[i; UID_LEN]),
+                route: FriendsRoute {
+                    public_keys: vec![local_pk.clone(), remote_pk.clone()],
+                },
+                invoice_id: InvoiceId::from(&[i; INVOICE_ID_LEN]),
+                dest_payment: 10,
+            };
+            control_request_send_funds(
+                &mut m_state,
+                &ephemeral,
+                &mut outgoing_control,
+                &mut send_commands,
+                16,
+                user_request,
+            )
+            .unwrap();
+        }
+        assert!(outgoing_control.is_empty());
+
+        control_set_friend_status(
+            &mut m_state,
+            &mut send_commands,
+            &mut outgoing_control,
+            &mut outgoing_channeler_config,
+            SetFriendStatus {
+                friend_public_key: remote_pk.clone(),
+                status: FriendStatus::Disabled,
+            },
+        )
+        .unwrap();
+
+        let friend = m_state.state().friends.get(&remote_pk).unwrap();
+        assert_eq!(friend.status, FriendStatus::Disabled);
+        assert!(friend.pending_user_requests.is_empty());
+
+        // Both requests were canceled, and the user was notified:
+        let mut request_ids = Vec::new();
+        for funder_outgoing_control in &outgoing_control {
+            match funder_outgoing_control {
+                FunderOutgoingControl::ResponseReceived(response_received) => {
+                    assert_eq!(
+                        response_received.result,
+                        ResponseSendFundsResult::Failure(local_pk.clone())
+                    );
+                    assert_eq!(
+                        response_received.opt_failure_reason,
+                        Some(FailureReason::Canceled)
+                    );
+                    request_ids.push(response_received.request_id);
+                }
+                _ => unreachable!(),
+            }
+        }
+        assert_eq!(
+            request_ids,
+            vec![Uid::from(&[0; UID_LEN]), Uid::from(&[1; UID_LEN])]
+        );
+
+        // The Channeler is told to drop the connection to the friend:
+        assert_eq!(outgoing_channeler_config.len(), 1);
+        match &outgoing_channeler_config[0] {
+            ChannelerConfig::RemoveFriend(friend_public_key) => {
+                assert_eq!(friend_public_key, &remote_pk)
+            }
+            _ => unreachable!(),
+        };
+    }
+
+    #[test]
+    fn test_control_resend_token() {
+        let local_pk = PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]);
+        // We hold the token in the channel with out_pk, and in_pk holds the token in the channel
+        // with us:
+        let out_pk = PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]);
+        let in_pk = PublicKey::from(&[0xcc; PUBLIC_KEY_LEN]);
+
+        let mut state =
+            FunderState::<u32>::new(local_pk.clone(), vec![dummy_named_relay_address(0)]);
+        let mut ephemeral = Ephemeral::new();
+        add_ready_friend(&mut state, &mut ephemeral, &out_pk, 1);
+        add_ready_friend(&mut state, &mut ephemeral, &in_pk, 2);
+
+        for (friend_public_key, is_outgoing) in &[(&out_pk, true), (&in_pk, false)] {
+            let token_channel =
+                TokenChannel::new_for_test(&local_pk, friend_public_key, 0i128, *is_outgoing);
+            let friend_mutation = FriendMutation::SetConsistent(token_channel);
+            state.mutate(&FunderMutation::FriendMutation((
+                (*friend_public_key).clone(),
+                friend_mutation,
+            )));
+        }
+
+        let is_outgoing = |friend_public_key: &PublicKey| match &state
+            .friends
+            .get(friend_public_key)
+            .unwrap()
+            .channel_status
+        {
+            ChannelStatus::Consistent(token_channel) => token_channel.is_outgoing(),
+            ChannelStatus::Inconsistent(_) => unreachable!(),
+        };
+        assert!(is_outgoing(&out_pk));
+        assert!(!is_outgoing(&in_pk));
+
+        let m_state = MutableFunderState::new(state);
+        let mut send_commands = SendCommands::new();
+
+        // Outgoing channel: The move token is resent:
+        control_resend_token(&m_state, &mut send_commands, out_pk.clone()).unwrap();
+        let friend_send_commands = send_commands.send_commands.get(&out_pk).unwrap();
+        assert!(friend_send_commands.resend_outgoing);
+
+        // Incoming channel: Nothing to resend:
+        control_resend_token(&m_state, &mut send_commands, in_pk.clone()).unwrap();
+        assert!(send_commands.send_commands.get(&in_pk).is_none());
+
+        // Nonexistent friend:
+        let pk_d = PublicKey::from(&[0xdd; PUBLIC_KEY_LEN]);
+        match control_resend_token(&m_state, &mut send_commands, pk_d) {
+            Err(HandleControlError::FriendDoesNotExist) => {}
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_control_reset_friend_channel() {
+        let local_pk = PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]);
+        let remote_pk = PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]);
+
+        let mut state =
+            FunderState::<u32>::new(local_pk.clone(), vec![dummy_named_relay_address(0)]);
+        let mut ephemeral = Ephemeral::new();
+        add_ready_friend(&mut state, &mut ephemeral, &remote_pk, 1);
+
+        let mut m_state = MutableFunderState::new(state);
+        let mut send_commands = SendCommands::new();
+
+        let remote_reset_token = Signature::from(&[1; SIGNATURE_LEN]);
+        let reset_friend_channel = |reset_token: &Signature| ResetFriendChannel {
+            friend_public_key: remote_pk.clone(),
+            reset_token: reset_token.clone(),
+        };
+
+        // The channel is consistent:
+        match control_reset_friend_channel(
+            &mut m_state,
+            &mut send_commands,
+            reset_friend_channel(&remote_reset_token),
+        ) {
+            Err(HandleControlError::NotInvitedToReset) => {}
+            _ => unreachable!(),
+        }
+
+        // The channel is inconsistent, but remote reset terms are not known yet:
+        let local_reset_terms = ResetTerms {
+            reset_token: Signature::from(&[2; SIGNATURE_LEN]),
+            inconsistency_counter: 1,
+            balance_for_reset: 0,
+        };
+        let channel_inconsistent = ChannelInconsistent {
+            opt_last_incoming_move_token: None,
+            local_reset_terms: local_reset_terms.clone(),
+            opt_remote_reset_terms: None,
+        };
+        let friend_mutation = FriendMutation::SetInconsistent(channel_inconsistent);
+        m_state.mutate(FunderMutation::FriendMutation((
+            remote_pk.clone(),
+            friend_mutation,
+        )));
+        match control_reset_friend_channel(
+            &mut m_state,
+            &mut send_commands,
+            reset_friend_channel(&remote_reset_token),
+        ) {
+            Err(HandleControlError::NotInvitedToReset) => {}
+            _ => unreachable!(),
+        }
+
+        // Remote reset terms are known:
+        let remote_reset_terms = ResetTerms {
+            reset_token: remote_reset_token.clone(),
+            inconsistency_counter: 1,
+            balance_for_reset: 0,
+        };
+        let channel_inconsistent = ChannelInconsistent {
+            opt_last_incoming_move_token: None,
+            local_reset_terms,
+            opt_remote_reset_terms: Some(remote_reset_terms),
+        };
+        let friend_mutation = FriendMutation::SetInconsistent(channel_inconsistent);
+        m_state.mutate(FunderMutation::FriendMutation((
+            remote_pk.clone(),
+            friend_mutation,
+        )));
+
+        // Wrong reset token:
+        match control_reset_friend_channel(
+            &mut m_state,
+            &mut send_commands,
+            reset_friend_channel(&Signature::from(&[3; SIGNATURE_LEN])),
+        ) {
+            Err(HandleControlError::ResetTokenMismatch) => {}
+            _ => unreachable!(),
+        }
+        assert!(send_commands.send_commands.get(&remote_pk).is_none());
+
+        // The local reset is deferred to the sender:
+        control_reset_friend_channel(
+            &mut m_state,
+            &mut send_commands,
+            reset_friend_channel(&remote_reset_token),
+        )
+        .unwrap();
+        let friend_send_commands = send_commands.send_commands.get(&remote_pk).unwrap();
+        assert!(friend_send_commands.local_reset);
+    }
+
+    #[test]
+    fn test_control_query_friend() {
+        let local_pk = PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]);
+        let remote_pk = PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]);
+
+        let mut state =
+            FunderState::<u32>::new(local_pk.clone(), vec![dummy_named_relay_address(0)]);
+        let mut ephemeral = Ephemeral::new();
+        add_ready_friend(&mut state, &mut ephemeral, &remote_pk, 1);
+
+        let m_state = MutableFunderState::new(state);
+        let mut outgoing_control = Vec::new();
+
+        control_query_friend(
+            &m_state,
+            &ephemeral,
+            &mut outgoing_control,
+            0,
+            remote_pk.clone(),
+        );
+        assert_eq!(outgoing_control.len(), 1);
+        match outgoing_control.pop().unwrap() {
+            FunderOutgoingControl::FriendQueryResult(friend_query_result) => {
+                assert_eq!(friend_query_result.friend_public_key, remote_pk);
+                let friend_report = friend_query_result.opt_friend_report.unwrap();
+                assert_eq!(friend_report.name, "friend1");
+                assert_eq!(friend_report.liveness, FriendLivenessReport::Online);
+                assert_eq!(friend_report.num_pending_user_requests, 0);
+            }
+            _ => unreachable!(),
+        }
+
+        // Query a nonexistent friend:
+        let pk_c = PublicKey::from(&[0xcc; PUBLIC_KEY_LEN]);
+        control_query_friend(&m_state, &ephemeral, &mut outgoing_control, 0, pk_c.clone());
+        assert_eq!(outgoing_control.len(), 1);
+        match outgoing_control.pop().unwrap() {
+            FunderOutgoingControl::FriendQueryResult(friend_query_result) => {
+                assert_eq!(friend_query_result.friend_public_key, pk_c);
+                assert!(friend_query_result.opt_friend_report.is_none());
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    /// Set the direction of the token channel with a friend, as if a move token with the given
+    /// move_token_counter was sent (is_outgoing = true) or received (is_outgoing = false).
+    fn exchange_token(
+        state: &mut FunderState<u32>,
+        friend_public_key: &PublicKey,
+        move_token_counter: u128,
+        is_outgoing: bool,
+    ) {
+        let (local_public_key, remote_public_key) = if is_outgoing {
+            (state.local_public_key.clone(), friend_public_key.clone())
+        } else {
+            (friend_public_key.clone(), state.local_public_key.clone())
+        };
+        let move_token = MoveToken {
+            operations: Vec::new(),
+            opt_local_relays: None,
+            old_token: Signature::from(&[1; SIGNATURE_LEN]),
+            local_public_key,
+            remote_public_key,
+            inconsistency_counter: 0,
+            move_token_counter,
+            balance: 0,
+            local_pending_debt: 0,
+            remote_pending_debt: 0,
+            rand_nonce: RandValue::from(&[2; RAND_VALUE_LEN]),
+            new_token: Signature::from(&[3; SIGNATURE_LEN]),
+        };
+        let set_direction = if is_outgoing {
+            SetDirection::Outgoing(move_token)
+        } else {
+            SetDirection::Incoming(create_hashed(&move_token))
+        };
+        let friend_mutation = FriendMutation::TcMutation(TcMutation::SetDirection(set_direction));
+        state.mutate(&FunderMutation::FriendMutation((
+            friend_public_key.clone(),
+            friend_mutation,
+        )));
+    }
+
+    #[test]
+    fn test_control_query_token_counters() {
+        let local_pk = PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]);
+        let remote_pk = PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]);
+
+        let mut state =
+            FunderState::<u32>::new(local_pk.clone(), vec![dummy_named_relay_address(0)]);
+        let mut ephemeral = Ephemeral::new();
+        add_ready_friend(&mut state, &mut ephemeral, &remote_pk, 1);
+
+        // A few token exchanges:
+        exchange_token(&mut state, &remote_pk, 1, false);
+        exchange_token(&mut state, &remote_pk, 2, true);
+        exchange_token(&mut state, &remote_pk, 3, false);
+        exchange_token(&mut state, &remote_pk, 4, true);
+
+        let m_state = MutableFunderState::new(state.clone());
+        let mut outgoing_control = Vec::new();
+        control_query_token_counters(&m_state, &mut outgoing_control, remote_pk.clone());
+        assert_eq!(outgoing_control.len(), 1);
+        match outgoing_control.pop().unwrap() {
+            FunderOutgoingControl::TokenCountersQueryResult(token_counters_query_result) => {
+                assert_eq!(token_counters_query_result.friend_public_key, remote_pk);
+                let token_channel_counters = token_counters_query_result
+                    .opt_token_channel_counters
+                    .unwrap();
+                assert_eq!(
+                    token_channel_counters.opt_local_outgoing,
+                    Some(MoveTokenCounters {
+                        move_token_counter: 4,
+                        inconsistency_counter: 0,
+                    })
+                );
+                assert_eq!(
+                    token_channel_counters.opt_last_incoming,
+                    Some(MoveTokenCounters {
+                        move_token_counter: 3,
+                        inconsistency_counter: 0,
+                    })
+                );
+            }
+            _ => unreachable!(),
+        }
+
+        // The remote side now holds the token:
+        exchange_token(&mut state, &remote_pk, 5, false);
+        let m_state = MutableFunderState::new(state);
+        control_query_token_counters(&m_state, &mut outgoing_control, remote_pk.clone());
+        assert_eq!(outgoing_control.len(), 1);
+        match outgoing_control.pop().unwrap() {
+            FunderOutgoingControl::TokenCountersQueryResult(token_counters_query_result) => {
+                let token_channel_counters = token_counters_query_result
+                    .opt_token_channel_counters
+                    .unwrap();
+                assert!(token_channel_counters.opt_local_outgoing.is_none());
+                assert_eq!(
+                    token_channel_counters.opt_last_incoming,
+                    Some(MoveTokenCounters {
+                        move_token_counter: 5,
+                        inconsistency_counter: 0,
+                    })
+                );
+            }
+            _ => unreachable!(),
+        }
+
+        // Query a nonexistent friend:
+        let pk_c = PublicKey::from(&[0xcc; PUBLIC_KEY_LEN]);
+        control_query_token_counters(&m_state, &mut outgoing_control, pk_c.clone());
+        assert_eq!(outgoing_control.len(), 1);
+        match outgoing_control.pop().unwrap() {
+            FunderOutgoingControl::TokenCountersQueryResult(token_counters_query_result) => {
+                assert_eq!(token_counters_query_result.friend_public_key, pk_c);
+                assert!(token_counters_query_result
+                    .opt_token_channel_counters
+                    .is_none());
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_control_add_friend_with_trust() {
+        let local_pk = PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]);
+        let remote_pk = PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]);
+
+        let state = FunderState::<u32>::new(local_pk.clone(), vec![dummy_named_relay_address(0)]);
+        let ephemeral = Ephemeral::new();
+        let mut m_state = MutableFunderState::new(state);
+
+        let add_friend_with_trust = AddFriendWithTrust {
+            add_friend: AddFriend {
+                friend_public_key: remote_pk.clone(),
+                relays: vec![dummy_relay_address(1)],
+                name: "friend1".to_owned(),
+                balance: 0i128,
+            },
+            initial_remote_max_debt: 100,
+        };
+        control_add_friend_with_trust(&mut m_state, add_friend_with_trust.clone()).unwrap();
+
+        // Both the friend and its initial trust were created together:
+        let friend = m_state.state().friends.get(&remote_pk).unwrap();
+        assert_eq!(friend.wanted_remote_max_debt, 100);
+        assert_eq!(friend.status, FriendStatus::Disabled);
+
+        let mut outgoing_control = Vec::new();
+        control_query_friend(
+            &m_state,
+            &ephemeral,
+            &mut outgoing_control,
+            0,
+            remote_pk.clone(),
+        );
+        match outgoing_control.pop().unwrap() {
+            FunderOutgoingControl::FriendQueryResult(friend_query_result) => {
+                let friend_report = friend_query_result.opt_friend_report.unwrap();
+                assert_eq!(friend_report.wanted_remote_max_debt, 100);
+            }
+            _ => unreachable!(),
+        }
+
+        // Adding the same friend again fails:
+        match control_add_friend_with_trust(&mut m_state, add_friend_with_trust) {
+            Err(HandleControlError::FriendAlreadyExists) => {}
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_control_remove_friend_settlement() {
+        let local_pk = PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]);
+        let busy_pk = PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]);
+        let indebted_pk = PublicKey::from(&[0xcc; PUBLIC_KEY_LEN]);
+        let settled_pk = PublicKey::from(&[0xdd; PUBLIC_KEY_LEN]);
+
+        let mut state =
+            FunderState::<u32>::new(local_pk.clone(), vec![dummy_named_relay_address(0)]);
+        let mut ephemeral = Ephemeral::new();
+        add_ready_friend(&mut state, &mut ephemeral, &busy_pk, 1);
+        add_ready_friend(&mut state, &mut ephemeral, &settled_pk, 3);
+
+        let add_friend = AddFriend {
+            friend_public_key: indebted_pk.clone(),
+            relays: vec![dummy_relay_address(2)],
+            name: "friend2".to_owned(),
+            balance: 20i128,
+        };
+        state.mutate(&FunderMutation::AddFriend(add_friend));
+
+        let mut m_state = MutableFunderState::new(state);
+        let mut send_commands = SendCommands::new();
+        let mut outgoing_control = Vec::new();
+        let mut outgoing_channeler_config = Vec::new();
+
+        control_set_friend_status(
+            &mut m_state,
+            &mut send_commands,
+            &mut outgoing_control,
+            &mut outgoing_channeler_config,
+            SetFriendStatus {
+                friend_public_key: busy_pk.clone(),
+                status: FriendStatus::Enabled,
+            },
+        )
+        .unwrap();
+        outgoing_channeler_config.clear();
+
+        // A user request to busy_pk is in flight:
+        let user_request = UserRequestSendFunds {
+            request_id: Uid::from(&[0; UID_LEN]),
+            route: FriendsRoute {
+                public_keys: vec![local_pk.clone(), busy_pk.clone()],
+            },
+            invoice_id: InvoiceId::from(&[0; INVOICE_ID_LEN]),
+            dest_payment: 10,
+        };
+        control_request_send_funds(
+            &mut m_state,
+            &ephemeral,
+            &mut outgoing_control,
+            &mut send_commands,
+            16,
+            user_request,
+        )
+        .unwrap();
+
+        // Removal is refused while a request is pending:
+        let res = control_remove_friend(
+            &mut m_state,
+            &mut send_commands,
+            &mut outgoing_control,
+            &mut outgoing_channeler_config,
+            RemoveFriend {
+                friend_public_key: busy_pk.clone(),
+                force: false,
+            },
+        );
+        match res {
+            Err(HandleControlError::FriendHasPendingOperations) => {}
+            _ => unreachable!(),
+        }
+
+        // Removal is refused while the balance is not zero:
+        let res = control_remove_friend(
+            &mut m_state,
+            &mut send_commands,
+            &mut outgoing_control,
+            &mut outgoing_channeler_config,
+            RemoveFriend {
+                friend_public_key: indebted_pk.clone(),
+                force: false,
+            },
+        );
+        match res {
+            Err(HandleControlError::FriendBalanceNotZero) => {}
+            _ => unreachable!(),
+        }
+
+        // Nothing was changed by the refused removals:
+        assert!(m_state.state().friends.contains_key(&busy_pk));
+        assert!(m_state.state().friends.contains_key(&indebted_pk));
+        assert!(outgoing_control.is_empty());
+        assert!(outgoing_channeler_config.is_empty());
+
+        // A settled friend can be removed without forcing:
+        control_remove_friend(
+            &mut m_state,
+            &mut send_commands,
+            &mut outgoing_control,
+            &mut outgoing_channeler_config,
+            RemoveFriend {
+                friend_public_key: settled_pk.clone(),
+                force: false,
+            },
+        )
+        .unwrap();
+        assert!(!m_state.state().friends.contains_key(&settled_pk));
+
+        // Forced removal cancels the pending request, and reports the failure:
+        control_remove_friend(
+            &mut m_state,
+            &mut send_commands,
+            &mut outgoing_control,
+            &mut outgoing_channeler_config,
+            RemoveFriend {
+                friend_public_key: busy_pk.clone(),
+                force: true,
+            },
+        )
+        .unwrap();
+        assert!(!m_state.state().friends.contains_key(&busy_pk));
+
+        assert_eq!(outgoing_control.len(), 1);
+        match &outgoing_control[0] {
+            FunderOutgoingControl::ResponseReceived(response_received) => {
+                assert_eq!(response_received.request_id, Uid::from(&[0; UID_LEN]));
+                assert_eq!(
+                    response_received.result,
+                    ResponseSendFundsResult::Failure(local_pk.clone())
+                );
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_control_set_friend_liveness_interval() {
+        let local_pk = PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]);
+        let remote_pk = PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]);
+
+        let mut state =
+            FunderState::<u32>::new(local_pk.clone(), vec![dummy_named_relay_address(0)]);
+        let mut ephemeral = Ephemeral::new();
+        add_ready_friend(&mut state, &mut ephemeral, &remote_pk, 1);
+
+        let m_state = MutableFunderState::new(state);
+        let mut m_ephemeral = MutableEphemeral::new(ephemeral);
+        let default_interval_ticks = 3;
+        assert_eq!(
+            m_ephemeral
+                .ephemeral()
+                .liveness
+                .friend_interval(&remote_pk, default_interval_ticks),
+            default_interval_ticks
+        );
+
+        let set_friend_liveness_interval = SetFriendLivenessInterval {
+            friend_public_key: remote_pk.clone(),
+            interval_ticks: 10,
+        };
+        control_set_friend_liveness_interval(
+            &m_state,
+            &mut m_ephemeral,
+            set_friend_liveness_interval,
+        )
+        .unwrap();
+        assert_eq!(
+            m_ephemeral
+                .ephemeral()
+                .liveness
+                .friend_interval(&remote_pk, default_interval_ticks),
+            10
+        );
+
+        // A nonexistent friend:
+        let set_friend_liveness_interval = SetFriendLivenessInterval {
+            friend_public_key: PublicKey::from(&[0xcc; PUBLIC_KEY_LEN]),
+            interval_ticks: 10,
+        };
+        match control_set_friend_liveness_interval(
+            &m_state,
+            &mut m_ephemeral,
+            set_friend_liveness_interval,
+        ) {
+            Err(HandleControlError::FriendDoesNotExist) => {}
+            _ => unreachable!(),
+        };
+        assert_eq!(m_ephemeral.ephemeral().liveness.friend_intervals.len(), 1);
     }
 }
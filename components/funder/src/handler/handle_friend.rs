@@ -1,44 +1,87 @@
 use common::canonical_serialize::CanonicalSerialize;
-use std::fmt::Debug;
+use common::int_convert::usize_to_u64;
+use std::fmt::{self, Debug};
 
 use crypto::crypto_rand::CryptoRandom;
 use crypto::identity::{PublicKey, Signature, SIGNATURE_LEN};
 
 use proto::app_server::messages::RelayAddress;
 use proto::funder::messages::{
-    ChannelerUpdateFriend, FailureSendFunds, FriendMessage, FunderOutgoingControl,
-    MoveTokenRequest, PendingRequest, RequestSendFunds, ResetTerms, ResponseReceived,
-    ResponseSendFunds, ResponseSendFundsResult,
+    ChannelerUpdateFriend, FailureReason, FailureSendFunds, FriendMessage, FriendStatus,
+    FunderOutgoingControl, MoveTokenRequest, PendingRequest, RequestSendFunds, ResetTerms,
+    ResponseReceived, ResponseSendFunds, ResponseSendFundsResult,
 };
 use proto::funder::signature_buff::{prepare_receipt, verify_move_token};
 
 use crate::mutual_credit::incoming::{
     IncomingFailureSendFunds, IncomingMessage, IncomingResponseSendFunds,
 };
-use crate::token_channel::{MoveTokenReceived, ReceiveMoveTokenOutput, TokenChannel};
+use crate::token_channel::{
+    MoveTokenReceived, ReceiveMoveTokenError, ReceiveMoveTokenOutput, TokenChannel,
+};
 
 use crate::types::{create_pending_request, ChannelerConfig};
 
 use crate::friend::{
     ChannelInconsistent, ChannelStatus, FriendMutation, ResponseOp, SentLocalRelays,
 };
-use crate::state::FunderMutation;
+use crate::state::{FunderMutation, FunderState};
 
-use crate::ephemeral::Ephemeral;
+use crate::ephemeral::{Ephemeral, EphemeralMutation};
 
 use crate::handler::canceler::{
     cancel_local_pending_requests, cancel_pending_requests, cancel_pending_user_requests,
-    reply_with_failure,
-};
-use crate::handler::handler::{
-    find_request_origin, is_friend_ready, MutableEphemeral, MutableFunderState,
+    cancel_remote_request, reply_with_failure,
 };
+use crate::handler::handler::{is_friend_ready, MutableEphemeral, MutableFunderState};
 use crate::handler::sender::SendCommands;
 
 #[derive(Debug)]
 pub enum HandleFriendError {
     FriendDoesNotExist,
     InconsistencyWhenTokenOwned,
+    /// The inconsistency counter of the channel reached its maximum value. Generating new reset
+    /// terms would wrap the counter around, so the channel requires manual intervention.
+    InconsistencyCounterOverflow,
+}
+
+/// Amount of hex digits of the friend's public key shown in log messages.
+const LOG_PUBLIC_KEY_HEX_LEN: usize = 8;
+
+/// Logging context of a single incoming friend message.
+/// Log messages are prefixed with the (abbreviated) friend public key and the message type, so
+/// that logs of different friends can be correlated.
+pub struct FriendLogContext {
+    friend_hex: String,
+    message_type: &'static str,
+}
+
+impl FriendLogContext {
+    pub fn new(remote_public_key: &PublicKey, message_type: &'static str) -> Self {
+        let mut friend_hex = remote_public_key.to_hex();
+        friend_hex.truncate(LOG_PUBLIC_KEY_HEX_LEN);
+        FriendLogContext {
+            friend_hex,
+            message_type,
+        }
+    }
+
+    pub fn from_friend_message<B>(
+        remote_public_key: &PublicKey,
+        friend_message: &FriendMessage<B>,
+    ) -> Self {
+        let message_type = match friend_message {
+            FriendMessage::MoveTokenRequest(_) => "MoveTokenRequest",
+            FriendMessage::InconsistencyError(_) => "InconsistencyError",
+        };
+        FriendLogContext::new(remote_public_key, message_type)
+    }
+}
+
+impl fmt::Display for FriendLogContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[friend={} msg={}]", self.friend_hex, self.message_type)
+    }
 }
 
 /// Generate a random token to be used for resetting the channel.
@@ -51,22 +94,29 @@ where
     Signature::from(buff)
 }
 
-pub fn gen_reset_terms<B, R>(token_channel: &TokenChannel<B>, rng: &R) -> ResetTerms
+/// Generate reset terms for a token channel.
+/// Fails if the inconsistency counter of the token channel can not be increased. A wrapped around
+/// counter would be interpreted as a fresh channel.
+pub fn gen_reset_terms<B, R>(
+    token_channel: &TokenChannel<B>,
+    rng: &R,
+) -> Result<ResetTerms, HandleFriendError>
 where
     R: CryptoRandom,
     B: Clone + PartialEq + Eq + CanonicalSerialize + Debug,
 {
-    // We add 2 for the new counter in case
-    // the remote side has already used the next counter.
+    let inconsistency_counter = token_channel
+        .get_inconsistency_counter()
+        .checked_add(1)
+        .ok_or(HandleFriendError::InconsistencyCounterOverflow)?;
+
     let reset_token = gen_channel_reset_token(rng);
 
-    ResetTerms {
+    Ok(ResetTerms::new(
         reset_token,
-        // TODO: Should we do something other than wrapping_add(1)?
-        // 2**64 inconsistencies are required for an overflow.
-        inconsistency_counter: token_channel.get_inconsistency_counter().wrapping_add(1),
-        balance_for_reset: token_channel.get_mutual_credit().balance_for_reset(),
-    }
+        inconsistency_counter,
+        token_channel.get_mutual_credit().balance_for_reset(),
+    ))
 }
 
 /// Check if channel reset is required (Remove side used the RESET token)
@@ -143,19 +193,57 @@ fn handle_request_send_funds<B>(
     m_state: &mut MutableFunderState<B>,
     ephemeral: &Ephemeral,
     send_commands: &mut SendCommands,
+    max_pending_requests: usize,
     remote_public_key: &PublicKey,
     request_send_funds: RequestSendFunds,
 ) where
     B: Clone + PartialEq + Eq + CanonicalSerialize + Debug,
 {
+    // A route must contain at least the remote friend and us. Shorter (degenerate) routes can not
+    // carry a payment. The mutual credit layer should have already rejected such routes, but we
+    // do not want to panic on adversarial input, so we reply with a failure instead.
+    if request_send_funds.route.len() < 2 {
+        reply_with_failure(
+            m_state,
+            send_commands,
+            remote_public_key,
+            &request_send_funds,
+            FailureReason::InvalidRoute,
+        );
+        return;
+    }
+
     // Find ourselves on the route. If we are not there, abort.
-    let remote_index = request_send_funds
+    let remote_index = match request_send_funds
         .route
         .find_pk_pair(&remote_public_key, &m_state.state().local_public_key)
-        .unwrap();
+    {
+        Some(remote_index) => remote_index,
+        None => {
+            reply_with_failure(
+                m_state,
+                send_commands,
+                remote_public_key,
+                &request_send_funds,
+                FailureReason::InvalidRoute,
+            );
+            return;
+        }
+    };
 
-    let local_index = remote_index.checked_add(1).unwrap();
-    let next_index = local_index.checked_add(1).unwrap();
+    let next_index = match remote_index.checked_add(2) {
+        Some(next_index) => next_index,
+        None => {
+            reply_with_failure(
+                m_state,
+                send_commands,
+                remote_public_key,
+                &request_send_funds,
+                FailureReason::InvalidRoute,
+            );
+            return;
+        }
+    };
     if next_index >= request_send_funds.route.len() {
         // We are the destination of this request. We return a response:
         let pending_request = create_pending_request(&request_send_funds);
@@ -169,7 +257,19 @@ fn handle_request_send_funds<B>(
     }
 
     // The node on the route has to be one of our friends:
-    let next_public_key = request_send_funds.route.index_to_pk(next_index).unwrap();
+    let next_public_key = match request_send_funds.route.index_to_pk(next_index) {
+        Some(next_public_key) => next_public_key,
+        None => {
+            reply_with_failure(
+                m_state,
+                send_commands,
+                remote_public_key,
+                &request_send_funds,
+                FailureReason::InvalidRoute,
+            );
+            return;
+        }
+    };
     let friend_exists = m_state.state().friends.contains_key(next_public_key);
 
     // This friend must be considered online for us to forward the message.
@@ -187,6 +287,21 @@ fn handle_request_send_funds<B>(
             send_commands,
             remote_public_key,
             &request_send_funds,
+            FailureReason::FriendNotReady,
+        );
+        return;
+    }
+
+    // Don't let requests pile up for a congested friend. We fail the request immediately, so that
+    // the origin of the request can try a different route:
+    let next_friend = m_state.state().friends.get(next_public_key).unwrap();
+    if next_friend.pending_requests.len() >= max_pending_requests {
+        reply_with_failure(
+            m_state,
+            send_commands,
+            remote_public_key,
+            &request_send_funds,
+            FailureReason::FriendCongested,
         );
         return;
     }
@@ -195,6 +310,14 @@ fn handle_request_send_funds<B>(
     forward_request(m_state, send_commands, request_send_funds);
 }
 
+/// Are we the node that originated this request?
+fn is_request_origin<B>(state: &FunderState<B>, pending_request: &PendingRequest) -> bool
+where
+    B: Clone + PartialEq + Eq + CanonicalSerialize + Debug,
+{
+    pending_request.route.pk_to_index(&state.local_public_key) == Some(0)
+}
+
 fn handle_response_send_funds<B>(
     m_state: &mut MutableFunderState<B>,
     send_commands: &mut SendCommands,
@@ -204,7 +327,15 @@ fn handle_response_send_funds<B>(
 ) where
     B: Clone + PartialEq + Eq + CanonicalSerialize + Debug,
 {
-    match find_request_origin(m_state.state(), &response_send_funds.request_id).cloned() {
+    match m_state
+        .state()
+        .find_request_origin(&response_send_funds.request_id)
+        .cloned()
+    {
+        None if !is_request_origin(m_state.state(), &pending_request) => {
+            // The request was already failed back towards its origin. There is no one to pass
+            // the response to.
+        }
         None => {
             // We are the origin of this request, and we got a response.
             // We provide a receipt to the user:
@@ -214,6 +345,8 @@ fn handle_response_send_funds<B>(
             outgoing_control.push(FunderOutgoingControl::ResponseReceived(ResponseReceived {
                 request_id: pending_request.request_id,
                 result: response_send_funds_result,
+                opt_elapsed_ticks: None,
+                opt_failure_reason: None,
             }));
             // We make our own copy of the receipt, in case the user abruptly crashes.
             // In that case the user will be able to obtain the receipt again later.
@@ -242,7 +375,14 @@ fn handle_failure_send_funds<B>(
 ) where
     B: Clone + PartialEq + Eq + CanonicalSerialize + Debug,
 {
-    match find_request_origin(m_state.state(), &failure_send_funds.request_id).cloned() {
+    match m_state
+        .state()
+        .find_request_origin(&failure_send_funds.request_id)
+        .cloned()
+    {
+        None if !is_request_origin(m_state.state(), &pending_request) => {
+            // The request was already failed back towards its origin.
+        }
         None => {
             // We are the origin of this request, and we got a failure
             // We should pass it back to encryptor.
@@ -252,6 +392,8 @@ fn handle_failure_send_funds<B>(
             outgoing_control.push(FunderOutgoingControl::ResponseReceived(ResponseReceived {
                 request_id: pending_request.request_id,
                 result: response_send_funds_result,
+                opt_elapsed_ticks: None,
+                opt_failure_reason: Some(failure_send_funds.reason),
             }));
         }
         Some(friend_public_key) => {
@@ -273,6 +415,7 @@ fn handle_move_token_output<B>(
     m_ephemeral: &mut MutableEphemeral,
     send_commands: &mut SendCommands,
     outgoing_control: &mut Vec<FunderOutgoingControl<B>>,
+    max_pending_requests: usize,
     remote_public_key: &PublicKey,
     incoming_messages: Vec<IncomingMessage>,
 ) where
@@ -285,6 +428,7 @@ fn handle_move_token_output<B>(
                     m_state,
                     m_ephemeral.ephemeral(),
                     send_commands,
+                    max_pending_requests,
                     remote_public_key,
                     request_send_funds,
                 );
@@ -313,21 +457,84 @@ fn handle_move_token_output<B>(
                     pending_request,
                 );
             }
+            IncomingMessage::Cancel(pending_request) => {
+                cancel_remote_request(
+                    m_state,
+                    send_commands,
+                    remote_public_key,
+                    pending_request,
+                    FailureReason::Canceled,
+                );
+            }
         }
     }
 }
 
+/// Roll back an unconfirmed update of the remote relays, if there is one.
+/// Called when the channel becomes inconsistent, as the move token chain that carried the update
+/// can not be trusted anymore.
+fn rollback_remote_relays<B>(
+    m_state: &mut MutableFunderState<B>,
+    outgoing_channeler_config: &mut Vec<ChannelerConfig<RelayAddress<B>>>,
+    remote_public_key: &PublicKey,
+) where
+    B: Clone + PartialEq + Eq + CanonicalSerialize + Debug,
+{
+    let friend = m_state.state().friends.get(remote_public_key).unwrap();
+    let prev_remote_relays = match &friend.opt_prev_remote_relays {
+        Some(prev_remote_relays) => prev_remote_relays.clone(),
+        None => return,
+    };
+
+    let friend_mutation = FriendMutation::SetRemoteRelays(prev_remote_relays.clone());
+    let funder_mutation =
+        FunderMutation::FriendMutation((remote_public_key.clone(), friend_mutation));
+    m_state.mutate(funder_mutation);
+
+    let friend_mutation = FriendMutation::SetOptPrevRemoteRelays(None);
+    let funder_mutation =
+        FunderMutation::FriendMutation((remote_public_key.clone(), friend_mutation));
+    m_state.mutate(funder_mutation);
+
+    let friend = m_state.state().friends.get(remote_public_key).unwrap();
+    if let FriendStatus::Enabled = friend.status {
+        // Notify Channeler to change the friend's address:
+        let update_friend = ChannelerUpdateFriend {
+            friend_public_key: remote_public_key.clone(),
+            friend_relays: prev_remote_relays,
+            local_relays: friend.sent_local_relays.to_vec(),
+        };
+        let channeler_config = ChannelerConfig::UpdateFriend(update_friend);
+        outgoing_channeler_config.push(channeler_config);
+    }
+}
+
 /// Handle an error with incoming move token.
 fn handle_move_token_error<B, R>(
     m_state: &mut MutableFunderState<B>,
     send_commands: &mut SendCommands,
     outgoing_control: &mut Vec<FunderOutgoingControl<B>>,
+    outgoing_channeler_config: &mut Vec<ChannelerConfig<RelayAddress<B>>>,
     rng: &R,
+    log_ctx: &FriendLogContext,
     remote_public_key: &PublicKey,
-) where
+    receive_move_token_error: &ReceiveMoveTokenError,
+) -> Result<(), HandleFriendError>
+where
     B: Clone + PartialEq + Eq + CanonicalSerialize + Debug,
     R: CryptoRandom,
 {
+    match receive_move_token_error {
+        ReceiveMoveTokenError::InvalidTransaction(process_trans_list_error) => warn!(
+            "{} handle_move_token_error(): Invalid operation at index {}: {:?}",
+            log_ctx, process_trans_list_error.index, process_trans_list_error.process_trans_error
+        ),
+        _ => warn!(
+            "{} handle_move_token_error(): Invalid move token: {:?}",
+            log_ctx, receive_move_token_error
+        ),
+    }
+
     let friend = m_state.state().friends.get(remote_public_key).unwrap();
     let token_channel = match &friend.channel_status {
         ChannelStatus::Consistent(token_channel) => token_channel,
@@ -335,13 +542,24 @@ fn handle_move_token_error<B, R>(
     };
     let opt_last_incoming_move_token = token_channel.get_last_incoming_move_token_hashed().cloned();
     // Send an InconsistencyError message to remote side:
-    let local_reset_terms = gen_reset_terms(&token_channel, rng);
+    let local_reset_terms = gen_reset_terms(&token_channel, rng)?;
 
     // Cancel all internal pending requests inside token channel:
     cancel_local_pending_requests(m_state, send_commands, outgoing_control, remote_public_key);
     // Cancel all pending requests to this friend:
-    cancel_pending_requests(m_state, send_commands, outgoing_control, remote_public_key);
-    cancel_pending_user_requests(m_state, outgoing_control, remote_public_key);
+    cancel_pending_requests(
+        m_state,
+        send_commands,
+        outgoing_control,
+        remote_public_key,
+        FailureReason::Canceled,
+    );
+    cancel_pending_user_requests(
+        m_state,
+        outgoing_control,
+        remote_public_key,
+        FailureReason::Canceled,
+    );
 
     // Keep outgoing InconsistencyError message details in memory:
     let channel_inconsistent = ChannelInconsistent {
@@ -353,7 +571,11 @@ fn handle_move_token_error<B, R>(
     let funder_mutation =
         FunderMutation::FriendMutation((remote_public_key.clone(), friend_mutation));
     m_state.mutate(funder_mutation);
+
+    rollback_remote_relays(m_state, outgoing_channeler_config, remote_public_key);
+
     send_commands.set_try_send(remote_public_key);
+    Ok(())
 }
 
 /// Handle success with incoming move token.
@@ -363,6 +585,7 @@ fn handle_move_token_success<B>(
     send_commands: &mut SendCommands,
     outgoing_control: &mut Vec<FunderOutgoingControl<B>>,
     outgoing_channeler_config: &mut Vec<ChannelerConfig<RelayAddress<B>>>,
+    max_pending_requests: usize,
     remote_public_key: &PublicKey,
     receive_move_token_output: ReceiveMoveTokenOutput<B>,
     token_wanted: bool,
@@ -388,20 +611,31 @@ fn handle_move_token_success<B>(
             } = move_token_received;
 
             // Update address for remote side if necessary:
-            if let Some(new_remote_relays) = opt_local_relays {
-                let friend = m_state.state().friends.get(remote_public_key).unwrap();
-                // Make sure that the newly sent remote address is different than the one we
-                // already have:
-                if friend.remote_relays != new_remote_relays {
-                    // Update remote address:
-                    let friend_mutation =
-                        FriendMutation::SetRemoteRelays(new_remote_relays.clone());
-                    let funder_mutation = FunderMutation::FriendMutation((
-                        remote_public_key.clone(),
-                        friend_mutation,
-                    ));
-                    m_state.mutate(funder_mutation);
-                }
+            let friend = m_state.state().friends.get(remote_public_key).unwrap();
+            // Make sure that the newly sent remote address is different than the one we
+            // already have:
+            let opt_new_remote_relays = opt_local_relays
+                .filter(|new_remote_relays| new_remote_relays != &friend.remote_relays);
+            if let Some(new_remote_relays) = opt_new_remote_relays {
+                // Remember the current remote address, in case we need to roll back this update:
+                let friend_mutation =
+                    FriendMutation::SetOptPrevRemoteRelays(Some(friend.remote_relays.clone()));
+                let funder_mutation =
+                    FunderMutation::FriendMutation((remote_public_key.clone(), friend_mutation));
+                m_state.mutate(funder_mutation);
+
+                // Update remote address:
+                let friend_mutation = FriendMutation::SetRemoteRelays(new_remote_relays);
+                let funder_mutation =
+                    FunderMutation::FriendMutation((remote_public_key.clone(), friend_mutation));
+                m_state.mutate(funder_mutation);
+            } else if friend.opt_prev_remote_relays.is_some() {
+                // A valid move token following the update confirms the last remote address
+                // update:
+                let friend_mutation = FriendMutation::SetOptPrevRemoteRelays(None);
+                let funder_mutation =
+                    FunderMutation::FriendMutation((remote_public_key.clone(), friend_mutation));
+                m_state.mutate(funder_mutation);
             }
 
             // Apply all mutations:
@@ -412,6 +646,21 @@ fn handle_move_token_success<B>(
                 m_state.mutate(funder_mutation);
             }
 
+            // Receiving a move token is a proof that the remote side has received our last
+            // outgoing move token, together with any SetRemoteMaxDebt operation it contained:
+            let friend = m_state.state().friends.get(remote_public_key).unwrap();
+            if let ChannelStatus::Consistent(token_channel) = &friend.channel_status {
+                let remote_max_debt = token_channel.get_remote_max_debt();
+                if friend.acked_remote_max_debt != remote_max_debt {
+                    let friend_mutation = FriendMutation::SetAckedRemoteMaxDebt(remote_max_debt);
+                    let funder_mutation = FunderMutation::FriendMutation((
+                        remote_public_key.clone(),
+                        friend_mutation,
+                    ));
+                    m_state.mutate(funder_mutation);
+                }
+            }
+
             // If address update was pending, we can clear it, as this is a proof that the
             // remote side has received our update:
             let friend = m_state.state().friends.get(remote_public_key).unwrap();
@@ -456,8 +705,14 @@ fn handle_move_token_success<B>(
                     send_commands,
                     outgoing_control,
                     remote_public_key,
+                    FailureReason::RequestsClosed,
+                );
+                cancel_pending_user_requests(
+                    m_state,
+                    outgoing_control,
+                    remote_public_key,
+                    FailureReason::RequestsClosed,
                 );
-                cancel_pending_user_requests(m_state, outgoing_control, remote_public_key);
             }
 
             handle_move_token_output(
@@ -465,6 +720,7 @@ fn handle_move_token_success<B>(
                 m_ephemeral,
                 send_commands,
                 outgoing_control,
+                max_pending_requests,
                 remote_public_key,
                 incoming_messages,
             );
@@ -482,6 +738,8 @@ fn handle_move_token_request<B, R>(
     outgoing_control: &mut Vec<FunderOutgoingControl<B>>,
     outgoing_channeler_config: &mut Vec<ChannelerConfig<RelayAddress<B>>>,
     rng: &R,
+    max_pending_requests: usize,
+    log_ctx: &FriendLogContext,
     remote_public_key: &PublicKey,
     friend_move_token_request: MoveTokenRequest<B>,
 ) -> Result<(), HandleFriendError>
@@ -523,19 +781,23 @@ where
                 send_commands,
                 outgoing_control,
                 outgoing_channeler_config,
+                max_pending_requests,
                 remote_public_key,
                 receive_move_token_output,
                 token_wanted,
             );
         }
-        Err(_receive_move_token_error) => {
+        Err(receive_move_token_error) => {
             handle_move_token_error(
                 m_state,
                 send_commands,
                 outgoing_control,
+                outgoing_channeler_config,
                 rng,
+                log_ctx,
                 remote_public_key,
-            );
+                &receive_move_token_error,
+            )?;
         }
     };
     Ok(())
@@ -545,7 +807,9 @@ fn handle_inconsistency_error<B, R>(
     m_state: &mut MutableFunderState<B>,
     send_commands: &mut SendCommands,
     outgoing_control: &mut Vec<FunderOutgoingControl<B>>,
+    outgoing_channeler_config: &mut Vec<ChannelerConfig<RelayAddress<B>>>,
     rng: &R,
+    log_ctx: &FriendLogContext,
     remote_public_key: &PublicKey,
     remote_reset_terms: ResetTerms,
 ) -> Result<(), HandleFriendError>
@@ -560,44 +824,101 @@ where
     }?;
 
     // Cancel all pending requests to this friend:
-    cancel_pending_requests(m_state, send_commands, outgoing_control, remote_public_key);
-    cancel_pending_user_requests(m_state, outgoing_control, remote_public_key);
-
-    // Save remote incoming inconsistency details:
-    let new_remote_reset_terms = remote_reset_terms;
+    cancel_pending_requests(
+        m_state,
+        send_commands,
+        outgoing_control,
+        remote_public_key,
+        FailureReason::Canceled,
+    );
+    cancel_pending_user_requests(
+        m_state,
+        outgoing_control,
+        remote_public_key,
+        FailureReason::Canceled,
+    );
 
     // Obtain information about our reset terms:
     let friend = m_state.state().friends.get(remote_public_key).unwrap();
-    let (should_send_outgoing, new_local_reset_terms, opt_last_incoming_move_token) =
-        match &friend.channel_status {
-            ChannelStatus::Consistent(token_channel) => {
-                if !token_channel.is_outgoing() {
-                    return Err(HandleFriendError::InconsistencyWhenTokenOwned);
+    let (
+        should_send_outgoing,
+        new_local_reset_terms,
+        opt_last_incoming_move_token,
+        opt_remote_reset_terms,
+    ) = match &friend.channel_status {
+        ChannelStatus::Consistent(token_channel) => {
+            if !token_channel.is_outgoing() {
+                return Err(HandleFriendError::InconsistencyWhenTokenOwned);
+            }
+            // Stale (possibly replayed) remote reset terms can not be used for a reset, so we
+            // don't keep them. We still become inconsistent and send our own reset terms:
+            let opt_remote_reset_terms = match remote_reset_terms
+                .validate_against(token_channel.get_inconsistency_counter())
+            {
+                Ok(()) => Some(remote_reset_terms),
+                Err(e) => {
+                    warn!(
+                        "{} handle_inconsistency_error(): Ignoring remote reset terms: {:?}",
+                        log_ctx, e
+                    );
+                    None
+                }
+            };
+            // Detect accounting drift between the two sides.
+            // If our expected balance overflows, it can not match the remote terms:
+            if let Some(new_remote_reset_terms) = &opt_remote_reset_terms {
+                let opt_expected_balance = token_channel.expected_balance_for_reset();
+                if opt_expected_balance != Some(new_remote_reset_terms.balance_for_reset) {
+                    warn!(
+                        "{} handle_inconsistency_error(): balance_for_reset is {}, expected {:?}",
+                        log_ctx, new_remote_reset_terms.balance_for_reset, opt_expected_balance
+                    );
                 }
-                (
-                    true,
-                    gen_reset_terms(&token_channel, rng),
-                    token_channel.get_last_incoming_move_token_hashed().cloned(),
-                )
             }
-            ChannelStatus::Inconsistent(channel_inconsistent) => (
+            (
+                true,
+                gen_reset_terms(&token_channel, rng)?,
+                token_channel.get_last_incoming_move_token_hashed().cloned(),
+                opt_remote_reset_terms,
+            )
+        }
+        ChannelStatus::Inconsistent(channel_inconsistent) => {
+            // Stale (possibly replayed) remote reset terms are ignored. We keep the remote reset
+            // terms we already have:
+            let opt_remote_reset_terms = match remote_reset_terms
+                .validate_against(channel_inconsistent.get_inconsistency_counter())
+            {
+                Ok(()) => Some(remote_reset_terms),
+                Err(e) => {
+                    warn!(
+                        "{} handle_inconsistency_error(): Ignoring remote reset terms: {:?}",
+                        log_ctx, e
+                    );
+                    channel_inconsistent.opt_remote_reset_terms.clone()
+                }
+            };
+            (
                 false,
                 channel_inconsistent.local_reset_terms.clone(),
                 channel_inconsistent.opt_last_incoming_move_token.clone(),
-            ),
-        };
+                opt_remote_reset_terms,
+            )
+        }
+    };
 
     // Keep outgoing InconsistencyError message details in memory:
     let channel_inconsistent = ChannelInconsistent {
         opt_last_incoming_move_token,
         local_reset_terms: new_local_reset_terms.clone(),
-        opt_remote_reset_terms: Some(new_remote_reset_terms),
+        opt_remote_reset_terms,
     };
     let friend_mutation = FriendMutation::SetInconsistent(channel_inconsistent);
     let funder_mutation =
         FunderMutation::FriendMutation((remote_public_key.clone(), friend_mutation));
     m_state.mutate(funder_mutation);
 
+    rollback_remote_relays(m_state, outgoing_channeler_config, remote_public_key);
+
     // Send an outgoing inconsistency message if required:
     if should_send_outgoing {
         send_commands.set_try_send(remote_public_key);
@@ -605,6 +926,32 @@ where
     Ok(())
 }
 
+/// Check if an inconsistency error from a friend should be processed, according to the rate limit
+/// of one processed inconsistency error every `inconsistency_error_interval_ticks` ticks.
+/// If the inconsistency error should be processed, the current tick is remembered.
+fn check_inconsistency_error_rate(
+    m_ephemeral: &mut MutableEphemeral,
+    inconsistency_error_interval_ticks: usize,
+    remote_public_key: &PublicKey,
+) -> bool {
+    let cur_tick = m_ephemeral.ephemeral().ticks;
+    if let Some(last_tick) = m_ephemeral
+        .ephemeral()
+        .inconsistency_error_ticks
+        .get(remote_public_key)
+    {
+        let elapsed_ticks = cur_tick.saturating_sub(*last_tick);
+        if elapsed_ticks < usize_to_u64(inconsistency_error_interval_ticks).unwrap() {
+            return false;
+        }
+    }
+    m_ephemeral.mutate(EphemeralMutation::SetInconsistencyErrorTick((
+        remote_public_key.clone(),
+        cur_tick,
+    )));
+    true
+}
+
 pub fn handle_friend_message<B, R>(
     m_state: &mut MutableFunderState<B>,
     m_ephemeral: &mut MutableEphemeral,
@@ -612,6 +959,8 @@ pub fn handle_friend_message<B, R>(
     outgoing_control: &mut Vec<FunderOutgoingControl<B>>,
     outgoing_channeler_config: &mut Vec<ChannelerConfig<RelayAddress<B>>>,
     rng: &R,
+    inconsistency_error_interval_ticks: usize,
+    max_pending_requests: usize,
     remote_public_key: &PublicKey,
     friend_message: FriendMessage<B>,
 ) -> Result<(), HandleFriendError>
@@ -625,6 +974,8 @@ where
         None => Err(HandleFriendError::FriendDoesNotExist),
     }?;
 
+    let log_ctx = FriendLogContext::from_friend_message(remote_public_key, &friend_message);
+
     match friend_message {
         FriendMessage::MoveTokenRequest(friend_move_token_request) => handle_move_token_request(
             m_state,
@@ -633,17 +984,960 @@ where
             outgoing_control,
             outgoing_channeler_config,
             rng,
+            max_pending_requests,
+            &log_ctx,
             remote_public_key,
             friend_move_token_request,
         ),
 
-        FriendMessage::InconsistencyError(remote_reset_terms) => handle_inconsistency_error(
-            m_state,
-            send_commands,
-            outgoing_control,
-            rng,
-            remote_public_key,
+        FriendMessage::InconsistencyError(remote_reset_terms) => {
+            if !check_inconsistency_error_rate(
+                m_ephemeral,
+                inconsistency_error_interval_ticks,
+                remote_public_key,
+            ) {
+                warn!(
+                    "{} handle_friend_message(): Too many inconsistency errors. Ignoring.",
+                    log_ctx
+                );
+                return Ok(());
+            }
+            handle_inconsistency_error(
+                m_state,
+                send_commands,
+                outgoing_control,
+                outgoing_channeler_config,
+                rng,
+                &log_ctx,
+                remote_public_key,
+                remote_reset_terms,
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crypto::crypto_rand::{RandValue, RAND_VALUE_LEN};
+    use crypto::identity::PUBLIC_KEY_LEN;
+    use crypto::invoice_id::{InvoiceId, INVOICE_ID_LEN};
+    use crypto::test_utils::{CounterRandom, DummyRandom};
+    use crypto::uid::{Uid, UID_LEN};
+    use proto::funder::messages::{AddFriend, FriendsRoute, MoveToken, RequestsStatus};
+
+    use crate::ephemeral::Ephemeral;
+    use crate::handler::handle_timer::handle_request_timeout_tick;
+    use crate::liveness::LivenessMutation;
+    use crate::mutual_credit::types::McMutation;
+    use crate::state::FunderState;
+    use crate::tests::utils::{
+        dummy_named_relay_address, dummy_relay_address, dummy_state_with_friend,
+    };
+    use crate::token_channel::TcMutation;
+
+    const MAX_PENDING_REQUESTS: usize = 16;
+
+    #[test]
+    fn test_gen_reset_terms_deterministic() {
+        let local_pk = PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]);
+        let remote_pk = PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]);
+        let token_channel = TokenChannel::<u32>::new(&local_pk, &remote_pk, 10i128);
+
+        let rng = CounterRandom::new(0);
+
+        let mut expected_token = [0u8; SIGNATURE_LEN];
+        for (i, byte) in expected_token.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        let reset_terms = gen_reset_terms(&token_channel, &rng).unwrap();
+        assert_eq!(reset_terms.reset_token, Signature::from(expected_token));
+        assert_eq!(
+            reset_terms.inconsistency_counter,
+            token_channel.get_inconsistency_counter() + 1
+        );
+        assert_eq!(reset_terms.balance_for_reset, 10i128);
+
+        // The next reset token continues the sequence:
+        for (i, byte) in expected_token.iter_mut().enumerate() {
+            *byte = (SIGNATURE_LEN + i) as u8;
+        }
+        let reset_terms = gen_reset_terms(&token_channel, &rng).unwrap();
+        assert_eq!(reset_terms.reset_token, Signature::from(expected_token));
+    }
+
+    /// A token channel that was reset with the given inconsistency counter.
+    fn dummy_reset_token_channel(inconsistency_counter: u64) -> TokenChannel<u32> {
+        let local_pk = PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]);
+        let remote_pk = PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]);
+        let reset_move_token = MoveToken {
+            operations: Vec::new(),
+            opt_local_relays: None,
+            old_token: Signature::from(&[1; SIGNATURE_LEN]),
+            local_public_key: local_pk.clone(),
+            remote_public_key: remote_pk.clone(),
+            inconsistency_counter,
+            move_token_counter: 0,
+            balance: 10,
+            local_pending_debt: 0,
+            remote_pending_debt: 0,
+            rand_nonce: RandValue::from(&[2; RAND_VALUE_LEN]),
+            new_token: Signature::from(&[3; SIGNATURE_LEN]),
+        };
+        TokenChannel::new_from_local_reset(&local_pk, &remote_pk, &reset_move_token, 10, None)
+    }
+
+    #[test]
+    fn test_gen_reset_terms_counter_overflow() {
+        let rng = CounterRandom::new(0);
+
+        // The last counter that can still be increased:
+        let token_channel = dummy_reset_token_channel(u64::max_value() - 1);
+        let reset_terms = gen_reset_terms(&token_channel, &rng).unwrap();
+        assert_eq!(reset_terms.inconsistency_counter, u64::max_value());
+        assert_eq!(reset_terms.balance_for_reset, 10i128);
+
+        // The counter is at its maximum value. We refuse to wrap it around:
+        let token_channel = dummy_reset_token_channel(u64::max_value());
+        match gen_reset_terms(&token_channel, &rng) {
+            Err(HandleFriendError::InconsistencyCounterOverflow) => {}
+            _ => unreachable!(),
+        };
+    }
+
+    #[test]
+    fn test_handle_inconsistency_error_stale_reset_terms() {
+        let local_pk = PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]);
+        let remote_pk = PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]);
+        let rng = DummyRandom::new(&[1u8]);
+        let log_ctx = FriendLogContext::new(&remote_pk, "InconsistencyError");
+
+        let remote_reset_terms = |inconsistency_counter| {
+            ResetTerms::new(
+                Signature::from(&[1; SIGNATURE_LEN]),
+                inconsistency_counter,
+                -10,
+            )
+        };
+
+        // Remote reset terms with a counter that lags behind ours (or is equal to ours) are not
+        // kept. Fresh remote reset terms are kept:
+        let cases = vec![(4, None), (5, None), (6, Some(remote_reset_terms(6)))];
+        for (inconsistency_counter, expected_remote_reset_terms) in cases {
+            // The channel was already reset a few times, and we hold the token:
+            let token_channel = dummy_reset_token_channel(5);
+            let state = dummy_state_with_friend(&local_pk, &remote_pk, Some(token_channel));
+
+            let mut m_state = MutableFunderState::new(state);
+            let mut send_commands = SendCommands::new();
+            let mut outgoing_control = Vec::new();
+            let mut outgoing_channeler_config = Vec::new();
+
+            handle_inconsistency_error(
+                &mut m_state,
+                &mut send_commands,
+                &mut outgoing_control,
+                &mut outgoing_channeler_config,
+                &rng,
+                &log_ctx,
+                &remote_pk,
+                remote_reset_terms(inconsistency_counter),
+            )
+            .unwrap();
+
+            // In any case we become inconsistent, and send our own reset terms:
+            let friend = m_state.state().friends.get(&remote_pk).unwrap();
+            match &friend.channel_status {
+                ChannelStatus::Inconsistent(channel_inconsistent) => {
+                    assert_eq!(
+                        channel_inconsistent.local_reset_terms.inconsistency_counter,
+                        6
+                    );
+                    assert_eq!(
+                        channel_inconsistent.opt_remote_reset_terms,
+                        expected_remote_reset_terms
+                    );
+                }
+                ChannelStatus::Consistent(_) => unreachable!(),
+            };
+            let friend_send_commands = send_commands.send_commands.get(&remote_pk).unwrap();
+            assert!(friend_send_commands.try_send);
+        }
+    }
+
+    #[test]
+    fn test_handle_inconsistency_error_stale_reset_terms_when_inconsistent() {
+        let local_pk = PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]);
+        let remote_pk = PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]);
+        let rng = DummyRandom::new(&[1u8]);
+        let log_ctx = FriendLogContext::new(&remote_pk, "InconsistencyError");
+
+        let remote_reset_terms = |inconsistency_counter, balance_for_reset| {
+            ResetTerms::new(
+                Signature::from(&[1; SIGNATURE_LEN]),
+                inconsistency_counter,
+                balance_for_reset,
+            )
+        };
+
+        // The channel was already reset a few times, and we hold the token:
+        let token_channel = dummy_reset_token_channel(5);
+        let state = dummy_state_with_friend(&local_pk, &remote_pk, Some(token_channel));
+        let mut m_state = MutableFunderState::new(state);
+
+        // The remote side sends fresh reset terms, and the channel becomes inconsistent.
+        // Then the remote side sends the same reset terms again, and finally it replays old reset
+        // terms from before the last reset:
+        let cases = vec![
+            remote_reset_terms(6, -10),
+            remote_reset_terms(6, -10),
+            remote_reset_terms(5, 20),
+        ];
+        for new_remote_reset_terms in cases {
+            let mut send_commands = SendCommands::new();
+            let mut outgoing_control = Vec::new();
+            let mut outgoing_channeler_config = Vec::new();
+            handle_inconsistency_error(
+                &mut m_state,
+                &mut send_commands,
+                &mut outgoing_control,
+                &mut outgoing_channeler_config,
+                &rng,
+                &log_ctx,
+                &remote_pk,
+                new_remote_reset_terms,
+            )
+            .unwrap();
+
+            // The replayed reset terms are ignored. We keep the fresh reset terms:
+            let friend = m_state.state().friends.get(&remote_pk).unwrap();
+            match &friend.channel_status {
+                ChannelStatus::Inconsistent(channel_inconsistent) => {
+                    assert_eq!(
+                        channel_inconsistent.local_reset_terms.inconsistency_counter,
+                        6
+                    );
+                    assert_eq!(
+                        channel_inconsistent.opt_remote_reset_terms,
+                        Some(remote_reset_terms(6, -10))
+                    );
+                }
+                ChannelStatus::Consistent(_) => unreachable!(),
+            };
+        }
+    }
+
+    #[test]
+    fn test_friend_log_context_display() {
+        let remote_pk = PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]);
+        let log_ctx = FriendLogContext::new(&remote_pk, "MoveTokenRequest");
+        assert_eq!(
+            format!("{}", log_ctx),
+            "[friend=BBBBBBBB msg=MoveTokenRequest]"
+        );
+    }
+
+    #[test]
+    fn test_handle_request_send_funds_single_node_route() {
+        let local_pk = PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]);
+        let remote_pk = PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]);
+
+        let state = dummy_state_with_friend(&local_pk, &remote_pk, None);
+
+        let ephemeral = Ephemeral::new();
+        let mut m_state = MutableFunderState::new(state);
+        let mut send_commands = SendCommands::new();
+
+        // A route that contains only us:
+        let request_send_funds = RequestSendFunds {
+            request_id: Uid::from(&[0; UID_LEN]),
+            route: FriendsRoute {
+                public_keys: vec![local_pk.clone()],
+            },
+            dest_payment: 10,
+            invoice_id: InvoiceId::from(&[1; INVOICE_ID_LEN]),
+        };
+
+        // Should not panic:
+        handle_request_send_funds(
+            &mut m_state,
+            &ephemeral,
+            &mut send_commands,
+            MAX_PENDING_REQUESTS,
+            &remote_pk,
+            request_send_funds.clone(),
+        );
+
+        let (_initial_state, _funder_mutations, final_state) = m_state.done();
+
+        // The request is failed back to the remote friend:
+        let friend = final_state.friends.get(&remote_pk).unwrap();
+        assert_eq!(friend.pending_responses.len(), 1);
+        match friend.pending_responses.front().unwrap() {
+            ResponseOp::UnsignedFailure((pending_request, failure_reason)) => {
+                assert_eq!(failure_reason, &FailureReason::InvalidRoute);
+                assert_eq!(
+                    pending_request,
+                    &create_pending_request(&request_send_funds)
+                );
+            }
+            _ => unreachable!(),
+        };
+        assert!(send_commands.send_commands.contains_key(&remote_pk));
+    }
+
+    #[test]
+    fn test_handle_request_send_funds_friend_congested() {
+        let local_pk = PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]);
+        let remote_pk = PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]);
+        let next_pk = PublicKey::from(&[0xcc; PUBLIC_KEY_LEN]);
+
+        let relays = vec![dummy_named_relay_address(0)];
+        let mut state = FunderState::<u32>::new(local_pk.clone(), relays);
+        for (i, friend_public_key) in [&remote_pk, &next_pk].iter().enumerate() {
+            let add_friend = AddFriend {
+                friend_public_key: (*friend_public_key).clone(),
+                relays: vec![dummy_relay_address(i as u8 + 1)],
+                name: format!("friend{}", i),
+                balance: 0i128,
+            };
+            state.mutate(&FunderMutation::AddFriend(add_friend));
+        }
+
+        // The next friend is ready: It is online, and its requests are open:
+        let mut token_channel = TokenChannel::new_for_test(&local_pk, &next_pk, 0i128, true);
+        token_channel.mutate(&TcMutation::McMutation(
+            McMutation::SetRemoteRequestsStatus(RequestsStatus::Open),
+        ));
+        state.mutate(&FunderMutation::FriendMutation((
+            next_pk.clone(),
+            FriendMutation::SetConsistent(token_channel),
+        )));
+        let mut ephemeral = Ephemeral::new();
+        ephemeral.mutate(&EphemeralMutation::LivenessMutation(
+            LivenessMutation::SetOnline(next_pk.clone()),
+        ));
+
+        let request_send_funds = |request_id_byte| RequestSendFunds {
+            request_id: Uid::from(&[request_id_byte; UID_LEN]),
+            route: FriendsRoute {
+                public_keys: vec![remote_pk.clone(), local_pk.clone(), next_pk.clone()],
+            },
+            dest_payment: 10,
+            invoice_id: InvoiceId::from(&[1; INVOICE_ID_LEN]),
+        };
+
+        let max_pending_requests = 2;
+        let mut m_state = MutableFunderState::new(state);
+        let mut send_commands = SendCommands::new();
+
+        // Requests are forwarded until the limit is reached:
+        for i in 0..=max_pending_requests {
+            handle_request_send_funds(
+                &mut m_state,
+                &ephemeral,
+                &mut send_commands,
+                max_pending_requests,
+                &remote_pk,
+                request_send_funds(i as u8),
+            );
+        }
+
+        let (_initial_state, _funder_mutations, final_state) = m_state.done();
+
+        let next_friend = final_state.friends.get(&next_pk).unwrap();
+        assert_eq!(next_friend.pending_requests.len(), max_pending_requests);
+
+        // The last request is failed back to the remote friend:
+        let friend = final_state.friends.get(&remote_pk).unwrap();
+        assert_eq!(friend.pending_responses.len(), 1);
+        match friend.pending_responses.front().unwrap() {
+            ResponseOp::UnsignedFailure((pending_request, failure_reason)) => {
+                assert_eq!(failure_reason, &FailureReason::FriendCongested);
+                assert_eq!(
+                    pending_request,
+                    &create_pending_request(&request_send_funds(max_pending_requests as u8))
+                );
+            }
+            _ => unreachable!(),
+        };
+        assert!(send_commands.send_commands.contains_key(&remote_pk));
+    }
+
+    #[test]
+    fn test_cancel_remote_request() {
+        let local_pk = PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]);
+        let remote_pk = PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]);
+        let next_pk = PublicKey::from(&[0xcc; PUBLIC_KEY_LEN]);
+
+        let relays = vec![dummy_named_relay_address(0)];
+        let mut state = FunderState::<u32>::new(local_pk.clone(), relays);
+        for (i, friend_public_key) in [&remote_pk, &next_pk].iter().enumerate() {
+            let add_friend = AddFriend {
+                friend_public_key: (*friend_public_key).clone(),
+                relays: vec![dummy_relay_address(i as u8 + 1)],
+                name: format!("friend{}", i),
+                balance: 0i128,
+            };
+            state.mutate(&FunderMutation::AddFriend(add_friend));
+        }
+
+        // The next friend is ready: It is online, and its requests are open:
+        let mut token_channel = TokenChannel::new_for_test(&local_pk, &next_pk, 0i128, true);
+        token_channel.mutate(&TcMutation::McMutation(
+            McMutation::SetRemoteRequestsStatus(RequestsStatus::Open),
+        ));
+        state.mutate(&FunderMutation::FriendMutation((
+            next_pk.clone(),
+            FriendMutation::SetConsistent(token_channel),
+        )));
+        let mut ephemeral = Ephemeral::new();
+        ephemeral.mutate(&EphemeralMutation::LivenessMutation(
+            LivenessMutation::SetOnline(next_pk.clone()),
+        ));
+
+        let request_send_funds = |request_id_byte| RequestSendFunds {
+            request_id: Uid::from(&[request_id_byte; UID_LEN]),
+            route: FriendsRoute {
+                public_keys: vec![remote_pk.clone(), local_pk.clone(), next_pk.clone()],
+            },
+            dest_payment: 10,
+            invoice_id: InvoiceId::from(&[1; INVOICE_ID_LEN]),
+        };
+
+        // Request 1 was already sent to the next friend inside a move token:
+        let mc_mutation =
+            McMutation::InsertLocalPendingRequest(create_pending_request(&request_send_funds(1)));
+        state.mutate(&FunderMutation::FriendMutation((
+            next_pk.clone(),
+            FriendMutation::TcMutation(TcMutation::McMutation(mc_mutation)),
+        )));
+
+        // Request 0 is waiting to be forwarded to the next friend:
+        let mut m_state = MutableFunderState::new(state);
+        let mut send_commands = SendCommands::new();
+        handle_request_send_funds(
+            &mut m_state,
+            &ephemeral,
+            &mut send_commands,
+            MAX_PENDING_REQUESTS,
+            &remote_pk,
+            request_send_funds(0),
+        );
+        let next_friend = m_state.state().friends.get(&next_pk).unwrap();
+        assert_eq!(next_friend.pending_requests.len(), 1);
+
+        // Canceling a request that was not forwarded yet fails it right away:
+        let mut send_commands = SendCommands::new();
+        cancel_remote_request(
+            &mut m_state,
+            &mut send_commands,
+            &remote_pk,
+            create_pending_request(&request_send_funds(0)),
+            FailureReason::Canceled,
+        );
+        let next_friend = m_state.state().friends.get(&next_pk).unwrap();
+        assert!(next_friend.pending_requests.is_empty());
+        assert!(next_friend.pending_cancels.is_empty());
+
+        let friend = m_state.state().friends.get(&remote_pk).unwrap();
+        assert_eq!(friend.pending_responses.len(), 1);
+        match friend.pending_responses.front().unwrap() {
+            ResponseOp::UnsignedFailure((pending_request, failure_reason)) => {
+                assert_eq!(failure_reason, &FailureReason::Canceled);
+                assert_eq!(
+                    pending_request,
+                    &create_pending_request(&request_send_funds(0))
+                );
+            }
+            _ => unreachable!(),
+        };
+        assert!(send_commands.send_commands.contains_key(&remote_pk));
+
+        // The failure is already on its way. Canceling again does nothing:
+        let mut send_commands = SendCommands::new();
+        cancel_remote_request(
+            &mut m_state,
+            &mut send_commands,
+            &remote_pk,
+            create_pending_request(&request_send_funds(0)),
+            FailureReason::Canceled,
+        );
+        let friend = m_state.state().friends.get(&remote_pk).unwrap();
+        assert_eq!(friend.pending_responses.len(), 1);
+        assert!(send_commands.send_commands.is_empty());
+
+        // Canceling a forwarded request passes the cancellation on to the next friend:
+        cancel_remote_request(
+            &mut m_state,
+            &mut send_commands,
+            &remote_pk,
+            create_pending_request(&request_send_funds(1)),
+            FailureReason::Canceled,
+        );
+        let next_friend = m_state.state().friends.get(&next_pk).unwrap();
+        assert_eq!(
+            next_friend
+                .pending_cancels
+                .iter()
+                .cloned()
+                .collect::<Vec<_>>(),
+            vec![Uid::from(&[1; UID_LEN])]
+        );
+        assert!(send_commands.send_commands.contains_key(&next_pk));
+
+        // Canceling again does not ask the next friend twice:
+        cancel_remote_request(
+            &mut m_state,
+            &mut send_commands,
+            &remote_pk,
+            create_pending_request(&request_send_funds(1)),
+            FailureReason::Canceled,
+        );
+        let next_friend = m_state.state().friends.get(&next_pk).unwrap();
+        assert_eq!(next_friend.pending_cancels.len(), 1);
+
+        // We don't fail the forwarded request ourselves. The failure arrives from the next friend:
+        let friend = m_state.state().friends.get(&remote_pk).unwrap();
+        assert_eq!(friend.pending_responses.len(), 1);
+    }
+
+    #[test]
+    fn test_response_after_request_timeout() {
+        let local_pk = PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]);
+        let remote_pk = PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]);
+        let next_pk = PublicKey::from(&[0xcc; PUBLIC_KEY_LEN]);
+        let request_timeout_ticks = 3;
+
+        let relays = vec![dummy_named_relay_address(0)];
+        let mut state = FunderState::<u32>::new(local_pk.clone(), relays);
+        for (i, friend_public_key) in [&remote_pk, &next_pk].iter().enumerate() {
+            let add_friend = AddFriend {
+                friend_public_key: (*friend_public_key).clone(),
+                relays: vec![dummy_relay_address(i as u8 + 1)],
+                name: format!("friend{}", i),
+                balance: 0i128,
+            };
+            state.mutate(&FunderMutation::AddFriend(add_friend));
+            let token_channel =
+                TokenChannel::new_for_test(&local_pk, friend_public_key, 0i128, true);
+            state.mutate(&FunderMutation::FriendMutation((
+                (*friend_public_key).clone(),
+                FriendMutation::SetConsistent(token_channel),
+            )));
+        }
+
+        // A request received from the remote friend was forwarded to the next friend:
+        let request_send_funds = RequestSendFunds {
+            request_id: Uid::from(&[0; UID_LEN]),
+            route: FriendsRoute {
+                public_keys: vec![remote_pk.clone(), local_pk.clone(), next_pk.clone()],
+            },
+            dest_payment: 10,
+            invoice_id: InvoiceId::from(&[1; INVOICE_ID_LEN]),
+        };
+        let pending_request = create_pending_request(&request_send_funds);
+        let mc_mutation = McMutation::InsertRemotePendingRequest(pending_request.clone());
+        state.mutate(&FunderMutation::FriendMutation((
+            remote_pk.clone(),
+            FriendMutation::TcMutation(TcMutation::McMutation(mc_mutation)),
+        )));
+        let mc_mutation = McMutation::InsertLocalPendingRequest(pending_request.clone());
+        state.mutate(&FunderMutation::FriendMutation((
+            next_pk.clone(),
+            FriendMutation::TcMutation(TcMutation::McMutation(mc_mutation)),
+        )));
+
+        // The request times out. We only ask the next friend to cancel it:
+        let mut m_state = MutableFunderState::new(state);
+        let mut m_ephemeral = MutableEphemeral::new(Ephemeral::new());
+        for _ in 0..=request_timeout_ticks {
+            m_ephemeral.mutate(EphemeralMutation::TimerTick);
+            let mut send_commands = SendCommands::new();
+            handle_request_timeout_tick(
+                &mut m_state,
+                &mut m_ephemeral,
+                &mut send_commands,
+                request_timeout_ticks,
+            );
+        }
+        let friend = m_state.state().friends.get(&remote_pk).unwrap();
+        assert!(friend.pending_responses.is_empty());
+        let next_friend = m_state.state().friends.get(&next_pk).unwrap();
+        assert_eq!(next_friend.pending_cancels.len(), 1);
+
+        // The response arrives from the next friend before the cancellation was processed:
+        let mc_mutation = McMutation::RemoveLocalPendingRequest(pending_request.request_id);
+        m_state.mutate(FunderMutation::FriendMutation((
+            next_pk.clone(),
+            FriendMutation::TcMutation(TcMutation::McMutation(mc_mutation)),
+        )));
+        let response_send_funds = ResponseSendFunds {
+            request_id: pending_request.request_id,
+            rand_nonce: RandValue::from(&[2; RAND_VALUE_LEN]),
+            signature: Signature::from(&[3; SIGNATURE_LEN]),
+        };
+        let mut send_commands = SendCommands::new();
+        let mut outgoing_control = Vec::new();
+        handle_response_send_funds(
+            &mut m_state,
+            &mut send_commands,
+            &mut outgoing_control,
+            response_send_funds.clone(),
+            pending_request,
+        );
+
+        // The response is passed back to the remote friend:
+        assert!(outgoing_control.is_empty());
+        assert!(send_commands.send_commands.contains_key(&remote_pk));
+        let friend = m_state.state().friends.get(&remote_pk).unwrap();
+        assert_eq!(friend.pending_responses.len(), 1);
+        match friend.pending_responses.front().unwrap() {
+            ResponseOp::Response(response) => assert_eq!(response, &response_send_funds),
+            _ => unreachable!(),
+        };
+    }
+
+    #[test]
+    fn test_handle_request_send_funds_not_on_route() {
+        let local_pk = PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]);
+        let remote_pk = PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]);
+        let pk_c = PublicKey::from(&[0xcc; PUBLIC_KEY_LEN]);
+        let pk_d = PublicKey::from(&[0xdd; PUBLIC_KEY_LEN]);
+
+        let routes = vec![
+            // We do not appear on the route at all:
+            vec![remote_pk.clone(), pk_c.clone(), pk_d.clone()],
+            // We appear on the route, but not right after the remote friend:
+            vec![
+                remote_pk.clone(),
+                pk_c.clone(),
+                local_pk.clone(),
+                pk_d.clone(),
+            ],
+            // We appear on the route before the remote friend:
+            vec![local_pk.clone(), remote_pk.clone(), pk_c.clone()],
+        ];
+
+        for public_keys in routes {
+            let state = dummy_state_with_friend(&local_pk, &remote_pk, None);
+
+            let ephemeral = Ephemeral::new();
+            let mut m_state = MutableFunderState::new(state);
+            let mut send_commands = SendCommands::new();
+
+            let request_send_funds = RequestSendFunds {
+                request_id: Uid::from(&[0; UID_LEN]),
+                route: FriendsRoute { public_keys },
+                dest_payment: 10,
+                invoice_id: InvoiceId::from(&[1; INVOICE_ID_LEN]),
+            };
+
+            // Should not panic:
+            handle_request_send_funds(
+                &mut m_state,
+                &ephemeral,
+                &mut send_commands,
+                MAX_PENDING_REQUESTS,
+                &remote_pk,
+                request_send_funds.clone(),
+            );
+
+            let (_initial_state, _funder_mutations, final_state) = m_state.done();
+
+            // The request is failed back to the remote friend:
+            let friend = final_state.friends.get(&remote_pk).unwrap();
+            assert_eq!(friend.pending_responses.len(), 1);
+            match friend.pending_responses.front().unwrap() {
+                ResponseOp::UnsignedFailure((pending_request, failure_reason)) => {
+                    assert_eq!(failure_reason, &FailureReason::InvalidRoute);
+                    assert_eq!(
+                        pending_request,
+                        &create_pending_request(&request_send_funds)
+                    );
+                }
+                _ => unreachable!(),
+            };
+            assert!(send_commands.send_commands.contains_key(&remote_pk));
+        }
+    }
+
+    #[test]
+    fn test_inconsistency_error_rate_limit() {
+        let local_pk = PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]);
+        let remote_pk = PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]);
+
+        // We hold the token when the remote side reports the inconsistency:
+        let token_channel = TokenChannel::new_for_test(&local_pk, &remote_pk, 0i128, true);
+        let state = dummy_state_with_friend(&local_pk, &remote_pk, Some(token_channel));
+
+        let mut m_state = MutableFunderState::new(state);
+        let mut m_ephemeral = MutableEphemeral::new(Ephemeral::new());
+        let mut send_commands = SendCommands::new();
+        let mut outgoing_control = Vec::new();
+        let mut outgoing_channeler_config = Vec::new();
+        let rng = DummyRandom::new(&[1u8]);
+        let inconsistency_error_interval_ticks = 4;
+
+        let reset_terms = |i: u8| ResetTerms {
+            reset_token: Signature::from(&[i; SIGNATURE_LEN]),
+            inconsistency_counter: 1,
+            balance_for_reset: 0,
+        };
+
+        // Returns the remote reset terms we currently remember:
+        let remote_reset_terms = |m_state: &MutableFunderState<u32>| {
+            let friend = m_state.state().friends.get(&remote_pk).unwrap();
+            match &friend.channel_status {
+                ChannelStatus::Inconsistent(channel_inconsistent) => {
+                    channel_inconsistent.opt_remote_reset_terms.clone()
+                }
+                ChannelStatus::Consistent(_) => unreachable!(),
+            }
+        };
+
+        for (i, expected_i) in [(1u8, 1u8), (2, 1)].iter() {
+            handle_friend_message(
+                &mut m_state,
+                &mut m_ephemeral,
+                &mut send_commands,
+                &mut outgoing_control,
+                &mut outgoing_channeler_config,
+                &rng,
+                inconsistency_error_interval_ticks,
+                MAX_PENDING_REQUESTS,
+                &remote_pk,
+                FriendMessage::InconsistencyError(reset_terms(*i)),
+            )
+            .unwrap();
+            // The second inconsistency error arrives within the window, and is ignored:
+            assert_eq!(remote_reset_terms(&m_state), Some(reset_terms(*expected_i)));
+        }
+
+        for _ in 0..inconsistency_error_interval_ticks {
+            m_ephemeral.mutate(EphemeralMutation::TimerTick);
+        }
+
+        // After the window passes, inconsistency errors are processed again:
+        handle_friend_message(
+            &mut m_state,
+            &mut m_ephemeral,
+            &mut send_commands,
+            &mut outgoing_control,
+            &mut outgoing_channeler_config,
+            &rng,
+            inconsistency_error_interval_ticks,
+            MAX_PENDING_REQUESTS,
+            &remote_pk,
+            FriendMessage::InconsistencyError(reset_terms(3)),
+        )
+        .unwrap();
+        assert_eq!(remote_reset_terms(&m_state), Some(reset_terms(3)));
+    }
+
+    #[test]
+    fn test_rollback_remote_relays_on_inconsistency() {
+        let local_pk = PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]);
+        let remote_pk = PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]);
+
+        let mut state = dummy_state_with_friend(&local_pk, &remote_pk, None);
+        let friend_mutation = FriendMutation::SetStatus(FriendStatus::Enabled);
+        state.mutate(&FunderMutation::FriendMutation((
+            remote_pk.clone(),
+            friend_mutation,
+        )));
+
+        let mut m_state = MutableFunderState::new(state);
+        let mut m_ephemeral = MutableEphemeral::new(Ephemeral::new());
+        let mut send_commands = SendCommands::new();
+        let mut outgoing_control = Vec::new();
+        let mut outgoing_channeler_config = Vec::new();
+
+        let move_token_received = |opt_local_relays| {
+            ReceiveMoveTokenOutput::Received(MoveTokenReceived {
+                incoming_messages: Vec::new(),
+                mutations: Vec::new(),
+                remote_requests_closed: false,
+                opt_local_relays,
+            })
+        };
+
+        // The remote side updates its relays:
+        handle_move_token_success(
+            &mut m_state,
+            &mut m_ephemeral,
+            &mut send_commands,
+            &mut outgoing_control,
+            &mut outgoing_channeler_config,
+            MAX_PENDING_REQUESTS,
+            &remote_pk,
+            move_token_received(Some(vec![dummy_relay_address(2)])),
+            false,
+        );
+        let friend = m_state.state().friends.get(&remote_pk).unwrap();
+        assert_eq!(friend.remote_relays, vec![dummy_relay_address(2)]);
+        assert_eq!(
+            friend.opt_prev_remote_relays,
+            Some(vec![dummy_relay_address(1)])
+        );
+
+        // The next move token confirms the update:
+        handle_move_token_success(
+            &mut m_state,
+            &mut m_ephemeral,
+            &mut send_commands,
+            &mut outgoing_control,
+            &mut outgoing_channeler_config,
+            MAX_PENDING_REQUESTS,
+            &remote_pk,
+            move_token_received(None),
+            false,
+        );
+        let friend = m_state.state().friends.get(&remote_pk).unwrap();
+        assert_eq!(friend.remote_relays, vec![dummy_relay_address(2)]);
+        assert!(friend.opt_prev_remote_relays.is_none());
+
+        // Another update, followed by an inconsistency:
+        handle_move_token_success(
+            &mut m_state,
+            &mut m_ephemeral,
+            &mut send_commands,
+            &mut outgoing_control,
+            &mut outgoing_channeler_config,
+            MAX_PENDING_REQUESTS,
+            &remote_pk,
+            move_token_received(Some(vec![dummy_relay_address(3)])),
+            false,
+        );
+        let friend = m_state.state().friends.get(&remote_pk).unwrap();
+        assert_eq!(friend.remote_relays, vec![dummy_relay_address(3)]);
+
+        // We hold the token when the remote side reports the inconsistency:
+        let token_channel = TokenChannel::new_for_test(&local_pk, &remote_pk, 0i128, true);
+        m_state.mutate(FunderMutation::FriendMutation((
+            remote_pk.clone(),
+            FriendMutation::SetConsistent(token_channel),
+        )));
+
+        let rng = DummyRandom::new(&[1u8]);
+        let remote_reset_terms = ResetTerms {
+            reset_token: Signature::from(&[1; SIGNATURE_LEN]),
+            inconsistency_counter: 1,
+            balance_for_reset: 0,
+        };
+        outgoing_channeler_config.clear();
+        handle_inconsistency_error(
+            &mut m_state,
+            &mut send_commands,
+            &mut outgoing_control,
+            &mut outgoing_channeler_config,
+            &rng,
+            &FriendLogContext::new(&remote_pk, "InconsistencyError"),
+            &remote_pk,
             remote_reset_terms,
-        ),
+        )
+        .unwrap();
+
+        // The unconfirmed update was rolled back:
+        let friend = m_state.state().friends.get(&remote_pk).unwrap();
+        assert_eq!(friend.remote_relays, vec![dummy_relay_address(2)]);
+        assert!(friend.opt_prev_remote_relays.is_none());
+        match &friend.channel_status {
+            ChannelStatus::Inconsistent(_) => {}
+            ChannelStatus::Consistent(_) => unreachable!(),
+        };
+
+        // The Channeler is notified about the restored relays:
+        assert_eq!(outgoing_channeler_config.len(), 1);
+        match &outgoing_channeler_config[0] {
+            ChannelerConfig::UpdateFriend(update_friend) => {
+                assert_eq!(update_friend.friend_public_key, remote_pk);
+                assert_eq!(update_friend.friend_relays, vec![dummy_relay_address(2)]);
+            }
+            _ => unreachable!(),
+        };
+    }
+
+    #[test]
+    fn test_remote_requests_closed_cancels_pending_requests() {
+        let local_pk = PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]);
+        let remote_pk = PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]);
+
+        let mut state = dummy_state_with_friend(&local_pk, &remote_pk, None);
+
+        let request_send_funds = |request_id_byte| RequestSendFunds {
+            request_id: Uid::from(&[request_id_byte; UID_LEN]),
+            route: FriendsRoute {
+                public_keys: vec![local_pk.clone(), remote_pk.clone()],
+            },
+            dest_payment: 10,
+            invoice_id: InvoiceId::from(&[1; INVOICE_ID_LEN]),
+        };
+
+        // A request and a user request, both waiting to be sent to the remote friend:
+        let friend_mutation = FriendMutation::PushBackPendingRequest(request_send_funds(0));
+        state.mutate(&FunderMutation::FriendMutation((
+            remote_pk.clone(),
+            friend_mutation,
+        )));
+        let friend_mutation = FriendMutation::PushBackPendingUserRequest(request_send_funds(1));
+        state.mutate(&FunderMutation::FriendMutation((
+            remote_pk.clone(),
+            friend_mutation,
+        )));
+
+        let mut m_state = MutableFunderState::new(state);
+        let mut m_ephemeral = MutableEphemeral::new(Ephemeral::new());
+        let mut send_commands = SendCommands::new();
+        let mut outgoing_control = Vec::new();
+        let mut outgoing_channeler_config = Vec::new();
+
+        // The remote side closes its requests:
+        let receive_move_token_output = ReceiveMoveTokenOutput::Received(MoveTokenReceived {
+            incoming_messages: Vec::new(),
+            mutations: Vec::new(),
+            remote_requests_closed: true,
+            opt_local_relays: None,
+        });
+        handle_move_token_success(
+            &mut m_state,
+            &mut m_ephemeral,
+            &mut send_commands,
+            &mut outgoing_control,
+            &mut outgoing_channeler_config,
+            MAX_PENDING_REQUESTS,
+            &remote_pk,
+            receive_move_token_output,
+            false,
+        );
+
+        let friend = m_state.state().friends.get(&remote_pk).unwrap();
+        assert!(friend.pending_requests.is_empty());
+        assert!(friend.pending_user_requests.is_empty());
+
+        // We are the origin of both requests. We are told that they failed because the remote
+        // side closed its requests:
+        let mut request_ids = Vec::new();
+        for funder_outgoing_control in &outgoing_control {
+            match funder_outgoing_control {
+                FunderOutgoingControl::ResponseReceived(response_received) => {
+                    assert_eq!(
+                        response_received.result,
+                        ResponseSendFundsResult::Failure(local_pk.clone())
+                    );
+                    assert_eq!(
+                        response_received.opt_failure_reason,
+                        Some(FailureReason::RequestsClosed)
+                    );
+                    request_ids.push(response_received.request_id);
+                }
+                _ => unreachable!(),
+            }
+        }
+        assert_eq!(
+            request_ids,
+            vec![Uid::from(&[0; UID_LEN]), Uid::from(&[1; UID_LEN])]
+        );
     }
 }
@@ -1,7 +1,9 @@
 use common::canonical_serialize::CanonicalSerialize;
 use std::fmt::Debug;
 
-use proto::funder::messages::{FriendStatus, FunderOutgoingControl};
+use crypto::identity::PublicKey;
+
+use proto::funder::messages::{FailureReason, FriendStatus, FunderOutgoingControl};
 
 use crate::types::IncomingLivenessMessage;
 
@@ -24,6 +26,7 @@ pub fn handle_liveness_message<B>(
     m_ephemeral: &mut MutableEphemeral,
     send_commands: &mut SendCommands,
     outgoing_control: &mut Vec<FunderOutgoingControl<B>>,
+    offline_grace_ticks: usize,
     liveness_message: IncomingLivenessMessage,
 ) -> Result<(), HandleLivenessError>
 where
@@ -59,26 +62,105 @@ where
             m_ephemeral.mutate(ephemeral_mutation);
         }
         IncomingLivenessMessage::Offline(friend_public_key) => {
-            // It is possible that the friend is disabled and we get an offline notification.
-            // This will usually happen if we just set the friend to be disabled. We will get the
-            // offline notification for the friend short time after we set it to be disabled.
-            let liveness_mutation = LivenessMutation::SetOffline(friend_public_key.clone());
-            let ephemeral_mutation = EphemeralMutation::LivenessMutation(liveness_mutation);
-            m_ephemeral.mutate(ephemeral_mutation);
-
-            // If the friend does not exist, we have nothing more to do here:
-            if m_state.state().friends.get(&friend_public_key).is_none() {
+            // If the friend was online, we first mark it as suspect. Requests pending for this
+            // friend are held until the grace period is over, in case the friend comes back
+            // online.
+            let is_enabled_friend = match m_state.state().friends.get(&friend_public_key) {
+                Some(friend) => friend.status == FriendStatus::Enabled,
+                None => false,
+            };
+            if offline_grace_ticks > 0
+                && is_enabled_friend
+                && m_ephemeral
+                    .ephemeral()
+                    .liveness
+                    .is_online(&friend_public_key)
+            {
+                let cur_tick = m_ephemeral.ephemeral().ticks;
+                let liveness_mutation =
+                    LivenessMutation::SetSuspect((friend_public_key.clone(), cur_tick));
+                let ephemeral_mutation = EphemeralMutation::LivenessMutation(liveness_mutation);
+                m_ephemeral.mutate(ephemeral_mutation);
                 return Ok(());
             }
 
-            // Cancel all messages pending for this friend:
-            cancel_pending_requests(m_state, send_commands, outgoing_control, &friend_public_key);
-            cancel_pending_user_requests(m_state, outgoing_control, &friend_public_key);
+            // It is possible that the friend is disabled and we get an offline notification.
+            // This will usually happen if we just set the friend to be disabled. We will get the
+            // offline notification for the friend short time after we set it to be disabled.
+            set_friend_offline(
+                m_state,
+                m_ephemeral,
+                send_commands,
+                outgoing_control,
+                &friend_public_key,
+            );
         }
     };
     Ok(())
 }
 
+/// Declare a friend as offline, and cancel all messages pending for this friend.
+fn set_friend_offline<B>(
+    m_state: &mut MutableFunderState<B>,
+    m_ephemeral: &mut MutableEphemeral,
+    send_commands: &mut SendCommands,
+    outgoing_control: &mut Vec<FunderOutgoingControl<B>>,
+    friend_public_key: &PublicKey,
+) where
+    B: Clone + CanonicalSerialize + PartialEq + Eq + Debug,
+{
+    let liveness_mutation = LivenessMutation::SetOffline(friend_public_key.clone());
+    let ephemeral_mutation = EphemeralMutation::LivenessMutation(liveness_mutation);
+    m_ephemeral.mutate(ephemeral_mutation);
+
+    // If the friend does not exist, we have nothing more to do here:
+    if m_state.state().friends.get(friend_public_key).is_none() {
+        return;
+    }
+
+    // Cancel all messages pending for this friend:
+    cancel_pending_requests(
+        m_state,
+        send_commands,
+        outgoing_control,
+        friend_public_key,
+        FailureReason::Canceled,
+    );
+    cancel_pending_user_requests(
+        m_state,
+        outgoing_control,
+        friend_public_key,
+        FailureReason::Canceled,
+    );
+}
+
+/// Declare offline all suspect friends that did not come back online during the grace period.
+/// Should be called after every timer tick.
+pub fn handle_liveness_tick<B>(
+    m_state: &mut MutableFunderState<B>,
+    m_ephemeral: &mut MutableEphemeral,
+    send_commands: &mut SendCommands,
+    outgoing_control: &mut Vec<FunderOutgoingControl<B>>,
+    offline_grace_ticks: usize,
+) where
+    B: Clone + CanonicalSerialize + PartialEq + Eq + Debug,
+{
+    let expired_suspects = m_ephemeral
+        .ephemeral()
+        .liveness
+        .expired_suspects(m_ephemeral.ephemeral().ticks, offline_grace_ticks as u64);
+
+    for friend_public_key in &expired_suspects {
+        set_friend_offline(
+            m_state,
+            m_ephemeral,
+            send_commands,
+            outgoing_control,
+            friend_public_key,
+        );
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -87,9 +169,14 @@ mod tests {
 
     use crypto::identity::{
         compare_public_key, generate_pkcs8_key_pair, Identity, SoftwareEd25519Identity,
+        PUBLIC_KEY_LEN,
     };
+    use crypto::invoice_id::{InvoiceId, INVOICE_ID_LEN};
     use crypto::test_utils::DummyRandom;
-    use proto::funder::messages::{AddFriend, FriendStatus};
+    use crypto::uid::{Uid, UID_LEN};
+    use proto::funder::messages::{
+        AddFriend, FriendStatus, FriendsRoute, RequestSendFunds, ResponseSendFundsResult,
+    };
 
     use crate::ephemeral::Ephemeral;
     use crate::friend::{ChannelStatus, FriendMutation};
@@ -99,6 +186,8 @@ mod tests {
     use crate::handler::sender::SendCommands;
     use crate::tests::utils::{dummy_named_relay_address, dummy_relay_address};
 
+    const TEST_OFFLINE_GRACE_TICKS: usize = 0;
+
     #[test]
     fn test_handle_liveness_basic() {
         let rng1 = DummyRandom::new(&[1u8]);
@@ -158,6 +247,7 @@ mod tests {
             &mut m_ephemeral,
             &mut send_commands,
             &mut outgoing_control,
+            TEST_OFFLINE_GRACE_TICKS,
             liveness_message,
         )
         .unwrap();
@@ -174,4 +264,148 @@ mod tests {
         let friend_send_commands = send_commands.send_commands.get(&remote_pk).unwrap();
         assert!(friend_send_commands.resend_outgoing);
     }
+
+    #[test]
+    fn test_handle_liveness_suspect() {
+        let local_pk = PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]);
+        let remote_pk = PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]);
+        let offline_grace_ticks = 3;
+
+        let relays = vec![dummy_named_relay_address(0)];
+        let mut state = FunderState::<u32>::new(local_pk.clone(), relays);
+        let add_friend = AddFriend {
+            friend_public_key: remote_pk.clone(),
+            relays: vec![dummy_relay_address(1)],
+            name: "remote_pk".into(),
+            balance: 0i128,
+        };
+        state.mutate(&FunderMutation::AddFriend(add_friend));
+        let friend_mutation = FriendMutation::SetStatus(FriendStatus::Enabled);
+        state.mutate(&FunderMutation::FriendMutation((
+            remote_pk.clone(),
+            friend_mutation,
+        )));
+
+        // A user request waiting to be sent to the remote friend:
+        let request_send_funds = RequestSendFunds {
+            request_id: Uid::from(&[1; UID_LEN]),
+            route: FriendsRoute {
+                public_keys: vec![local_pk.clone(), remote_pk.clone()],
+            },
+            dest_payment: 10,
+            invoice_id: InvoiceId::from(&[1; INVOICE_ID_LEN]),
+        };
+        let friend_mutation = FriendMutation::PushBackPendingUserRequest(request_send_funds);
+        state.mutate(&FunderMutation::FriendMutation((
+            remote_pk.clone(),
+            friend_mutation,
+        )));
+
+        let mut m_state = MutableFunderState::new(state);
+        let mut m_ephemeral = MutableEphemeral::new(Ephemeral::new());
+        let mut send_commands = SendCommands::new();
+        let mut outgoing_control = Vec::new();
+
+        let mut handle_liveness = |m_state: &mut MutableFunderState<u32>,
+                                   m_ephemeral: &mut MutableEphemeral,
+                                   outgoing_control: &mut Vec<_>,
+                                   liveness_message| {
+            handle_liveness_message(
+                m_state,
+                m_ephemeral,
+                &mut send_commands,
+                outgoing_control,
+                offline_grace_ticks,
+                liveness_message,
+            )
+            .unwrap();
+        };
+
+        let tick = |m_state: &mut MutableFunderState<u32>,
+                    m_ephemeral: &mut MutableEphemeral,
+                    outgoing_control: &mut Vec<_>| {
+            m_ephemeral.mutate(EphemeralMutation::TimerTick);
+            handle_liveness_tick(
+                m_state,
+                m_ephemeral,
+                &mut SendCommands::new(),
+                outgoing_control,
+                offline_grace_ticks,
+            );
+        };
+
+        handle_liveness(
+            &mut m_state,
+            &mut m_ephemeral,
+            &mut outgoing_control,
+            IncomingLivenessMessage::Online(remote_pk.clone()),
+        );
+        assert!(m_ephemeral.ephemeral().liveness.is_online(&remote_pk));
+
+        // Online -> Suspect:
+        handle_liveness(
+            &mut m_state,
+            &mut m_ephemeral,
+            &mut outgoing_control,
+            IncomingLivenessMessage::Offline(remote_pk.clone()),
+        );
+        assert!(!m_ephemeral.ephemeral().liveness.is_online(&remote_pk));
+        assert!(m_ephemeral.ephemeral().liveness.is_suspect(&remote_pk));
+
+        // The pending user request is held during the grace period:
+        for _ in 0..offline_grace_ticks - 1 {
+            tick(&mut m_state, &mut m_ephemeral, &mut outgoing_control);
+        }
+        assert!(m_ephemeral.ephemeral().liveness.is_suspect(&remote_pk));
+        assert!(outgoing_control.is_empty());
+        let friend = m_state.state().friends.get(&remote_pk).unwrap();
+        assert_eq!(friend.pending_user_requests.len(), 1);
+
+        // Suspect -> Online. Nothing is canceled:
+        handle_liveness(
+            &mut m_state,
+            &mut m_ephemeral,
+            &mut outgoing_control,
+            IncomingLivenessMessage::Online(remote_pk.clone()),
+        );
+        assert!(m_ephemeral.ephemeral().liveness.is_online(&remote_pk));
+        assert!(!m_ephemeral.ephemeral().liveness.is_suspect(&remote_pk));
+        for _ in 0..offline_grace_ticks {
+            tick(&mut m_state, &mut m_ephemeral, &mut outgoing_control);
+        }
+        assert!(m_ephemeral.ephemeral().liveness.is_online(&remote_pk));
+        assert!(outgoing_control.is_empty());
+
+        // Online -> Suspect -> Offline:
+        handle_liveness(
+            &mut m_state,
+            &mut m_ephemeral,
+            &mut outgoing_control,
+            IncomingLivenessMessage::Offline(remote_pk.clone()),
+        );
+        for _ in 0..offline_grace_ticks - 1 {
+            tick(&mut m_state, &mut m_ephemeral, &mut outgoing_control);
+        }
+        assert!(m_ephemeral.ephemeral().liveness.is_suspect(&remote_pk));
+        assert!(outgoing_control.is_empty());
+
+        tick(&mut m_state, &mut m_ephemeral, &mut outgoing_control);
+        assert!(!m_ephemeral.ephemeral().liveness.is_online(&remote_pk));
+        assert!(!m_ephemeral.ephemeral().liveness.is_suspect(&remote_pk));
+
+        // The pending user request was canceled:
+        let friend = m_state.state().friends.get(&remote_pk).unwrap();
+        assert!(friend.pending_user_requests.is_empty());
+        assert_eq!(outgoing_control.len(), 1);
+        match &outgoing_control[0] {
+            FunderOutgoingControl::ResponseReceived(response_received) => {
+                assert_eq!(response_received.request_id, Uid::from(&[1; UID_LEN]));
+                assert_eq!(
+                    response_received.result,
+                    ResponseSendFundsResult::Failure(local_pk.clone())
+                );
+            }
+            _ => unreachable!(),
+        }
+    }
 }
@@ -0,0 +1,927 @@
+use std::collections::HashSet;
+use std::fmt::Debug;
+
+use common::canonical_serialize::CanonicalSerialize;
+
+use crypto::identity::PublicKey;
+
+use proto::funder::messages::{FailureReason, FriendStatus, FunderOutgoingControl, PendingRequest};
+
+use crate::ephemeral::{Ephemeral, EphemeralMutation};
+use crate::friend::ChannelStatus;
+use crate::state::FunderState;
+
+use crate::handler::canceler::cancel_remote_request;
+use crate::handler::handle_liveness::handle_liveness_tick;
+use crate::handler::handler::{MutableEphemeral, MutableFunderState};
+use crate::handler::sender::{estimate_should_send, SendCommands};
+
+/// Does this friend have outgoing operations that could be sent right now?
+fn has_pending_outgoing<B>(
+    state: &FunderState<B>,
+    ephemeral: &Ephemeral,
+    friend_public_key: &PublicKey,
+) -> bool
+where
+    B: Clone + CanonicalSerialize + PartialEq + Eq + Debug,
+{
+    let friend = match state.friends.get(friend_public_key) {
+        Some(friend) => friend,
+        None => return false,
+    };
+
+    if friend.status != FriendStatus::Enabled || !ephemeral.liveness.is_online(friend_public_key) {
+        return false;
+    }
+
+    if let ChannelStatus::Inconsistent(_) = &friend.channel_status {
+        return false;
+    }
+
+    estimate_should_send(state, friend_public_key)
+}
+
+/// Do we hold the token of this friend while having outgoing operations that could be sent?
+/// If we are waiting for the token instead, the pending operations are handled by
+/// `handle_stall_tick`.
+fn should_flush<B>(
+    state: &FunderState<B>,
+    ephemeral: &Ephemeral,
+    friend_public_key: &PublicKey,
+) -> bool
+where
+    B: Clone + CanonicalSerialize + PartialEq + Eq + Debug,
+{
+    if !has_pending_outgoing(state, ephemeral, friend_public_key) {
+        return false;
+    }
+
+    match &state.friends.get(friend_public_key).unwrap().channel_status {
+        ChannelStatus::Consistent(token_channel) => !token_channel.is_outgoing(),
+        ChannelStatus::Inconsistent(_) => false,
+    }
+}
+
+/// Flush the pending outgoing operations of every friend that had pending outgoing operations
+/// for `flush_idle_ticks` ticks while we hold its token. This bounds the time an operation may
+/// wait in a partially filled batch. A `flush_idle_ticks` of 0 disables flushing on timer.
+pub fn handle_flush_tick<B>(
+    m_state: &MutableFunderState<B>,
+    m_ephemeral: &mut MutableEphemeral,
+    send_commands: &mut SendCommands,
+    flush_idle_ticks: usize,
+) where
+    B: Clone + CanonicalSerialize + PartialEq + Eq + Debug,
+{
+    if flush_idle_ticks == 0 {
+        return;
+    }
+
+    // Forget friends that do not have pending outgoing operations anymore:
+    let flushed_friends = m_ephemeral
+        .ephemeral()
+        .flush_pending_ticks
+        .keys()
+        .filter(|friend_public_key| {
+            !should_flush(m_state.state(), m_ephemeral.ephemeral(), friend_public_key)
+        })
+        .cloned()
+        .collect::<Vec<_>>();
+
+    for friend_public_key in flushed_friends {
+        m_ephemeral.mutate(EphemeralMutation::RemoveFlushPendingTick(friend_public_key));
+    }
+
+    let pending_friends = m_state
+        .state()
+        .friends
+        .keys()
+        .filter(|friend_public_key| {
+            should_flush(m_state.state(), m_ephemeral.ephemeral(), friend_public_key)
+        })
+        .cloned()
+        .collect::<Vec<_>>();
+
+    let ticks = m_ephemeral.ephemeral().ticks;
+    for friend_public_key in pending_friends {
+        let opt_pending_tick = m_ephemeral
+            .ephemeral()
+            .flush_pending_ticks
+            .get(&friend_public_key)
+            .cloned();
+
+        match opt_pending_tick {
+            None => {}
+            Some(pending_tick) => {
+                if ticks.saturating_sub(pending_tick) < flush_idle_ticks as u64 {
+                    continue;
+                }
+                send_commands.set_try_send(&friend_public_key);
+            }
+        }
+
+        // Start counting from the current tick:
+        m_ephemeral.mutate(EphemeralMutation::SetFlushPendingTick((
+            friend_public_key,
+            ticks,
+        )));
+    }
+}
+
+/// If we are waiting for the token from this friend while having outgoing operations that could
+/// be sent, returns the move token counter of our last outgoing move token.
+fn waiting_move_token_counter<B>(
+    state: &FunderState<B>,
+    ephemeral: &Ephemeral,
+    friend_public_key: &PublicKey,
+) -> Option<u128>
+where
+    B: Clone + CanonicalSerialize + PartialEq + Eq + Debug,
+{
+    if !has_pending_outgoing(state, ephemeral, friend_public_key) {
+        return None;
+    }
+
+    let friend = state.friends.get(friend_public_key)?;
+    match &friend.channel_status {
+        ChannelStatus::Consistent(token_channel) if token_channel.is_outgoing() => {
+            Some(token_channel.get_move_token_counter())
+        }
+        _ => None,
+    }
+}
+
+/// Request the token again from every friend we have been waiting on for `token_stall_ticks`
+/// ticks while having pending outgoing operations, without the token moving.
+/// If both sides believe that the other side holds the token, the channel stalls until one of the
+/// sides sends its move token again. A `token_stall_ticks` of 0 disables stall detection.
+pub fn handle_stall_tick<B>(
+    m_state: &MutableFunderState<B>,
+    m_ephemeral: &mut MutableEphemeral,
+    send_commands: &mut SendCommands,
+    token_stall_ticks: usize,
+) where
+    B: Clone + CanonicalSerialize + PartialEq + Eq + Debug,
+{
+    if token_stall_ticks == 0 {
+        return;
+    }
+
+    // Forget friends we are not waiting on anymore:
+    let released_friends = m_ephemeral
+        .ephemeral()
+        .token_wait_ticks
+        .keys()
+        .filter(|friend_public_key| {
+            waiting_move_token_counter(m_state.state(), m_ephemeral.ephemeral(), friend_public_key)
+                .is_none()
+        })
+        .cloned()
+        .collect::<Vec<_>>();
+
+    for friend_public_key in released_friends {
+        m_ephemeral.mutate(EphemeralMutation::RemoveTokenWaitTick(friend_public_key));
+    }
+
+    let waiting_friends = m_state
+        .state()
+        .friends
+        .keys()
+        .filter_map(|friend_public_key| {
+            waiting_move_token_counter(m_state.state(), m_ephemeral.ephemeral(), friend_public_key)
+                .map(|move_token_counter| (friend_public_key.clone(), move_token_counter))
+        })
+        .collect::<Vec<_>>();
+
+    let ticks = m_ephemeral.ephemeral().ticks;
+    for (friend_public_key, move_token_counter) in waiting_friends {
+        let opt_token_wait = m_ephemeral
+            .ephemeral()
+            .token_wait_ticks
+            .get(&friend_public_key)
+            .cloned();
+
+        match opt_token_wait {
+            Some((wait_move_token_counter, wait_tick))
+                if wait_move_token_counter == move_token_counter =>
+            {
+                if ticks.saturating_sub(wait_tick) < token_stall_ticks as u64 {
+                    continue;
+                }
+                // The token did not move for too long. Resend our last move token, asking for
+                // the token:
+                send_commands.set_try_send(&friend_public_key);
+            }
+            // We just started waiting, or the token has moved since we last checked:
+            _ => {}
+        }
+
+        // Start counting from the current tick:
+        m_ephemeral.mutate(EphemeralMutation::SetTokenWaitTick((
+            friend_public_key,
+            move_token_counter,
+            ticks,
+        )));
+    }
+}
+
+/// Collect all the requests received from friends that are pending at our node, together with
+/// the friend every request was received from. The credits of these requests are frozen until
+/// a response or a failure is sent back.
+fn pending_remote_requests<B>(state: &FunderState<B>) -> Vec<(PublicKey, PendingRequest)>
+where
+    B: Clone + CanonicalSerialize + PartialEq + Eq + Debug,
+{
+    let mut pending_requests = Vec::new();
+    for (friend_public_key, friend) in &state.friends {
+        let token_channel = match &friend.channel_status {
+            ChannelStatus::Consistent(token_channel) => token_channel,
+            ChannelStatus::Inconsistent(_) => continue,
+        };
+        for pending_request in token_channel
+            .get_mutual_credit()
+            .state()
+            .pending_requests
+            .pending_remote_requests
+            .values()
+        {
+            pending_requests.push((friend_public_key.clone(), pending_request.clone()));
+        }
+    }
+    pending_requests
+}
+
+/// Cancel every request that was pending at our node for `request_timeout_ticks` ticks without a
+/// response. A request that was not forwarded yet is failed back right away. For a request that
+/// was already forwarded, the next node on the route is asked to cancel it, and the failure (or a
+/// late response) arrives back through the usual path. This bounds the time credits may be frozen
+/// by a dead route.
+/// `request_timeout_ticks` must be longer than the longest legitimate round trip of a request. A
+/// `request_timeout_ticks` of 0 disables the timeout.
+pub fn handle_request_timeout_tick<B>(
+    m_state: &mut MutableFunderState<B>,
+    m_ephemeral: &mut MutableEphemeral,
+    send_commands: &mut SendCommands,
+    request_timeout_ticks: usize,
+) where
+    B: Clone + CanonicalSerialize + PartialEq + Eq + Debug,
+{
+    if request_timeout_ticks == 0 {
+        return;
+    }
+
+    let pending_requests = pending_remote_requests(m_state.state());
+
+    // Forget requests that are not pending anymore:
+    let pending_request_ids = pending_requests
+        .iter()
+        .map(|(_, pending_request)| pending_request.request_id)
+        .collect::<HashSet<_>>();
+
+    let resolved_request_ids = m_ephemeral
+        .ephemeral()
+        .pending_request_ticks
+        .keys()
+        .filter(|request_id| !pending_request_ids.contains(request_id))
+        .cloned()
+        .collect::<Vec<_>>();
+
+    for request_id in resolved_request_ids {
+        m_ephemeral.mutate(EphemeralMutation::RemovePendingRequestTick(request_id));
+    }
+
+    let ticks = m_ephemeral.ephemeral().ticks;
+    for (friend_public_key, pending_request) in pending_requests {
+        let opt_pending_tick = m_ephemeral
+            .ephemeral()
+            .pending_request_ticks
+            .get(&pending_request.request_id)
+            .cloned();
+
+        match opt_pending_tick {
+            None => {
+                // Start counting from the current tick:
+                m_ephemeral.mutate(EphemeralMutation::SetPendingRequestTick((
+                    pending_request.request_id,
+                    ticks,
+                )));
+            }
+            Some(pending_tick) => {
+                if ticks.saturating_sub(pending_tick) < request_timeout_ticks as u64 {
+                    continue;
+                }
+                let request_id = pending_request.request_id;
+                cancel_remote_request(
+                    m_state,
+                    send_commands,
+                    &friend_public_key,
+                    pending_request,
+                    FailureReason::TimedOut,
+                );
+                // If the request is still pending, ask again only after another timeout:
+                m_ephemeral.mutate(EphemeralMutation::SetPendingRequestTick((
+                    request_id, ticks,
+                )));
+            }
+        }
+    }
+}
+
+/// Handle a timer tick: Advance the tick counter, declare offline friends that did not come back
+/// online during their grace period (Canceling all requests pending for them), flush pending
+/// outgoing operations of idle friends, request the token from friends over stalled channels and
+/// fail requests that were pending at our node for too long.
+pub fn handle_timer_tick<B>(
+    m_state: &mut MutableFunderState<B>,
+    m_ephemeral: &mut MutableEphemeral,
+    send_commands: &mut SendCommands,
+    outgoing_control: &mut Vec<FunderOutgoingControl<B>>,
+    offline_grace_ticks: usize,
+    flush_idle_ticks: usize,
+    token_stall_ticks: usize,
+    request_timeout_ticks: usize,
+) where
+    B: Clone + CanonicalSerialize + PartialEq + Eq + Debug,
+{
+    m_ephemeral.mutate(EphemeralMutation::TimerTick);
+    handle_liveness_tick(
+        m_state,
+        m_ephemeral,
+        send_commands,
+        outgoing_control,
+        offline_grace_ticks,
+    );
+    handle_flush_tick(m_state, m_ephemeral, send_commands, flush_idle_ticks);
+    handle_stall_tick(m_state, m_ephemeral, send_commands, token_stall_ticks);
+    handle_request_timeout_tick(m_state, m_ephemeral, send_commands, request_timeout_ticks);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crypto::crypto_rand::{RandValue, RAND_VALUE_LEN};
+    use crypto::identity::{Signature, PUBLIC_KEY_LEN, SIGNATURE_LEN};
+    use crypto::invoice_id::{InvoiceId, INVOICE_ID_LEN};
+    use crypto::uid::{Uid, UID_LEN};
+
+    use proto::funder::messages::{
+        AddFriend, FriendsRoute, MoveToken, RequestSendFunds, ResponseSendFundsResult,
+    };
+
+    use crate::friend::{FriendMutation, ResponseOp, SentLocalRelays};
+    use crate::liveness::LivenessMutation;
+    use crate::mutual_credit::types::McMutation;
+    use crate::state::FunderMutation;
+    use crate::tests::utils::{
+        dummy_named_relay_address, dummy_relay_address, dummy_state_with_friend,
+    };
+    use crate::token_channel::{SetDirection, TcMutation, TokenChannel};
+    use crate::types::create_pending_request;
+
+    /// Tick the timer, and check if the friend was flushed.
+    fn tick_flushed(
+        m_state: &MutableFunderState<u32>,
+        m_ephemeral: &mut MutableEphemeral,
+        friend_public_key: &PublicKey,
+        flush_idle_ticks: usize,
+    ) -> bool {
+        m_ephemeral.mutate(EphemeralMutation::TimerTick);
+        let mut send_commands = SendCommands::new();
+        handle_flush_tick(m_state, m_ephemeral, &mut send_commands, flush_idle_ticks);
+        send_commands
+            .send_commands
+            .get(friend_public_key)
+            .map(|friend_send_commands| friend_send_commands.try_send)
+            .unwrap_or(false)
+    }
+
+    #[test]
+    fn test_handle_flush_tick() {
+        let local_pk = PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]);
+        let remote_pk = PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]);
+        let flush_idle_ticks = 4;
+
+        // We hold the token:
+        let token_channel = TokenChannel::new_for_test(&local_pk, &remote_pk, 0, false);
+        let mut state = dummy_state_with_friend(&local_pk, &remote_pk, Some(token_channel));
+
+        // The friend is enabled and already knows our relays, so nothing needs to be sent:
+        let sent_local_relays = SentLocalRelays::LastSent(state.relays.clone());
+        for friend_mutation in vec![
+            FriendMutation::SetStatus(FriendStatus::Enabled),
+            FriendMutation::SetSentLocalRelays(sent_local_relays),
+        ] {
+            state.mutate(&FunderMutation::FriendMutation((
+                remote_pk.clone(),
+                friend_mutation,
+            )));
+        }
+
+        let mut m_ephemeral = MutableEphemeral::new(Ephemeral::new());
+        m_ephemeral.mutate(EphemeralMutation::LivenessMutation(
+            LivenessMutation::SetOnline(remote_pk.clone()),
+        ));
+
+        let mut m_state = MutableFunderState::new(state);
+        for _ in 0..2 * flush_idle_ticks {
+            assert!(!tick_flushed(
+                &m_state,
+                &mut m_ephemeral,
+                &remote_pk,
+                flush_idle_ticks
+            ));
+        }
+
+        // A single request is queued. This is far from filling a batch:
+        let request_send_funds = RequestSendFunds {
+            request_id: Uid::from(&[0; UID_LEN]),
+            route: FriendsRoute {
+                public_keys: vec![local_pk.clone(), remote_pk.clone()],
+            },
+            dest_payment: 10,
+            invoice_id: InvoiceId::from(&[0; INVOICE_ID_LEN]),
+        };
+        m_state.mutate(FunderMutation::FriendMutation((
+            remote_pk.clone(),
+            FriendMutation::PushBackPendingUserRequest(request_send_funds),
+        )));
+
+        // The friend is flushed only after it was idle for flush_idle_ticks:
+        for _ in 0..flush_idle_ticks {
+            assert!(!tick_flushed(
+                &m_state,
+                &mut m_ephemeral,
+                &remote_pk,
+                flush_idle_ticks
+            ));
+        }
+        assert!(tick_flushed(
+            &m_state,
+            &mut m_ephemeral,
+            &remote_pk,
+            flush_idle_ticks
+        ));
+
+        // Flushing on timer is disabled:
+        for _ in 0..2 * flush_idle_ticks {
+            assert!(!tick_flushed(&m_state, &mut m_ephemeral, &remote_pk, 0));
+        }
+
+        // The friend goes offline, and is not flushed anymore:
+        m_ephemeral.mutate(EphemeralMutation::LivenessMutation(
+            LivenessMutation::SetOffline(remote_pk.clone()),
+        ));
+        for _ in 0..2 * flush_idle_ticks {
+            assert!(!tick_flushed(
+                &m_state,
+                &mut m_ephemeral,
+                &remote_pk,
+                flush_idle_ticks
+            ));
+        }
+        assert!(m_ephemeral.ephemeral().flush_pending_ticks.is_empty());
+
+        // The friend is online again. We send our move token, and wait for the token from the
+        // friend. Requesting the token again is left to stall detection, so the friend is not
+        // flushed anymore:
+        m_ephemeral.mutate(EphemeralMutation::LivenessMutation(
+            LivenessMutation::SetOnline(remote_pk.clone()),
+        ));
+        let move_token = MoveToken {
+            operations: Vec::new(),
+            opt_local_relays: None,
+            old_token: Signature::from(&[1; SIGNATURE_LEN]),
+            local_public_key: local_pk.clone(),
+            remote_public_key: remote_pk.clone(),
+            inconsistency_counter: 0,
+            move_token_counter: 1,
+            balance: 0,
+            local_pending_debt: 0,
+            remote_pending_debt: 0,
+            rand_nonce: RandValue::from(&[2; RAND_VALUE_LEN]),
+            new_token: Signature::from(&[3; SIGNATURE_LEN]),
+        };
+        let tc_mutation = TcMutation::SetDirection(SetDirection::Outgoing(move_token));
+        m_state.mutate(FunderMutation::FriendMutation((
+            remote_pk.clone(),
+            FriendMutation::TcMutation(tc_mutation),
+        )));
+        for _ in 0..2 * flush_idle_ticks {
+            assert!(!tick_flushed(
+                &m_state,
+                &mut m_ephemeral,
+                &remote_pk,
+                flush_idle_ticks
+            ));
+        }
+        assert!(m_ephemeral.ephemeral().flush_pending_ticks.is_empty());
+    }
+
+    /// Tick the timer, and check if the token was requested from the friend.
+    fn tick_stalled(
+        m_state: &MutableFunderState<u32>,
+        m_ephemeral: &mut MutableEphemeral,
+        friend_public_key: &PublicKey,
+        token_stall_ticks: usize,
+    ) -> bool {
+        m_ephemeral.mutate(EphemeralMutation::TimerTick);
+        let mut send_commands = SendCommands::new();
+        handle_stall_tick(m_state, m_ephemeral, &mut send_commands, token_stall_ticks);
+        send_commands
+            .send_commands
+            .get(friend_public_key)
+            .map(|friend_send_commands| friend_send_commands.try_send)
+            .unwrap_or(false)
+    }
+
+    #[test]
+    fn test_handle_stall_tick() {
+        let local_pk = PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]);
+        let remote_pk = PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]);
+        let token_stall_ticks = 4;
+
+        // The friend is enabled and already knows our relays. We have sent the last move token,
+        // and we are waiting for the token from the friend:
+        let token_channel = TokenChannel::new_for_test(&local_pk, &remote_pk, 0, true);
+        let mut state = dummy_state_with_friend(&local_pk, &remote_pk, Some(token_channel));
+        let sent_local_relays = SentLocalRelays::LastSent(state.relays.clone());
+        for friend_mutation in vec![
+            FriendMutation::SetStatus(FriendStatus::Enabled),
+            FriendMutation::SetSentLocalRelays(sent_local_relays),
+        ] {
+            state.mutate(&FunderMutation::FriendMutation((
+                remote_pk.clone(),
+                friend_mutation,
+            )));
+        }
+
+        let mut m_ephemeral = MutableEphemeral::new(Ephemeral::new());
+        m_ephemeral.mutate(EphemeralMutation::LivenessMutation(
+            LivenessMutation::SetOnline(remote_pk.clone()),
+        ));
+
+        // Nothing is queued, so we are not bothered by waiting for the token:
+        let mut m_state = MutableFunderState::new(state);
+        for _ in 0..2 * token_stall_ticks {
+            assert!(!tick_stalled(
+                &m_state,
+                &mut m_ephemeral,
+                &remote_pk,
+                token_stall_ticks
+            ));
+        }
+
+        // A request is queued, but the token does not arrive:
+        let request_send_funds = RequestSendFunds {
+            request_id: Uid::from(&[0; UID_LEN]),
+            route: FriendsRoute {
+                public_keys: vec![local_pk.clone(), remote_pk.clone()],
+            },
+            dest_payment: 10,
+            invoice_id: InvoiceId::from(&[0; INVOICE_ID_LEN]),
+        };
+        m_state.mutate(FunderMutation::FriendMutation((
+            remote_pk.clone(),
+            FriendMutation::PushBackPendingUserRequest(request_send_funds),
+        )));
+
+        // The token is requested again every token_stall_ticks ticks:
+        for _ in 0..2 {
+            for _ in 0..token_stall_ticks {
+                assert!(!tick_stalled(
+                    &m_state,
+                    &mut m_ephemeral,
+                    &remote_pk,
+                    token_stall_ticks
+                ));
+            }
+            assert!(tick_stalled(
+                &m_state,
+                &mut m_ephemeral,
+                &remote_pk,
+                token_stall_ticks
+            ));
+        }
+
+        // The token has moved (We received the token and sent a new move token). We start
+        // counting again:
+        let move_token = MoveToken {
+            operations: Vec::new(),
+            opt_local_relays: None,
+            old_token: Signature::from(&[1; SIGNATURE_LEN]),
+            local_public_key: local_pk.clone(),
+            remote_public_key: remote_pk.clone(),
+            inconsistency_counter: 0,
+            move_token_counter: 2,
+            balance: 0,
+            local_pending_debt: 0,
+            remote_pending_debt: 0,
+            rand_nonce: RandValue::from(&[2; RAND_VALUE_LEN]),
+            new_token: Signature::from(&[3; SIGNATURE_LEN]),
+        };
+        let tc_mutation = TcMutation::SetDirection(SetDirection::Outgoing(move_token));
+        m_state.mutate(FunderMutation::FriendMutation((
+            remote_pk.clone(),
+            FriendMutation::TcMutation(tc_mutation),
+        )));
+        for _ in 0..token_stall_ticks {
+            assert!(!tick_stalled(
+                &m_state,
+                &mut m_ephemeral,
+                &remote_pk,
+                token_stall_ticks
+            ));
+        }
+        assert!(tick_stalled(
+            &m_state,
+            &mut m_ephemeral,
+            &remote_pk,
+            token_stall_ticks
+        ));
+
+        // Stall detection is disabled:
+        for _ in 0..2 * token_stall_ticks {
+            assert!(!tick_stalled(&m_state, &mut m_ephemeral, &remote_pk, 0));
+        }
+
+        // The friend goes offline. We don't wait for the token anymore:
+        m_ephemeral.mutate(EphemeralMutation::LivenessMutation(
+            LivenessMutation::SetOffline(remote_pk.clone()),
+        ));
+        for _ in 0..2 * token_stall_ticks {
+            assert!(!tick_stalled(
+                &m_state,
+                &mut m_ephemeral,
+                &remote_pk,
+                token_stall_ticks
+            ));
+        }
+        assert!(m_ephemeral.ephemeral().token_wait_ticks.is_empty());
+    }
+
+    #[test]
+    fn test_handle_request_timeout_tick() {
+        let local_pk = PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]);
+        let remote_pk = PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]);
+        let next_pk = PublicKey::from(&[0xcc; PUBLIC_KEY_LEN]);
+        let request_timeout_ticks = 3;
+
+        let mut state =
+            FunderState::<u32>::new(local_pk.clone(), vec![dummy_named_relay_address(0)]);
+        for (i, friend_public_key) in [&remote_pk, &next_pk].iter().enumerate() {
+            let add_friend = AddFriend {
+                friend_public_key: (*friend_public_key).clone(),
+                relays: vec![dummy_relay_address(i as u8 + 1)],
+                name: format!("friend{}", i),
+                balance: 0i128,
+            };
+            state.mutate(&FunderMutation::AddFriend(add_friend));
+            let token_channel =
+                TokenChannel::new_for_test(&local_pk, friend_public_key, 0i128, true);
+            state.mutate(&FunderMutation::FriendMutation((
+                (*friend_public_key).clone(),
+                FriendMutation::SetConsistent(token_channel),
+            )));
+        }
+
+        let request_send_funds = |request_id_byte| RequestSendFunds {
+            request_id: Uid::from(&[request_id_byte; UID_LEN]),
+            route: FriendsRoute {
+                public_keys: vec![remote_pk.clone(), local_pk.clone(), next_pk.clone()],
+            },
+            dest_payment: 10,
+            invoice_id: InvoiceId::from(&[0; INVOICE_ID_LEN]),
+        };
+
+        // Both requests were received from the remote friend. Request 0 was already forwarded
+        // to the next friend, and request 1 is waiting to be forwarded. Neither of them will
+        // ever get a response:
+        let mut mutations = Vec::new();
+        for request_id_byte in 0..2 {
+            let pending_request = create_pending_request(&request_send_funds(request_id_byte));
+            let mc_mutation = McMutation::InsertRemotePendingRequest(pending_request);
+            mutations.push((
+                remote_pk.clone(),
+                FriendMutation::TcMutation(TcMutation::McMutation(mc_mutation)),
+            ));
+        }
+        let mc_mutation =
+            McMutation::InsertLocalPendingRequest(create_pending_request(&request_send_funds(0)));
+        mutations.push((
+            next_pk.clone(),
+            FriendMutation::TcMutation(TcMutation::McMutation(mc_mutation)),
+        ));
+        mutations.push((
+            next_pk.clone(),
+            FriendMutation::PushBackPendingRequest(request_send_funds(1)),
+        ));
+        for mutation in mutations {
+            state.mutate(&FunderMutation::FriendMutation(mutation));
+        }
+
+        let mut m_state = MutableFunderState::new(state);
+        let mut m_ephemeral = MutableEphemeral::new(Ephemeral::new());
+
+        // The requests are kept until the timeout:
+        for _ in 0..request_timeout_ticks {
+            m_ephemeral.mutate(EphemeralMutation::TimerTick);
+            let mut send_commands = SendCommands::new();
+            handle_request_timeout_tick(
+                &mut m_state,
+                &mut m_ephemeral,
+                &mut send_commands,
+                request_timeout_ticks,
+            );
+            assert!(send_commands.send_commands.is_empty());
+        }
+        assert_eq!(m_ephemeral.ephemeral().pending_request_ticks.len(), 2);
+
+        // The timeout has passed. The queued request is failed back to the remote friend, and the
+        // next friend is asked to cancel the forwarded request:
+        m_ephemeral.mutate(EphemeralMutation::TimerTick);
+        let mut send_commands = SendCommands::new();
+        handle_request_timeout_tick(
+            &mut m_state,
+            &mut m_ephemeral,
+            &mut send_commands,
+            request_timeout_ticks,
+        );
+        assert!(send_commands.send_commands.contains_key(&remote_pk));
+        assert!(send_commands.send_commands.contains_key(&next_pk));
+
+        let friend = m_state.state().friends.get(&remote_pk).unwrap();
+        assert_eq!(friend.pending_responses.len(), 1);
+        match friend.pending_responses.front().unwrap() {
+            ResponseOp::UnsignedFailure((pending_request, failure_reason)) => {
+                assert_eq!(failure_reason, &FailureReason::TimedOut);
+                assert_eq!(
+                    pending_request,
+                    &create_pending_request(&request_send_funds(1))
+                );
+            }
+            _ => unreachable!(),
+        };
+
+        let next_friend = m_state.state().friends.get(&next_pk).unwrap();
+        assert!(next_friend.pending_requests.is_empty());
+        assert_eq!(
+            next_friend
+                .pending_cancels
+                .iter()
+                .cloned()
+                .collect::<Vec<_>>(),
+            vec![Uid::from(&[0; UID_LEN])]
+        );
+
+        // The cancellation is sent to the next friend:
+        m_state.mutate(FunderMutation::FriendMutation((
+            next_pk.clone(),
+            FriendMutation::PopFrontPendingCancel,
+        )));
+
+        // We do not fail the requests again, and ask the next friend to cancel again only after
+        // another timeout:
+        for _ in 0..request_timeout_ticks {
+            m_ephemeral.mutate(EphemeralMutation::TimerTick);
+            let mut send_commands = SendCommands::new();
+            handle_request_timeout_tick(
+                &mut m_state,
+                &mut m_ephemeral,
+                &mut send_commands,
+                request_timeout_ticks,
+            );
+            assert!(send_commands.send_commands.is_empty());
+        }
+        m_ephemeral.mutate(EphemeralMutation::TimerTick);
+        let mut send_commands = SendCommands::new();
+        handle_request_timeout_tick(
+            &mut m_state,
+            &mut m_ephemeral,
+            &mut send_commands,
+            request_timeout_ticks,
+        );
+        assert!(!send_commands.send_commands.contains_key(&remote_pk));
+        assert!(send_commands.send_commands.contains_key(&next_pk));
+        let friend = m_state.state().friends.get(&remote_pk).unwrap();
+        assert_eq!(friend.pending_responses.len(), 1);
+        let next_friend = m_state.state().friends.get(&next_pk).unwrap();
+        assert_eq!(next_friend.pending_cancels.len(), 1);
+
+        // Once the requests are not pending anymore, we stop tracking them:
+        for request_id_byte in 0..2 {
+            let mc_mutation =
+                McMutation::RemoveRemotePendingRequest(Uid::from(&[request_id_byte; UID_LEN]));
+            m_state.mutate(FunderMutation::FriendMutation((
+                remote_pk.clone(),
+                FriendMutation::TcMutation(TcMutation::McMutation(mc_mutation)),
+            )));
+        }
+        m_ephemeral.mutate(EphemeralMutation::TimerTick);
+        let mut send_commands = SendCommands::new();
+        handle_request_timeout_tick(
+            &mut m_state,
+            &mut m_ephemeral,
+            &mut send_commands,
+            request_timeout_ticks,
+        );
+        assert!(m_ephemeral.ephemeral().pending_request_ticks.is_empty());
+    }
+
+    #[test]
+    fn test_handle_timer_tick() {
+        let local_pk = PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]);
+        let remote_pk = PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]);
+        let offline_grace_ticks = 3;
+        let flush_idle_ticks = 0;
+        let token_stall_ticks = 0;
+        let request_timeout_ticks = 0;
+
+        let mut state = dummy_state_with_friend(&local_pk, &remote_pk, None);
+
+        let request_id = Uid::from(&[0; UID_LEN]);
+        let request_send_funds = RequestSendFunds {
+            request_id: request_id.clone(),
+            route: FriendsRoute {
+                public_keys: vec![local_pk.clone(), remote_pk.clone()],
+            },
+            dest_payment: 10,
+            invoice_id: InvoiceId::from(&[0; INVOICE_ID_LEN]),
+        };
+        for friend_mutation in vec![
+            FriendMutation::SetStatus(FriendStatus::Enabled),
+            FriendMutation::PushBackPendingUserRequest(request_send_funds),
+        ] {
+            state.mutate(&FunderMutation::FriendMutation((
+                remote_pk.clone(),
+                friend_mutation,
+            )));
+        }
+
+        // The friend was online, and is now suspect:
+        let mut m_ephemeral = MutableEphemeral::new(Ephemeral::new());
+        m_ephemeral.mutate(EphemeralMutation::LivenessMutation(
+            LivenessMutation::SetOnline(remote_pk.clone()),
+        ));
+        m_ephemeral.mutate(EphemeralMutation::LivenessMutation(
+            LivenessMutation::SetSuspect((remote_pk.clone(), 0)),
+        ));
+
+        let mut m_state = MutableFunderState::new(state);
+        let mut send_commands = SendCommands::new();
+        let mut outgoing_control = Vec::new();
+
+        // During the grace period the pending request is kept:
+        for _ in 0..offline_grace_ticks - 1 {
+            handle_timer_tick(
+                &mut m_state,
+                &mut m_ephemeral,
+                &mut send_commands,
+                &mut outgoing_control,
+                offline_grace_ticks,
+                flush_idle_ticks,
+                token_stall_ticks,
+                request_timeout_ticks,
+            );
+            assert!(m_ephemeral.ephemeral().liveness.is_suspect(&remote_pk));
+            assert!(outgoing_control.is_empty());
+        }
+
+        // The grace period is over. The friend is declared offline and the pending request is
+        // canceled:
+        handle_timer_tick(
+            &mut m_state,
+            &mut m_ephemeral,
+            &mut send_commands,
+            &mut outgoing_control,
+            offline_grace_ticks,
+            flush_idle_ticks,
+            token_stall_ticks,
+            request_timeout_ticks,
+        );
+        assert_eq!(m_ephemeral.ephemeral().ticks, offline_grace_ticks as u64);
+        assert!(!m_ephemeral.ephemeral().liveness.is_suspect(&remote_pk));
+        assert!(!m_ephemeral.ephemeral().liveness.is_online(&remote_pk));
+
+        let friend = m_state.state().friends.get(&remote_pk).unwrap();
+        assert!(friend.pending_user_requests.is_empty());
+
+        assert_eq!(outgoing_control.len(), 1);
+        match &outgoing_control[0] {
+            FunderOutgoingControl::ResponseReceived(response_received) => {
+                assert_eq!(response_received.request_id, request_id);
+                match &response_received.result {
+                    ResponseSendFundsResult::Failure(reporting_public_key) => {
+                        assert_eq!(reporting_public_key, &local_pk)
+                    }
+                    _ => unreachable!(),
+                };
+                assert_eq!(
+                    response_received.opt_failure_reason,
+                    Some(FailureReason::Canceled)
+                );
+            }
+            _ => unreachable!(),
+        };
+    }
+}
@@ -7,7 +7,9 @@ use crypto::identity::PublicKey;
 use crypto::uid::Uid;
 
 use proto::app_server::messages::RelayAddress;
-use proto::funder::messages::FunderOutgoingControl;
+use proto::funder::messages::{
+    EvictedReceipt, FunderControl, FunderIncomingControl, FunderMetrics, FunderOutgoingControl,
+};
 use proto::report::messages::{FunderReportMutation, FunderReportMutations};
 
 use identity::IdentityClient;
@@ -18,12 +20,16 @@ use crate::handler::handle_control::handle_control_message;
 use crate::handler::handle_friend::{handle_friend_message, HandleFriendError};
 use crate::handler::handle_init::handle_init;
 use crate::handler::handle_liveness::{handle_liveness_message, HandleLivenessError};
+use crate::handler::handle_timer::handle_timer_tick;
 use crate::handler::sender::{create_friend_messages, SendCommands};
 
 use crate::ephemeral::{Ephemeral, EphemeralMutation};
-use crate::friend::ChannelStatus;
+use crate::friend::{ChannelStatus, FriendMutation, ResponseOp};
 use crate::report::{ephemeral_mutation_to_report_mutations, funder_mutation_to_report_mutations};
-use crate::types::{ChannelerConfig, FunderIncoming, FunderIncomingComm, FunderOutgoingComm};
+use crate::token_channel::{SetDirection, TcMutation};
+use crate::types::{
+    ChannelerConfig, FunderConfig, FunderIncoming, FunderIncomingComm, FunderOutgoingComm,
+};
 
 pub struct MutableFunderState<B: Clone> {
     initial_state: FunderState<B>,
@@ -83,6 +89,8 @@ impl MutableEphemeral {
     }
 }
 
+/// A recoverable error of handling a single incoming message.
+/// The message is dropped, and the funder keeps running.
 #[derive(Debug)]
 pub enum FunderHandlerError {
     // HandleControlError(HandleControlError),
@@ -100,38 +108,6 @@ where
     pub outgoing_control: Vec<FunderOutgoingControl<B>>,
 }
 
-/// Find the originator of a pending local request.
-/// This should be a pending remote request at some other friend.
-/// Returns the public key of a friend. If we are the origin of this request, the function returns None.
-///
-/// TODO: We need to change this search to be O(1) in the future. Possibly by maintaining a map
-/// between request_id and (friend_public_key, friend).
-pub fn find_request_origin<'a, B>(
-    state: &'a FunderState<B>,
-    request_id: &Uid,
-) -> Option<&'a PublicKey>
-where
-    B: Clone + CanonicalSerialize + PartialEq + Eq + Debug,
-{
-    for (friend_public_key, friend) in &state.friends {
-        match &friend.channel_status {
-            ChannelStatus::Inconsistent(_) => continue,
-            ChannelStatus::Consistent(token_channel) => {
-                if token_channel
-                    .get_mutual_credit()
-                    .state()
-                    .pending_requests
-                    .pending_remote_requests
-                    .contains_key(request_id)
-                {
-                    return Some(friend_public_key);
-                }
-            }
-        }
-    }
-    None
-}
-
 pub fn is_friend_ready<B>(
     state: &FunderState<B>,
     ephemeral: &Ephemeral,
@@ -170,8 +146,7 @@ pub fn funder_handle_incoming<B, R>(
     mut m_state: &mut MutableFunderState<B>,
     mut m_ephemeral: &mut MutableEphemeral,
     rng: &R,
-    max_node_relays: usize,
-    max_pending_user_requests: usize,
+    funder_config: &FunderConfig,
     funder_incoming: FunderIncoming<B>,
 ) -> Result<FunderHandleIncomingOutput<B>, FunderHandlerError>
 where
@@ -197,8 +172,9 @@ where
                 &mut send_commands,
                 &mut outgoing_control,
                 &mut outgoing_channeler_config,
-                max_node_relays,
-                max_pending_user_requests,
+                funder_config.max_node_relays,
+                funder_config.max_pending_user_requests,
+                funder_config.pending_queue_threshold,
                 funder_incoming_control.funder_control,
             ) {
                 error!("handle_control_error(): {:?}", e);
@@ -213,6 +189,7 @@ where
                     &mut m_ephemeral,
                     &mut send_commands,
                     &mut outgoing_control,
+                    funder_config.offline_grace_ticks,
                     liveness_message,
                 )
                 .map_err(FunderHandlerError::HandleLivenessError)?,
@@ -225,6 +202,8 @@ where
                         &mut outgoing_control,
                         &mut outgoing_channeler_config,
                         rng,
+                        funder_config.inconsistency_error_interval_ticks,
+                        funder_config.max_pending_requests,
                         &origin_public_key,
                         friend_message,
                     )
@@ -233,6 +212,20 @@ where
             };
             None
         }
+
+        FunderIncoming::TimerTick => {
+            handle_timer_tick(
+                &mut m_state,
+                &mut m_ephemeral,
+                &mut send_commands,
+                &mut outgoing_control,
+                funder_config.offline_grace_ticks,
+                funder_config.flush_idle_ticks,
+                funder_config.token_stall_ticks,
+                funder_config.request_timeout_ticks,
+            );
+            None
+        }
     };
 
     Ok((
@@ -243,10 +236,148 @@ where
     ))
 }
 
+/// Remember the tick in which a user request was accepted, so that we can report the elapsed
+/// time when a response arrives.
+/// A request is accepted if it was queued to be sent to a friend. Rejected requests (For example,
+/// a request with the request_id of a request already in flight) are not tracked.
+fn track_user_request_start<B>(
+    m_state: &MutableFunderState<B>,
+    m_ephemeral: &mut MutableEphemeral,
+    request_id: Uid,
+) where
+    B: Clone + CanonicalSerialize + PartialEq + Eq + Debug,
+{
+    if m_ephemeral
+        .ephemeral()
+        .request_start_ticks
+        .contains_key(&request_id)
+    {
+        // This request is already being tracked:
+        return;
+    }
+    let is_accepted = m_state.state().friends.values().any(|friend| {
+        friend
+            .pending_user_requests
+            .iter()
+            .any(|request| request.request_id == request_id)
+    });
+    if !is_accepted {
+        return;
+    }
+    let start_tick = m_ephemeral.ephemeral().ticks;
+    m_ephemeral.mutate(EphemeralMutation::SetRequestStartTick((
+        request_id, start_tick,
+    )));
+}
+
+/// Fill in the elapsed time for every response sent to the user, and stop tracking the
+/// corresponding requests.
+fn set_responses_elapsed_ticks<B>(
+    m_state: &MutableFunderState<B>,
+    m_ephemeral: &mut MutableEphemeral,
+    outgoing_control: &mut Vec<FunderOutgoingControl<B>>,
+) where
+    B: Clone + CanonicalSerialize + PartialEq + Eq + Debug,
+{
+    for funder_outgoing_control in outgoing_control {
+        let response_received = match funder_outgoing_control {
+            FunderOutgoingControl::ResponseReceived(response_received) => response_received,
+            _ => continue,
+        };
+        if m_state
+            .state()
+            .is_request_in_flight(&response_received.request_id)
+        {
+            // This is a response to a rejected user request with the same request_id.
+            // The original request is still in flight:
+            continue;
+        }
+        let start_tick = match m_ephemeral
+            .ephemeral()
+            .request_start_ticks
+            .get(&response_received.request_id)
+        {
+            Some(start_tick) => *start_tick,
+            None => continue,
+        };
+        response_received.opt_elapsed_ticks = m_ephemeral.ephemeral().ticks.checked_sub(start_tick);
+        m_ephemeral.mutate(EphemeralMutation::RemoveRequestStartTick(
+            response_received.request_id,
+        ));
+    }
+}
+
+/// Evict the oldest ready receipts, until there are at most `max_ready_receipts` ready receipts.
+/// Every evicted receipt is sent to the user, to allow keeping it elsewhere.
+fn evict_ready_receipts<B>(
+    m_state: &mut MutableFunderState<B>,
+    outgoing_control: &mut Vec<FunderOutgoingControl<B>>,
+    max_ready_receipts: usize,
+) where
+    B: Clone + CanonicalSerialize + PartialEq + Eq + Debug,
+{
+    while m_state.state().ready_receipts.len() > max_ready_receipts {
+        let request_id = match m_state.state().ready_receipts_order.front() {
+            Some(request_id) => *request_id,
+            None => break,
+        };
+        let receipt = match m_state.state().ready_receipts.get(&request_id) {
+            Some(receipt) => receipt.clone(),
+            None => break,
+        };
+        m_state.mutate(FunderMutation::RemoveReceipt(request_id));
+        outgoing_control.push(FunderOutgoingControl::ReceiptEvicted(EvictedReceipt {
+            request_id,
+            receipt,
+        }));
+    }
+}
+
+/// Count the events that occurred while handling a single message.
+fn collect_metrics<B>(funder_mutations: &[FunderMutation<B>]) -> FunderMetrics
+where
+    B: Clone,
+{
+    let mut metrics = FunderMetrics::default();
+    for funder_mutation in funder_mutations {
+        let friend_mutation = match funder_mutation {
+            FunderMutation::FriendMutation((_, friend_mutation)) => friend_mutation,
+            // A receipt is added only when a response to a request we originated is delivered to
+            // the user. Returning an existing receipt again does not add it:
+            FunderMutation::AddReceipt(_) => {
+                metrics.responses_delivered += 1;
+                continue;
+            }
+            _ => continue,
+        };
+        match friend_mutation {
+            FriendMutation::PushBackPendingRequest(_) => metrics.requests_forwarded += 1,
+            FriendMutation::PushBackPendingResponse(response_op) => match response_op {
+                ResponseOp::Response(_) | ResponseOp::UnsignedResponse(_) => {
+                    metrics.responses_delivered += 1
+                }
+                ResponseOp::UnsignedFailure(_) => metrics.failures_generated += 1,
+                ResponseOp::Failure(_) => {}
+            },
+            FriendMutation::SetInconsistent(_) => metrics.inconsistencies_entered += 1,
+            FriendMutation::TcMutation(TcMutation::SetDirection(set_direction)) => {
+                match set_direction {
+                    SetDirection::Incoming(_) => metrics.move_tokens_received += 1,
+                    SetDirection::Outgoing(_) => metrics.move_tokens_sent += 1,
+                }
+            }
+            _ => {}
+        }
+    }
+
+    metrics
+}
+
 fn create_report_mutations<B>(
     initial_state: FunderState<B>,
     funder_mutations: &[FunderMutation<B>],
     ephemeral_mutations: &[EphemeralMutation],
+    pending_queue_threshold: usize,
 ) -> Vec<FunderReportMutation<B>>
 where
     B: Clone + CanonicalSerialize + PartialEq + Eq + Debug,
@@ -257,6 +388,7 @@ where
         report_mutations.extend(funder_mutation_to_report_mutations(
             funder_mutation,
             &running_state,
+            pending_queue_threshold,
         ));
         running_state.mutate(funder_mutation);
     }
@@ -279,9 +411,7 @@ pub async fn funder_handle_message<'a, B, R>(
     rng: &'a R,
     funder_state: FunderState<B>,
     funder_ephemeral: Ephemeral,
-    max_node_relays: usize,
-    max_operations_in_batch: usize,
-    max_pending_user_requests: usize,
+    funder_config: &'a FunderConfig,
     funder_incoming: FunderIncoming<B>,
 ) -> Result<FunderHandlerOutput<B>, FunderHandlerError>
 where
@@ -292,16 +422,33 @@ where
     let mut m_ephemeral = MutableEphemeral::new(funder_ephemeral);
     let mut outgoing_comms = Vec::new();
 
-    let (send_commands, handle_outgoing_control, outgoing_channeler_config, opt_app_request_id) =
+    let opt_user_request_id = match &funder_incoming {
+        FunderIncoming::Control(FunderIncomingControl {
+            funder_control: FunderControl::RequestSendFunds(user_request_send_funds),
+            ..
+        }) => Some(user_request_send_funds.request_id),
+        _ => None,
+    };
+
+    let (send_commands, mut handle_outgoing_control, outgoing_channeler_config, opt_app_request_id) =
         funder_handle_incoming(
             &mut m_state,
             &mut m_ephemeral,
             rng,
-            max_node_relays,
-            max_pending_user_requests,
+            funder_config,
             funder_incoming,
         )?;
 
+    if let Some(request_id) = opt_user_request_id {
+        track_user_request_start(&m_state, &mut m_ephemeral, request_id);
+    }
+
+    evict_ready_receipts(
+        &mut m_state,
+        &mut handle_outgoing_control,
+        funder_config.max_ready_receipts,
+    );
+
     for channeler_config in outgoing_channeler_config {
         outgoing_comms.push(FunderOutgoingComm::ChannelerConfig(channeler_config));
     }
@@ -309,12 +456,12 @@ where
     // Send all possible messages according to SendCommands
     // TODO: Maybe we should output outgoing_comms instead of friend_messages and
     // outgoing_channeler_config. When we merge the two, we might be out of order!
-    let (sender_outgoing_control, friend_messages, outgoing_channeler_config) =
+    let (mut sender_outgoing_control, friend_messages, outgoing_channeler_config) =
         await!(create_friend_messages(
             &mut m_state,
             m_ephemeral.ephemeral(),
             &send_commands,
-            max_operations_in_batch,
+            funder_config.max_operations_in_batch,
             identity_client,
             rng
         ));
@@ -327,13 +474,22 @@ where
         outgoing_comms.push(FunderOutgoingComm::FriendMessage(friend_message));
     }
 
+    set_responses_elapsed_ticks(&m_state, &mut m_ephemeral, &mut handle_outgoing_control);
+    set_responses_elapsed_ticks(&m_state, &mut m_ephemeral, &mut sender_outgoing_control);
+
     // Add reports:
     let (initial_state, funder_mutations, _state) = m_state.done();
+
+    let metrics = collect_metrics(&funder_mutations[..]);
+    if metrics != FunderMetrics::default() {
+        m_ephemeral.mutate(EphemeralMutation::AddMetrics(metrics));
+    }
     let (ephemeral_mutations, _ephemeral) = m_ephemeral.done();
     let report_mutations = create_report_mutations(
         initial_state,
         &funder_mutations[..],
         &ephemeral_mutations[..],
+        funder_config.pending_queue_threshold,
     );
 
     let funder_report_mutations = FunderReportMutations {
@@ -361,3 +517,160 @@ where
         outgoing_control,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crypto::hash::{HashResult, HASH_RESULT_LEN};
+    use crypto::identity::{Signature, PUBLIC_KEY_LEN, SIGNATURE_LEN};
+    use crypto::invoice_id::{InvoiceId, INVOICE_ID_LEN};
+    use crypto::uid::UID_LEN;
+    use proto::funder::messages::{
+        AddFriend, FriendsRoute, Receipt, ResponseReceived, ResponseSendFundsResult,
+        UserRequestSendFunds,
+    };
+
+    use crate::tests::utils::{dummy_named_relay_address, dummy_relay_address};
+
+    #[test]
+    fn test_evict_ready_receipts() {
+        let local_pk = PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]);
+        let state = FunderState::<u32>::new(local_pk, vec![dummy_named_relay_address(0)]);
+        let mut m_state = MutableFunderState::new(state);
+        let mut outgoing_control = Vec::new();
+        let max_ready_receipts = 2;
+
+        let receipt = |i: u8| Receipt {
+            response_hash: HashResult::from(&[i; HASH_RESULT_LEN]),
+            invoice_id: InvoiceId::from(&[i; INVOICE_ID_LEN]),
+            dest_payment: 10,
+            signature: Signature::from(&[i; SIGNATURE_LEN]),
+        };
+
+        for i in 0..max_ready_receipts as u8 {
+            m_state.mutate(FunderMutation::AddReceipt((
+                Uid::from(&[i; UID_LEN]),
+                receipt(i),
+            )));
+            evict_ready_receipts(&mut m_state, &mut outgoing_control, max_ready_receipts);
+        }
+        assert!(outgoing_control.is_empty());
+        assert_eq!(m_state.state().ready_receipts.len(), max_ready_receipts);
+
+        // Adding a receipt beyond the limit evicts the oldest receipt:
+        m_state.mutate(FunderMutation::AddReceipt((
+            Uid::from(&[2; UID_LEN]),
+            receipt(2),
+        )));
+        evict_ready_receipts(&mut m_state, &mut outgoing_control, max_ready_receipts);
+
+        assert_eq!(outgoing_control.len(), 1);
+        match outgoing_control.pop().unwrap() {
+            FunderOutgoingControl::ReceiptEvicted(evicted_receipt) => {
+                assert_eq!(evicted_receipt.request_id, Uid::from(&[0; UID_LEN]));
+                assert_eq!(evicted_receipt.receipt, receipt(0));
+            }
+            _ => unreachable!(),
+        };
+
+        let ready_receipts = &m_state.state().ready_receipts;
+        assert_eq!(ready_receipts.len(), max_ready_receipts);
+        assert!(!ready_receipts.contains_key(&Uid::from(&[0; UID_LEN])));
+        assert!(ready_receipts.contains_key(&Uid::from(&[1; UID_LEN])));
+        assert!(ready_receipts.contains_key(&Uid::from(&[2; UID_LEN])));
+        assert_eq!(
+            m_state
+                .state()
+                .ready_receipts_order
+                .iter()
+                .cloned()
+                .collect::<Vec<_>>(),
+            vec![Uid::from(&[1; UID_LEN]), Uid::from(&[2; UID_LEN])]
+        );
+    }
+
+    #[test]
+    fn test_track_user_request_start() {
+        let local_pk = PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]);
+        let remote_pk = PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]);
+        let state = FunderState::<u32>::new(local_pk.clone(), vec![dummy_named_relay_address(0)]);
+        let mut m_state = MutableFunderState::new(state);
+        m_state.mutate(FunderMutation::AddFriend(AddFriend {
+            friend_public_key: remote_pk.clone(),
+            relays: vec![dummy_relay_address(1)],
+            name: "remote_pk".into(),
+            balance: 0,
+        }));
+        let mut m_ephemeral = MutableEphemeral::new(Ephemeral::new());
+        m_ephemeral.mutate(EphemeralMutation::TimerTick);
+
+        let request_id = Uid::from(&[1; UID_LEN]);
+        let user_request_send_funds = UserRequestSendFunds {
+            request_id,
+            route: FriendsRoute {
+                public_keys: vec![local_pk.clone(), remote_pk.clone()],
+            },
+            invoice_id: InvoiceId::from(&[0; INVOICE_ID_LEN]),
+            dest_payment: 10,
+        };
+        let failure_response = |request_id| {
+            FunderOutgoingControl::ResponseReceived(ResponseReceived {
+                request_id,
+                result: ResponseSendFundsResult::Failure(local_pk.clone()),
+                opt_elapsed_ticks: None,
+                opt_failure_reason: None,
+            })
+        };
+
+        // A rejected request is not tracked:
+        track_user_request_start(&m_state, &mut m_ephemeral, request_id);
+        assert!(m_ephemeral.ephemeral().request_start_ticks.is_empty());
+
+        // The request is accepted at tick 1:
+        m_state.mutate(FunderMutation::FriendMutation((
+            remote_pk.clone(),
+            FriendMutation::PushBackPendingUserRequest(user_request_send_funds.into_request()),
+        )));
+        track_user_request_start(&m_state, &mut m_ephemeral, request_id);
+        assert_eq!(
+            m_ephemeral.ephemeral().request_start_ticks.get(&request_id),
+            Some(&1)
+        );
+
+        for _ in 0..3 {
+            m_ephemeral.mutate(EphemeralMutation::TimerTick);
+        }
+
+        // A duplicate request is rejected. Its failure response does not consume the start tick
+        // of the original request:
+        track_user_request_start(&m_state, &mut m_ephemeral, request_id);
+        let mut outgoing_control = vec![failure_response(request_id)];
+        set_responses_elapsed_ticks(&m_state, &mut m_ephemeral, &mut outgoing_control);
+        match &outgoing_control[0] {
+            FunderOutgoingControl::ResponseReceived(response_received) => {
+                assert_eq!(response_received.opt_elapsed_ticks, None)
+            }
+            _ => unreachable!(),
+        };
+        assert_eq!(
+            m_ephemeral.ephemeral().request_start_ticks.get(&request_id),
+            Some(&1)
+        );
+
+        // The original request fails:
+        m_state.mutate(FunderMutation::FriendMutation((
+            remote_pk.clone(),
+            FriendMutation::PopFrontPendingUserRequest,
+        )));
+        let mut outgoing_control = vec![failure_response(request_id)];
+        set_responses_elapsed_ticks(&m_state, &mut m_ephemeral, &mut outgoing_control);
+        match &outgoing_control[0] {
+            FunderOutgoingControl::ResponseReceived(response_received) => {
+                assert_eq!(response_received.opt_elapsed_ticks, Some(3))
+            }
+            _ => unreachable!(),
+        };
+        assert!(m_ephemeral.ephemeral().request_start_ticks.is_empty());
+    }
+}
@@ -8,8 +8,8 @@ use crypto::identity::PublicKey;
 
 use proto::app_server::messages::RelayAddress;
 use proto::funder::messages::{
-    ChannelerUpdateFriend, FriendMessage, FriendTcOp, FunderOutgoingControl, MoveTokenRequest,
-    RequestsStatus, ResponseReceived, ResponseSendFundsResult,
+    ChannelerUpdateFriend, FriendMessage, FriendTcOp, FunderOutgoingControl, RequestsStatus,
+    ResponseReceived, ResponseSendFundsResult,
 };
 
 use identity::IdentityClient;
@@ -26,7 +26,7 @@ use crate::friend::{
 use crate::token_channel::{SetDirection, TcDirection, TcMutation, TokenChannel};
 
 use crate::ephemeral::Ephemeral;
-use crate::handler::handler::{find_request_origin, MutableFunderState};
+use crate::handler::handler::MutableFunderState;
 use crate::state::{FunderMutation, FunderState};
 
 #[derive(Debug, Clone)]
@@ -202,15 +202,9 @@ fn transmit_outgoing<B>(
         ChannelStatus::Inconsistent(_) => unreachable!(),
     };
 
-    let move_token = match &token_channel.get_direction() {
-        TcDirection::Outgoing(tc_outgoing) => tc_outgoing.create_outgoing_move_token(),
-        TcDirection::Incoming(_) => unreachable!(),
-    };
-
-    let move_token_request = MoveTokenRequest {
-        friend_move_token: move_token,
-        token_wanted,
-    };
+    let move_token_request = token_channel
+        .get_outgoing_move_token_request(token_wanted)
+        .unwrap();
 
     outgoing_messages.push((
         friend_public_key.clone(),
@@ -383,7 +377,10 @@ async fn send_friend_iter1<'a, B, R>(
 /// Do we need to send anything to the remote side?
 /// Note that this is only an estimation. It is possible that when the token from remote side
 /// arrives, the state will be different.
-fn estimate_should_send<'a, B>(state: &'a FunderState<B>, friend_public_key: &'a PublicKey) -> bool
+pub fn estimate_should_send<'a, B>(
+    state: &'a FunderState<B>,
+    friend_public_key: &'a PublicKey,
+) -> bool
 where
     B: Clone + PartialEq + Eq + CanonicalSerialize + Debug,
 {
@@ -423,6 +420,10 @@ where
         return true;
     }
 
+    if !friend.pending_cancels.is_empty() {
+        return true;
+    }
+
     if !friend.pending_requests.is_empty() {
         return true;
     }
@@ -466,12 +467,17 @@ where
     // We are here if an error occurred.
     // We cancel the request:
 
-    match find_request_origin(m_state.state(), &request_send_funds.request_id).cloned() {
+    match m_state
+        .state()
+        .find_request_origin(&request_send_funds.request_id)
+        .cloned()
+    {
         Some(origin_public_key) => {
             // The friend with public key `origin_public_key` is the origin of this request.
             // We send him back a failure message:
             let pending_request = create_pending_request(request_send_funds);
-            let u_failure_op = ResponseOp::UnsignedFailure(pending_request);
+            let u_failure_op =
+                ResponseOp::UnsignedFailure((pending_request, FailureReason::InsufficientTrust));
             let friend_mutation = FriendMutation::PushBackPendingResponse(u_failure_op);
             let funder_mutation =
                 FunderMutation::FriendMutation((origin_public_key.clone(), friend_mutation));
@@ -484,6 +490,8 @@ where
             let response_received = ResponseReceived {
                 request_id: request_send_funds.request_id,
                 result: ResponseSendFundsResult::Failure(m_state.state().local_public_key.clone()),
+                opt_elapsed_ticks: None,
+                opt_failure_reason: Some(FailureReason::InsufficientTrust),
             };
             outgoing_control.push(FunderOutgoingControl::ResponseReceived(response_received));
         }
@@ -520,6 +528,7 @@ where
     let response_received = ResponseReceived {
         request_id: request_send_funds.request_id,
         result: ResponseSendFundsResult::Failure(m_state.state().local_public_key.clone()),
+        opt_elapsed_ticks: None,
     };
     outgoing_control.push(FunderOutgoingControl::ResponseReceived(response_received));
 
@@ -548,11 +557,12 @@ where
             )))
         }
         ResponseOp::Failure(failure) => FriendTcOp::FailureSendFunds(failure),
-        ResponseOp::UnsignedFailure(pending_request) => {
+        ResponseOp::UnsignedFailure((pending_request, failure_reason)) => {
             let rand_nonce = RandValue::new(rng);
             FriendTcOp::FailureSendFunds(await!(create_failure_send_funds(
                 &pending_request,
                 &(m_state.state().local_public_key),
+                failure_reason,
                 rand_nonce,
                 &mut identity_client
             )))
@@ -581,8 +591,9 @@ where
     - Check if last sent local address is up to date.
     - Collect as many operations as possible (Not more than max ops per batch)
         1. Responses (response, failure)
-        2. Pending requests
-        3. User pending requests
+        2. Cancellations of requests
+        3. Pending requests
+        4. User pending requests
     - When adding requests, check the following:
         - Valid by freeze guard.
         - Valid from credits point of view.
@@ -713,6 +724,41 @@ where
 
     let friend = m_state.state().friends.get(friend_public_key).unwrap();
 
+    // Send pending cancellations of requests:
+    let mut pending_cancels = friend.pending_cancels.clone();
+    while let Some(request_id) = pending_cancels.pop_front() {
+        let friend = m_state.state().friends.get(friend_public_key).unwrap();
+        let token_channel = match &friend.channel_status {
+            ChannelStatus::Consistent(token_channel) => token_channel,
+            ChannelStatus::Inconsistent(_) => unreachable!(),
+        };
+        // The request might have been resolved since the cancellation was queued. In that case
+        // there is nothing to cancel:
+        let is_pending = token_channel
+            .get_mutual_credit()
+            .state()
+            .pending_requests
+            .pending_local_requests
+            .contains_key(&request_id);
+
+        if is_pending {
+            let pending_op = FriendTcOp::CancelRequest(request_id);
+            await!(queue_operation_or_failure(
+                m_state,
+                pending_move_token,
+                failure_public_keys,
+                outgoing_control,
+                &pending_op
+            ))?;
+        }
+        let friend_mutation = FriendMutation::PopFrontPendingCancel;
+        let funder_mutation =
+            FunderMutation::FriendMutation((friend_public_key.clone(), friend_mutation));
+        m_state.mutate(funder_mutation);
+    }
+
+    let friend = m_state.state().friends.get(friend_public_key).unwrap();
+
     // Send pending requests:
     // TODO: Possibly replace this clone with something more efficient later:
     let mut pending_requests = friend.pending_requests.clone();
@@ -851,16 +897,9 @@ async fn send_move_token<'a, B, R>(
         ChannelStatus::Inconsistent(_) => unreachable!(),
     };
 
-    let tc_outgoing = match token_channel.get_direction() {
-        TcDirection::Outgoing(tc_outgoing) => tc_outgoing,
-        TcDirection::Incoming(_) => unreachable!(),
-    };
-
-    let friend_move_token = tc_outgoing.create_outgoing_move_token();
-    let move_token_request = MoveTokenRequest {
-        friend_move_token,
-        token_wanted,
-    };
+    let move_token_request = token_channel
+        .get_outgoing_move_token_request(token_wanted)
+        .unwrap();
 
     outgoing_messages.push((
         friend_public_key.clone(),
@@ -16,8 +16,8 @@ use crypto::uid::{Uid, UID_LEN};
 
 use proto::funder::messages::{
     AddFriend, FriendMessage, FriendStatus, FriendsRoute, FunderControl, FunderIncomingControl,
-    RequestsStatus, SetFriendRemoteMaxDebt, SetFriendStatus, SetRequestsStatus,
-    UserRequestSendFunds,
+    FunderOutgoingControl, RequestsStatus, SetFriendRemoteMaxDebt, SetFriendStatus,
+    SetRequestsStatus, UserRequestSendFunds,
 };
 
 use crate::ephemeral::Ephemeral;
@@ -399,6 +399,9 @@ async fn task_handler_pair_basic<'a>(
         _ => unreachable!(),
     };
     assert_eq!(remote_max_debt, 100);
+    // Node1 has not yet received a move token from Node2, so the change is not acknowledged:
+    assert_eq!(friend2.wanted_remote_max_debt, 100);
+    assert_eq!(friend2.acked_remote_max_debt, 0);
 
     let friend1 = state2.friends.get(&pk1).unwrap();
     let local_max_debt = match &friend1.channel_status {
@@ -519,6 +522,10 @@ async fn task_handler_pair_basic<'a>(
     )))
     .unwrap();
 
+    // Receiving the token from Node2 acknowledges the remote max debt change:
+    let friend2 = state1.friends.get(&pk2).unwrap();
+    assert_eq!(friend2.acked_remote_max_debt, 100);
+
     // Node1 declares that his requests are open:
     let friend_message =
         if let FunderOutgoingComm::FriendMessage((_pk, friend_message)) = &outgoing_comms[0] {
@@ -589,6 +596,20 @@ async fn task_handler_pair_basic<'a>(
             unreachable!();
         };
 
+    // Some time passes at Node2 before the request reaches Node1:
+    for _ in 0..3 {
+        let (outgoing_comms, outgoing_control) = await!(Box::pin(apply_funder_incoming(
+            FunderIncoming::TimerTick,
+            &mut state2,
+            &mut ephemeral2,
+            &mut rng,
+            identity_client2
+        )))
+        .unwrap();
+        assert!(outgoing_comms.is_empty());
+        assert!(outgoing_control.is_empty());
+    }
+
     // Node1 receives RequestSendFunds from Node2:
     let funder_incoming =
         FunderIncoming::Comm(FunderIncomingComm::Friend((pk2.clone(), friend_message)));
@@ -622,7 +643,7 @@ async fn task_handler_pair_basic<'a>(
     // Node2 receives ResponseSendFunds from Node1:
     let funder_incoming =
         FunderIncoming::Comm(FunderIncomingComm::Friend((pk1.clone(), friend_message)));
-    let (_outgoing_comms, _outgoing_control) = await!(Box::pin(apply_funder_incoming(
+    let (_outgoing_comms, outgoing_control) = await!(Box::pin(apply_funder_incoming(
         funder_incoming,
         &mut state2,
         &mut ephemeral2,
@@ -631,6 +652,18 @@ async fn task_handler_pair_basic<'a>(
     )))
     .unwrap();
 
+    // Node2 reports the response to the user, together with the elapsed time:
+    let response_received = outgoing_control
+        .iter()
+        .find_map(|funder_outgoing_control| match funder_outgoing_control {
+            FunderOutgoingControl::ResponseReceived(response_received) => Some(response_received),
+            _ => None,
+        })
+        .unwrap();
+    assert_eq!(response_received.request_id, Uid::from(&[3; UID_LEN]));
+    assert_eq!(response_received.opt_elapsed_ticks, Some(3));
+    assert!(ephemeral2.request_start_ticks.is_empty());
+
     // Current balance from Node1 point of view:
     let friend2 = state1.friends.get(&pk2).unwrap();
     let mutual_credit_state = match &friend2.channel_status {
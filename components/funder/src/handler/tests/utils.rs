@@ -9,11 +9,19 @@ use proto::funder::messages::FunderOutgoingControl;
 use crate::ephemeral::Ephemeral;
 use crate::handler::handler::{funder_handle_message, FunderHandlerError, FunderHandlerOutput};
 use crate::state::FunderState;
-use crate::types::{FunderIncoming, FunderOutgoingComm};
+use crate::types::{FunderConfig, FunderIncoming, FunderOutgoingComm};
 
 const TEST_MAX_NODE_RELAYS: usize = 16;
 const TEST_MAX_OPERATIONS_IN_BATCH: usize = 16;
 const TEST_MAX_PENDING_USER_REQUESTS: usize = 16;
+const TEST_OFFLINE_GRACE_TICKS: usize = 0;
+const TEST_INCONSISTENCY_ERROR_INTERVAL_TICKS: usize = 0;
+const TEST_MAX_READY_RECEIPTS: usize = 64;
+const TEST_MAX_PENDING_REQUESTS: usize = 64;
+const TEST_FLUSH_IDLE_TICKS: usize = 0;
+const TEST_TOKEN_STALL_TICKS: usize = 0;
+const TEST_REQUEST_TIMEOUT_TICKS: usize = 0;
+const TEST_PENDING_QUEUE_THRESHOLD: usize = 0;
 
 /// A helper function. Applies an incoming funder message, updating state and ephemeral
 /// accordingly:
@@ -28,14 +36,26 @@ where
     B: Clone + PartialEq + Eq + CanonicalSerialize + Debug + 'a,
     R: CryptoRandom + 'a,
 {
+    let funder_config = FunderConfig {
+        max_operations_in_batch: TEST_MAX_OPERATIONS_IN_BATCH,
+        max_node_relays: TEST_MAX_NODE_RELAYS,
+        max_pending_user_requests: TEST_MAX_PENDING_USER_REQUESTS,
+        offline_grace_ticks: TEST_OFFLINE_GRACE_TICKS,
+        inconsistency_error_interval_ticks: TEST_INCONSISTENCY_ERROR_INTERVAL_TICKS,
+        max_ready_receipts: TEST_MAX_READY_RECEIPTS,
+        max_pending_requests: TEST_MAX_PENDING_REQUESTS,
+        flush_idle_ticks: TEST_FLUSH_IDLE_TICKS,
+        token_stall_ticks: TEST_TOKEN_STALL_TICKS,
+        request_timeout_ticks: TEST_REQUEST_TIMEOUT_TICKS,
+        pending_queue_threshold: TEST_PENDING_QUEUE_THRESHOLD,
+    };
+
     let funder_handler_output = await!(funder_handle_message(
         identity_client,
         rng,
         state.clone(),
         ephemeral.clone(),
-        TEST_MAX_NODE_RELAYS,
-        TEST_MAX_OPERATIONS_IN_BATCH,
-        TEST_MAX_PENDING_USER_REQUESTS,
+        &funder_config,
         funder_incoming
     ))?;
 
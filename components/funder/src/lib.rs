@@ -3,6 +3,7 @@
 #![feature(nll)]
 #![feature(generators)]
 #![feature(never_type)]
+#![cfg_attr(test, feature(test))]
 #![cfg_attr(not(feature = "cargo-clippy"), allow(unknown_lints))]
 #![deny(trivial_numeric_casts, warnings)]
 #![allow(intra_doc_link_resolution_failure)]
@@ -17,6 +18,8 @@
 extern crate log;
 #[macro_use]
 extern crate serde_derive;
+#[cfg(test)]
+extern crate test;
 
 mod credit_calc;
 mod ephemeral;
@@ -32,5 +35,9 @@ mod tests;
 mod token_channel;
 pub mod types;
 
+pub use self::credit_calc::{CreditCalcError, CreditCalculator};
 pub use self::funder::{funder_loop, FunderError};
+pub use self::mutual_credit::types::MutualCredit;
 pub use self::state::{FunderMutation, FunderState};
+pub use self::token_channel::{verify_move_token_standalone, VerifyMoveTokenError};
+pub use self::types::FunderConfig;
@@ -1,21 +1,32 @@
 use crypto::identity::PublicKey;
+use im::hashmap::HashMap as ImHashMap;
 use im::hashset::HashSet as ImHashSet;
 
 #[derive(Clone, Default)]
 pub struct Liveness {
     pub friends: ImHashSet<PublicKey>,
+    /// Friends that were reported offline, but are not yet declared offline.
+    /// Maps every suspect friend to the tick in which it became suspect.
+    pub suspects: ImHashMap<PublicKey, u64>,
+    /// Per friend offline grace period (in ticks).
+    /// Friends not in this map use the global default.
+    pub friend_intervals: ImHashMap<PublicKey, u64>,
 }
 
 #[derive(Debug)]
 pub enum LivenessMutation {
     SetOnline(PublicKey),
     SetOffline(PublicKey),
+    SetSuspect((PublicKey, u64)),
+    SetFriendInterval((PublicKey, u64)),
 }
 
 impl Liveness {
     pub fn new() -> Liveness {
         Liveness {
             friends: ImHashSet::new(),
+            suspects: ImHashMap::new(),
+            friend_intervals: ImHashMap::new(),
         }
     }
 
@@ -23,16 +34,63 @@ impl Liveness {
         match mutation {
             LivenessMutation::SetOnline(public_key) => {
                 self.friends.insert(public_key.clone());
+                let _ = self.suspects.remove(public_key);
             }
             LivenessMutation::SetOffline(public_key) => {
                 let _ = self.friends.remove(public_key);
+                let _ = self.suspects.remove(public_key);
+            }
+            LivenessMutation::SetSuspect((public_key, suspect_tick)) => {
+                let _ = self.friends.remove(public_key);
+                self.suspects.insert(public_key.clone(), *suspect_tick);
+            }
+            LivenessMutation::SetFriendInterval((public_key, interval_ticks)) => {
+                self.set_friend_interval(public_key, *interval_ticks);
             }
         }
     }
 
+    /// Set the offline grace period for a specific friend, overriding the global default.
+    pub fn set_friend_interval(&mut self, friend_public_key: &PublicKey, interval_ticks: u64) {
+        self.friend_intervals
+            .insert(friend_public_key.clone(), interval_ticks);
+    }
+
+    /// Get the offline grace period for a friend.
+    pub fn friend_interval(
+        &self,
+        friend_public_key: &PublicKey,
+        default_interval_ticks: u64,
+    ) -> u64 {
+        self.friend_intervals
+            .get(friend_public_key)
+            .cloned()
+            .unwrap_or(default_interval_ticks)
+    }
+
     pub fn is_online(&self, friend_public_key: &PublicKey) -> bool {
         self.friends.contains(&friend_public_key)
     }
+
+    /// A suspect friend is not online (We do not send it messages),
+    /// but requests pending for it are not canceled yet.
+    pub fn is_suspect(&self, friend_public_key: &PublicKey) -> bool {
+        self.suspects.contains_key(&friend_public_key)
+    }
+
+    /// Get all suspect friends that have been suspect for at least their grace period
+    /// (`default_grace_ticks` unless configured otherwise for the friend),
+    /// and should now be declared offline.
+    pub fn expired_suspects(&self, cur_tick: u64, default_grace_ticks: u64) -> Vec<PublicKey> {
+        self.suspects
+            .iter()
+            .filter(|(public_key, suspect_tick)| {
+                cur_tick.saturating_sub(**suspect_tick)
+                    >= self.friend_interval(public_key, default_grace_ticks)
+            })
+            .map(|(public_key, _)| public_key.clone())
+            .collect()
+    }
 }
 
 #[cfg(test)]
@@ -86,4 +144,77 @@ mod tests {
         assert!(!liveness.is_online(&pk_b));
         assert!(!liveness.is_online(&pk_c));
     }
+
+    #[test]
+    fn test_liveness_suspect() {
+        let mut liveness = Liveness::new();
+        let pk_a = PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]);
+        let pk_b = PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]);
+
+        liveness.mutate(&LivenessMutation::SetOnline(pk_a.clone()));
+        liveness.mutate(&LivenessMutation::SetOnline(pk_b.clone()));
+
+        liveness.mutate(&LivenessMutation::SetSuspect((pk_a.clone(), 5)));
+        assert!(!liveness.is_online(&pk_a));
+        assert!(liveness.is_suspect(&pk_a));
+        assert!(liveness.is_online(&pk_b));
+        assert!(!liveness.is_suspect(&pk_b));
+
+        assert!(liveness.expired_suspects(5, 3).is_empty());
+        assert!(liveness.expired_suspects(7, 3).is_empty());
+        assert_eq!(liveness.expired_suspects(8, 3), vec![pk_a.clone()]);
+
+        // Getting back online clears the suspect state:
+        liveness.mutate(&LivenessMutation::SetOnline(pk_a.clone()));
+        assert!(liveness.is_online(&pk_a));
+        assert!(!liveness.is_suspect(&pk_a));
+        assert!(liveness.expired_suspects(8, 3).is_empty());
+
+        // Going offline clears the suspect state:
+        liveness.mutate(&LivenessMutation::SetSuspect((pk_b.clone(), 8)));
+        assert!(liveness.is_suspect(&pk_b));
+        liveness.mutate(&LivenessMutation::SetOffline(pk_b.clone()));
+        assert!(!liveness.is_online(&pk_b));
+        assert!(!liveness.is_suspect(&pk_b));
+    }
+
+    #[test]
+    fn test_liveness_friend_intervals() {
+        let mut liveness = Liveness::new();
+        let pk_lan = PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]);
+        let pk_satellite = PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]);
+        let default_grace_ticks = 4;
+
+        liveness.set_friend_interval(&pk_lan, 1);
+        liveness.mutate(&LivenessMutation::SetFriendInterval((
+            pk_satellite.clone(),
+            10,
+        )));
+        assert_eq!(liveness.friend_interval(&pk_lan, default_grace_ticks), 1);
+        assert_eq!(
+            liveness.friend_interval(&pk_satellite, default_grace_ticks),
+            10
+        );
+        let pk_other = PublicKey::from(&[0xcc; PUBLIC_KEY_LEN]);
+        assert_eq!(liveness.friend_interval(&pk_other, default_grace_ticks), 4);
+
+        // Both friends become suspect at tick 3:
+        liveness.mutate(&LivenessMutation::SetSuspect((pk_lan.clone(), 3)));
+        liveness.mutate(&LivenessMutation::SetSuspect((pk_satellite.clone(), 3)));
+
+        assert!(liveness.expired_suspects(3, default_grace_ticks).is_empty());
+        assert_eq!(
+            liveness.expired_suspects(4, default_grace_ticks),
+            vec![pk_lan.clone()]
+        );
+        liveness.mutate(&LivenessMutation::SetOffline(pk_lan.clone()));
+
+        assert!(liveness
+            .expired_suspects(12, default_grace_ticks)
+            .is_empty());
+        assert_eq!(
+            liveness.expired_suspects(13, default_grace_ticks),
+            vec![pk_satellite.clone()]
+        );
+    }
 }
@@ -0,0 +1,85 @@
+use test::{black_box, Bencher};
+
+use crypto::identity::{PublicKey, PUBLIC_KEY_LEN};
+use crypto::invoice_id::{InvoiceId, INVOICE_ID_LEN};
+use crypto::uid::{Uid, UID_LEN};
+
+use proto::funder::messages::{FriendTcOp, FriendsRoute, RequestSendFunds};
+
+use crate::mutual_credit::incoming::process_operations_list;
+use crate::mutual_credit::outgoing::OutgoingMc;
+use crate::mutual_credit::types::{MutualCredit, MAX_FUNDER_DEBT};
+
+/// Amount of valid operations in the benchmarked batch.
+const BATCH_LEN: usize = 1024;
+
+/// Create a mutual credit that accepts requests from the remote side, and a large batch of
+/// incoming requests. The last operation of the batch is invalid, so the whole batch fails.
+fn failing_batch() -> (MutualCredit, Vec<FriendTcOp>) {
+    let local_public_key = PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]);
+    let remote_public_key = PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]);
+    let dest_public_key = PublicKey::from(&[0xcc; PUBLIC_KEY_LEN]);
+    let mut mutual_credit = MutualCredit::new(&local_public_key, &remote_public_key, 0);
+
+    for friend_tc_op in &[
+        FriendTcOp::SetRemoteMaxDebt(MAX_FUNDER_DEBT),
+        FriendTcOp::EnableRequests,
+    ] {
+        let mut outgoing = OutgoingMc::new(&mutual_credit);
+        for mutation in outgoing.queue_operation(friend_tc_op).unwrap() {
+            mutual_credit.mutate(&mutation);
+        }
+    }
+
+    let request_send_funds = |i: usize| {
+        let mut request_id = [0u8; UID_LEN];
+        request_id[..8].copy_from_slice(&(i as u64).to_be_bytes());
+        RequestSendFunds {
+            request_id: Uid::from(&request_id),
+            route: FriendsRoute {
+                public_keys: vec![
+                    remote_public_key.clone(),
+                    local_public_key.clone(),
+                    dest_public_key.clone(),
+                ],
+            },
+            dest_payment: 1,
+            invoice_id: InvoiceId::from(&[0; INVOICE_ID_LEN]),
+        }
+    };
+
+    let mut operations = (0..BATCH_LEN)
+        .map(|i| FriendTcOp::RequestSendFunds(request_send_funds(i)))
+        .collect::<Vec<_>>();
+    // A request with an existing request_id is invalid:
+    operations.push(FriendTcOp::RequestSendFunds(request_send_funds(0)));
+
+    (mutual_credit, operations)
+}
+
+/// Process a failing batch in place. The mutual credit is rolled back after every iteration.
+#[bench]
+fn bench_process_operations_list_rollback(b: &mut Bencher) {
+    let (mut mutual_credit, operations) = failing_batch();
+    b.iter(|| {
+        let res = process_operations_list(&mut mutual_credit, operations.clone());
+        black_box(res.is_err())
+    });
+    assert!(mutual_credit
+        .state()
+        .pending_requests
+        .pending_remote_requests
+        .is_empty());
+}
+
+/// Process a failing batch over a clone of the mutual credit, discarding the clone on failure.
+/// This is the approach used before processing could be rolled back in place.
+#[bench]
+fn bench_process_operations_list_clone(b: &mut Bencher) {
+    let (mutual_credit, operations) = failing_batch();
+    b.iter(|| {
+        let mut mutual_credit = mutual_credit.clone();
+        let res = process_operations_list(&mut mutual_credit, operations.clone());
+        black_box(res.is_err())
+    });
+}
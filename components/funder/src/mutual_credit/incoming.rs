@@ -1,4 +1,5 @@
 use crypto::identity::verify_signature;
+use crypto::uid::Uid;
 
 use common::int_convert::usize_to_u32;
 use common::safe_arithmetic::SafeSignedArithmetic;
@@ -11,9 +12,9 @@ use proto::funder::signature_buff::{create_response_signature_buffer, verify_fai
 
 use crate::types::create_pending_request;
 
-use crate::credit_calc::CreditCalculator;
+use crate::credit_calc::{CreditCalcError, CreditCalculator};
 
-use super::types::{McMutation, MutualCredit, MAX_FUNDER_DEBT};
+use super::types::{McBalance, McMutation, McRequestsStatus, MutualCredit, MAX_FUNDER_DEBT};
 
 /*
 pub struct IncomingRequestSendFunds {
@@ -38,6 +39,9 @@ pub enum IncomingMessage {
     Request(RequestSendFunds),
     Response(IncomingResponseSendFunds),
     Failure(IncomingFailureSendFunds),
+    /// The remote side asks us to cancel a request it has sent us.
+    /// Contains the (still pending) remote request.
+    Cancel(PendingRequest),
 }
 
 /// Resulting tasks to perform after processing an incoming operation.
@@ -57,7 +61,7 @@ pub enum ProcessOperationError {
     RouteTooLong,
     InsufficientTrust,
     CreditsCalcOverflow,
-    CreditCalculatorFailure,
+    CreditCalc(CreditCalcError),
     RequestAlreadyExists,
     RequestDoesNotExist,
     InvalidResponseSignature,
@@ -67,30 +71,43 @@ pub enum ProcessOperationError {
     LocalRequestsClosed,
 }
 
+/// An invalid operation in a list of incoming operations.
 #[derive(Debug)]
 pub struct ProcessTransListError {
-    index: usize,
-    process_trans_error: ProcessOperationError,
+    /// Zero based index of the invalid operation in the list
+    pub index: usize,
+    /// The reason the operation is invalid
+    pub process_trans_error: ProcessOperationError,
 }
 
+/// Process a list of incoming operations over a mutual credit.
+/// The operations are applied as one transaction: If any of the operations is invalid,
+/// all the operations applied so far are rolled back in place, and the mutual credit is left
+/// unchanged.
 pub fn process_operations_list(
     mutual_credit: &mut MutualCredit,
     operations: Vec<FriendTcOp>,
 ) -> Result<Vec<ProcessOperationOutput>, ProcessTransListError> {
     let mut outputs = Vec::new();
 
-    // We do not change the original MutualCredit.
-    // Instead, we are operating over a clone:
-    // This operation is not very expensive, because we are using immutable data structures
-    // (specifically, HashMaps).
+    // The balance and requests status are small, so we keep a copy of them.
+    // Changes to the pending requests are rolled back using the recorded mutations.
+    let initial_balance = mutual_credit.state().balance.clone();
+    let initial_requests_status = mutual_credit.state().requests_status.clone();
 
     for (index, funds) in operations.into_iter().enumerate() {
         match process_operation(mutual_credit, funds) {
             Err(e) => {
+                rollback_operations(
+                    mutual_credit,
+                    &outputs,
+                    initial_balance,
+                    initial_requests_status,
+                );
                 return Err(ProcessTransListError {
                     index,
                     process_trans_error: e,
-                })
+                });
             }
             Ok(trans_output) => outputs.push(trans_output),
         }
@@ -98,6 +115,65 @@ pub fn process_operations_list(
     Ok(outputs)
 }
 
+/// Revert all the mutations applied by successfully processed incoming operations.
+/// Note that a failing operation does not apply any mutations.
+fn rollback_operations(
+    mutual_credit: &mut MutualCredit,
+    outputs: &[ProcessOperationOutput],
+    initial_balance: McBalance,
+    initial_requests_status: McRequestsStatus,
+) {
+    for output in outputs.iter().rev() {
+        for mc_mutation in output.mc_mutations.iter().rev() {
+            let undo_mutation = match mc_mutation {
+                McMutation::InsertRemotePendingRequest(pending_request) => {
+                    McMutation::RemoveRemotePendingRequest(pending_request.request_id)
+                }
+                McMutation::RemoveLocalPendingRequest(_) => {
+                    // A local pending request is only removed by an incoming response or
+                    // failure, and the removed pending request is kept in the incoming message:
+                    let pending_request = match &output.incoming_message {
+                        Some(IncomingMessage::Response(incoming_response)) => {
+                            &incoming_response.pending_request
+                        }
+                        Some(IncomingMessage::Failure(incoming_failure)) => {
+                            &incoming_failure.pending_request
+                        }
+                        _ => unreachable!(),
+                    };
+                    McMutation::InsertLocalPendingRequest(pending_request.clone())
+                }
+                // Incoming operations never insert local pending requests or remove remote
+                // pending requests:
+                McMutation::InsertLocalPendingRequest(_)
+                | McMutation::RemoveRemotePendingRequest(_) => unreachable!(),
+                // Restored from the initial values below:
+                McMutation::SetLocalRequestsStatus(_)
+                | McMutation::SetRemoteRequestsStatus(_)
+                | McMutation::SetLocalMaxDebt(_)
+                | McMutation::SetRemoteMaxDebt(_)
+                | McMutation::SetBalance(_)
+                | McMutation::SetLocalPendingDebt(_)
+                | McMutation::SetRemotePendingDebt(_) => continue,
+            };
+            mutual_credit.mutate(&undo_mutation);
+        }
+    }
+
+    let undo_mutations = vec![
+        McMutation::SetLocalRequestsStatus(initial_requests_status.local),
+        McMutation::SetRemoteRequestsStatus(initial_requests_status.remote),
+        McMutation::SetLocalMaxDebt(initial_balance.local_max_debt),
+        McMutation::SetRemoteMaxDebt(initial_balance.remote_max_debt),
+        McMutation::SetBalance(initial_balance.balance),
+        McMutation::SetLocalPendingDebt(initial_balance.local_pending_debt),
+        McMutation::SetRemotePendingDebt(initial_balance.remote_pending_debt),
+    ];
+    for undo_mutation in &undo_mutations {
+        mutual_credit.mutate(undo_mutation);
+    }
+}
+
 pub fn process_operation(
     mutual_credit: &mut MutualCredit,
     friend_tc_op: FriendTcOp,
@@ -117,6 +193,7 @@ pub fn process_operation(
         FriendTcOp::FailureSendFunds(failure_send_funds) => {
             process_failure_send_funds(mutual_credit, failure_send_funds)
         }
+        FriendTcOp::CancelRequest(request_id) => process_cancel_request(mutual_credit, request_id),
     }
 }
 
@@ -209,7 +286,7 @@ fn process_request_send_funds(
     // Calculate amount of credits to freeze
     let own_freeze_credits = credit_calc
         .credits_to_freeze(local_index)
-        .ok_or(ProcessOperationError::CreditCalculatorFailure)?;
+        .map_err(ProcessOperationError::CreditCalc)?;
 
     // Make sure we can freeze the credits
     let balance = &mutual_credit.state().balance;
@@ -321,7 +398,9 @@ fn process_response_send_funds(
 
     let remote_index = usize_to_u32(local_index.checked_add(1).unwrap()).unwrap();
     let success_credits = credit_calc.credits_on_success(remote_index).unwrap();
-    let freeze_credits = credit_calc.credits_to_freeze(remote_index).unwrap();
+    let freeze_credits = credit_calc
+        .credits_to_freeze(remote_index)
+        .map_err(ProcessOperationError::CreditCalc)?;
 
     // Decrease frozen credits and decrease balance:
     let new_local_pending_debt = mutual_credit
@@ -417,7 +496,9 @@ fn process_failure_send_funds(
     let failure_credits = credit_calc
         .credits_on_failure(remote_index, reporting_index)
         .unwrap();
-    let freeze_credits = credit_calc.credits_to_freeze(remote_index).unwrap();
+    let freeze_credits = credit_calc
+        .credits_to_freeze(remote_index)
+        .map_err(ProcessOperationError::CreditCalc)?;
 
     // Decrease frozen credits and decrease balance:
     let new_local_pending_debt = mutual_credit
@@ -453,3 +534,23 @@ fn process_failure_send_funds(
         mc_mutations,
     })
 }
+
+fn process_cancel_request(
+    mutual_credit: &mut MutualCredit,
+    request_id: Uid,
+) -> Result<ProcessOperationOutput, ProcessOperationError> {
+    // The request must still be pending at our side. The credits frozen for this request are
+    // released only when we send back a failure (or a response) for this request:
+    let pending_request = mutual_credit
+        .state()
+        .pending_requests
+        .pending_remote_requests
+        .get(&request_id)
+        .ok_or(ProcessOperationError::RequestDoesNotExist)?
+        .clone();
+
+    Ok(ProcessOperationOutput {
+        incoming_message: Some(IncomingMessage::Cancel(pending_request)),
+        mc_mutations: Vec::new(),
+    })
+}
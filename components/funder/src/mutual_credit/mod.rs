@@ -1,5 +1,7 @@
 #![warn(unused)]
 
+#[cfg(test)]
+mod benches;
 pub mod incoming;
 pub mod outgoing;
 #[cfg(test)]
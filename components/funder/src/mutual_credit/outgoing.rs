@@ -1,21 +1,25 @@
 use crypto::identity::verify_signature;
+use crypto::uid::Uid;
 
 use common::int_convert::usize_to_u32;
 use common::safe_arithmetic::SafeSignedArithmetic;
 
+use proto::consts::MAX_OPERATIONS_IN_BATCH;
 use proto::funder::messages::{
     FailureSendFunds, FriendTcOp, RequestSendFunds, RequestsStatus, ResponseSendFunds,
 };
 use proto::funder::signature_buff::{create_response_signature_buffer, verify_failure_signature};
 
 use super::types::{McMutation, MutualCredit, MAX_FUNDER_DEBT};
-use crate::credit_calc::CreditCalculator;
+use crate::credit_calc::{CreditCalcError, CreditCalculator};
 use crate::types::create_pending_request;
 
 /// Processes outgoing funds for a token channel.
 /// Used to batch as many funds as possible.
 pub struct OutgoingMc {
     mutual_credit: MutualCredit,
+    /// Amount of operations that were queued successfully.
+    queued_len: usize,
 }
 
 #[derive(Debug)]
@@ -24,7 +28,7 @@ pub enum QueueOperationError {
     InvalidRoute,
     PkPairNotInRoute,
     RouteTooLong,
-    CreditCalculatorFailure,
+    CreditCalc(CreditCalcError),
     CreditsCalcOverflow,
     InsufficientTrust,
     RequestAlreadyExists,
@@ -42,12 +46,33 @@ impl OutgoingMc {
     pub fn new(mutual_credit: &MutualCredit) -> OutgoingMc {
         OutgoingMc {
             mutual_credit: mutual_credit.clone(),
+            queued_len: 0,
         }
     }
 
+    /// Amount of operations queued so far in this batch.
+    pub fn queued_len(&self) -> usize {
+        self.queued_len
+    }
+
+    /// Is the batch full? A move token may not contain more than MAX_OPERATIONS_IN_BATCH
+    /// operations.
+    pub fn is_full(&self) -> bool {
+        self.queued_len >= MAX_OPERATIONS_IN_BATCH
+    }
+
     pub fn queue_operation(
         &mut self,
         operation: &FriendTcOp,
+    ) -> Result<Vec<McMutation>, QueueOperationError> {
+        let mc_mutations = self.queue_operation_inner(operation)?;
+        self.queued_len = self.queued_len.saturating_add(1);
+        Ok(mc_mutations)
+    }
+
+    fn queue_operation_inner(
+        &mut self,
+        operation: &FriendTcOp,
     ) -> Result<Vec<McMutation>, QueueOperationError> {
         // TODO: Maybe remove clone from here later:
         match operation.clone() {
@@ -65,6 +90,7 @@ impl OutgoingMc {
             FriendTcOp::FailureSendFunds(failure_send_funds) => {
                 self.queue_failure_send_funds(failure_send_funds)
             }
+            FriendTcOp::CancelRequest(request_id) => self.queue_cancel_request(request_id),
         }
     }
 
@@ -138,7 +164,7 @@ impl OutgoingMc {
         // Calculate amount of credits to freeze
         let own_freeze_credits = credit_calc
             .credits_to_freeze(remote_index)
-            .ok_or(QueueOperationError::CreditCalculatorFailure)?;
+            .map_err(QueueOperationError::CreditCalc)?;
 
         let balance = &self.mutual_credit.state().balance;
 
@@ -245,7 +271,9 @@ impl OutgoingMc {
         tc_mutations.push(tc_mutation);
 
         let success_credits = credit_calc.credits_on_success(local_index).unwrap();
-        let freeze_credits = credit_calc.credits_to_freeze(local_index).unwrap();
+        let freeze_credits = credit_calc
+            .credits_to_freeze(local_index)
+            .map_err(QueueOperationError::CreditCalc)?;
 
         // Decrease frozen credits and increase balance:
         let new_remote_pending_debt = self
@@ -343,7 +371,9 @@ impl OutgoingMc {
         let failure_credits = credit_calc
             .credits_on_failure(local_index, reporting_index)
             .unwrap();
-        let freeze_credits = credit_calc.credits_to_freeze(local_index).unwrap();
+        let freeze_credits = credit_calc
+            .credits_to_freeze(local_index)
+            .map_err(QueueOperationError::CreditCalc)?;
 
         // Decrease frozen credits:
         let new_remote_pending_debt = self
@@ -373,4 +403,22 @@ impl OutgoingMc {
 
         Ok(tc_mutations)
     }
+
+    fn queue_cancel_request(
+        &mut self,
+        request_id: Uid,
+    ) -> Result<Vec<McMutation>, QueueOperationError> {
+        // We may only cancel a request we have sent, and that was not resolved yet.
+        // The frozen credits are released later, when the failure for this request arrives:
+        if !self
+            .mutual_credit
+            .state()
+            .pending_requests
+            .pending_local_requests
+            .contains_key(&request_id)
+        {
+            return Err(QueueOperationError::RequestDoesNotExist);
+        }
+        Ok(Vec::new())
+    }
 }
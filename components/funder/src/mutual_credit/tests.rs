@@ -8,18 +8,22 @@ use crypto::uid::{Uid, UID_LEN};
 use crypto::crypto_rand::{RandValue, RAND_VALUE_LEN};
 use crypto::invoice_id::{InvoiceId, INVOICE_ID_LEN};
 
+use proto::consts::MAX_OPERATIONS_IN_BATCH;
 use proto::funder::messages::{
-    FailureSendFunds, FriendTcOp, FriendsRoute, RequestSendFunds, RequestsStatus, ResponseSendFunds,
+    FailureReason, FailureSendFunds, FriendTcOp, FriendsRoute, PendingRequest, RequestSendFunds,
+    RequestsStatus, ResponseSendFunds,
 };
 use proto::funder::signature_buff::{
     create_failure_signature_buffer, create_response_signature_buffer,
 };
 
-use crate::mutual_credit::types::MutualCredit;
+use crate::credit_calc::CreditCalcError;
+use crate::mutual_credit::types::{InvariantViolation, McMutation, MutualCredit, MAX_FUNDER_DEBT};
 use crate::types::create_pending_request;
 
 use crate::mutual_credit::incoming::{
-    process_operation, ProcessOperationError, ProcessOperationOutput,
+    process_operation, process_operations_list, IncomingMessage, ProcessOperationError,
+    ProcessOperationOutput, ProcessTransListError,
 };
 use crate::mutual_credit::outgoing::{OutgoingMc, QueueOperationError};
 
@@ -223,6 +227,7 @@ fn test_request_failure_send_funds() {
     let mut failure_send_funds = FailureSendFunds {
         request_id,
         reporting_public_key: public_key_b.clone(),
+        reason: FailureReason::InsufficientTrust,
         rand_nonce,
         signature: Signature::from(&[0; SIGNATURE_LEN]),
     };
@@ -242,3 +247,579 @@ fn test_request_failure_send_funds() {
     assert_eq!(mutual_credit.state().balance.local_pending_debt, 0);
     assert_eq!(mutual_credit.state().balance.remote_pending_debt, 0);
 }
+
+#[test]
+fn test_cancel_request() {
+    let rng = DummyRandom::new(&[1u8]);
+    let pkcs8 = generate_pkcs8_key_pair(&rng);
+    let identity = SoftwareEd25519Identity::from_pkcs8(&pkcs8).unwrap();
+
+    let local_public_key = identity.get_public_key();
+    let remote_public_key = PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]);
+    let balance = 0;
+    let mut mutual_credit = MutualCredit::new(&local_public_key, &remote_public_key, balance);
+
+    // Trust each other, and open requests in both directions:
+    apply_incoming(&mut mutual_credit, FriendTcOp::SetRemoteMaxDebt(100)).unwrap();
+    apply_incoming(&mut mutual_credit, FriendTcOp::EnableRequests).unwrap();
+    apply_outgoing(&mut mutual_credit, &FriendTcOp::SetRemoteMaxDebt(100)).unwrap();
+    apply_outgoing(&mut mutual_credit, &FriendTcOp::EnableRequests).unwrap();
+
+    let route = FriendsRoute {
+        public_keys: vec![
+            local_public_key.clone(),
+            remote_public_key.clone(),
+            PublicKey::from(&[0xcc; PUBLIC_KEY_LEN]),
+        ],
+    };
+    let local_request = RequestSendFunds {
+        request_id: Uid::from(&[1; UID_LEN]),
+        route,
+        dest_payment: 10,
+        invoice_id: InvoiceId::from(&[0; INVOICE_ID_LEN]),
+    };
+
+    // We can not cancel a request we have not sent:
+    match apply_outgoing(
+        &mut mutual_credit,
+        &FriendTcOp::CancelRequest(local_request.request_id),
+    ) {
+        Err(QueueOperationError::RequestDoesNotExist) => {}
+        _ => unreachable!(),
+    };
+
+    apply_outgoing(
+        &mut mutual_credit,
+        &FriendTcOp::RequestSendFunds(local_request.clone()),
+    )
+    .unwrap();
+    let local_pending_debt = mutual_credit.state().balance.local_pending_debt;
+    assert!(local_pending_debt > 0);
+
+    // Canceling does not release the frozen credits. They are released when the failure arrives:
+    apply_outgoing(
+        &mut mutual_credit,
+        &FriendTcOp::CancelRequest(local_request.request_id),
+    )
+    .unwrap();
+    assert_eq!(
+        mutual_credit.state().balance.local_pending_debt,
+        local_pending_debt
+    );
+    assert!(mutual_credit
+        .state()
+        .pending_requests
+        .pending_local_requests
+        .contains_key(&local_request.request_id));
+
+    // The remote side asks us to cancel a request it has sent us:
+    let route = FriendsRoute {
+        public_keys: vec![
+            remote_public_key.clone(),
+            local_public_key.clone(),
+            PublicKey::from(&[0xcc; PUBLIC_KEY_LEN]),
+        ],
+    };
+    let remote_request = RequestSendFunds {
+        request_id: Uid::from(&[2; UID_LEN]),
+        route,
+        dest_payment: 10,
+        invoice_id: InvoiceId::from(&[0; INVOICE_ID_LEN]),
+    };
+
+    match apply_incoming(
+        &mut mutual_credit,
+        FriendTcOp::CancelRequest(remote_request.request_id),
+    ) {
+        Err(ProcessOperationError::RequestDoesNotExist) => {}
+        _ => unreachable!(),
+    };
+
+    apply_incoming(
+        &mut mutual_credit,
+        FriendTcOp::RequestSendFunds(remote_request.clone()),
+    )
+    .unwrap();
+    let remote_pending_debt = mutual_credit.state().balance.remote_pending_debt;
+    assert!(remote_pending_debt > 0);
+
+    let output = apply_incoming(
+        &mut mutual_credit,
+        FriendTcOp::CancelRequest(remote_request.request_id),
+    )
+    .unwrap();
+    assert!(output.mc_mutations.is_empty());
+    let pending_request = match output.incoming_message {
+        Some(IncomingMessage::Cancel(pending_request)) => pending_request,
+        _ => unreachable!(),
+    };
+    assert_eq!(pending_request, create_pending_request(&remote_request));
+    assert_eq!(
+        mutual_credit.state().balance.remote_pending_debt,
+        remote_pending_debt
+    );
+
+    // We fail the canceled request, releasing the frozen credits:
+    let mut failure_send_funds = FailureSendFunds {
+        request_id: remote_request.request_id,
+        reporting_public_key: local_public_key.clone(),
+        reason: FailureReason::Canceled,
+        rand_nonce: RandValue::from(&[5; RAND_VALUE_LEN]),
+        signature: Signature::from(&[0; SIGNATURE_LEN]),
+    };
+    let sign_buffer = create_failure_signature_buffer(&failure_send_funds, &pending_request);
+    failure_send_funds.signature = identity.sign(&sign_buffer);
+
+    apply_outgoing(
+        &mut mutual_credit,
+        &FriendTcOp::FailureSendFunds(failure_send_funds),
+    )
+    .unwrap();
+    assert_eq!(mutual_credit.state().balance.remote_pending_debt, 0);
+    assert!(mutual_credit
+        .state()
+        .pending_requests
+        .pending_remote_requests
+        .is_empty());
+}
+
+#[test]
+fn test_response_send_funds_invalid_signature() {
+    let local_public_key = PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]);
+    let remote_public_key = PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]);
+    let balance = 0;
+    let mut mutual_credit = MutualCredit::new(&local_public_key, &remote_public_key, balance);
+
+    apply_incoming(&mut mutual_credit, FriendTcOp::SetRemoteMaxDebt(100)).unwrap();
+    apply_incoming(&mut mutual_credit, FriendTcOp::EnableRequests).unwrap();
+
+    let rng = DummyRandom::new(&[1u8]);
+    let pkcs8 = generate_pkcs8_key_pair(&rng);
+    let identity = SoftwareEd25519Identity::from_pkcs8(&pkcs8).unwrap();
+    let public_key_c = identity.get_public_key();
+
+    // An identity that is not the destination of the request:
+    let rng = DummyRandom::new(&[2u8]);
+    let pkcs8 = generate_pkcs8_key_pair(&rng);
+    let forger_identity = SoftwareEd25519Identity::from_pkcs8(&pkcs8).unwrap();
+
+    let request_id = Uid::from(&[3; UID_LEN]);
+    let route = FriendsRoute {
+        public_keys: vec![
+            PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]),
+            PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]),
+            public_key_c.clone(),
+        ],
+    };
+    let invoice_id = InvoiceId::from(&[0; INVOICE_ID_LEN]);
+
+    let request_send_funds = RequestSendFunds {
+        request_id: request_id.clone(),
+        route,
+        dest_payment: 10,
+        invoice_id,
+    };
+
+    let pending_request = create_pending_request(&request_send_funds);
+    apply_outgoing(
+        &mut mutual_credit,
+        &FriendTcOp::RequestSendFunds(request_send_funds),
+    )
+    .unwrap();
+
+    let local_pending_debt = mutual_credit.state().balance.local_pending_debt;
+    assert!(local_pending_debt > 0);
+
+    let rand_nonce = RandValue::from(&[5; RAND_VALUE_LEN]);
+    let mut response_send_funds = ResponseSendFunds {
+        request_id: request_id.clone(),
+        rand_nonce: rand_nonce.clone(),
+        signature: Signature::from(&[0; SIGNATURE_LEN]),
+    };
+
+    // An all zero signature should be rejected:
+    let res = apply_incoming(
+        &mut mutual_credit,
+        FriendTcOp::ResponseSendFunds(response_send_funds.clone()),
+    );
+    match res {
+        Err(ProcessOperationError::InvalidResponseSignature) => {}
+        _ => unreachable!(),
+    }
+
+    // A signature by someone other than the destination should be rejected:
+    let sign_buffer = create_response_signature_buffer(&response_send_funds, &pending_request);
+    response_send_funds.signature = forger_identity.sign(&sign_buffer);
+    let res = apply_incoming(
+        &mut mutual_credit,
+        FriendTcOp::ResponseSendFunds(response_send_funds),
+    );
+    match res {
+        Err(ProcessOperationError::InvalidResponseSignature) => {}
+        _ => unreachable!(),
+    }
+
+    // The request is still pending, and no credits have moved:
+    assert!(mutual_credit
+        .state()
+        .pending_requests
+        .pending_local_requests
+        .contains_key(&request_id));
+    assert_eq!(mutual_credit.state().balance.balance, 0);
+    assert_eq!(
+        mutual_credit.state().balance.local_pending_debt,
+        local_pending_debt
+    );
+}
+
+#[test]
+fn test_balance_accessors() {
+    let local_public_key = PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]);
+    let remote_public_key = PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]);
+
+    // Positive balance: The remote side owes us.
+    let mut mutual_credit = MutualCredit::new(&local_public_key, &remote_public_key, 100);
+    assert_eq!(mutual_credit.net_balance(), 100);
+    assert_eq!(mutual_credit.we_owe(), 0);
+    assert_eq!(mutual_credit.owed_to_us(), 100);
+
+    mutual_credit.mutate(&McMutation::SetRemotePendingDebt(20));
+    assert_eq!(mutual_credit.owed_to_us(), 120);
+    mutual_credit.mutate(&McMutation::SetLocalPendingDebt(30));
+    assert_eq!(mutual_credit.net_balance(), 100);
+    assert_eq!(mutual_credit.we_owe(), 0);
+    mutual_credit.mutate(&McMutation::SetLocalPendingDebt(130));
+    assert_eq!(mutual_credit.we_owe(), 30);
+
+    // Negative balance: We owe the remote side.
+    let mut mutual_credit = MutualCredit::new(&local_public_key, &remote_public_key, -100);
+    assert_eq!(mutual_credit.net_balance(), -100);
+    assert_eq!(mutual_credit.we_owe(), 100);
+    assert_eq!(mutual_credit.owed_to_us(), 0);
+
+    mutual_credit.mutate(&McMutation::SetLocalPendingDebt(20));
+    assert_eq!(mutual_credit.we_owe(), 120);
+    mutual_credit.mutate(&McMutation::SetRemotePendingDebt(30));
+    assert_eq!(mutual_credit.owed_to_us(), 0);
+    mutual_credit.mutate(&McMutation::SetRemotePendingDebt(130));
+    assert_eq!(mutual_credit.owed_to_us(), 30);
+}
+
+#[test]
+fn test_check_invariants() {
+    let local_public_key = PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]);
+    let remote_public_key = PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]);
+    let public_key_c = PublicKey::from(&[0xcc; PUBLIC_KEY_LEN]);
+    let mut mutual_credit = MutualCredit::new(&local_public_key, &remote_public_key, 0);
+
+    // Open requests and set max debts in both directions:
+    apply_incoming(&mut mutual_credit, FriendTcOp::SetRemoteMaxDebt(100)).unwrap();
+    apply_incoming(&mut mutual_credit, FriendTcOp::EnableRequests).unwrap();
+    apply_outgoing(&mut mutual_credit, &FriendTcOp::SetRemoteMaxDebt(100)).unwrap();
+    apply_outgoing(&mut mutual_credit, &FriendTcOp::EnableRequests).unwrap();
+
+    // A local pending request:
+    let request_send_funds = RequestSendFunds {
+        request_id: Uid::from(&[3; UID_LEN]),
+        route: FriendsRoute {
+            public_keys: vec![
+                local_public_key.clone(),
+                remote_public_key.clone(),
+                public_key_c.clone(),
+            ],
+        },
+        dest_payment: 10,
+        invoice_id: InvoiceId::from(&[0; INVOICE_ID_LEN]),
+    };
+    apply_outgoing(
+        &mut mutual_credit,
+        &FriendTcOp::RequestSendFunds(request_send_funds),
+    )
+    .unwrap();
+
+    // A remote pending request:
+    let request_send_funds = RequestSendFunds {
+        request_id: Uid::from(&[4; UID_LEN]),
+        route: FriendsRoute {
+            public_keys: vec![
+                remote_public_key.clone(),
+                local_public_key.clone(),
+                public_key_c.clone(),
+            ],
+        },
+        dest_payment: 10,
+        invoice_id: InvoiceId::from(&[0; INVOICE_ID_LEN]),
+    };
+    apply_incoming(
+        &mut mutual_credit,
+        FriendTcOp::RequestSendFunds(request_send_funds),
+    )
+    .unwrap();
+
+    assert!(mutual_credit.state().balance.local_pending_debt > 0);
+    assert!(mutual_credit.state().balance.remote_pending_debt > 0);
+    assert_eq!(mutual_credit.check_invariants(), Ok(()));
+
+    // Apply a corrupting mutation over a copy of the valid mutual credit:
+    let corrupt = |mc_mutation: McMutation| {
+        let mut corrupted = mutual_credit.clone();
+        corrupted.mutate(&mc_mutation);
+        corrupted.check_invariants()
+    };
+
+    assert_eq!(
+        corrupt(McMutation::SetLocalMaxDebt(MAX_FUNDER_DEBT + 1)),
+        Err(InvariantViolation::LocalMaxDebtTooLarge)
+    );
+    assert_eq!(
+        corrupt(McMutation::SetRemoteMaxDebt(MAX_FUNDER_DEBT + 1)),
+        Err(InvariantViolation::RemoteMaxDebtTooLarge)
+    );
+
+    let invalid_pending_request = PendingRequest {
+        request_id: Uid::from(&[5; UID_LEN]),
+        route: FriendsRoute {
+            public_keys: vec![local_public_key.clone(), public_key_c.clone()],
+        },
+        dest_payment: 10,
+        invoice_id: InvoiceId::from(&[0; INVOICE_ID_LEN]),
+    };
+    assert_eq!(
+        corrupt(McMutation::InsertLocalPendingRequest(
+            invalid_pending_request
+        )),
+        Err(InvariantViolation::InvalidPendingRequest(Uid::from(
+            &[5; UID_LEN]
+        )))
+    );
+
+    let local_pending_debt = mutual_credit.state().balance.local_pending_debt;
+    assert_eq!(
+        corrupt(McMutation::SetLocalPendingDebt(local_pending_debt + 1)),
+        Err(InvariantViolation::LocalPendingDebtMismatch)
+    );
+    assert_eq!(
+        corrupt(McMutation::RemoveLocalPendingRequest(Uid::from(
+            &[3; UID_LEN]
+        ))),
+        Err(InvariantViolation::LocalPendingDebtMismatch)
+    );
+
+    assert_eq!(
+        corrupt(McMutation::SetRemotePendingDebt(0)),
+        Err(InvariantViolation::RemotePendingDebtMismatch)
+    );
+    assert_eq!(
+        corrupt(McMutation::RemoveRemotePendingRequest(Uid::from(
+            &[4; UID_LEN]
+        ))),
+        Err(InvariantViolation::RemotePendingDebtMismatch)
+    );
+
+    assert_eq!(
+        corrupt(McMutation::SetBalance(i128::max_value())),
+        Err(InvariantViolation::BalanceForResetOverflow)
+    );
+}
+
+#[test]
+fn test_process_operations_list_rollback() {
+    let local_public_key = PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]);
+    let remote_public_key = PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]);
+    let mut mutual_credit = MutualCredit::new(&local_public_key, &remote_public_key, 0);
+
+    apply_incoming(&mut mutual_credit, FriendTcOp::SetRemoteMaxDebt(100)).unwrap();
+    apply_incoming(&mut mutual_credit, FriendTcOp::EnableRequests).unwrap();
+    apply_outgoing(&mut mutual_credit, &FriendTcOp::SetRemoteMaxDebt(100)).unwrap();
+    apply_outgoing(&mut mutual_credit, &FriendTcOp::EnableRequests).unwrap();
+
+    let rng = DummyRandom::new(&[1u8]);
+    let pkcs8 = generate_pkcs8_key_pair(&rng);
+    let identity = SoftwareEd25519Identity::from_pkcs8(&pkcs8).unwrap();
+    let public_key_c = identity.get_public_key();
+
+    // A local pending request:
+    let request_send_funds = RequestSendFunds {
+        request_id: Uid::from(&[3; UID_LEN]),
+        route: FriendsRoute {
+            public_keys: vec![
+                local_public_key.clone(),
+                remote_public_key.clone(),
+                public_key_c.clone(),
+            ],
+        },
+        dest_payment: 10,
+        invoice_id: InvoiceId::from(&[0; INVOICE_ID_LEN]),
+    };
+    let pending_request = create_pending_request(&request_send_funds);
+    apply_outgoing(
+        &mut mutual_credit,
+        &FriendTcOp::RequestSendFunds(request_send_funds),
+    )
+    .unwrap();
+
+    let initial_balance = mutual_credit.state().balance.clone();
+    let initial_requests_status = mutual_credit.state().requests_status.clone();
+
+    let mut response_send_funds = ResponseSendFunds {
+        request_id: Uid::from(&[3; UID_LEN]),
+        rand_nonce: RandValue::from(&[5; RAND_VALUE_LEN]),
+        signature: Signature::from(&[0; SIGNATURE_LEN]),
+    };
+    let sign_buffer = create_response_signature_buffer(&response_send_funds, &pending_request);
+    response_send_funds.signature = identity.sign(&sign_buffer);
+
+    let remote_request_send_funds = RequestSendFunds {
+        request_id: Uid::from(&[4; UID_LEN]),
+        route: FriendsRoute {
+            public_keys: vec![
+                remote_public_key.clone(),
+                local_public_key.clone(),
+                public_key_c.clone(),
+            ],
+        },
+        dest_payment: 10,
+        invoice_id: InvoiceId::from(&[0; INVOICE_ID_LEN]),
+    };
+
+    let operations = vec![
+        FriendTcOp::SetRemoteMaxDebt(50),
+        FriendTcOp::RequestSendFunds(remote_request_send_funds),
+        FriendTcOp::ResponseSendFunds(response_send_funds),
+        FriendTcOp::DisableRequests,
+        // Requests are already disabled, so this operation is invalid:
+        FriendTcOp::DisableRequests,
+    ];
+    assert!(process_operations_list(&mut mutual_credit, operations).is_err());
+
+    // All the valid operations were rolled back:
+    let balance = &mutual_credit.state().balance;
+    assert_eq!(balance.balance, initial_balance.balance);
+    assert_eq!(balance.local_max_debt, initial_balance.local_max_debt);
+    assert_eq!(balance.remote_max_debt, initial_balance.remote_max_debt);
+    assert_eq!(
+        balance.local_pending_debt,
+        initial_balance.local_pending_debt
+    );
+    assert_eq!(
+        balance.remote_pending_debt,
+        initial_balance.remote_pending_debt
+    );
+    assert_eq!(
+        mutual_credit.state().requests_status,
+        initial_requests_status
+    );
+
+    let pending_requests = &mutual_credit.state().pending_requests;
+    assert_eq!(pending_requests.pending_local_requests.len(), 1);
+    assert_eq!(
+        pending_requests
+            .pending_local_requests
+            .get(&Uid::from(&[3; UID_LEN]))
+            .unwrap(),
+        &pending_request
+    );
+    assert!(pending_requests.pending_remote_requests.is_empty());
+    assert_eq!(mutual_credit.check_invariants(), Ok(()));
+}
+
+#[test]
+fn test_process_operations_list_error_index() {
+    let local_public_key = PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]);
+    let remote_public_key = PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]);
+    let mut mutual_credit = MutualCredit::new(&local_public_key, &remote_public_key, 0);
+
+    // A response to a request that was never sent:
+    let response_send_funds = ResponseSendFunds {
+        request_id: Uid::from(&[3; UID_LEN]),
+        rand_nonce: RandValue::from(&[5; RAND_VALUE_LEN]),
+        signature: Signature::from(&[0; SIGNATURE_LEN]),
+    };
+
+    let operations = vec![
+        FriendTcOp::EnableRequests,
+        FriendTcOp::SetRemoteMaxDebt(100),
+        FriendTcOp::ResponseSendFunds(response_send_funds),
+        FriendTcOp::DisableRequests,
+    ];
+
+    match process_operations_list(&mut mutual_credit, operations) {
+        Err(ProcessTransListError {
+            index: 2,
+            process_trans_error: ProcessOperationError::RequestDoesNotExist,
+        }) => {}
+        _ => unreachable!(),
+    }
+}
+
+#[test]
+fn test_request_send_funds_freeze_overflow() {
+    let local_public_key = PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]);
+    let remote_public_key = PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]);
+    let balance = 0;
+    let mut mutual_credit = MutualCredit::new(&local_public_key, &remote_public_key, balance);
+
+    // Open our requests, so that the remote side can send us requests:
+    mutual_credit.mutate(&McMutation::SetLocalRequestsStatus(RequestsStatus::Open));
+
+    // The fee of the intermediate nodes does not fit in a u128:
+    let request_send_funds = RequestSendFunds {
+        request_id: Uid::from(&[3; UID_LEN]),
+        route: FriendsRoute {
+            public_keys: vec![
+                remote_public_key.clone(),
+                local_public_key.clone(),
+                PublicKey::from(&[0xcc; PUBLIC_KEY_LEN]),
+                PublicKey::from(&[0xdd; PUBLIC_KEY_LEN]),
+            ],
+        },
+        dest_payment: u128::max_value(),
+        invoice_id: InvoiceId::from(&[0; INVOICE_ID_LEN]),
+    };
+
+    let res = apply_incoming(
+        &mut mutual_credit,
+        FriendTcOp::RequestSendFunds(request_send_funds),
+    );
+    match res {
+        Err(ProcessOperationError::CreditCalc(CreditCalcError::Overflow)) => {}
+        _ => unreachable!(),
+    };
+    assert_eq!(mutual_credit.state().balance.remote_pending_debt, 0);
+}
+
+#[test]
+fn test_outgoing_queued_len() {
+    let local_public_key = PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]);
+    let remote_public_key = PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]);
+    let balance = 0;
+    let mutual_credit = MutualCredit::new(&local_public_key, &remote_public_key, balance);
+
+    let mut outgoing = OutgoingMc::new(&mutual_credit);
+    assert_eq!(outgoing.queued_len(), 0);
+    assert!(!outgoing.is_full());
+
+    // A failed operation is not counted.
+    // Remote requests are closed, so we can not send a request:
+    let request_send_funds = RequestSendFunds {
+        request_id: Uid::from(&[3; UID_LEN]),
+        route: FriendsRoute {
+            public_keys: vec![local_public_key.clone(), remote_public_key.clone()],
+        },
+        dest_payment: 10,
+        invoice_id: InvoiceId::from(&[0; INVOICE_ID_LEN]),
+    };
+    assert!(outgoing
+        .queue_operation(&FriendTcOp::RequestSendFunds(request_send_funds))
+        .is_err());
+    assert_eq!(outgoing.queued_len(), 0);
+
+    for i in 0..MAX_OPERATIONS_IN_BATCH {
+        assert!(!outgoing.is_full());
+        outgoing
+            .queue_operation(&FriendTcOp::SetRemoteMaxDebt(i as u128))
+            .unwrap();
+        assert_eq!(outgoing.queued_len(), i + 1);
+    }
+    assert!(outgoing.is_full());
+}
@@ -1,17 +1,20 @@
 use im::hashmap::HashMap as ImHashMap;
 
+use common::int_convert::usize_to_u32;
 use common::safe_arithmetic::SafeSignedArithmetic;
 use crypto::identity::PublicKey;
 use crypto::uid::Uid;
 
 use proto::funder::messages::{PendingRequest, RequestsStatus};
 
+use crate::credit_calc::CreditCalculator;
+
 /// The maximum possible funder debt.
 /// We don't use the full u128 because i128 can not go beyond this value.
 pub const MAX_FUNDER_DEBT: u128 = (1 << 127) - 1;
 
 // TODO: Rename this to McIdents
-#[derive(Clone, Serialize, Deserialize, Debug)]
+#[derive(Eq, PartialEq, Clone, Serialize, Deserialize, Debug)]
 pub struct McIdents {
     /// My public key
     pub local_public_key: PublicKey,
@@ -20,7 +23,7 @@ pub struct McIdents {
 }
 
 // TODO: Rename this to McBalance
-#[derive(Clone, Serialize, Deserialize, Debug)]
+#[derive(Eq, PartialEq, Clone, Serialize, Deserialize, Debug)]
 pub struct McBalance {
     /// Amount of credits this side has against the remote side.
     /// The other side keeps the negation of this value.
@@ -52,7 +55,7 @@ impl McBalance {
 
 // TODO: Rename pending_local_requests to a shorter name, like local.
 
-#[derive(Clone, Serialize, Deserialize, Debug)]
+#[derive(Eq, PartialEq, Clone, Serialize, Deserialize, Debug)]
 pub struct McPendingRequests {
     /// Pending requests that were opened locally and not yet completed
     pub pending_local_requests: ImHashMap<Uid, PendingRequest>,
@@ -86,7 +89,7 @@ impl McRequestsStatus {
     }
 }
 
-#[derive(Clone, Serialize, Deserialize, Debug)]
+#[derive(Eq, PartialEq, Clone, Serialize, Deserialize, Debug)]
 pub struct MutualCreditState {
     pub idents: McIdents,
     pub balance: McBalance,
@@ -94,11 +97,29 @@ pub struct MutualCreditState {
     pub requests_status: McRequestsStatus,
 }
 
-#[derive(Clone, Serialize, Deserialize, Debug)]
+#[derive(Eq, PartialEq, Clone, Serialize, Deserialize, Debug)]
 pub struct MutualCredit {
     state: MutualCreditState,
 }
 
+/// A broken MutualCredit invariant. See `MutualCredit::check_invariants`.
+#[derive(Eq, PartialEq, Debug)]
+pub enum InvariantViolation {
+    /// local_max_debt is larger than MAX_FUNDER_DEBT
+    LocalMaxDebtTooLarge,
+    /// remote_max_debt is larger than MAX_FUNDER_DEBT
+    RemoteMaxDebtTooLarge,
+    /// A pending request that does not go through the local and remote public keys,
+    /// or for which we can not calculate the amount of frozen credits.
+    InvalidPendingRequest(Uid),
+    /// local_pending_debt is not the sum of credits frozen by pending local requests
+    LocalPendingDebtMismatch,
+    /// remote_pending_debt is not the sum of credits frozen by pending remote requests
+    RemotePendingDebtMismatch,
+    /// balance_for_reset() can not be calculated without overflow
+    BalanceForResetOverflow,
+}
+
 #[derive(Eq, PartialEq, Debug, Clone, Serialize, Deserialize)]
 pub enum McMutation {
     SetLocalRequestsStatus(RequestsStatus),
@@ -151,6 +172,111 @@ impl MutualCredit {
         &self.state
     }
 
+    /// Verify the consistency of the accounting of this mutual credit:
+    /// - Max debts are not larger than MAX_FUNDER_DEBT.
+    /// - local_pending_debt (remote_pending_debt) equals the sum of credits frozen by all
+    ///   pending local (remote) requests.
+    /// - balance_for_reset() can be calculated without overflow.
+    ///
+    /// Pending debts are unsigned, and therefore can not become negative.
+    /// Note that the balance may go beyond the max debt bounds, because the remote side may
+    /// decrease our max debt at any time.
+    pub fn check_invariants(&self) -> Result<(), InvariantViolation> {
+        let balance = &self.state.balance;
+        if balance.local_max_debt > MAX_FUNDER_DEBT {
+            return Err(InvariantViolation::LocalMaxDebtTooLarge);
+        }
+        if balance.remote_max_debt > MAX_FUNDER_DEBT {
+            return Err(InvariantViolation::RemoteMaxDebtTooLarge);
+        }
+
+        let local_public_key = &self.state.idents.local_public_key;
+        let remote_public_key = &self.state.idents.remote_public_key;
+        let pending_requests = &self.state.pending_requests;
+
+        // Local requests are frozen at the remote side's index on the route:
+        let mut local_pending_debt = 0u128;
+        for pending_request in pending_requests.pending_local_requests.values() {
+            let freeze_credits =
+                pending_request_freeze(pending_request, local_public_key, remote_public_key)
+                    .ok_or(InvariantViolation::InvalidPendingRequest(
+                        pending_request.request_id,
+                    ))?;
+            local_pending_debt = local_pending_debt
+                .checked_add(freeze_credits)
+                .ok_or(InvariantViolation::LocalPendingDebtMismatch)?;
+        }
+        if local_pending_debt != balance.local_pending_debt {
+            return Err(InvariantViolation::LocalPendingDebtMismatch);
+        }
+
+        // Remote requests are frozen at our index on the route:
+        let mut remote_pending_debt = 0u128;
+        for pending_request in pending_requests.pending_remote_requests.values() {
+            let freeze_credits =
+                pending_request_freeze(pending_request, remote_public_key, local_public_key)
+                    .ok_or(InvariantViolation::InvalidPendingRequest(
+                        pending_request.request_id,
+                    ))?;
+            remote_pending_debt = remote_pending_debt
+                .checked_add(freeze_credits)
+                .ok_or(InvariantViolation::RemotePendingDebtMismatch)?;
+        }
+        if remote_pending_debt != balance.remote_pending_debt {
+            return Err(InvariantViolation::RemotePendingDebtMismatch);
+        }
+
+        if balance
+            .balance
+            .checked_add_unsigned(balance.remote_pending_debt)
+            .is_none()
+        {
+            return Err(InvariantViolation::BalanceForResetOverflow);
+        }
+
+        Ok(())
+    }
+
+    /// The current balance, not including pending debts.
+    ///
+    /// Sign convention: A positive balance means that the remote side owes us credits.
+    /// A negative balance means that we owe credits to the remote side.
+    /// The remote side keeps the negation of this value.
+    pub fn net_balance(&self) -> i128 {
+        self.state.balance.balance
+    }
+
+    /// The amount of credits we will owe the remote side if all the requests we have sent
+    /// (local pending debt) are completed successfully. Zero if we will not owe anything.
+    pub fn we_owe(&self) -> u128 {
+        let worst_balance = self
+            .state
+            .balance
+            .balance
+            .saturating_sub_unsigned(self.state.balance.local_pending_debt);
+        if worst_balance < 0 {
+            worst_balance.safe_abs()
+        } else {
+            0
+        }
+    }
+
+    /// The amount of credits the remote side will owe us if all the requests it has sent
+    /// (remote pending debt) are completed successfully. Zero if the remote side will not owe
+    /// us anything.
+    pub fn owed_to_us(&self) -> u128 {
+        let best_balance = self
+            .state
+            .balance
+            .balance
+            .saturating_add_unsigned(self.state.balance.remote_pending_debt);
+        if best_balance > 0 {
+            best_balance.safe_abs()
+        } else {
+            0
+        }
+    }
+
     pub fn mutate(&mut self, tc_mutation: &McMutation) {
         match tc_mutation {
             McMutation::SetLocalRequestsStatus(requests_status) => {
@@ -245,3 +371,20 @@ impl MutualCredit {
         self.state.balance.local_pending_debt = local_pending_debt;
     }
 }
+
+/// Calculate the amount of credits frozen by a pending request, sent from `from_public_key` to
+/// `to_public_key`.
+fn pending_request_freeze(
+    pending_request: &PendingRequest,
+    from_public_key: &PublicKey,
+    to_public_key: &PublicKey,
+) -> Option<u128> {
+    let from_index = pending_request
+        .route
+        .find_pk_pair(from_public_key, to_public_key)?;
+    let to_index = usize_to_u32(from_index.checked_add(1)?)?;
+    let route_len = usize_to_u32(pending_request.route.len())?;
+    CreditCalculator::new(route_len, pending_request.dest_payment)
+        .credits_to_freeze(to_index)
+        .ok()
+}
@@ -3,6 +3,9 @@ use im::hashmap::HashMap as ImHashMap;
 use common::canonical_serialize::CanonicalSerialize;
 use common::int_convert::usize_to_u64;
 
+use crypto::identity::PublicKey;
+
+use proto::funder::messages::FriendStatus;
 use proto::report::messages::{
     AddFriendReport, ChannelInconsistentReport, ChannelStatusReport, DirectionReport,
     FriendLivenessReport, FriendReport, FriendReportMutation, FriendStatusReport, FunderReport,
@@ -14,7 +17,7 @@ use crate::types::MoveTokenHashed;
 
 use crate::ephemeral::{Ephemeral, EphemeralMutation};
 use crate::friend::{ChannelStatus, FriendMutation, FriendState, SentLocalRelays};
-use crate::liveness::LivenessMutation;
+use crate::liveness::{Liveness, LivenessMutation};
 use crate::mutual_credit::types::{McBalance, McRequestsStatus};
 use crate::state::{FunderMutation, FunderState};
 use crate::token_channel::{TcDirection, TcMutation, TokenChannel};
@@ -131,9 +134,27 @@ where
     }
 }
 
+/// Is any of the pending queues of this friend longer than `pending_queue_threshold`?
+/// A `pending_queue_threshold` of 0 disables the threshold.
+fn pending_queues_over_threshold<B>(
+    friend_state: &FriendState<B>,
+    pending_queue_threshold: usize,
+) -> bool
+where
+    B: Clone + CanonicalSerialize,
+{
+    if pending_queue_threshold == 0 {
+        return false;
+    }
+    friend_state.pending_requests.len() > pending_queue_threshold
+        || friend_state.pending_responses.len() > pending_queue_threshold
+        || friend_state.pending_user_requests.len() > pending_queue_threshold
+}
+
 fn create_friend_report<B>(
     friend_state: &FriendState<B>,
     friend_liveness: &FriendLivenessReport,
+    pending_queue_threshold: usize,
 ) -> FriendReport<B>
 where
     B: Clone + CanonicalSerialize,
@@ -158,21 +179,87 @@ where
         num_pending_responses: usize_to_u64(friend_state.pending_responses.len()).unwrap(),
         status: FriendStatusReport::from(&friend_state.status),
         num_pending_user_requests: usize_to_u64(friend_state.pending_user_requests.len()).unwrap(),
+        acked_remote_max_debt: friend_state.acked_remote_max_debt,
+        pending_queues_over_threshold: pending_queues_over_threshold(
+            friend_state,
+            pending_queue_threshold,
+        ),
+    }
+}
+
+/// A suspect friend is reported as online. It is reported as offline only when it is declared
+/// offline, and reported as online again when it recovers.
+fn friend_liveness_report(
+    liveness: &Liveness,
+    friend_public_key: &PublicKey,
+) -> FriendLivenessReport {
+    if liveness.is_online(friend_public_key) || liveness.is_suspect(friend_public_key) {
+        FriendLivenessReport::Online
+    } else {
+        FriendLivenessReport::Offline
     }
 }
 
-pub fn create_report<B>(funder_state: &FunderState<B>, ephemeral: &Ephemeral) -> FunderReport<B>
+/// Create a report for a single friend.
+/// Returns None if the friend does not exist.
+pub fn create_single_friend_report<B>(
+    funder_state: &FunderState<B>,
+    ephemeral: &Ephemeral,
+    friend_public_key: &PublicKey,
+    pending_queue_threshold: usize,
+) -> Option<FriendReport<B>>
+where
+    B: Clone + CanonicalSerialize,
+{
+    let friend_state = funder_state.friends.get(friend_public_key)?;
+    let friend_liveness = friend_liveness_report(&ephemeral.liveness, friend_public_key);
+    Some(create_friend_report(
+        &friend_state,
+        &friend_liveness,
+        pending_queue_threshold,
+    ))
+}
+
+/// A summary of all friends: The status (Enabled/Disabled) of every friend, together with its
+/// liveness (true if online). Sorted by public key.
+/// A friend is considered online here exactly when it is reported as online.
+pub fn friends_summary<B>(
+    funder_state: &FunderState<B>,
+    ephemeral: &Ephemeral,
+) -> Vec<(PublicKey, FriendStatus, bool)>
+where
+    B: Clone + CanonicalSerialize,
+{
+    let mut summary = funder_state
+        .friends
+        .iter()
+        .map(|(friend_public_key, friend_state)| {
+            let is_online =
+                friend_liveness_report(&ephemeral.liveness, friend_public_key).is_online();
+            (
+                friend_public_key.clone(),
+                friend_state.status.clone(),
+                is_online,
+            )
+        })
+        .collect::<Vec<_>>();
+    summary.sort_by(|(pk_a, _, _), (pk_b, _, _)| pk_a.cmp(pk_b));
+    summary
+}
+
+pub fn create_report<B>(
+    funder_state: &FunderState<B>,
+    ephemeral: &Ephemeral,
+    pending_queue_threshold: usize,
+) -> FunderReport<B>
 where
     B: Clone + CanonicalSerialize,
 {
     let mut friends = ImHashMap::new();
     for (friend_public_key, friend_state) in &funder_state.friends {
-        let friend_liveness = if ephemeral.liveness.is_online(friend_public_key) {
-            FriendLivenessReport::Online
-        } else {
-            FriendLivenessReport::Offline
-        };
-        let friend_report = create_friend_report(&friend_state, &friend_liveness);
+        let friend_liveness = friend_liveness_report(&ephemeral.liveness, friend_public_key);
+        let friend_report =
+            create_friend_report(&friend_state, &friend_liveness, pending_queue_threshold);
         friends.insert(friend_public_key.clone(), friend_report);
     }
 
@@ -184,23 +271,27 @@ where
     }
 }
 
-pub fn create_initial_report<B>(funder_state: &FunderState<B>) -> FunderReport<B>
+pub fn create_initial_report<B>(
+    funder_state: &FunderState<B>,
+    pending_queue_threshold: usize,
+) -> FunderReport<B>
 where
     B: Clone + CanonicalSerialize,
 {
-    create_report(funder_state, &Ephemeral::new())
+    create_report(funder_state, &Ephemeral::new(), pending_queue_threshold)
 }
 
 pub fn friend_mutation_to_report_mutations<B>(
     friend_mutation: &FriendMutation<B>,
     friend: &FriendState<B>,
+    pending_queue_threshold: usize,
 ) -> Vec<FriendReportMutation<B>>
 where
     B: Clone + CanonicalSerialize,
 {
     let mut friend_after = friend.clone();
     friend_after.mutate(friend_mutation);
-    match friend_mutation {
+    let mut report_mutations = match friend_mutation {
         FriendMutation::TcMutation(tc_mutation) => match tc_mutation {
             TcMutation::McMutation(_) | TcMutation::SetDirection(_) => {
                 let channel_status_report = ChannelStatusReport::from(&friend_after.channel_status);
@@ -223,6 +314,11 @@ where
                 *wanted_remote_max_debt,
             )]
         }
+        FriendMutation::SetAckedRemoteMaxDebt(acked_remote_max_debt) => {
+            vec![FriendReportMutation::SetAckedRemoteMaxDebt(
+                *acked_remote_max_debt,
+            )]
+        }
         FriendMutation::SetWantedLocalRequestsStatus(requests_status) => {
             vec![FriendReportMutation::SetWantedLocalRequestsStatus(
                 RequestsStatusReport::from(requests_status),
@@ -238,6 +334,14 @@ where
                 usize_to_u64(friend_after.pending_requests.len()).unwrap(),
             )]
         }
+        FriendMutation::RemovePendingRequest(_request_id) => {
+            vec![FriendReportMutation::SetNumPendingRequests(
+                usize_to_u64(friend_after.pending_requests.len()).unwrap(),
+            )]
+        }
+        FriendMutation::PushBackPendingCancel(_) | FriendMutation::PopFrontPendingCancel => {
+            Vec::new()
+        }
         FriendMutation::PushBackPendingResponse(_response_op) => {
             vec![FriendReportMutation::SetNumPendingResponses(
                 usize_to_u64(friend_after.pending_responses.len()).unwrap(),
@@ -258,12 +362,18 @@ where
                 usize_to_u64(friend_after.pending_user_requests.len()).unwrap(),
             )]
         }
+        FriendMutation::RemovePendingUserRequest(_request_id) => {
+            vec![FriendReportMutation::SetNumPendingUserRequests(
+                usize_to_u64(friend_after.pending_user_requests.len()).unwrap(),
+            )]
+        }
         FriendMutation::SetStatus(friend_status) => vec![FriendReportMutation::SetStatus(
             FriendStatusReport::from(friend_status),
         )],
         FriendMutation::SetRemoteRelays(remote_relays) => {
             vec![FriendReportMutation::SetRemoteRelays(remote_relays.clone())]
         }
+        FriendMutation::SetOptPrevRemoteRelays(_) => Vec::new(),
         FriendMutation::SetName(name) => vec![FriendReportMutation::SetName(name.clone())],
         FriendMutation::SetSentLocalRelays(sent_local_relays) => {
             vec![FriendReportMutation::SetSentLocalRelays(
@@ -281,7 +391,16 @@ where
                 FriendReportMutation::SetOptLastIncomingMoveToken(opt_move_token_hashed_report);
             vec![set_channel_status, set_last_incoming_move_token]
         }
+    };
+
+    // Report when the pending queues cross the threshold:
+    let over_threshold = pending_queues_over_threshold(&friend_after, pending_queue_threshold);
+    if pending_queues_over_threshold(friend, pending_queue_threshold) != over_threshold {
+        report_mutations.push(FriendReportMutation::SetPendingQueuesOverThreshold(
+            over_threshold,
+        ));
     }
+    report_mutations
 }
 
 /// Convert a FunderMutation to FunderReportMutation
@@ -294,6 +413,7 @@ where
 pub fn funder_mutation_to_report_mutations<B>(
     funder_mutation: &FunderMutation<B>,
     funder_state: &FunderState<B>,
+    pending_queue_threshold: usize,
 ) -> Vec<FunderReportMutation<B>>
 where
     B: Clone + CanonicalSerialize,
@@ -303,7 +423,7 @@ where
     match funder_mutation {
         FunderMutation::FriendMutation((public_key, friend_mutation)) => {
             let friend = funder_state.friends.get(public_key).unwrap();
-            friend_mutation_to_report_mutations(&friend_mutation, &friend)
+            friend_mutation_to_report_mutations(&friend_mutation, &friend, pending_queue_threshold)
                 .into_iter()
                 .map(|friend_report_mutation| {
                     FunderReportMutation::FriendReportMutation((
@@ -407,6 +527,251 @@ where
                     friend_report_mutation,
                 ))]
             }
+            // A suspect friend is still reported as online. It will be reported as offline only
+            // if it does not come back online during the grace period.
+            LivenessMutation::SetSuspect(_) => Vec::new(),
+            LivenessMutation::SetFriendInterval(_) => Vec::new(),
         },
+        EphemeralMutation::TimerTick
+        | EphemeralMutation::SetRequestStartTick(_)
+        | EphemeralMutation::RemoveRequestStartTick(_)
+        | EphemeralMutation::SetInconsistencyErrorTick(_)
+        | EphemeralMutation::SetFlushPendingTick(_)
+        | EphemeralMutation::RemoveFlushPendingTick(_)
+        | EphemeralMutation::SetTokenWaitTick(_)
+        | EphemeralMutation::RemoveTokenWaitTick(_)
+        | EphemeralMutation::SetPendingRequestTick(_)
+        | EphemeralMutation::RemovePendingRequestTick(_)
+        | EphemeralMutation::AddMetrics(_) => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crypto::identity::PUBLIC_KEY_LEN;
+    use crypto::invoice_id::{InvoiceId, INVOICE_ID_LEN};
+    use crypto::uid::{Uid, UID_LEN};
+
+    use proto::funder::messages::{AddFriend, FriendsRoute, RequestSendFunds};
+
+    use crate::tests::utils::dummy_named_relay_address;
+
+    #[test]
+    fn test_report_pending_requests_depth() {
+        let local_pk = PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]);
+        let remote_pk = PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]);
+        let mut friend =
+            FriendState::<u32>::new(&local_pk, &remote_pk, vec![], "remote".to_owned(), 0);
+
+        for i in 0..5u8 {
+            let request_send_funds = RequestSendFunds {
+                request_id: Uid::from(&[i; UID_LEN]),
+                route: FriendsRoute {
+                    public_keys: vec![local_pk.clone(), remote_pk.clone()],
+                },
+                dest_payment: 10,
+                invoice_id: InvoiceId::from(&[0; INVOICE_ID_LEN]),
+            };
+            let friend_mutation = FriendMutation::PushBackPendingRequest(request_send_funds);
+            let report_mutations =
+                friend_mutation_to_report_mutations(&friend_mutation, &friend, 0);
+            assert_eq!(
+                report_mutations,
+                vec![FriendReportMutation::SetNumPendingRequests(
+                    u64::from(i) + 1
+                )]
+            );
+            friend.mutate(&friend_mutation);
+        }
+
+        let friend_mutation = FriendMutation::PopFrontPendingRequest;
+        let report_mutations = friend_mutation_to_report_mutations(&friend_mutation, &friend, 0);
+        assert_eq!(
+            report_mutations,
+            vec![FriendReportMutation::SetNumPendingRequests(4)]
+        );
+        friend.mutate(&friend_mutation);
+
+        let friend_report = create_friend_report(&friend, &FriendLivenessReport::Offline, 0);
+        assert_eq!(friend_report.num_pending_requests, 4);
+        assert_eq!(friend_report.num_pending_responses, 0);
+        assert_eq!(friend_report.num_pending_user_requests, 0);
+        assert!(!friend_report.pending_queues_over_threshold);
+    }
+
+    #[test]
+    fn test_report_pending_queues_threshold() {
+        let local_pk = PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]);
+        let remote_pk = PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]);
+        let pending_queue_threshold = 2;
+        let mut friend =
+            FriendState::<u32>::new(&local_pk, &remote_pk, vec![], "remote".to_owned(), 0);
+
+        // Apply a friend mutation, and return the threshold report mutations it produced:
+        let mut apply = |friend_mutation| {
+            let report_mutations = friend_mutation_to_report_mutations(
+                &friend_mutation,
+                &friend,
+                pending_queue_threshold,
+            );
+            friend.mutate(&friend_mutation);
+            // The report mutations agree with a report created from scratch:
+            let friend_report = create_friend_report(
+                &friend,
+                &FriendLivenessReport::Offline,
+                pending_queue_threshold,
+            );
+            report_mutations
+                .into_iter()
+                .filter_map(|report_mutation| match report_mutation {
+                    FriendReportMutation::SetPendingQueuesOverThreshold(over_threshold) => {
+                        assert_eq!(over_threshold, friend_report.pending_queues_over_threshold);
+                        Some(over_threshold)
+                    }
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let request_send_funds = |i| RequestSendFunds {
+            request_id: Uid::from(&[i; UID_LEN]),
+            route: FriendsRoute {
+                public_keys: vec![local_pk.clone(), remote_pk.clone()],
+            },
+            dest_payment: 10,
+            invoice_id: InvoiceId::from(&[0; INVOICE_ID_LEN]),
+        };
+
+        // Up to the threshold, nothing is reported:
+        for i in 0..2u8 {
+            let friend_mutation = FriendMutation::PushBackPendingUserRequest(request_send_funds(i));
+            assert!(apply(friend_mutation).is_empty());
+        }
+
+        // Crossing the threshold is reported once:
+        let friend_mutation = FriendMutation::PushBackPendingUserRequest(request_send_funds(2));
+        assert_eq!(apply(friend_mutation), vec![true]);
+        let friend_mutation = FriendMutation::PushBackPendingRequest(request_send_funds(3));
+        assert!(apply(friend_mutation).is_empty());
+
+        // Going back below the threshold is reported too:
+        assert_eq!(
+            apply(FriendMutation::PopFrontPendingUserRequest),
+            vec![false]
+        );
+        assert!(apply(FriendMutation::PopFrontPendingUserRequest).is_empty());
+    }
+
+    #[test]
+    fn test_friends_summary() {
+        let local_pk = PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]);
+        let pk_b = PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]);
+        let pk_c = PublicKey::from(&[0xcc; PUBLIC_KEY_LEN]);
+        let pk_d = PublicKey::from(&[0xdd; PUBLIC_KEY_LEN]);
+        let pk_e = PublicKey::from(&[0xee; PUBLIC_KEY_LEN]);
+
+        let mut funder_state =
+            FunderState::<u32>::new(local_pk.clone(), vec![dummy_named_relay_address(0)]);
+        for friend_public_key in &[&pk_b, &pk_c, &pk_d, &pk_e] {
+            let add_friend = AddFriend {
+                friend_public_key: (*friend_public_key).clone(),
+                relays: vec![],
+                name: "friend".to_owned(),
+                balance: 0,
+            };
+            funder_state.mutate(&FunderMutation::AddFriend(add_friend));
+        }
+
+        // b and c are enabled, d and e are disabled:
+        for friend_public_key in &[&pk_b, &pk_c] {
+            funder_state.mutate(&FunderMutation::FriendMutation((
+                (*friend_public_key).clone(),
+                FriendMutation::SetStatus(FriendStatus::Enabled),
+            )));
+        }
+
+        // b and d are online, c and e are offline:
+        let mut ephemeral = Ephemeral::new();
+        for friend_public_key in &[&pk_b, &pk_d] {
+            ephemeral.mutate(&EphemeralMutation::LivenessMutation(
+                LivenessMutation::SetOnline((*friend_public_key).clone()),
+            ));
+        }
+
+        assert_eq!(
+            friends_summary(&funder_state, &ephemeral),
+            vec![
+                (pk_b.clone(), FriendStatus::Enabled, true),
+                (pk_c.clone(), FriendStatus::Enabled, false),
+                (pk_d.clone(), FriendStatus::Disabled, true),
+                (pk_e.clone(), FriendStatus::Disabled, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_report_liveness_transitions() {
+        let local_pk = PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]);
+        let remote_pk = PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]);
+
+        let mut funder_state =
+            FunderState::<u32>::new(local_pk.clone(), vec![dummy_named_relay_address(0)]);
+        let add_friend = AddFriend {
+            friend_public_key: remote_pk.clone(),
+            relays: vec![],
+            name: "remote".to_owned(),
+            balance: 0,
+        };
+        funder_state.mutate(&FunderMutation::AddFriend(add_friend));
+
+        let mut ephemeral = Ephemeral::new();
+
+        // Apply a liveness mutation, and return the liveness report mutations it produced:
+        let mut apply = |liveness_mutation| {
+            let ephemeral_mutation = EphemeralMutation::LivenessMutation(liveness_mutation);
+            let report_mutations =
+                ephemeral_mutation_to_report_mutations(&ephemeral_mutation, &funder_state);
+            ephemeral.mutate(&ephemeral_mutation);
+            report_mutations
+                .into_iter()
+                .map(|report_mutation| match report_mutation {
+                    FunderReportMutation::FriendReportMutation((
+                        public_key,
+                        FriendReportMutation::SetLiveness(friend_liveness),
+                    )) => {
+                        assert_eq!(public_key, remote_pk);
+                        // The report mutations agree with a report created from scratch:
+                        assert_eq!(
+                            friend_liveness,
+                            friend_liveness_report(&ephemeral.liveness, &remote_pk)
+                        );
+                        friend_liveness
+                    }
+                    _ => unreachable!(),
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            apply(LivenessMutation::SetOnline(remote_pk.clone())),
+            vec![FriendLivenessReport::Online]
+        );
+
+        // Going suspect is not reported:
+        assert!(apply(LivenessMutation::SetSuspect((remote_pk.clone(), 0))).is_empty());
+
+        // Declared offline:
+        assert_eq!(
+            apply(LivenessMutation::SetOffline(remote_pk.clone())),
+            vec![FriendLivenessReport::Offline]
+        );
+
+        // Recovery:
+        assert_eq!(
+            apply(LivenessMutation::SetOnline(remote_pk.clone())),
+            vec![FriendLivenessReport::Online]
+        );
     }
 }
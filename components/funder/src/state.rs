@@ -1,14 +1,17 @@
+use std::collections::HashSet;
+
 use im::hashmap::HashMap as ImHashMap;
 use im::vector::Vector as ImVec;
 
 use common::canonical_serialize::CanonicalSerialize;
 use crypto::identity::PublicKey;
+use crypto::invoice_id::InvoiceId;
 use crypto::uid::Uid;
 
 use proto::app_server::messages::NamedRelayAddress;
 use proto::funder::messages::{AddFriend, Receipt};
 
-use crate::friend::{FriendMutation, FriendState};
+use crate::friend::{ChannelStatus, FriendMutation, FriendState};
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct FunderState<B: Clone> {
@@ -18,6 +21,14 @@ pub struct FunderState<B: Clone> {
     pub relays: ImVec<NamedRelayAddress<B>>,
     pub friends: ImHashMap<PublicKey, FriendState<B>>,
     pub ready_receipts: ImHashMap<Uid, Receipt>,
+    /// The request_id-s of the ready receipts, from the oldest to the newest.
+    /// Used to evict the oldest receipts first.
+    #[serde(default)]
+    pub ready_receipts_order: ImVec<Uid>,
+    /// The request_id-s of the ready receipts of every invoice, from the oldest to the newest.
+    /// A secondary index over ready_receipts.
+    #[serde(default)]
+    pub invoice_ready_receipts: ImHashMap<InvoiceId, ImVec<Uid>>,
 }
 
 #[allow(clippy::large_enum_variant)]
@@ -45,10 +56,221 @@ where
             relays,
             friends: ImHashMap::new(),
             ready_receipts: ImHashMap::new(),
+            ready_receipts_order: ImVec::new(),
+            invoice_ready_receipts: ImHashMap::new(),
         }
     }
     // TODO: Add code for initialization from database?
 
+    /// Rebuild ready_receipts_order and invoice_ready_receipts from ready_receipts.
+    ///
+    /// States saved before the indices were added are loaded with empty indices. The known
+    /// order of the ready receipts is kept, and receipts missing from it are appended in order
+    /// of their request_id, as their original order is not known.
+    pub fn rebuild_receipt_indices(&mut self) {
+        let ready_receipts = &self.ready_receipts;
+        let mut ready_receipts_order: Vec<Uid> = Vec::new();
+        let mut seen_request_ids = HashSet::new();
+        for request_id in &self.ready_receipts_order {
+            if ready_receipts.contains_key(request_id) && seen_request_ids.insert(request_id) {
+                ready_receipts_order.push(request_id.clone());
+            }
+        }
+        let mut missing_request_ids: Vec<Uid> = ready_receipts
+            .keys()
+            .filter(|request_id| !seen_request_ids.contains(request_id))
+            .cloned()
+            .collect();
+        missing_request_ids.sort();
+        ready_receipts_order.extend(missing_request_ids);
+
+        let mut invoice_ready_receipts: ImHashMap<InvoiceId, ImVec<Uid>> = ImHashMap::new();
+        for request_id in &ready_receipts_order {
+            let invoice_id = match ready_receipts.get(request_id) {
+                Some(receipt) => &receipt.invoice_id,
+                None => continue,
+            };
+            let mut request_ids = invoice_ready_receipts
+                .get(invoice_id)
+                .cloned()
+                .unwrap_or_else(ImVec::new);
+            request_ids.push_back(request_id.clone());
+            invoice_ready_receipts.insert(invoice_id.clone(), request_ids);
+        }
+
+        self.ready_receipts_order = ready_receipts_order.into_iter().collect();
+        self.invoice_ready_receipts = invoice_ready_receipts;
+    }
+
+    /// Iterate over all the receipts that were not yet acknowledged by the user, together with
+    /// the request_id of the request that produced them.
+    /// Allows the user to reclaim receipts after a crash.
+    pub fn pending_receipts(&self) -> impl Iterator<Item = (&Uid, &Receipt)> {
+        self.ready_receipts.iter()
+    }
+
+    /// Iterate over all the ready receipts for a given invoice, from the oldest to the newest.
+    /// There could be more than one receipt for an invoice, for example if the payment was split.
+    pub fn receipts_by_invoice_id(
+        &self,
+        invoice_id: &InvoiceId,
+    ) -> impl Iterator<Item = (&Uid, &Receipt)> {
+        self.invoice_ready_receipts
+            .get(invoice_id)
+            .into_iter()
+            .flat_map(|request_ids| request_ids.iter())
+            .filter_map(move |request_id| {
+                self.ready_receipts
+                    .get(request_id)
+                    .map(|receipt| (request_id, receipt))
+            })
+    }
+
+    /// Remove a request_id from the invoice index of ready receipts.
+    fn remove_invoice_ready_receipt(&mut self, invoice_id: &InvoiceId, uid: &Uid) {
+        let mut request_ids = match self.invoice_ready_receipts.get(invoice_id) {
+            Some(request_ids) => request_ids.clone(),
+            None => return,
+        };
+        request_ids.retain(|request_id| request_id != uid);
+        if request_ids.is_empty() {
+            let _ = self.invoice_ready_receipts.remove(invoice_id);
+        } else {
+            self.invoice_ready_receipts
+                .insert(invoice_id.clone(), request_ids);
+        }
+    }
+
+    /// Find the originator of a pending local request.
+    ///
+    /// Every request we forward is kept as a pending remote request in the token channel with
+    /// the friend that sent it to us. We scan the pending remote requests of all friends with a
+    /// consistent token channel, and return the public key of the friend that has a pending
+    /// remote request with the given `request_id`. This friend is the previous node on the
+    /// route, and not necessarily the node that created the request.
+    ///
+    /// Returns None if no such friend exists, which means that we are the origin of this request.
+    ///
+    /// TODO: We need to change this search to be O(1) in the future. Possibly by maintaining a map
+    /// between request_id and (friend_public_key, friend).
+    pub fn find_request_origin(&self, request_id: &Uid) -> Option<&PublicKey> {
+        for (friend_public_key, friend) in &self.friends {
+            match &friend.channel_status {
+                ChannelStatus::Inconsistent(_) => continue,
+                ChannelStatus::Consistent(token_channel) => {
+                    if token_channel
+                        .get_mutual_credit()
+                        .state()
+                        .pending_requests
+                        .pending_remote_requests
+                        .contains_key(request_id)
+                    {
+                        return Some(friend_public_key);
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    /// Check if a request with the given `request_id` is in flight.
+    /// This includes requests waiting to be sent to any friend (user requests and forwarded
+    /// requests), and requests that are pending inside any token channel, in either direction.
+    pub fn is_request_in_flight(&self, request_id: &Uid) -> bool {
+        for friend in self.friends.values() {
+            if friend
+                .pending_user_requests
+                .iter()
+                .any(|request| &request.request_id == request_id)
+            {
+                return true;
+            }
+            if friend
+                .pending_requests
+                .iter()
+                .any(|request| &request.request_id == request_id)
+            {
+                return true;
+            }
+            if let ChannelStatus::Consistent(token_channel) = &friend.channel_status {
+                let pending_requests = &token_channel.get_mutual_credit().state().pending_requests;
+                if pending_requests
+                    .pending_local_requests
+                    .contains_key(request_id)
+                    || pending_requests
+                        .pending_remote_requests
+                        .contains_key(request_id)
+                {
+                    return true;
+                }
+            }
+        }
+        false
+    }
+
+    /// Compute a list of mutations that transforms this state into `other`.
+    ///
+    /// Currently only friends are compared: Friends that were added or removed, and changes in the
+    /// configuration, status and token channel of existing friends.
+    /// TODO: Add diffs for relays, receipts and the pending queues of friends.
+    pub fn diff(&self, other: &FunderState<B>) -> Vec<FunderMutation<B>>
+    where
+        B: PartialEq,
+    {
+        let mut funder_mutations = Vec::new();
+
+        let mut removed_friends = self
+            .friends
+            .keys()
+            .filter(|friend_public_key| !other.friends.contains_key(friend_public_key))
+            .cloned()
+            .collect::<Vec<_>>();
+        removed_friends.sort();
+        for friend_public_key in removed_friends {
+            funder_mutations.push(FunderMutation::RemoveFriend(friend_public_key));
+        }
+
+        let mut other_friends = other.friends.iter().collect::<Vec<_>>();
+        other_friends.sort_by(|(pk_a, _), (pk_b, _)| pk_a.cmp(pk_b));
+        for (friend_public_key, other_friend) in other_friends {
+            let friend_mutations = match self.friends.get(friend_public_key) {
+                Some(friend) => diff_friend(friend, other_friend),
+                None => {
+                    let balance = match &other_friend.channel_status {
+                        ChannelStatus::Consistent(token_channel) => {
+                            token_channel.get_mutual_credit().net_balance()
+                        }
+                        ChannelStatus::Inconsistent(_) => 0,
+                    };
+                    let add_friend = AddFriend {
+                        friend_public_key: friend_public_key.clone(),
+                        relays: other_friend.remote_relays.clone(),
+                        name: other_friend.name.clone(),
+                        balance,
+                    };
+                    // The state of the friend right after it is added:
+                    let new_friend = FriendState::new(
+                        &self.local_public_key,
+                        friend_public_key,
+                        add_friend.relays.clone(),
+                        add_friend.name.clone(),
+                        add_friend.balance,
+                    );
+                    funder_mutations.push(FunderMutation::AddFriend(add_friend));
+                    diff_friend(&new_friend, other_friend)
+                }
+            };
+            for friend_mutation in friend_mutations {
+                funder_mutations.push(FunderMutation::FriendMutation((
+                    friend_public_key.clone(),
+                    friend_mutation,
+                )));
+            }
+        }
+
+        funder_mutations
+    }
+
     // TODO: Use MutableState trait instead:
     pub fn mutate(&mut self, funder_mutation: &FunderMutation<B>) {
         match funder_mutation {
@@ -88,12 +310,480 @@ where
                 let _ = self.friends.remove(&public_key);
             }
             FunderMutation::AddReceipt((uid, send_funds_receipt)) => {
-                self.ready_receipts
-                    .insert(uid.clone(), send_funds_receipt.clone());
+                match self
+                    .ready_receipts
+                    .insert(uid.clone(), send_funds_receipt.clone())
+                {
+                    None => self.ready_receipts_order.push_back(uid.clone()),
+                    Some(old_receipt) => {
+                        self.remove_invoice_ready_receipt(&old_receipt.invoice_id, uid)
+                    }
+                }
+                let invoice_id = &send_funds_receipt.invoice_id;
+                let mut request_ids = self
+                    .invoice_ready_receipts
+                    .get(invoice_id)
+                    .cloned()
+                    .unwrap_or_else(ImVec::new);
+                request_ids.push_back(uid.clone());
+                self.invoice_ready_receipts
+                    .insert(invoice_id.clone(), request_ids);
             }
             FunderMutation::RemoveReceipt(uid) => {
-                let _ = self.ready_receipts.remove(uid);
+                if let Some(receipt) = self.ready_receipts.remove(uid) {
+                    self.ready_receipts_order.retain(|cur_uid| cur_uid != uid);
+                    self.remove_invoice_ready_receipt(&receipt.invoice_id, uid);
+                }
+            }
+        }
+    }
+}
+
+/// Compute a list of mutations that transforms the state of a friend into `other`.
+fn diff_friend<B>(friend: &FriendState<B>, other: &FriendState<B>) -> Vec<FriendMutation<B>>
+where
+    B: Clone + CanonicalSerialize + PartialEq,
+{
+    let mut friend_mutations = Vec::new();
+
+    if friend.remote_relays != other.remote_relays {
+        friend_mutations.push(FriendMutation::SetRemoteRelays(other.remote_relays.clone()));
+    }
+    if friend.opt_prev_remote_relays != other.opt_prev_remote_relays {
+        friend_mutations.push(FriendMutation::SetOptPrevRemoteRelays(
+            other.opt_prev_remote_relays.clone(),
+        ));
+    }
+    if friend.name != other.name {
+        friend_mutations.push(FriendMutation::SetName(other.name.clone()));
+    }
+    if friend.channel_status != other.channel_status {
+        friend_mutations.push(match &other.channel_status {
+            ChannelStatus::Consistent(token_channel) => {
+                FriendMutation::SetConsistent(token_channel.clone())
+            }
+            ChannelStatus::Inconsistent(channel_inconsistent) => {
+                FriendMutation::SetInconsistent(channel_inconsistent.clone())
+            }
+        });
+    }
+    if friend.wanted_remote_max_debt != other.wanted_remote_max_debt {
+        friend_mutations.push(FriendMutation::SetWantedRemoteMaxDebt(
+            other.wanted_remote_max_debt,
+        ));
+    }
+    if friend.acked_remote_max_debt != other.acked_remote_max_debt {
+        friend_mutations.push(FriendMutation::SetAckedRemoteMaxDebt(
+            other.acked_remote_max_debt,
+        ));
+    }
+    if friend.wanted_local_requests_status != other.wanted_local_requests_status {
+        friend_mutations.push(FriendMutation::SetWantedLocalRequestsStatus(
+            other.wanted_local_requests_status.clone(),
+        ));
+    }
+    if friend.status != other.status {
+        friend_mutations.push(FriendMutation::SetStatus(other.status.clone()));
+    }
+
+    friend_mutations
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use common::safe_arithmetic::Ratio;
+    use crypto::hash::{HashResult, HASH_RESULT_LEN};
+    use crypto::identity::{Signature, PUBLIC_KEY_LEN, SIGNATURE_LEN};
+    use crypto::invoice_id::INVOICE_ID_LEN;
+    use crypto::uid::UID_LEN;
+    use proto::funder::messages::{
+        FriendStatus, FriendsRoute, PendingRequest, RequestsStatus, ResetTerms,
+    };
+
+    use crate::friend::ChannelInconsistent;
+    use crate::mutual_credit::types::McMutation;
+    use crate::tests::utils::{dummy_named_relay_address, dummy_relay_address};
+    use crate::token_channel::{TcMutation, TokenChannel};
+
+    /// The ratio of the credits shared with the friend `origin_public_key` that may be used for
+    /// requests forwarded to the friend `dest_public_key`.
+    ///
+    /// The credits shared with the origin friend are divided between all the other friends,
+    /// proportionally to the credits shared with them (See `FriendState::get_shared_credits`).
+    ///
+    /// Returns None if one of the friends does not exist, if the origin and destination are the
+    /// same friend, if the sum of shared credits overflows or if no credits are shared with the
+    /// other friends.
+    fn get_usable_ratio(
+        state: &FunderState<u32>,
+        origin_public_key: &PublicKey,
+        dest_public_key: &PublicKey,
+    ) -> Option<Ratio> {
+        if origin_public_key == dest_public_key || !state.friends.contains_key(origin_public_key) {
+            return None;
+        }
+        let dest_shared_credits = state.friends.get(dest_public_key)?.get_shared_credits();
+
+        let mut total_shared_credits = 0u128;
+        for (friend_public_key, friend) in &state.friends {
+            if friend_public_key == origin_public_key {
+                continue;
             }
+            total_shared_credits = total_shared_credits.checked_add(friend.get_shared_credits())?;
+        }
+        Ratio::from_fraction(dest_shared_credits, total_shared_credits)
+    }
+
+    fn add_friend(state: &mut FunderState<u32>, friend_public_key: &PublicKey, index: u8) {
+        add_friend_with_balance(state, friend_public_key, index, 0);
+    }
+
+    fn add_friend_with_balance(
+        state: &mut FunderState<u32>,
+        friend_public_key: &PublicKey,
+        index: u8,
+        balance: i128,
+    ) {
+        let add_friend = AddFriend {
+            friend_public_key: friend_public_key.clone(),
+            relays: vec![dummy_relay_address(index)],
+            name: format!("friend{}", index),
+            balance,
+        };
+        state.mutate(&FunderMutation::AddFriend(add_friend));
+    }
+
+    #[test]
+    fn test_find_request_origin_multi_hop() {
+        // Route: pk_a -> pk_b -> local_pk -> pk_d
+        // pk_b and pk_d are our friends. pk_a is two hops away from us.
+        let pk_a = PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]);
+        let pk_b = PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]);
+        let local_pk = PublicKey::from(&[0xcc; PUBLIC_KEY_LEN]);
+        let pk_d = PublicKey::from(&[0xdd; PUBLIC_KEY_LEN]);
+
+        let mut state =
+            FunderState::<u32>::new(local_pk.clone(), vec![dummy_named_relay_address(0)]);
+        add_friend(&mut state, &pk_b, 1);
+        add_friend(&mut state, &pk_d, 2);
+
+        let forwarded_request = PendingRequest {
+            request_id: Uid::from(&[1; UID_LEN]),
+            route: FriendsRoute {
+                public_keys: vec![pk_a.clone(), pk_b.clone(), local_pk.clone(), pk_d.clone()],
+            },
+            dest_payment: 10,
+            invoice_id: InvoiceId::from(&[0; INVOICE_ID_LEN]),
+        };
+
+        // The request was forwarded to us by pk_b:
+        let mc_mutation = McMutation::InsertRemotePendingRequest(forwarded_request.clone());
+        let friend_mutation = FriendMutation::TcMutation(TcMutation::McMutation(mc_mutation));
+        state.mutate(&FunderMutation::FriendMutation((
+            pk_b.clone(),
+            friend_mutation,
+        )));
+
+        // We forwarded the request to pk_d:
+        let mc_mutation = McMutation::InsertLocalPendingRequest(forwarded_request.clone());
+        let friend_mutation = FriendMutation::TcMutation(TcMutation::McMutation(mc_mutation));
+        state.mutate(&FunderMutation::FriendMutation((
+            pk_d.clone(),
+            friend_mutation,
+        )));
+
+        // The origin is the friend that sent us the request, and not the creator of the request:
+        assert_eq!(
+            state.find_request_origin(&forwarded_request.request_id),
+            Some(&pk_b)
+        );
+
+        // A request that we have created ourselves:
+        let local_request = PendingRequest {
+            request_id: Uid::from(&[2; UID_LEN]),
+            route: FriendsRoute {
+                public_keys: vec![local_pk.clone(), pk_d.clone()],
+            },
+            dest_payment: 10,
+            invoice_id: InvoiceId::from(&[0; INVOICE_ID_LEN]),
+        };
+        let mc_mutation = McMutation::InsertLocalPendingRequest(local_request.clone());
+        let friend_mutation = FriendMutation::TcMutation(TcMutation::McMutation(mc_mutation));
+        state.mutate(&FunderMutation::FriendMutation((
+            pk_d.clone(),
+            friend_mutation,
+        )));
+
+        assert_eq!(state.find_request_origin(&local_request.request_id), None);
+
+        // An unknown request:
+        assert_eq!(state.find_request_origin(&Uid::from(&[3; UID_LEN])), None);
+    }
+
+    #[test]
+    fn test_get_usable_ratio() {
+        let local_pk = PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]);
+        let pk_b = PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]);
+        let pk_c = PublicKey::from(&[0xcc; PUBLIC_KEY_LEN]);
+        let pk_d = PublicKey::from(&[0xdd; PUBLIC_KEY_LEN]);
+        let pk_e = PublicKey::from(&[0xee; PUBLIC_KEY_LEN]);
+
+        let mut state = FunderState::<u32>::new(local_pk, vec![dummy_named_relay_address(0)]);
+        add_friend_with_balance(&mut state, &pk_b, 1, 5);
+        add_friend_with_balance(&mut state, &pk_c, 2, 30);
+        add_friend_with_balance(&mut state, &pk_d, 3, 10);
+
+        // The credits shared with pk_b are divided between pk_c and pk_d:
+        assert_eq!(
+            get_usable_ratio(&state, &pk_b, &pk_c),
+            Some(Ratio::Numerator(3 << 62))
+        );
+        assert_eq!(
+            get_usable_ratio(&state, &pk_b, &pk_d),
+            Some(Ratio::Numerator(1 << 62))
+        );
+        // pk_b's own shared credits are not counted when pk_d is the origin:
+        assert_eq!(
+            get_usable_ratio(&state, &pk_d, &pk_c),
+            Ratio::from_fraction(30, 35)
+        );
+
+        assert_eq!(get_usable_ratio(&state, &pk_b, &pk_b), None);
+        assert_eq!(get_usable_ratio(&state, &pk_b, &pk_e), None);
+        assert_eq!(get_usable_ratio(&state, &pk_e, &pk_c), None);
+
+        // No credits are shared with the other friends:
+        let mut state = FunderState::<u32>::new(
+            PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]),
+            vec![dummy_named_relay_address(0)],
+        );
+        add_friend(&mut state, &pk_b, 1);
+        add_friend(&mut state, &pk_c, 2);
+        assert_eq!(get_usable_ratio(&state, &pk_b, &pk_c), None);
+    }
+
+    #[test]
+    fn test_receipts_by_invoice_id() {
+        let local_pk = PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]);
+        let mut state = FunderState::<u32>::new(local_pk, vec![dummy_named_relay_address(0)]);
+
+        let invoice_x = InvoiceId::from(&[0x10; INVOICE_ID_LEN]);
+        let invoice_y = InvoiceId::from(&[0x20; INVOICE_ID_LEN]);
+        let receipt = |i: u8, invoice_id: &InvoiceId| Receipt {
+            response_hash: HashResult::from(&[i; HASH_RESULT_LEN]),
+            invoice_id: invoice_id.clone(),
+            dest_payment: u128::from(i),
+            signature: Signature::from(&[i; SIGNATURE_LEN]),
+        };
+
+        // A payment to invoice_x was split into two requests:
+        let receipts = vec![
+            (Uid::from(&[0; UID_LEN]), receipt(0, &invoice_x)),
+            (Uid::from(&[1; UID_LEN]), receipt(1, &invoice_y)),
+            (Uid::from(&[2; UID_LEN]), receipt(2, &invoice_x)),
+        ];
+        for (uid, receipt) in &receipts {
+            state.mutate(&FunderMutation::AddReceipt((uid.clone(), receipt.clone())));
+        }
+
+        let receipts_x = state.receipts_by_invoice_id(&invoice_x).collect::<Vec<_>>();
+        assert_eq!(
+            receipts_x,
+            vec![
+                (&receipts[0].0, &receipts[0].1),
+                (&receipts[2].0, &receipts[2].1)
+            ]
+        );
+        let receipts_y = state.receipts_by_invoice_id(&invoice_y).collect::<Vec<_>>();
+        assert_eq!(receipts_y, vec![(&receipts[1].0, &receipts[1].1)]);
+
+        let invoice_z = InvoiceId::from(&[0x30; INVOICE_ID_LEN]);
+        assert_eq!(state.receipts_by_invoice_id(&invoice_z).count(), 0);
+
+        // The index stays consistent when receipts are removed:
+        state.mutate(&FunderMutation::RemoveReceipt(receipts[0].0.clone()));
+        let receipts_x = state.receipts_by_invoice_id(&invoice_x).collect::<Vec<_>>();
+        assert_eq!(receipts_x, vec![(&receipts[2].0, &receipts[2].1)]);
+
+        state.mutate(&FunderMutation::RemoveReceipt(receipts[1].0.clone()));
+        assert_eq!(state.receipts_by_invoice_id(&invoice_y).count(), 0);
+        assert!(!state.invoice_ready_receipts.contains_key(&invoice_y));
+    }
+
+    #[test]
+    fn test_rebuild_receipt_indices() {
+        let local_pk = PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]);
+        let mut state = FunderState::<u32>::new(local_pk, vec![dummy_named_relay_address(0)]);
+
+        let invoice_x = InvoiceId::from(&[0x10; INVOICE_ID_LEN]);
+        let invoice_y = InvoiceId::from(&[0x20; INVOICE_ID_LEN]);
+        let receipt = |i: u8, invoice_id: &InvoiceId| Receipt {
+            response_hash: HashResult::from(&[i; HASH_RESULT_LEN]),
+            invoice_id: invoice_id.clone(),
+            dest_payment: u128::from(i),
+            signature: Signature::from(&[i; SIGNATURE_LEN]),
+        };
+        for (i, invoice_id) in vec![(0, &invoice_x), (1, &invoice_y), (2, &invoice_x)] {
+            state.mutate(&FunderMutation::AddReceipt((
+                Uid::from(&[i; UID_LEN]),
+                receipt(i, invoice_id),
+            )));
+        }
+
+        // Rebuilding complete indices changes nothing:
+        let mut rebuilt = state.clone();
+        rebuilt.rebuild_receipt_indices();
+        assert_eq!(rebuilt.ready_receipts_order, state.ready_receipts_order);
+        assert_eq!(rebuilt.invoice_ready_receipts, state.invoice_ready_receipts);
+
+        // A state saved before the indices were added is loaded with empty indices:
+        let mut loaded = state.clone();
+        loaded.ready_receipts_order = ImVec::new();
+        loaded.invoice_ready_receipts = ImHashMap::new();
+        assert_eq!(loaded.receipts_by_invoice_id(&invoice_x).count(), 0);
+
+        loaded.rebuild_receipt_indices();
+        assert_eq!(loaded.ready_receipts_order, state.ready_receipts_order);
+        assert_eq!(loaded.invoice_ready_receipts, state.invoice_ready_receipts);
+        assert_eq!(loaded.receipts_by_invoice_id(&invoice_x).count(), 2);
+        assert_eq!(loaded.receipts_by_invoice_id(&invoice_y).count(), 1);
+    }
+
+    #[test]
+    fn test_diff_friends() {
+        let local_pk = PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]);
+        let pk_b = PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]);
+        let pk_c = PublicKey::from(&[0xcc; PUBLIC_KEY_LEN]);
+        let pk_d = PublicKey::from(&[0xdd; PUBLIC_KEY_LEN]);
+
+        let mut state =
+            FunderState::<u32>::new(local_pk.clone(), vec![dummy_named_relay_address(0)]);
+        add_friend(&mut state, &pk_b, 1);
+        add_friend(&mut state, &pk_c, 2);
+
+        // A state is not different from itself:
+        assert!(state.diff(&state).is_empty());
+
+        let mut target = state.clone();
+
+        // pk_b is removed:
+        target.mutate(&FunderMutation::RemoveFriend(pk_b.clone()));
+
+        // pk_c has a new balance, name, status and trust:
+        let token_channel = TokenChannel::new(&local_pk, &pk_c, 25);
+        for friend_mutation in vec![
+            FriendMutation::SetConsistent(token_channel),
+            FriendMutation::SetName("renamed".to_owned()),
+            FriendMutation::SetStatus(FriendStatus::Enabled),
+            FriendMutation::SetWantedRemoteMaxDebt(40),
+        ] {
+            target.mutate(&FunderMutation::FriendMutation((
+                pk_c.clone(),
+                friend_mutation,
+            )));
+        }
+
+        // pk_d is added, and its channel is inconsistent:
+        add_friend(&mut target, &pk_d, 3);
+        let channel_inconsistent = ChannelInconsistent {
+            opt_last_incoming_move_token: None,
+            local_reset_terms: ResetTerms {
+                reset_token: Signature::from(&[0x11; SIGNATURE_LEN]),
+                inconsistency_counter: 1,
+                balance_for_reset: -15,
+            },
+            opt_remote_reset_terms: None,
+        };
+        for friend_mutation in vec![
+            FriendMutation::SetInconsistent(channel_inconsistent),
+            FriendMutation::SetWantedLocalRequestsStatus(RequestsStatus::Open),
+        ] {
+            target.mutate(&FunderMutation::FriendMutation((
+                pk_d.clone(),
+                friend_mutation,
+            )));
+        }
+
+        for funder_mutation in &state.diff(&target) {
+            state.mutate(funder_mutation);
+        }
+
+        // Applying the diff reproduces the target state:
+        assert!(state.diff(&target).is_empty());
+        assert!(!state.friends.contains_key(&pk_b));
+        assert_eq!(state.friends.len(), 2);
+
+        let friend_c = state.friends.get(&pk_c).unwrap();
+        assert_eq!(friend_c.name, "renamed");
+        assert_eq!(friend_c.status, FriendStatus::Enabled);
+        assert_eq!(friend_c.wanted_remote_max_debt, 40);
+        match &friend_c.channel_status {
+            ChannelStatus::Consistent(token_channel) => assert_eq!(
+                token_channel.get_mutual_credit().state().balance.balance,
+                25
+            ),
+            ChannelStatus::Inconsistent(_) => unreachable!(),
+        }
+
+        let friend_d = state.friends.get(&pk_d).unwrap();
+        assert_eq!(friend_d.name, "friend3");
+        assert_eq!(friend_d.remote_relays, vec![dummy_relay_address(3)]);
+        assert_eq!(friend_d.wanted_local_requests_status, RequestsStatus::Open);
+        match &friend_d.channel_status {
+            ChannelStatus::Inconsistent(channel_inconsistent) => assert_eq!(
+                channel_inconsistent.local_reset_terms.balance_for_reset,
+                -15
+            ),
+            ChannelStatus::Consistent(_) => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_diff_friends_pending_requests() {
+        let local_pk = PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]);
+        let pk_b = PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]);
+
+        let mut state =
+            FunderState::<u32>::new(local_pk.clone(), vec![dummy_named_relay_address(0)]);
+        add_friend(&mut state, &pk_b, 1);
+
+        // The channels differ only by a pending request. Balance, direction and the move token
+        // counters are all the same:
+        let mut target = state.clone();
+        let pending_request = PendingRequest {
+            request_id: Uid::from(&[1; UID_LEN]),
+            route: FriendsRoute {
+                public_keys: vec![local_pk.clone(), pk_b.clone()],
+            },
+            dest_payment: 10,
+            invoice_id: InvoiceId::from(&[0; INVOICE_ID_LEN]),
+        };
+        let mc_mutation = McMutation::InsertLocalPendingRequest(pending_request);
+        let friend_mutation = FriendMutation::TcMutation(TcMutation::McMutation(mc_mutation));
+        target.mutate(&FunderMutation::FriendMutation((
+            pk_b.clone(),
+            friend_mutation,
+        )));
+
+        let funder_mutations = state.diff(&target);
+        assert!(!funder_mutations.is_empty());
+        for funder_mutation in &funder_mutations {
+            state.mutate(funder_mutation);
+        }
+
+        // Applying the diff reproduces the pending request:
+        assert!(state.diff(&target).is_empty());
+        let friend_b = state.friends.get(&pk_b).unwrap();
+        match &friend_b.channel_status {
+            ChannelStatus::Consistent(token_channel) => assert!(token_channel
+                .get_mutual_credit()
+                .state()
+                .pending_requests
+                .pending_local_requests
+                .contains_key(&Uid::from(&[1; UID_LEN]))),
+            ChannelStatus::Inconsistent(_) => unreachable!(),
         }
     }
 }
@@ -1,17 +1,26 @@
 use futures::executor::ThreadPool;
 use futures::task::Spawn;
+use futures::SinkExt;
 
-use crypto::identity::PublicKey;
+use crypto::identity::{PublicKey, Signature, PUBLIC_KEY_LEN, SIGNATURE_LEN};
 use crypto::invoice_id::{InvoiceId, INVOICE_ID_LEN};
 use crypto::uid::{Uid, UID_LEN};
 
 use proto::funder::messages::{
-    FriendStatus, FriendsRoute, FunderControl, FunderIncomingControl, ReceiptAck, RequestsStatus,
-    ResetFriendChannel, ResponseSendFundsResult, UserRequestSendFunds,
+    FailureReason, FriendMessage, FriendStatus, FriendsRoute, FunderControl, FunderIncomingControl,
+    ReceiptAck, RequestsStatus, ResetFriendChannel, ResetTerms, ResponseSendFundsResult,
+    SetFriendRemoteMaxDebt, UserRequestSendFunds,
 };
-use proto::report::messages::{ChannelStatusReport, FunderReport};
+use proto::report::messages::{
+    ChannelStatusReport, DirectionReport, FunderReport, RequestsStatusReport,
+};
+
+use crate::types::{FunderIncomingComm, IncomingLivenessMessage};
 
-use super::utils::{create_node_controls, dummy_named_relay_address, dummy_relay_address};
+use super::utils::{
+    create_node, create_node_controls, create_node_controls_with_router, dummy_named_relay_address,
+    dummy_relay_address,
+};
 
 async fn task_funder_basic(spawner: impl Spawn + Clone + Send + 'static) {
     let num_nodes = 2;
@@ -215,6 +224,120 @@ fn test_funder_forward_payment() {
     thread_pool.run(task_funder_forward_payment(thread_pool.clone()));
 }
 
+async fn task_funder_metrics(spawner: impl Spawn + Clone + Send + 'static) {
+    /*
+     * 0 -- 1 -- 2
+     */
+    let num_nodes = 3;
+    let mut node_controls = await!(create_node_controls(num_nodes, spawner));
+
+    let public_keys = node_controls
+        .iter()
+        .map(|nc| nc.public_key.clone())
+        .collect::<Vec<PublicKey>>();
+
+    // Add friends:
+    let relays0 = vec![dummy_relay_address(0)];
+    let relays1 = vec![dummy_relay_address(1)];
+    let relays2 = vec![dummy_relay_address(2)];
+    await!(node_controls[0].add_friend(&public_keys[1], relays1, "node1", 8));
+    await!(node_controls[1].add_friend(&public_keys[0], relays0.clone(), "node0", -8));
+    await!(node_controls[1].add_friend(&public_keys[2], relays2, "node2", 6));
+    await!(node_controls[2].add_friend(&public_keys[1], relays0, "node0", -6));
+
+    // Enable friends:
+    await!(node_controls[0].set_friend_status(&public_keys[1], FriendStatus::Enabled));
+    await!(node_controls[1].set_friend_status(&public_keys[0], FriendStatus::Enabled));
+    await!(node_controls[1].set_friend_status(&public_keys[2], FriendStatus::Enabled));
+    await!(node_controls[2].set_friend_status(&public_keys[1], FriendStatus::Enabled));
+
+    // Set remote max debt:
+    await!(node_controls[0].set_remote_max_debt(&public_keys[1], 200));
+    await!(node_controls[1].set_remote_max_debt(&public_keys[0], 100));
+    await!(node_controls[1].set_remote_max_debt(&public_keys[2], 300));
+    await!(node_controls[2].set_remote_max_debt(&public_keys[1], 400));
+
+    // Open requests, allowing this route: 0 --> 1 --> 2
+    await!(node_controls[1].set_requests_status(&public_keys[0], RequestsStatus::Open));
+    await!(node_controls[2].set_requests_status(&public_keys[1], RequestsStatus::Open));
+
+    await!(node_controls[0].wait_until_ready(&public_keys[1]));
+    await!(node_controls[1].wait_until_ready(&public_keys[2]));
+
+    // Send credits 0 --> 2
+    let user_request_send_funds = UserRequestSendFunds {
+        request_id: Uid::from(&[3; UID_LEN]),
+        route: FriendsRoute {
+            public_keys: vec![
+                public_keys[0].clone(),
+                public_keys[1].clone(),
+                public_keys[2].clone(),
+            ],
+        },
+        invoice_id: InvoiceId::from(&[1; INVOICE_ID_LEN]),
+        dest_payment: 20,
+    };
+    let incoming_control_message = FunderIncomingControl::new(
+        Uid::from(&[42; UID_LEN]),
+        FunderControl::RequestSendFunds(user_request_send_funds.clone()),
+    );
+    await!(node_controls[0].send(incoming_control_message)).unwrap();
+    let response_received = await!(node_controls[0].recv_until_response()).unwrap();
+    let receipt = match response_received.result {
+        ResponseSendFundsResult::Failure(_) => unreachable!(),
+        ResponseSendFundsResult::Success(send_funds_receipt) => send_funds_receipt,
+    };
+
+    // node0 got the response for its request:
+    let funder_metrics = await!(node_controls[0].query_metrics());
+    assert_eq!(funder_metrics.responses_delivered, 1);
+
+    // Sending the same request again returns the existing receipt. This is not counted as
+    // another delivered response:
+    let incoming_control_message = FunderIncomingControl::new(
+        Uid::from(&[43; UID_LEN]),
+        FunderControl::RequestSendFunds(user_request_send_funds),
+    );
+    await!(node_controls[0].send(incoming_control_message)).unwrap();
+    let response_received = await!(node_controls[0].recv_until_response()).unwrap();
+    match response_received.result {
+        ResponseSendFundsResult::Failure(_) => unreachable!(),
+        ResponseSendFundsResult::Success(send_funds_receipt) => {
+            assert_eq!(send_funds_receipt, receipt)
+        }
+    };
+    let funder_metrics = await!(node_controls[0].query_metrics());
+    assert_eq!(funder_metrics.responses_delivered, 1);
+
+    // Make sure that node2 got the credits:
+    let pred = |report: &FunderReport<_>| {
+        let friend = match report.friends.get(&public_keys[1]) {
+            None => return false,
+            Some(friend) => friend,
+        };
+        let tc_report = match &friend.channel_status {
+            ChannelStatusReport::Consistent(tc_report) => tc_report,
+            _ => return false,
+        };
+        tc_report.balance.balance == -6 + 20
+    };
+    await!(node_controls[2].recv_until(pred));
+
+    // node1 forwarded the request, and later the response:
+    let funder_metrics = await!(node_controls[1].query_metrics());
+    assert_eq!(funder_metrics.requests_forwarded, 1);
+    assert_eq!(funder_metrics.responses_delivered, 1);
+    assert_eq!(funder_metrics.failures_generated, 0);
+    assert!(funder_metrics.move_tokens_sent > 0);
+    assert!(funder_metrics.move_tokens_received > 0);
+}
+
+#[test]
+fn test_funder_metrics() {
+    let mut thread_pool = ThreadPool::new().unwrap();
+    thread_pool.run(task_funder_metrics(thread_pool.clone()));
+}
+
 async fn task_funder_payment_failure(spawner: impl Spawn + Clone + Send + 'static) {
     /*
      * 0 -- 1 -- 2
@@ -308,6 +431,162 @@ fn test_funder_payment_failure() {
     thread_pool.run(task_funder_payment_failure(thread_pool.clone()));
 }
 
+async fn task_funder_cancel_request(spawner: impl Spawn + Clone + Send + 'static) {
+    /*
+     * 0 -- 1 -- 2 -- 3
+     * Node 0 sends a payment along the route 0 -- 1 -- 2 -- 3, and cancels it while it waits at
+     * node 2. The cancellation travels along the route, and node 2 returns a failure.
+     */
+    let num_nodes = 4;
+    let (mut node_controls, mut router_control) =
+        await!(create_node_controls_with_router(num_nodes, spawner));
+
+    // Create topology:
+    // ----------------
+    let public_keys = node_controls
+        .iter()
+        .map(|nc| nc.public_key.clone())
+        .collect::<Vec<PublicKey>>();
+
+    for i in 0..num_nodes - 1 {
+        let j = i + 1;
+
+        // Add friends:
+        let relays_i = vec![dummy_relay_address(i as u8)];
+        let relays_j = vec![dummy_relay_address(j as u8)];
+        await!(node_controls[i].add_friend(&public_keys[j], relays_j, "next", 0));
+        await!(node_controls[j].add_friend(&public_keys[i], relays_i, "prev", 0));
+
+        // Enable friends:
+        await!(node_controls[i].set_friend_status(&public_keys[j], FriendStatus::Enabled));
+        await!(node_controls[j].set_friend_status(&public_keys[i], FriendStatus::Enabled));
+
+        // Set remote max debt:
+        await!(node_controls[i].set_remote_max_debt(&public_keys[j], 200));
+        await!(node_controls[j].set_remote_max_debt(&public_keys[i], 200));
+
+        // Open requests, allowing requests along the route: 0 --> 1 --> 2 --> 3
+        await!(node_controls[j].set_requests_status(&public_keys[i], RequestsStatus::Open));
+    }
+
+    // Wait until route is ready (Online + Consistent + open requests)
+    for i in 0..num_nodes - 1 {
+        await!(node_controls[i].wait_until_ready(&public_keys[i + 1]));
+    }
+
+    // Node 3 stops sending messages to node 2. Node 2 sends its move token to node 3, and is
+    // left waiting for the token:
+    await!(router_control.hold_link(&public_keys[3], &public_keys[2]));
+    let set_friend_remote_max_debt = SetFriendRemoteMaxDebt {
+        friend_public_key: public_keys[3].clone(),
+        remote_max_debt: 250,
+    };
+    let incoming_control_message = FunderIncomingControl::new(
+        Uid::from(&[45; UID_LEN]),
+        FunderControl::SetFriendRemoteMaxDebt(set_friend_remote_max_debt),
+    );
+    await!(node_controls[2].send(incoming_control_message)).unwrap();
+
+    let pred = |report: &FunderReport<_>| {
+        let friend = report.friends.get(&public_keys[3]).unwrap();
+        match &friend.channel_status {
+            ChannelStatusReport::Consistent(tc_report) => {
+                tc_report.direction == DirectionReport::Outgoing
+            }
+            _ => false,
+        }
+    };
+    await!(node_controls[2].recv_until(pred));
+
+    // Send credits 0 --> 3
+    let user_request_send_funds = UserRequestSendFunds {
+        request_id: Uid::from(&[3; UID_LEN]),
+        route: FriendsRoute {
+            public_keys: public_keys.clone(),
+        },
+        invoice_id: InvoiceId::from(&[1; INVOICE_ID_LEN]),
+        dest_payment: 20,
+    };
+    let incoming_control_message = FunderIncomingControl::new(
+        Uid::from(&[46; UID_LEN]),
+        FunderControl::RequestSendFunds(user_request_send_funds),
+    );
+    await!(node_controls[0].send(incoming_control_message)).unwrap();
+
+    // The request waits at node 2 to be forwarded to node 3:
+    let pred = |report: &FunderReport<_>| {
+        let friend = report.friends.get(&public_keys[3]).unwrap();
+        friend.num_pending_requests == 1
+    };
+    await!(node_controls[2].recv_until(pred));
+
+    // Node 0 cancels the request:
+    let incoming_control_message = FunderIncomingControl::new(
+        Uid::from(&[47; UID_LEN]),
+        FunderControl::CancelRequest(Uid::from(&[3; UID_LEN])),
+    );
+    await!(node_controls[0].send(incoming_control_message)).unwrap();
+
+    let response_received = await!(node_controls[0].recv_until_response()).unwrap();
+    assert_eq!(response_received.request_id, Uid::from(&[3; UID_LEN]));
+    match response_received.result {
+        ResponseSendFundsResult::Failure(reporting_public_key) => {
+            assert_eq!(reporting_public_key, public_keys[2])
+        }
+        ResponseSendFundsResult::Success(_) => unreachable!(),
+    };
+    assert_eq!(
+        response_received.opt_failure_reason,
+        Some(FailureReason::Canceled)
+    );
+
+    // The request was never forwarded to node 3, and the credits frozen by node 1 are released:
+    let pred = |report: &FunderReport<_>| {
+        let friend = report.friends.get(&public_keys[1]).unwrap();
+        let tc_report = match &friend.channel_status {
+            ChannelStatusReport::Consistent(tc_report) => tc_report,
+            _ => return false,
+        };
+        report
+            .friends
+            .get(&public_keys[3])
+            .unwrap()
+            .num_pending_requests
+            == 0
+            && tc_report.balance.remote_pending_debt == 0
+    };
+    await!(node_controls[2].recv_until(pred));
+
+    // Node 3 resumes sending messages. Payments along the route work again:
+    await!(router_control.release_link(&public_keys[3], &public_keys[2]));
+
+    let user_request_send_funds = UserRequestSendFunds {
+        request_id: Uid::from(&[4; UID_LEN]),
+        route: FriendsRoute {
+            public_keys: public_keys.clone(),
+        },
+        invoice_id: InvoiceId::from(&[2; INVOICE_ID_LEN]),
+        dest_payment: 20,
+    };
+    let incoming_control_message = FunderIncomingControl::new(
+        Uid::from(&[48; UID_LEN]),
+        FunderControl::RequestSendFunds(user_request_send_funds),
+    );
+    await!(node_controls[0].send(incoming_control_message)).unwrap();
+    let response_received = await!(node_controls[0].recv_until_response()).unwrap();
+    assert_eq!(response_received.request_id, Uid::from(&[4; UID_LEN]));
+    match response_received.result {
+        ResponseSendFundsResult::Success(_) => {}
+        ResponseSendFundsResult::Failure(_) => unreachable!(),
+    };
+}
+
+#[test]
+fn test_funder_cancel_request() {
+    let mut thread_pool = ThreadPool::new().unwrap();
+    thread_pool.run(task_funder_cancel_request(thread_pool.clone()));
+}
+
 /// Test a basic inconsistency between two adjacent nodes
 async fn task_funder_inconsistency_basic<S>(spawner: S)
 where
@@ -433,3 +712,109 @@ fn test_funder_add_relay() {
     let mut thread_pool = ThreadPool::new().unwrap();
     thread_pool.run(task_funder_add_relay(thread_pool.clone()));
 }
+
+async fn task_funder_close_requests(spawner: impl Spawn + Clone + Send + 'static) {
+    let num_nodes = 2;
+    let mut node_controls = await!(create_node_controls(num_nodes, spawner));
+
+    let public_keys = node_controls
+        .iter()
+        .map(|nc| nc.public_key.clone())
+        .collect::<Vec<PublicKey>>();
+
+    let relays0 = vec![dummy_relay_address(0)];
+    let relays1 = vec![dummy_relay_address(1)];
+    await!(node_controls[0].add_friend(&public_keys[1], relays1, "node1", 8));
+    await!(node_controls[1].add_friend(&public_keys[0], relays0, "node0", -8));
+
+    await!(node_controls[0].set_friend_status(&public_keys[1], FriendStatus::Enabled));
+    await!(node_controls[1].set_friend_status(&public_keys[0], FriendStatus::Enabled));
+
+    await!(node_controls[1].set_remote_max_debt(&public_keys[0], 100));
+
+    // Node1 opens requests, and then closes them again:
+    await!(node_controls[1].set_requests_status(&public_keys[0], RequestsStatus::Open));
+    await!(node_controls[0].wait_until_ready(&public_keys[1]));
+    await!(node_controls[1].set_requests_status(&public_keys[0], RequestsStatus::Closed));
+
+    // Wait until node0 learns that node1 closed its requests:
+    let pred = |report: &FunderReport<_>| {
+        let friend = report.friends.get(&public_keys[1]).unwrap();
+        let tc_report = match &friend.channel_status {
+            ChannelStatusReport::Consistent(tc_report) => tc_report,
+            _ => return false,
+        };
+        tc_report.requests_status.remote == RequestsStatusReport::from(&RequestsStatus::Closed)
+    };
+    await!(node_controls[0].recv_until(pred));
+
+    // Attempt to send credits 0 --> 1. We expect the request to fail:
+    let user_request_send_funds = UserRequestSendFunds {
+        request_id: Uid::from(&[3; UID_LEN]),
+        route: FriendsRoute {
+            public_keys: vec![public_keys[0].clone(), public_keys[1].clone()],
+        },
+        invoice_id: InvoiceId::from(&[1; INVOICE_ID_LEN]),
+        dest_payment: 5,
+    };
+    let incoming_control_message = FunderIncomingControl::new(
+        Uid::from(&[45; UID_LEN]),
+        FunderControl::RequestSendFunds(user_request_send_funds),
+    );
+    await!(node_controls[0].send(incoming_control_message)).unwrap();
+    let response_received = await!(node_controls[0].recv_until_response()).unwrap();
+    assert_eq!(response_received.request_id, Uid::from(&[3; UID_LEN]));
+    match response_received.result {
+        ResponseSendFundsResult::Failure(reporting_public_key) => {
+            assert_eq!(reporting_public_key, public_keys[0])
+        }
+        ResponseSendFundsResult::Success(_) => unreachable!(),
+    };
+
+    // No credits were moved:
+    let friend = node_controls[0]
+        .report
+        .friends
+        .get(&public_keys[1])
+        .unwrap();
+    let tc_report = match &friend.channel_status {
+        ChannelStatusReport::Consistent(tc_report) => tc_report,
+        _ => unreachable!(),
+    };
+    assert_eq!(tc_report.balance.balance, 8);
+}
+
+#[test]
+fn test_funder_close_requests() {
+    let mut thread_pool = ThreadPool::new().unwrap();
+    thread_pool.run(task_funder_close_requests(thread_pool.clone()));
+}
+
+async fn task_funder_bad_friend_message(spawner: impl Spawn + Clone + Send + 'static) {
+    let (mut node_control, mut send_comm, _recv_comm) = await!(create_node(0, spawner));
+
+    // Messages from a friend that does not exist:
+    let unknown_public_key = PublicKey::from(&[0xee; PUBLIC_KEY_LEN]);
+    let reset_terms = ResetTerms::new(Signature::from(&[1; SIGNATURE_LEN]), 1, 0);
+    await!(send_comm.send(FunderIncomingComm::Friend((
+        unknown_public_key.clone(),
+        FriendMessage::InconsistencyError(reset_terms),
+    ))))
+    .unwrap();
+    await!(send_comm.send(FunderIncomingComm::Liveness(
+        IncomingLivenessMessage::Online(unknown_public_key.clone())
+    )))
+    .unwrap();
+
+    // The funder loop is still alive, and handles further messages:
+    let funder_metrics = await!(node_control.query_metrics());
+    assert_eq!(funder_metrics.move_tokens_received, 0);
+
+    await!(node_control.add_relay(dummy_named_relay_address(1)));
+}
+
+#[test]
+fn test_funder_bad_friend_message() {
+    let mut thread_pool = ThreadPool::new().unwrap();
+    thread_pool.run(task_funder_bad_friend_message(thread_pool.clone()));
+}
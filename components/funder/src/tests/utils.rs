@@ -3,7 +3,7 @@ use std::collections::{HashMap, HashSet};
 use common::canonical_serialize::CanonicalSerialize;
 use common::mutable_state::MutableState;
 
-use futures::channel::mpsc;
+use futures::channel::{mpsc, oneshot};
 use futures::stream::select;
 use futures::task::{Spawn, SpawnExt};
 use futures::{future, FutureExt, SinkExt, StreamExt};
@@ -21,8 +21,9 @@ use proto::report::messages::{
 
 use proto::app_server::messages::{NamedRelayAddress, RelayAddress};
 use proto::funder::messages::{
-    AddFriend, FriendStatus, FunderControl, FunderIncomingControl, FunderOutgoingControl,
-    RequestsStatus, ResponseReceived, SetFriendRemoteMaxDebt, SetFriendStatus, SetRequestsStatus,
+    AddFriend, EvictedReceipt, FriendMessage, FriendQueryResult, FriendStatus, FunderControl,
+    FunderIncomingControl, FunderMetrics, FunderOutgoingControl, RequestsStatus, ResponseReceived,
+    SetFriendRemoteMaxDebt, SetFriendStatus, SetRequestsStatus, TokenCountersQueryResult,
 };
 
 use database::DatabaseClient;
@@ -30,17 +31,27 @@ use database::DatabaseClient;
 use identity::{create_identity, IdentityClient};
 
 use crate::ephemeral::Ephemeral;
+use crate::friend::FriendMutation;
 use crate::funder::inner_funder_loop;
 use crate::report::create_report;
-use crate::state::FunderState;
+use crate::state::{FunderMutation, FunderState};
+use crate::token_channel::TokenChannel;
 
 use crate::types::{
-    ChannelerConfig, FunderIncomingComm, FunderOutgoingComm, IncomingLivenessMessage,
+    ChannelerConfig, FunderConfig, FunderIncomingComm, FunderOutgoingComm, IncomingLivenessMessage,
 };
 
 const TEST_MAX_NODE_RELAYS: usize = 16;
 const TEST_MAX_OPERATIONS_IN_BATCH: usize = 16;
 const TEST_MAX_PENDING_USER_REQUESTS: usize = 16;
+const TEST_OFFLINE_GRACE_TICKS: usize = 0;
+const TEST_INCONSISTENCY_ERROR_INTERVAL_TICKS: usize = 0;
+const TEST_MAX_READY_RECEIPTS: usize = 64;
+const TEST_MAX_PENDING_REQUESTS: usize = 64;
+const TEST_FLUSH_IDLE_TICKS: usize = 0;
+const TEST_TOKEN_STALL_TICKS: usize = 0;
+const TEST_REQUEST_TIMEOUT_TICKS: usize = 0;
+const TEST_PENDING_QUEUE_THRESHOLD: usize = 0;
 
 // This is required to make sure the tests are not stuck.
 //
@@ -63,6 +74,32 @@ pub fn dummy_relay_address(index: u8) -> RelayAddress<u32> {
     dummy_named_relay_address(index).into()
 }
 
+/// A helper function to quickly create a FunderState with a single friend.
+/// The token channel with the friend is replaced by `opt_token_channel`, if given.
+pub fn dummy_state_with_friend(
+    local_public_key: &PublicKey,
+    friend_public_key: &PublicKey,
+    opt_token_channel: Option<TokenChannel<u32>>,
+) -> FunderState<u32> {
+    let mut state =
+        FunderState::<u32>::new(local_public_key.clone(), vec![dummy_named_relay_address(0)]);
+    let add_friend = AddFriend {
+        friend_public_key: friend_public_key.clone(),
+        relays: vec![dummy_relay_address(1)],
+        name: "friend1".to_owned(),
+        balance: 0i128,
+    };
+    state.mutate(&FunderMutation::AddFriend(add_friend));
+
+    if let Some(token_channel) = opt_token_channel {
+        state.mutate(&FunderMutation::FriendMutation((
+            friend_public_key.clone(),
+            FriendMutation::SetConsistent(token_channel),
+        )));
+    }
+    state
+}
+
 #[derive(Debug)]
 struct Node<B> {
     friends: HashSet<PublicKey>,
@@ -76,19 +113,37 @@ struct NewNode<B> {
     comm_out: mpsc::Sender<FunderIncomingComm<B>>,
 }
 
+#[derive(Debug)]
+enum RouterCommand {
+    /// Hold friend messages sent from one node to another: (src_public_key, dest_public_key)
+    HoldLink((PublicKey, PublicKey)),
+    /// Deliver all held friend messages, and stop holding: (src_public_key, dest_public_key)
+    ReleaseLink((PublicKey, PublicKey)),
+}
+
 #[derive(Debug)]
 enum RouterEvent<B> {
     NewNode(NewNode<B>),
     OutgoingComm((PublicKey, FunderOutgoingComm<B>)), // (src_public_key, outgoing_comm)
+    Command((RouterCommand, oneshot::Sender<()>)),
 }
 
+/// Held friend messages, by (src_public_key, dest_public_key)
+type HeldLinks<B> = HashMap<(PublicKey, PublicKey), Vec<FriendMessage<B>>>;
+
 async fn router_handle_outgoing_comm<'a, B: 'a>(
     nodes: &'a mut HashMap<PublicKey, Node<B>>,
+    held_links: &'a mut HeldLinks<B>,
     src_public_key: PublicKey,
     outgoing_comm: FunderOutgoingComm<B>,
 ) {
     match outgoing_comm {
         FunderOutgoingComm::FriendMessage((dest_public_key, friend_message)) => {
+            let link = (src_public_key.clone(), dest_public_key.clone());
+            if let Some(held_messages) = held_links.get_mut(&link) {
+                held_messages.push(friend_message);
+                return;
+            }
             let node = nodes.get_mut(&dest_public_key).unwrap();
             assert!(node.friends.contains(&src_public_key));
             let incoming_comm_message =
@@ -145,20 +200,47 @@ async fn router_handle_outgoing_comm<'a, B: 'a>(
     }
 }
 
+async fn router_handle_command<'a, B: 'a>(
+    nodes: &'a mut HashMap<PublicKey, Node<B>>,
+    held_links: &'a mut HeldLinks<B>,
+    router_command: RouterCommand,
+) {
+    match router_command {
+        RouterCommand::HoldLink(link) => {
+            held_links.entry(link).or_insert_with(Vec::new);
+        }
+        RouterCommand::ReleaseLink(link) => {
+            let held_messages = held_links.remove(&link).unwrap_or_default();
+            let (src_public_key, dest_public_key) = link;
+            let node = nodes.get_mut(&dest_public_key).unwrap();
+            for friend_message in held_messages {
+                let incoming_comm_message =
+                    FunderIncomingComm::Friend((src_public_key.clone(), friend_message));
+                await!(node.comm_out.send(incoming_comm_message)).unwrap();
+            }
+        }
+    }
+}
+
 /// A future that forwards communication between nodes. Used for testing.
 /// Simulates the Channeler interface
-async fn router<B, S>(incoming_new_node: mpsc::Receiver<NewNode<B>>, mut spawner: S)
-where
+async fn router<B, S>(
+    incoming_new_node: mpsc::Receiver<NewNode<B>>,
+    incoming_commands: mpsc::Receiver<(RouterCommand, oneshot::Sender<()>)>,
+    mut spawner: S,
+) where
     B: Send + 'static,
     S: Spawn + Clone,
 {
     let mut nodes: HashMap<PublicKey, Node<B>> = HashMap::new();
+    let mut held_links: HeldLinks<B> = HashMap::new();
     let (comm_sender, comm_receiver) = mpsc::channel::<(PublicKey, FunderOutgoingComm<B>)>(0);
 
     let incoming_new_node = incoming_new_node.map(|new_node| RouterEvent::NewNode(new_node));
     let comm_receiver = comm_receiver.map(|tuple| RouterEvent::OutgoingComm(tuple));
+    let incoming_commands = incoming_commands.map(|command| RouterEvent::Command(command));
 
-    let mut events = select(incoming_new_node, comm_receiver);
+    let mut events = select(select(incoming_new_node, comm_receiver), incoming_commands);
 
     while let Some(event) = await!(events.next()) {
         match event {
@@ -187,10 +269,19 @@ where
             RouterEvent::OutgoingComm((src_public_key, outgoing_comm)) => {
                 await!(router_handle_outgoing_comm(
                     &mut nodes,
+                    &mut held_links,
                     src_public_key,
                     outgoing_comm
                 ));
             }
+            RouterEvent::Command((router_command, ack_sender)) => {
+                await!(router_handle_command(
+                    &mut nodes,
+                    &mut held_links,
+                    router_command
+                ));
+                let _ = ack_sender.send(());
+            }
         };
     }
 }
@@ -199,6 +290,9 @@ pub struct NodeControl<B: Clone> {
     pub public_key: PublicKey,
     send_control: mpsc::Sender<FunderIncomingControl<B>>,
     recv_control: mpsc::Receiver<FunderOutgoingControl<B>>,
+    /// Send timer ticks to the funder.
+    /// Note that the funder exits if this sender is dropped.
+    pub send_timer_tick: mpsc::Sender<()>,
     pub report: FunderReport<B>,
 }
 
@@ -206,6 +300,10 @@ pub struct NodeControl<B: Clone> {
 pub enum NodeRecv<B: Clone> {
     ReportMutations(FunderReportMutations<B>),
     ResponseReceived(ResponseReceived),
+    FriendQueryResult(FriendQueryResult<B>),
+    ReceiptEvicted(EvictedReceipt),
+    MetricsQueryResult(FunderMetrics),
+    TokenCountersQueryResult(TokenCountersQueryResult),
 }
 
 impl<B> NodeControl<B>
@@ -228,6 +326,18 @@ where
             FunderOutgoingControl::ResponseReceived(response_received) => {
                 Some(NodeRecv::ResponseReceived(response_received))
             }
+            FunderOutgoingControl::FriendQueryResult(friend_query_result) => {
+                Some(NodeRecv::FriendQueryResult(friend_query_result))
+            }
+            FunderOutgoingControl::ReceiptEvicted(evicted_receipt) => {
+                Some(NodeRecv::ReceiptEvicted(evicted_receipt))
+            }
+            FunderOutgoingControl::MetricsQueryResult(funder_metrics) => {
+                Some(NodeRecv::MetricsQueryResult(funder_metrics))
+            }
+            FunderOutgoingControl::TokenCountersQueryResult(token_counters_query_result) => Some(
+                NodeRecv::TokenCountersQueryResult(token_counters_query_result),
+            ),
         }
     }
 
@@ -238,7 +348,11 @@ where
         while !predicate(&self.report) {
             match await!(self.recv()).unwrap() {
                 NodeRecv::ReportMutations(_) => {}
-                NodeRecv::ResponseReceived(_) => unreachable!(),
+                NodeRecv::ResponseReceived(_)
+                | NodeRecv::FriendQueryResult(_)
+                | NodeRecv::ReceiptEvicted(_)
+                | NodeRecv::MetricsQueryResult(_)
+                | NodeRecv::TokenCountersQueryResult(_) => unreachable!(),
             };
         }
     }
@@ -248,6 +362,26 @@ where
             match await!(self.recv())? {
                 NodeRecv::ReportMutations(_) => {}
                 NodeRecv::ResponseReceived(response_received) => return Some(response_received),
+                NodeRecv::FriendQueryResult(_)
+                | NodeRecv::ReceiptEvicted(_)
+                | NodeRecv::MetricsQueryResult(_)
+                | NodeRecv::TokenCountersQueryResult(_) => unreachable!(),
+            };
+        }
+    }
+
+    pub async fn query_metrics(&mut self) -> FunderMetrics {
+        let incoming_control_message =
+            FunderIncomingControl::new(Uid::from(&[32; UID_LEN]), FunderControl::QueryMetrics);
+        await!(self.send(incoming_control_message)).unwrap();
+        loop {
+            match await!(self.recv()).unwrap() {
+                NodeRecv::ReportMutations(_) => {}
+                NodeRecv::MetricsQueryResult(funder_metrics) => return funder_metrics,
+                NodeRecv::ResponseReceived(_)
+                | NodeRecv::FriendQueryResult(_)
+                | NodeRecv::ReceiptEvicted(_)
+                | NodeRecv::TokenCountersQueryResult(_) => unreachable!(),
             };
         }
     }
@@ -399,16 +533,169 @@ where
     }
 }
 
+/// Create a single funder node, with index `i`.
+/// Returns the node control, together with the node's incoming and outgoing communication
+/// channels.
+pub async fn create_node<S>(
+    i: u8,
+    mut spawner: S,
+) -> (
+    NodeControl<u32>,
+    mpsc::Sender<FunderIncomingComm<u32>>,
+    mpsc::Receiver<FunderOutgoingComm<u32>>,
+)
+where
+    S: Spawn + Clone + Send + 'static,
+{
+    let rng = DummyRandom::new(&[i]);
+    let pkcs8 = generate_pkcs8_key_pair(&rng);
+    let identity1 = SoftwareEd25519Identity::from_pkcs8(&pkcs8).unwrap();
+    let (requests_sender, identity_server) = create_identity(identity1);
+    let identity_client = IdentityClient::new(requests_sender);
+    spawner
+        .spawn(identity_server.then(|_| future::ready(())))
+        .unwrap();
+
+    let public_key = await!(identity_client.request_public_key()).unwrap();
+    let relays = vec![dummy_named_relay_address(i)];
+    let funder_state = FunderState::new(public_key.clone(), relays);
+    let ephemeral = Ephemeral::new();
+    let base_report = create_report(&funder_state, &ephemeral, TEST_PENDING_QUEUE_THRESHOLD);
+
+    // let report = create_report(&self.state, &self.ephemeral);
+    // self.add_outgoing_control(FunderOutgoingControl::Report(report));
+
+    let (db_request_sender, mut incoming_db_requests) = mpsc::channel(0);
+    let db_client = DatabaseClient::new(db_request_sender);
+
+    let fut_dispose_db_requests = async move {
+        // Read all incoming db requests:
+        while let Some(request) = await!(incoming_db_requests.next()) {
+            let _ = request.response_sender.send(());
+        }
+    };
+    spawner.spawn(fut_dispose_db_requests).unwrap();
+
+    let (send_control, incoming_control) = mpsc::channel(CHANNEL_SIZE);
+    let (control_sender, recv_control) = mpsc::channel(CHANNEL_SIZE);
+
+    let (send_comm, incoming_comm) = mpsc::channel(CHANNEL_SIZE);
+    let (comm_sender, recv_comm) = mpsc::channel(CHANNEL_SIZE);
+
+    let (send_timer_tick, timer_stream) = mpsc::channel::<()>(CHANNEL_SIZE);
+
+    let funder_config = FunderConfig {
+        max_operations_in_batch: TEST_MAX_OPERATIONS_IN_BATCH,
+        max_node_relays: TEST_MAX_NODE_RELAYS,
+        max_pending_user_requests: TEST_MAX_PENDING_USER_REQUESTS,
+        offline_grace_ticks: TEST_OFFLINE_GRACE_TICKS,
+        inconsistency_error_interval_ticks: TEST_INCONSISTENCY_ERROR_INTERVAL_TICKS,
+        max_ready_receipts: TEST_MAX_READY_RECEIPTS,
+        max_pending_requests: TEST_MAX_PENDING_REQUESTS,
+        flush_idle_ticks: TEST_FLUSH_IDLE_TICKS,
+        token_stall_ticks: TEST_TOKEN_STALL_TICKS,
+        request_timeout_ticks: TEST_REQUEST_TIMEOUT_TICKS,
+        pending_queue_threshold: TEST_PENDING_QUEUE_THRESHOLD,
+    };
+
+    let funder_fut = inner_funder_loop(
+        identity_client.clone(),
+        DummyRandom::new(&[i]),
+        incoming_control,
+        incoming_comm,
+        timer_stream,
+        control_sender,
+        comm_sender,
+        funder_state,
+        db_client,
+        funder_config,
+        None,
+    );
+
+    spawner
+        .spawn(funder_fut.then(|_| future::ready(())))
+        .unwrap();
+
+    /*
+    let base_report = match await!(recv_control.next()).unwrap() {
+        FunderOutgoingControl::Report(report) => report,
+        _ => unreachable!(),
+    };
+    */
+
+    let node_control = NodeControl {
+        public_key,
+        send_control,
+        recv_control,
+        send_timer_tick,
+        report: base_report,
+    };
+    (node_control, send_comm, recv_comm)
+}
+
+/// Controls the delivery of friend messages by the router. Used for testing.
+pub struct RouterControl {
+    send_command: mpsc::Sender<(RouterCommand, oneshot::Sender<()>)>,
+}
+
+impl RouterControl {
+    async fn send(&mut self, router_command: RouterCommand) {
+        let (ack_sender, ack_receiver) = oneshot::channel();
+        await!(self.send_command.send((router_command, ack_sender))).unwrap();
+        await!(ack_receiver).unwrap();
+    }
+
+    /// Hold all friend messages sent from `src_public_key` to `dest_public_key`,
+    /// until the link is released.
+    pub async fn hold_link<'a>(
+        &'a mut self,
+        src_public_key: &'a PublicKey,
+        dest_public_key: &'a PublicKey,
+    ) {
+        await!(self.send(RouterCommand::HoldLink((
+            src_public_key.clone(),
+            dest_public_key.clone()
+        ))));
+    }
+
+    /// Deliver all held friend messages sent from `src_public_key` to `dest_public_key`.
+    pub async fn release_link<'a>(
+        &'a mut self,
+        src_public_key: &'a PublicKey,
+        dest_public_key: &'a PublicKey,
+    ) {
+        await!(self.send(RouterCommand::ReleaseLink((
+            src_public_key.clone(),
+            dest_public_key.clone()
+        ))));
+    }
+}
+
 /// Create a few node_controls, together with a router connecting them all.
 /// This allows having a conversation between any two nodes.
 /// We use A = u32:
-pub async fn create_node_controls<S>(num_nodes: usize, mut spawner: S) -> Vec<NodeControl<u32>>
+pub async fn create_node_controls<S>(num_nodes: usize, spawner: S) -> Vec<NodeControl<u32>>
+where
+    S: Spawn + Clone + Send + 'static,
+{
+    let (node_controls, _router_control) =
+        await!(create_node_controls_with_router(num_nodes, spawner));
+    node_controls
+}
+
+/// Create a few node_controls, together with a router connecting them all.
+/// Returns also a RouterControl, allowing to hold messages between nodes.
+pub async fn create_node_controls_with_router<S>(
+    num_nodes: usize,
+    mut spawner: S,
+) -> (Vec<NodeControl<u32>>, RouterControl)
 where
     S: Spawn + Clone + Send + 'static,
 {
     let (mut send_new_node, recv_new_node) = mpsc::channel::<NewNode<u32>>(0);
+    let (send_command, recv_command) = mpsc::channel(0);
     spawner
-        .spawn(router(recv_new_node, spawner.clone()))
+        .spawn(router(recv_new_node, recv_command, spawner.clone()))
         .unwrap();
 
     // Avoid problems with casting to u8:
@@ -416,80 +703,16 @@ where
     let mut node_controls = Vec::new();
 
     for i in 0..num_nodes {
-        let rng = DummyRandom::new(&[i as u8]);
-        let pkcs8 = generate_pkcs8_key_pair(&rng);
-        let identity1 = SoftwareEd25519Identity::from_pkcs8(&pkcs8).unwrap();
-        let (requests_sender, identity_server) = create_identity(identity1);
-        let identity_client = IdentityClient::new(requests_sender);
-        spawner
-            .spawn(identity_server.then(|_| future::ready(())))
-            .unwrap();
-
-        let public_key = await!(identity_client.request_public_key()).unwrap();
-        let relays = vec![dummy_named_relay_address(i as u8)];
-        let funder_state = FunderState::new(public_key.clone(), relays);
-        let ephemeral = Ephemeral::new();
-        let base_report = create_report(&funder_state, &ephemeral);
-
-        // let report = create_report(&self.state, &self.ephemeral);
-        // self.add_outgoing_control(FunderOutgoingControl::Report(report));
-
-        let (db_request_sender, mut incoming_db_requests) = mpsc::channel(0);
-        let db_client = DatabaseClient::new(db_request_sender);
-
-        let fut_dispose_db_requests = async move {
-            // Read all incoming db requests:
-            while let Some(request) = await!(incoming_db_requests.next()) {
-                let _ = request.response_sender.send(());
-            }
-        };
-        spawner.spawn(fut_dispose_db_requests).unwrap();
-
-        let (send_control, incoming_control) = mpsc::channel(CHANNEL_SIZE);
-        let (control_sender, recv_control) = mpsc::channel(CHANNEL_SIZE);
-
-        let (send_comm, incoming_comm) = mpsc::channel(CHANNEL_SIZE);
-        let (comm_sender, recv_comm) = mpsc::channel(CHANNEL_SIZE);
-
-        let funder_fut = inner_funder_loop(
-            identity_client.clone(),
-            DummyRandom::new(&[i as u8]),
-            incoming_control,
-            incoming_comm,
-            control_sender,
-            comm_sender,
-            funder_state,
-            db_client,
-            TEST_MAX_NODE_RELAYS,
-            TEST_MAX_OPERATIONS_IN_BATCH,
-            TEST_MAX_PENDING_USER_REQUESTS,
-            None,
-        );
-
-        spawner
-            .spawn(funder_fut.then(|_| future::ready(())))
-            .unwrap();
-
-        /*
-        let base_report = match await!(recv_control.next()).unwrap() {
-            FunderOutgoingControl::Report(report) => report,
-            _ => unreachable!(),
-        };
-        */
+        let (node_control, send_comm, recv_comm) = await!(create_node(i as u8, spawner.clone()));
 
         let new_node = NewNode {
-            public_key: public_key.clone(),
+            public_key: node_control.public_key.clone(),
             comm_in: recv_comm,
             comm_out: send_comm,
         };
         await!(send_new_node.send(new_node)).unwrap();
 
-        node_controls.push(NodeControl {
-            public_key: await!(identity_client.request_public_key()).unwrap(),
-            send_control,
-            recv_control,
-            report: base_report,
-        });
+        node_controls.push(node_control);
     }
-    node_controls
+    (node_controls, RouterControl { send_command })
 }
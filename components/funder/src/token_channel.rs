@@ -2,13 +2,14 @@ use std::cmp::Ordering;
 use std::convert::TryFrom;
 
 use common::canonical_serialize::CanonicalSerialize;
+use common::safe_arithmetic::SafeSignedArithmetic;
 
 use crypto::crypto_rand::{RandValue, RAND_VALUE_LEN};
 use crypto::hash::sha_512_256;
 use crypto::identity::{compare_public_key, PublicKey, Signature, PUBLIC_KEY_LEN, SIGNATURE_LEN};
 
 use proto::app_server::messages::RelayAddress;
-use proto::funder::messages::{FriendTcOp, MoveToken};
+use proto::funder::messages::{FriendTcOp, MoveToken, MoveTokenRequest};
 use proto::funder::signature_buff::verify_move_token;
 
 use crate::mutual_credit::incoming::{
@@ -32,27 +33,27 @@ pub enum TcMutation<B> {
     SetDirection(SetDirection<B>),
 }
 
-#[derive(Clone, Serialize, Deserialize, Debug)]
+#[derive(Eq, PartialEq, Clone, Serialize, Deserialize, Debug)]
 pub struct TcOutgoing<B> {
     pub mutual_credit: MutualCredit,
     pub move_token_out: MoveToken<B>,
     pub opt_prev_move_token_in: Option<MoveTokenHashed>,
 }
 
-#[derive(Clone, Serialize, Deserialize, Debug)]
+#[derive(Eq, PartialEq, Clone, Serialize, Deserialize, Debug)]
 pub struct TcIncoming {
     pub mutual_credit: MutualCredit,
     pub move_token_in: MoveTokenHashed,
 }
 
 #[allow(clippy::large_enum_variant)]
-#[derive(Clone, Serialize, Deserialize, Debug)]
+#[derive(Eq, PartialEq, Clone, Serialize, Deserialize, Debug)]
 pub enum TcDirection<B> {
     Incoming(TcIncoming),
     Outgoing(TcOutgoing<B>),
 }
 
-#[derive(Clone, Serialize, Deserialize, Debug)]
+#[derive(Eq, PartialEq, Clone, Serialize, Deserialize, Debug)]
 pub struct TokenChannel<B> {
     direction: TcDirection<B>,
 }
@@ -69,6 +70,32 @@ pub enum ReceiveMoveTokenError {
     TooManyOperations,
 }
 
+/// The relation between a move token and the move token that preceded it in a token channel.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ChainRelation {
+    /// The next move token continues the chain after the previous one.
+    Extends,
+    /// The next move token is the previous move token, received again.
+    Duplicate,
+    /// The next move token is the one that came before the previous move token. This means that
+    /// the remote side did not receive the previous move token, and we should retransmit it.
+    Retransmit,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ChainError {
+    /// The two move tokens are not linked.
+    Inconsistency,
+}
+
+#[derive(Debug)]
+pub enum VerifyMoveTokenError {
+    PublicKeyMismatch,
+    InvalidSignature,
+    InvalidTransaction(ProcessTransListError),
+    InvalidStatedBalance,
+}
+
 #[derive(Debug)]
 pub struct MoveTokenReceived<B> {
     pub incoming_messages: Vec<IncomingMessage>,
@@ -137,14 +164,113 @@ fn initial_move_token<B>(
     }
 }
 
+/// Check how the move token `next` is linked to the move token `prev` (Given in hashed form).
+/// `opt_prev_old_token` is the old_token of `prev`, if it is known. Without it a retransmission
+/// can not be detected.
+/// Only the chain linking rules (old_token and new_token) are checked here. Signatures and
+/// counters are verified separately.
+pub fn validate_chain_link<B>(
+    prev: &MoveTokenHashed,
+    opt_prev_old_token: Option<&Signature>,
+    next: &MoveToken<B>,
+) -> Result<ChainRelation, ChainError>
+where
+    B: CanonicalSerialize,
+{
+    // We compare the whole move token and not just the signature (new_token), because the
+    // genesis move token does not have a valid signature.
+    if prev == &create_hashed(next) {
+        Ok(ChainRelation::Duplicate)
+    } else if next.old_token == prev.new_token {
+        Ok(ChainRelation::Extends)
+    } else if opt_prev_old_token == Some(&next.new_token) {
+        Ok(ChainRelation::Retransmit)
+    } else {
+        Err(ChainError::Inconsistency)
+    }
+}
+
+/// Verify a captured move token, without participating in the token channel.
+/// `prev_mutual_credit` is the mutual credit state of the receiving side, right before the move
+/// token was received.
+///
+/// The signature is checked against `signer_public_key` (This also covers the hash of the
+/// operations), and the operations are applied to a copy of `prev_mutual_credit` to make sure
+/// that the stated balance and pending debts match the result.
+/// Useful for dispute resolution.
+pub fn verify_move_token_standalone<B>(
+    prev_mutual_credit: &MutualCredit,
+    move_token: &MoveToken<B>,
+    signer_public_key: &PublicKey,
+) -> Result<(), VerifyMoveTokenError>
+where
+    B: CanonicalSerialize,
+{
+    let idents = &prev_mutual_credit.state().idents;
+    if &move_token.local_public_key != signer_public_key
+        || move_token.local_public_key != idents.remote_public_key
+        || move_token.remote_public_key != idents.local_public_key
+    {
+        return Err(VerifyMoveTokenError::PublicKeyMismatch);
+    }
+
+    if !verify_move_token(move_token, signer_public_key) {
+        return Err(VerifyMoveTokenError::InvalidSignature);
+    }
+
+    let mut mutual_credit = prev_mutual_credit.clone();
+    process_operations_list(&mut mutual_credit, move_token.operations.clone())
+        .map_err(VerifyMoveTokenError::InvalidTransaction)?;
+
+    // The stated balance is from the point of view of the sender:
+    let balance = &mutual_credit.state().balance;
+    if balance.balance != -move_token.balance
+        || balance.local_pending_debt != move_token.remote_pending_debt
+        || balance.remote_pending_debt != move_token.local_pending_debt
+    {
+        return Err(VerifyMoveTokenError::InvalidStatedBalance);
+    }
+
+    Ok(())
+}
+
 impl<B> TokenChannel<B>
 where
     B: Clone + CanonicalSerialize,
 {
     pub fn new(local_public_key: &PublicKey, remote_public_key: &PublicKey, balance: i128) -> Self {
+        let is_outgoing = TokenChannel::<B>::is_initiator(local_public_key, remote_public_key);
+        TokenChannel::new_with_direction(local_public_key, remote_public_key, balance, is_outgoing)
+    }
+
+    /// Is the local side the first sender of a new token channel?
+    /// The side with the lower public key (See `compare_public_key`) is the first sender.
+    /// For two different public keys, exactly one side is the initiator.
+    pub fn is_initiator(local_public_key: &PublicKey, remote_public_key: &PublicKey) -> bool {
+        compare_public_key(local_public_key, remote_public_key) == Ordering::Less
+    }
+
+    /// Create a token channel with a given balance and initial direction, regardless of the order
+    /// of the public keys. Useful for setting up a channel in a specific state in tests.
+    #[cfg(test)]
+    pub fn new_for_test(
+        local_public_key: &PublicKey,
+        remote_public_key: &PublicKey,
+        balance: i128,
+        is_outgoing: bool,
+    ) -> Self {
+        TokenChannel::new_with_direction(local_public_key, remote_public_key, balance, is_outgoing)
+    }
+
+    fn new_with_direction(
+        local_public_key: &PublicKey,
+        remote_public_key: &PublicKey,
+        balance: i128,
+        is_outgoing: bool,
+    ) -> Self {
         let mutual_credit = MutualCredit::new(&local_public_key, &remote_public_key, balance);
 
-        if compare_public_key(&local_public_key, &remote_public_key) == Ordering::Less {
+        if is_outgoing {
             // We are the first sender
             let tc_outgoing = TcOutgoing {
                 mutual_credit,
@@ -213,6 +339,23 @@ where
         }
     }
 
+    /// Independently compute the balance_for_reset we expect the remote side to state in its
+    /// reset terms, according to our own accounting.
+    ///
+    /// The remote side keeps the negation of our balance, and its remote pending debt is our local
+    /// pending debt. If there are no pending debts, this is exactly the negation of our own
+    /// balance_for_reset.
+    ///
+    /// Returns None on overflow. No valid reset terms can state such a balance.
+    pub fn expected_balance_for_reset(&self) -> Option<i128> {
+        let mutual_credit = self.get_mutual_credit();
+        let local_pending_debt = mutual_credit.state().balance.local_pending_debt;
+        mutual_credit
+            .net_balance()
+            .checked_neg()
+            .and_then(|neg_balance| neg_balance.checked_add_unsigned(local_pending_debt))
+    }
+
     pub fn get_remote_max_debt(&self) -> u128 {
         self.get_mutual_credit().state().balance.remote_max_debt
     }
@@ -267,6 +410,21 @@ where
         }
     }
 
+    /// Get the outgoing move token request, to be sent (or resent) to the remote side.
+    /// Returns None if the token channel is incoming (We don't have an outgoing move token).
+    pub fn get_outgoing_move_token_request(
+        &self,
+        token_wanted: bool,
+    ) -> Option<MoveTokenRequest<B>> {
+        match &self.direction {
+            TcDirection::Incoming(_) => None,
+            TcDirection::Outgoing(tc_outgoing) => Some(MoveTokenRequest {
+                friend_move_token: tc_outgoing.create_outgoing_move_token(),
+                token_wanted,
+            }),
+        }
+    }
+
     pub fn get_inconsistency_counter(&self) -> u64 {
         match &self.direction {
             TcDirection::Incoming(tc_incoming) => tc_incoming.move_token_in.inconsistency_counter,
@@ -292,6 +450,13 @@ where
         &self,
         new_move_token: MoveToken<B>,
     ) -> Result<ReceiveMoveTokenOutput<B>, ReceiveMoveTokenError> {
+        // Move tokens are bound to the current inconsistency counter. This makes sure that move
+        // tokens from before a reset can not be replayed after the reset, even if their move token
+        // counter seems valid.
+        if new_move_token.inconsistency_counter != self.get_inconsistency_counter() {
+            return Err(ReceiveMoveTokenError::InvalidInconsistencyCounter);
+        }
+
         match &self.direction {
             TcDirection::Incoming(tc_incoming) => tc_incoming.handle_incoming(new_move_token),
             TcDirection::Outgoing(tc_outgoing) => tc_outgoing.handle_incoming(new_move_token),
@@ -308,14 +473,13 @@ impl TcIncoming {
     where
         B: CanonicalSerialize,
     {
-        // We compare the whole move token message and not just the signature (new_token)
-        // because we don't check the signature in this flow.
-        if self.move_token_in == create_hashed(&new_move_token) {
-            // Duplicate
-            Ok(ReceiveMoveTokenOutput::Duplicate)
-        } else {
-            // Inconsistency
-            Err(ReceiveMoveTokenError::ChainInconsistency)
+        // We don't check the signature in this flow, so only an exact duplicate is accepted:
+        match validate_chain_link(&self.move_token_in, None, &new_move_token) {
+            Ok(ChainRelation::Duplicate) => Ok(ReceiveMoveTokenOutput::Duplicate),
+            // The remote side can not send two move tokens in a row:
+            Ok(ChainRelation::Extends)
+            | Ok(ChainRelation::Retransmit)
+            | Err(ChainError::Inconsistency) => Err(ReceiveMoveTokenError::ChainInconsistency),
         }
     }
 
@@ -334,7 +498,7 @@ impl TcIncoming {
             self.move_token_in.local_public_key.clone(),
             self.move_token_in.inconsistency_counter,
             self.move_token_in.move_token_counter.wrapping_add(1),
-            self.mutual_credit.state().balance.balance,
+            self.mutual_credit.net_balance(),
             self.mutual_credit.state().balance.local_pending_debt,
             self.mutual_credit.state().balance.remote_pending_debt,
             rand_nonce,
@@ -364,16 +528,24 @@ where
             return Err(ReceiveMoveTokenError::ChainInconsistency);
         }
 
-        if new_move_token.old_token == self.move_token_out.new_token {
-            self.handle_incoming_token_match(new_move_token)
-        // self.outgoing_to_incoming(friend_move_token, new_move_token)
-        } else if self.move_token_out.old_token == new_move_token.new_token {
-            // We should retransmit our move token message to the remote side.
-            Ok(ReceiveMoveTokenOutput::RetransmitOutgoing(
-                self.move_token_out.clone(),
-            ))
-        } else {
-            Err(ReceiveMoveTokenError::ChainInconsistency)
+        let move_token_out_hashed = create_hashed(&self.move_token_out);
+        let relation = validate_chain_link(
+            &move_token_out_hashed,
+            Some(&self.move_token_out.old_token),
+            &new_move_token,
+        );
+        match relation {
+            Ok(ChainRelation::Extends) => self.handle_incoming_token_match(new_move_token),
+            Ok(ChainRelation::Retransmit) => {
+                // We should retransmit our move token message to the remote side.
+                Ok(ReceiveMoveTokenOutput::RetransmitOutgoing(
+                    self.move_token_out.clone(),
+                ))
+            }
+            // We can not receive our own outgoing move token:
+            Ok(ChainRelation::Duplicate) | Err(ChainError::Inconsistency) => {
+                Err(ReceiveMoveTokenError::ChainInconsistency)
+            }
         }
     }
 
@@ -405,6 +577,8 @@ where
             return Err(ReceiveMoveTokenError::InvalidMoveTokenCounter);
         }
 
+        // We keep our own mutual credit unchanged. This clone is cheap, because the pending
+        // requests are kept in immutable data structures.
         let mut mutual_credit = self.mutual_credit.clone();
         let res = process_operations_list(&mut mutual_credit, new_move_token.operations.clone());
 
@@ -416,9 +590,6 @@ where
                 let mut incoming_messages = Vec::new();
                 let mut mutations = Vec::new();
 
-                // We apply mutations on this token channel, to verify stated balance values
-                let mut check_mutual_credit = self.mutual_credit.clone();
-
                 let mut final_remote_requests: bool = initial_remote_requests;
                 for output in outputs {
                     let ProcessOperationOutput {
@@ -430,7 +601,6 @@ where
                         incoming_messages.push(funds);
                     }
                     for mc_mutation in mc_mutations {
-                        check_mutual_credit.mutate(&mc_mutation);
                         if let McMutation::SetRemoteRequestsStatus(requests_status) = &mc_mutation {
                             final_remote_requests = requests_status.is_open();
                         }
@@ -438,14 +608,16 @@ where
                     }
                 }
 
-                // Verify stated balances:
-                let check_balance = &check_mutual_credit.state().balance;
+                // Verify stated balances.
+                // mutual_credit already has all the mutations applied:
+                let check_balance = &mutual_credit.state().balance;
                 if check_balance.balance != -new_move_token.balance
                     || check_balance.local_pending_debt != new_move_token.remote_pending_debt
                     || check_balance.remote_pending_debt != new_move_token.local_pending_debt
                 {
                     return Err(ReceiveMoveTokenError::InvalidStatedBalance);
                 }
+                debug_assert_eq!(mutual_credit.check_invariants(), Ok(()));
 
                 mutations.push(TcMutation::SetDirection(SetDirection::Incoming(
                     create_hashed(&new_move_token),
@@ -455,7 +627,7 @@ where
                     incoming_messages,
                     mutations,
                     // Were the remote requests initially open and now it is closed?
-                    remote_requests_closed: final_remote_requests && !initial_remote_requests,
+                    remote_requests_closed: initial_remote_requests && !final_remote_requests,
                     opt_local_relays: new_move_token.opt_local_relays.clone(),
                 };
 
@@ -479,8 +651,11 @@ mod tests {
     use crypto::identity::{generate_pkcs8_key_pair, SoftwareEd25519Identity};
     use crypto::test_utils::DummyRandom;
 
+    use proto::funder::messages::RequestsStatus;
     use proto::funder::signature_buff::move_token_signature_buff;
 
+    use crate::tests::utils::dummy_relay_address;
+
     /// A helper function to sign an UnsignedMoveToken using an identity:
     fn dummy_sign_move_token<B, I>(
         unsigned_move_token: UnsignedMoveToken<B>,
@@ -547,6 +722,119 @@ mod tests {
         assert!(tc_outgoing.opt_prev_move_token_in.is_none());
     }
 
+    #[test]
+    fn test_is_initiator() {
+        for i in 0..16u8 {
+            let pk_a = PublicKey::from(&[i; PUBLIC_KEY_LEN]);
+            let pk_b = PublicKey::from(&[i.wrapping_add(0x80); PUBLIC_KEY_LEN]);
+
+            // Exactly one side is the initiator:
+            let is_a_initiator = TokenChannel::<u32>::is_initiator(&pk_a, &pk_b);
+            let is_b_initiator = TokenChannel::<u32>::is_initiator(&pk_b, &pk_a);
+            assert!(is_a_initiator ^ is_b_initiator);
+
+            // Each side, constructed independently, agrees on the initial direction:
+            let token_channel_a_b = TokenChannel::<u32>::new(&pk_a, &pk_b, 0i128);
+            let token_channel_b_a = TokenChannel::<u32>::new(&pk_b, &pk_a, 0i128);
+            assert_eq!(token_channel_a_b.is_outgoing(), is_a_initiator);
+            assert_eq!(token_channel_b_a.is_outgoing(), is_b_initiator);
+            match (
+                token_channel_a_b.get_direction(),
+                token_channel_b_a.get_direction(),
+            ) {
+                (TcDirection::Outgoing(_), TcDirection::Incoming(_))
+                | (TcDirection::Incoming(_), TcDirection::Outgoing(_)) => {}
+                _ => unreachable!(),
+            };
+        }
+    }
+
+    #[test]
+    fn test_get_outgoing_move_token_request() {
+        let pk_a = PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]);
+        let pk_b = PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]);
+        let out_tc = TokenChannel::<u32>::new_for_test(&pk_a, &pk_b, 0i128, true);
+        let in_tc = TokenChannel::<u32>::new_for_test(&pk_b, &pk_a, 0i128, false);
+
+        assert!(in_tc.get_outgoing_move_token_request(true).is_none());
+        assert!(in_tc.get_outgoing_move_token_request(false).is_none());
+
+        let move_token_request = out_tc.get_outgoing_move_token_request(true).unwrap();
+        assert!(move_token_request.token_wanted);
+        let tc_outgoing = match out_tc.get_direction() {
+            TcDirection::Outgoing(tc_outgoing) => tc_outgoing,
+            TcDirection::Incoming(_) => unreachable!(),
+        };
+        assert_eq!(
+            move_token_request.friend_move_token,
+            tc_outgoing.create_outgoing_move_token()
+        );
+        assert!(
+            !out_tc
+                .get_outgoing_move_token_request(false)
+                .unwrap()
+                .token_wanted
+        );
+    }
+
+    #[test]
+    fn test_expected_balance_for_reset() {
+        let pk_a = PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]);
+        let pk_b = PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]);
+        let mut token_channel_a_b = TokenChannel::<u32>::new(&pk_a, &pk_b, 10i128);
+        let mut token_channel_b_a = TokenChannel::<u32>::new(&pk_b, &pk_a, -10i128);
+
+        // Without pending debts, our reset balance is the negation of theirs:
+        let balance_for_reset_a = token_channel_a_b.get_mutual_credit().balance_for_reset();
+        let balance_for_reset_b = token_channel_b_a.get_mutual_credit().balance_for_reset();
+        assert_eq!(balance_for_reset_a, 10);
+        assert_eq!(balance_for_reset_a, -balance_for_reset_b);
+        assert_eq!(
+            token_channel_a_b.expected_balance_for_reset(),
+            Some(balance_for_reset_b)
+        );
+        assert_eq!(
+            token_channel_b_a.expected_balance_for_reset(),
+            Some(balance_for_reset_a)
+        );
+
+        // A has 3 frozen credits for a request it sent to B:
+        token_channel_a_b.mutate(&TcMutation::McMutation(McMutation::SetLocalPendingDebt(3)));
+        token_channel_b_a.mutate(&TcMutation::McMutation(McMutation::SetRemotePendingDebt(3)));
+
+        let balance_for_reset_a = token_channel_a_b.get_mutual_credit().balance_for_reset();
+        let balance_for_reset_b = token_channel_b_a.get_mutual_credit().balance_for_reset();
+        assert_eq!(balance_for_reset_a, 10);
+        assert_eq!(balance_for_reset_b, -7);
+        assert_eq!(
+            token_channel_a_b.expected_balance_for_reset(),
+            Some(balance_for_reset_b)
+        );
+        assert_eq!(
+            token_channel_b_a.expected_balance_for_reset(),
+            Some(balance_for_reset_a)
+        );
+    }
+
+    #[test]
+    fn test_expected_balance_for_reset_overflow() {
+        let pk_a = PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]);
+        let pk_b = PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]);
+
+        // The negation of i128::min_value() does not fit in an i128:
+        let token_channel = TokenChannel::<u32>::new(&pk_a, &pk_b, i128::min_value());
+        assert_eq!(token_channel.expected_balance_for_reset(), None);
+
+        // The negated balance fits, but adding the local pending debt overflows:
+        let mut token_channel = TokenChannel::<u32>::new(&pk_a, &pk_b, -i128::max_value());
+        assert_eq!(
+            token_channel.expected_balance_for_reset(),
+            Some(i128::max_value())
+        );
+        token_channel.mutate(&TcMutation::McMutation(McMutation::SetLocalPendingDebt(1)));
+        assert_eq!(token_channel.expected_balance_for_reset(), None);
+    }
+
     /// Sort the two identity client.
     /// The result will be a pair where the first is initially configured to have outgoing message,
     /// and the second is initially configured to have incoming message.
@@ -682,6 +970,340 @@ mod tests {
         set_remote_max_debt21(&identity2, &identity1, &mut tc2, &mut tc1);
     }
 
+    #[test]
+    fn test_new_for_test() {
+        let pk_a = PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]);
+        let pk_b = PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]);
+
+        for &a_outgoing in &[true, false] {
+            let tc_a_b = TokenChannel::<u32>::new_for_test(&pk_a, &pk_b, 7i128, a_outgoing);
+            let tc_b_a = TokenChannel::<u32>::new_for_test(&pk_b, &pk_a, -7i128, !a_outgoing);
+            assert_eq!(tc_a_b.is_outgoing(), a_outgoing);
+            assert_eq!(tc_b_a.is_outgoing(), !a_outgoing);
+            assert_eq!(tc_a_b.get_mutual_credit().state().balance.balance, 7);
+            assert_eq!(tc_b_a.get_mutual_credit().state().balance.balance, -7);
+
+            // Both sides agree about the last move token:
+            let (out_tc, in_tc) = if a_outgoing {
+                (&tc_a_b, &tc_b_a)
+            } else {
+                (&tc_b_a, &tc_a_b)
+            };
+            let out_hashed = match out_tc.get_direction() {
+                TcDirection::Outgoing(tc_outgoing) => create_hashed(&tc_outgoing.move_token_out),
+                TcDirection::Incoming(_) => unreachable!(),
+            };
+            assert_eq!(
+                in_tc.get_last_incoming_move_token_hashed(),
+                Some(&out_hashed)
+            );
+        }
+    }
+
+    #[test]
+    fn test_simulate_receive_move_token_local_relays() {
+        let rng1 = DummyRandom::new(&[1u8]);
+        let pkcs8 = generate_pkcs8_key_pair(&rng1);
+        let identity1 = SoftwareEd25519Identity::from_pkcs8(&pkcs8).unwrap();
+
+        let rng2 = DummyRandom::new(&[2u8]);
+        let pkcs8 = generate_pkcs8_key_pair(&rng2);
+        let identity2 = SoftwareEd25519Identity::from_pkcs8(&pkcs8).unwrap();
+
+        let pk1 = identity1.get_public_key();
+        let pk2 = identity2.get_public_key();
+        let mut tc1 = TokenChannel::<u32>::new_for_test(&pk1, &pk2, 0i128, true);
+        let tc2 = TokenChannel::<u32>::new_for_test(&pk2, &pk1, 0i128, false);
+
+        // tc2 sends a move token with its new relays to tc1:
+        let tc2_incoming = match tc2.get_direction() {
+            TcDirection::Incoming(tc2_incoming) => tc2_incoming,
+            TcDirection::Outgoing(_) => unreachable!(),
+        };
+        let local_relays = vec![dummy_relay_address(3), dummy_relay_address(4)];
+        let rand_nonce = RandValue::from(&[6; RAND_VALUE_LEN]);
+        let unsigned_move_token = tc2_incoming.create_unsigned_move_token(
+            Vec::new(),
+            Some(local_relays.clone()),
+            rand_nonce,
+        );
+        let friend_move_token = dummy_sign_move_token(unsigned_move_token, &identity2);
+
+        // tc1 receives the relays of the remote side together with the move token:
+        let move_token_received = match tc1.simulate_receive_move_token(friend_move_token) {
+            Ok(ReceiveMoveTokenOutput::Received(move_token_received)) => move_token_received,
+            _ => unreachable!(),
+        };
+        assert_eq!(move_token_received.opt_local_relays, Some(local_relays));
+
+        for tc_mutation in &move_token_received.mutations {
+            tc1.mutate(tc_mutation);
+        }
+        assert!(!tc1.is_outgoing());
+    }
+
+    #[test]
+    fn test_simulate_receive_move_token_remote_requests_closed() {
+        let rng1 = DummyRandom::new(&[1u8]);
+        let pkcs8 = generate_pkcs8_key_pair(&rng1);
+        let identity1 = SoftwareEd25519Identity::from_pkcs8(&pkcs8).unwrap();
+
+        let rng2 = DummyRandom::new(&[2u8]);
+        let pkcs8 = generate_pkcs8_key_pair(&rng2);
+        let identity2 = SoftwareEd25519Identity::from_pkcs8(&pkcs8).unwrap();
+
+        let pk1 = identity1.get_public_key();
+        let pk2 = identity2.get_public_key();
+        let mut tc1 = TokenChannel::<u32>::new_for_test(&pk1, &pk2, 0i128, true);
+        let mut tc2 = TokenChannel::<u32>::new_for_test(&pk2, &pk1, 0i128, false);
+
+        // tc2 has previously opened its requests to tc1:
+        tc1.mutate(&TcMutation::McMutation(
+            McMutation::SetRemoteRequestsStatus(RequestsStatus::Open),
+        ));
+        tc2.mutate(&TcMutation::McMutation(McMutation::SetLocalRequestsStatus(
+            RequestsStatus::Open,
+        )));
+
+        // tc2 closes its requests:
+        let tc2_incoming = match tc2.get_direction() {
+            TcDirection::Incoming(tc2_incoming) => tc2_incoming,
+            TcDirection::Outgoing(_) => unreachable!(),
+        };
+        let friend_tc_op = FriendTcOp::DisableRequests;
+        let mut outgoing_mc = tc2_incoming.begin_outgoing_move_token();
+        outgoing_mc.queue_operation(&friend_tc_op).unwrap();
+        let rand_nonce = RandValue::from(&[6; RAND_VALUE_LEN]);
+        let unsigned_move_token =
+            tc2_incoming.create_unsigned_move_token(vec![friend_tc_op], None, rand_nonce);
+        let friend_move_token = dummy_sign_move_token(unsigned_move_token, &identity2);
+
+        let move_token_received = match tc1.simulate_receive_move_token(friend_move_token) {
+            Ok(ReceiveMoveTokenOutput::Received(move_token_received)) => move_token_received,
+            _ => unreachable!(),
+        };
+        assert!(move_token_received.remote_requests_closed);
+
+        for tc_mutation in &move_token_received.mutations {
+            tc1.mutate(tc_mutation);
+        }
+        assert_eq!(
+            tc1.get_mutual_credit().state().requests_status.remote,
+            RequestsStatus::Closed
+        );
+    }
+
+    #[test]
+    fn test_simulate_receive_move_token_replay_after_reset() {
+        let rng1 = DummyRandom::new(&[1u8]);
+        let pkcs8 = generate_pkcs8_key_pair(&rng1);
+        let identity1 = SoftwareEd25519Identity::from_pkcs8(&pkcs8).unwrap();
+
+        let rng2 = DummyRandom::new(&[2u8]);
+        let pkcs8 = generate_pkcs8_key_pair(&rng2);
+        let identity2 = SoftwareEd25519Identity::from_pkcs8(&pkcs8).unwrap();
+
+        let pk1 = identity1.get_public_key();
+        let pk2 = identity2.get_public_key();
+        let tc1 = TokenChannel::<u32>::new_for_test(&pk1, &pk2, 0i128, true);
+        let tc2 = TokenChannel::<u32>::new_for_test(&pk2, &pk1, 0i128, false);
+
+        // tc2 creates a move token before the reset:
+        let tc2_incoming = match tc2.get_direction() {
+            TcDirection::Incoming(tc2_incoming) => tc2_incoming,
+            TcDirection::Outgoing(_) => unreachable!(),
+        };
+        let rand_nonce = RandValue::from(&[7; RAND_VALUE_LEN]);
+        let unsigned_move_token =
+            tc2_incoming.create_unsigned_move_token(Vec::new(), None, rand_nonce);
+        let pre_reset_move_token = dummy_sign_move_token(unsigned_move_token, &identity2);
+        assert!(tc1
+            .simulate_receive_move_token(pre_reset_move_token.clone())
+            .is_ok());
+
+        // tc1 resets the channel, according to the reset terms of tc2:
+        let remote_reset_token = Signature::from(&[3; SIGNATURE_LEN]);
+        let inconsistency_counter = tc1.get_inconsistency_counter().wrapping_add(1);
+        let rand_nonce = RandValue::from(&[8; RAND_VALUE_LEN]);
+        let u_reset_move_token = create_unsigned_move_token(
+            Vec::new(),
+            None,
+            remote_reset_token,
+            pk1.clone(),
+            pk2.clone(),
+            inconsistency_counter,
+            0,
+            0,
+            0,
+            0,
+            rand_nonce,
+        );
+        let reset_move_token = dummy_sign_move_token(u_reset_move_token, &identity1);
+        let tc1 = TokenChannel::new_from_local_reset(&pk1, &pk2, &reset_move_token, 0, None);
+        let tc2 = TokenChannel::new_from_remote_reset(&pk2, &pk1, &reset_move_token, 0);
+
+        // Replaying the pre-reset move token is rejected by both sides:
+        match tc1.simulate_receive_move_token(pre_reset_move_token.clone()) {
+            Err(ReceiveMoveTokenError::InvalidInconsistencyCounter) => {}
+            _ => unreachable!(),
+        };
+        match tc2.simulate_receive_move_token(pre_reset_move_token.clone()) {
+            Err(ReceiveMoveTokenError::InvalidInconsistencyCounter) => {}
+            _ => unreachable!(),
+        };
+
+        // A pre-reset counter is rejected even if the move token continues the new chain:
+        let rand_nonce = RandValue::from(&[9; RAND_VALUE_LEN]);
+        let u_move_token = create_unsigned_move_token(
+            Vec::new(),
+            None,
+            reset_move_token.new_token.clone(),
+            pk2.clone(),
+            pk1.clone(),
+            pre_reset_move_token.inconsistency_counter,
+            1,
+            0,
+            0,
+            0,
+            rand_nonce,
+        );
+        let move_token = dummy_sign_move_token(u_move_token, &identity2);
+        match tc1.simulate_receive_move_token(move_token) {
+            Err(ReceiveMoveTokenError::InvalidInconsistencyCounter) => {}
+            _ => unreachable!(),
+        };
+
+        // A move token with the new inconsistency counter is accepted:
+        let tc2_incoming = match tc2.get_direction() {
+            TcDirection::Incoming(tc2_incoming) => tc2_incoming,
+            TcDirection::Outgoing(_) => unreachable!(),
+        };
+        let rand_nonce = RandValue::from(&[10; RAND_VALUE_LEN]);
+        let unsigned_move_token =
+            tc2_incoming.create_unsigned_move_token(Vec::new(), None, rand_nonce);
+        let move_token = dummy_sign_move_token(unsigned_move_token, &identity2);
+        match tc1.simulate_receive_move_token(move_token) {
+            Ok(ReceiveMoveTokenOutput::Received(_)) => {}
+            _ => unreachable!(),
+        };
+    }
+
+    /// Create a move token with the given tokens, for testing chain links.
+    fn dummy_chain_move_token(old_token: u8, new_token: u8) -> MoveToken<u32> {
+        MoveToken {
+            operations: Vec::new(),
+            opt_local_relays: None,
+            old_token: Signature::from(&[old_token; SIGNATURE_LEN]),
+            local_public_key: PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]),
+            remote_public_key: PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]),
+            inconsistency_counter: 0,
+            move_token_counter: 0,
+            balance: 0,
+            local_pending_debt: 0,
+            remote_pending_debt: 0,
+            rand_nonce: RandValue::from(&[0; RAND_VALUE_LEN]),
+            new_token: Signature::from(&[new_token; SIGNATURE_LEN]),
+        }
+    }
+
+    #[test]
+    fn test_validate_chain_link() {
+        let prev = dummy_chain_move_token(1, 2);
+        let prev_hashed = create_hashed(&prev);
+        let prev_old_token = Some(&prev.old_token);
+
+        assert_eq!(
+            validate_chain_link(&prev_hashed, prev_old_token, &dummy_chain_move_token(2, 3)),
+            Ok(ChainRelation::Extends)
+        );
+        assert_eq!(
+            validate_chain_link(&prev_hashed, prev_old_token, &prev.clone()),
+            Ok(ChainRelation::Duplicate)
+        );
+        assert_eq!(
+            validate_chain_link(&prev_hashed, prev_old_token, &dummy_chain_move_token(0, 1)),
+            Ok(ChainRelation::Retransmit)
+        );
+
+        // A retransmission can not be detected without the old token:
+        assert_eq!(
+            validate_chain_link(&prev_hashed, None, &dummy_chain_move_token(0, 1)),
+            Err(ChainError::Inconsistency)
+        );
+
+        // Same tokens, but different content:
+        let mut modified = prev.clone();
+        modified.balance = 5;
+        assert_eq!(
+            validate_chain_link(&prev_hashed, prev_old_token, &modified),
+            Err(ChainError::Inconsistency)
+        );
+
+        // Unrelated move token:
+        assert_eq!(
+            validate_chain_link(&prev_hashed, prev_old_token, &dummy_chain_move_token(4, 5)),
+            Err(ChainError::Inconsistency)
+        );
+    }
+
+    #[test]
+    fn test_verify_move_token_standalone() {
+        let rng1 = DummyRandom::new(&[1u8]);
+        let pkcs8 = generate_pkcs8_key_pair(&rng1);
+        let identity1 = SoftwareEd25519Identity::from_pkcs8(&pkcs8).unwrap();
+
+        let rng2 = DummyRandom::new(&[2u8]);
+        let pkcs8 = generate_pkcs8_key_pair(&rng2);
+        let identity2 = SoftwareEd25519Identity::from_pkcs8(&pkcs8).unwrap();
+
+        let pk1 = identity1.get_public_key();
+        let pk2 = identity2.get_public_key();
+
+        // The mutual credit of the receiving side (pk2), before the move token was received:
+        let prev_mutual_credit = MutualCredit::new(&pk2, &pk1, 10);
+
+        // pk1 sends a move token to pk2. Note that the balance is stated from the point of view
+        // of pk1:
+        let unsigned_move_token = create_unsigned_move_token::<u32>(
+            vec![FriendTcOp::SetRemoteMaxDebt(100)],
+            None,
+            Signature::from(&[0; SIGNATURE_LEN]),
+            pk1.clone(),
+            pk2.clone(),
+            0,
+            1,
+            -10,
+            0,
+            0,
+            RandValue::from(&[1; RAND_VALUE_LEN]),
+        );
+        let move_token = dummy_sign_move_token(unsigned_move_token.clone(), &identity1);
+        verify_move_token_standalone(&prev_mutual_credit, &move_token, &pk1).unwrap();
+
+        // Signed by the wrong side:
+        match verify_move_token_standalone(&prev_mutual_credit, &move_token, &pk2) {
+            Err(VerifyMoveTokenError::PublicKeyMismatch) => {}
+            _ => unreachable!(),
+        };
+
+        // Tampering with the balance invalidates the signature:
+        let mut tampered_move_token = move_token.clone();
+        tampered_move_token.balance = 5;
+        match verify_move_token_standalone(&prev_mutual_credit, &tampered_move_token, &pk1) {
+            Err(VerifyMoveTokenError::InvalidSignature) => {}
+            _ => unreachable!(),
+        };
+
+        // A properly signed move token with a balance that does not match the operations:
+        let mut tampered_unsigned_move_token = unsigned_move_token;
+        tampered_unsigned_move_token.balance = 5;
+        let tampered_move_token = dummy_sign_move_token(tampered_unsigned_move_token, &identity1);
+        match verify_move_token_standalone(&prev_mutual_credit, &tampered_move_token, &pk1) {
+            Err(VerifyMoveTokenError::InvalidStatedBalance) => {}
+            _ => unreachable!(),
+        };
+    }
+
     // TODO: Add more tests.
     // - Test behaviour of Duplicate, ChainInconsistency
 }
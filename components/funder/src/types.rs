@@ -6,8 +6,9 @@ use crypto::identity::{PublicKey, Signature};
 
 use proto::app_server::messages::RelayAddress;
 use proto::funder::messages::{
-    ChannelerUpdateFriend, FailureSendFunds, FriendMessage, FriendTcOp, FunderIncomingControl,
-    FunderOutgoingControl, MoveToken, PendingRequest, RequestSendFunds, ResponseSendFunds,
+    ChannelerUpdateFriend, FailureReason, FailureSendFunds, FriendMessage, FriendTcOp,
+    FunderIncomingControl, FunderOutgoingControl, MoveToken, PendingRequest, RequestSendFunds,
+    ResponseSendFunds,
 };
 
 use proto::funder::signature_buff::{
@@ -71,12 +72,14 @@ pub async fn create_response_send_funds<'a>(
 pub async fn create_failure_send_funds<'a>(
     pending_request: &'a PendingRequest,
     local_public_key: &'a PublicKey,
+    failure_reason: FailureReason,
     rand_nonce: RandValue,
     identity_client: &'a mut IdentityClient,
 ) -> FailureSendFunds {
     let u_failure_send_funds = FailureSendFunds {
         request_id: pending_request.request_id,
         reporting_public_key: local_public_key.clone(),
+        reason: failure_reason,
         rand_nonce,
         signature: (),
     };
@@ -87,6 +90,7 @@ pub async fn create_failure_send_funds<'a>(
     FailureSendFunds {
         request_id: u_failure_send_funds.request_id,
         reporting_public_key: u_failure_send_funds.reporting_public_key,
+        reason: u_failure_send_funds.reason,
         rand_nonce: u_failure_send_funds.rand_nonce,
         signature,
     }
@@ -250,6 +254,7 @@ pub enum FunderIncoming<B> {
     Init,
     Control(FunderIncomingControl<B>),
     Comm(FunderIncomingComm<B>),
+    TimerTick,
 }
 
 #[allow(clippy::large_enum_variant)]
@@ -268,3 +273,165 @@ pub enum FunderOutgoingComm<B> {
     FriendMessage((PublicKey, FriendMessage<B>)),
     ChannelerConfig(ChannelerConfig<RelayAddress<B>>),
 }
+
+/// Limits and intervals (In timer ticks) used by the Funder.
+#[derive(Debug, Clone)]
+pub struct FunderConfig {
+    /// Maximum amount of operations in one move token message
+    pub max_operations_in_batch: usize,
+    /// Maximum amount of relays a node may use.
+    pub max_node_relays: usize,
+    /// The size we allocate for the user send funds requests queue.
+    pub max_pending_user_requests: usize,
+    /// The amount of ticks we wait before declaring a friend that was reported as offline to be
+    /// offline.
+    pub offline_grace_ticks: usize,
+    /// Minimal amount of ticks between two processed inconsistency errors from the same friend.
+    pub inconsistency_error_interval_ticks: usize,
+    /// Maximum amount of ready receipts kept by the funder.
+    pub max_ready_receipts: usize,
+    /// Maximum amount of requests waiting to be forwarded to a single friend.
+    pub max_pending_requests: usize,
+    /// The amount of ticks a friend may have pending outgoing operations before we flush them.
+    pub flush_idle_ticks: usize,
+    /// The amount of ticks we may wait for the token from a friend before requesting it again.
+    pub token_stall_ticks: usize,
+    /// The amount of ticks a request may be pending at our node before we cancel it.
+    pub request_timeout_ticks: usize,
+    /// The length of a friend's pending queue above which it is reported as over threshold.
+    pub pending_queue_threshold: usize,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::executor::ThreadPool;
+    use futures::task::{Spawn, SpawnExt};
+    use futures::{future, FutureExt};
+
+    use identity::create_identity;
+
+    use crypto::crypto_rand::RAND_VALUE_LEN;
+    use crypto::identity::{
+        generate_pkcs8_key_pair, verify_signature, Identity, SoftwareEd25519Identity,
+        PUBLIC_KEY_LEN,
+    };
+    use crypto::invoice_id::{InvoiceId, INVOICE_ID_LEN};
+    use crypto::test_utils::DummyRandom;
+    use crypto::uid::{Uid, UID_LEN};
+
+    use proto::funder::messages::FriendsRoute;
+    use proto::funder::signature_buff::verify_failure_signature;
+
+    /// Create an IdentityClient for a deterministic identity, together with its public key.
+    fn dummy_identity_client<S>(seed: u8, mut spawner: S) -> (IdentityClient, PublicKey)
+    where
+        S: Spawn,
+    {
+        let rng = DummyRandom::new(&[seed]);
+        let pkcs8 = generate_pkcs8_key_pair(&rng);
+        let identity = SoftwareEd25519Identity::from_pkcs8(&pkcs8).unwrap();
+        let public_key = identity.get_public_key();
+        let (requests_sender, identity_server) = create_identity(identity);
+        spawner
+            .spawn(identity_server.then(|_| future::ready(())))
+            .unwrap();
+        (IdentityClient::new(requests_sender), public_key)
+    }
+
+    fn dummy_pending_request(
+        mid_public_key: &PublicKey,
+        dest_public_key: &PublicKey,
+    ) -> PendingRequest {
+        let request_send_funds = RequestSendFunds {
+            request_id: Uid::from(&[3; UID_LEN]),
+            route: FriendsRoute {
+                public_keys: vec![
+                    PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]),
+                    mid_public_key.clone(),
+                    dest_public_key.clone(),
+                ],
+            },
+            dest_payment: 10,
+            invoice_id: InvoiceId::from(&[0; INVOICE_ID_LEN]),
+        };
+        create_pending_request(&request_send_funds)
+    }
+
+    async fn task_create_response_send_funds<S>(spawner: S)
+    where
+        S: Spawn,
+    {
+        let (mut identity_client, public_key) = dummy_identity_client(1, spawner);
+        let mid_public_key = PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]);
+        let pending_request = dummy_pending_request(&mid_public_key, &public_key);
+        let rand_nonce = RandValue::from(&[5; RAND_VALUE_LEN]);
+
+        let response_send_funds = await!(create_response_send_funds(
+            &pending_request,
+            rand_nonce.clone(),
+            &mut identity_client
+        ));
+        assert_eq!(response_send_funds.request_id, pending_request.request_id);
+        assert_eq!(response_send_funds.rand_nonce, rand_nonce);
+
+        let signature_buff =
+            create_response_signature_buffer(&response_send_funds, &pending_request);
+        assert!(verify_signature(
+            &signature_buff,
+            &public_key,
+            &response_send_funds.signature
+        ));
+
+        // The signature must not verify against a different public key:
+        let other_public_key = PublicKey::from(&[0xcc; PUBLIC_KEY_LEN]);
+        assert!(!verify_signature(
+            &signature_buff,
+            &other_public_key,
+            &response_send_funds.signature
+        ));
+    }
+
+    #[test]
+    fn test_create_response_send_funds() {
+        let mut thread_pool = ThreadPool::new().unwrap();
+        thread_pool.run(task_create_response_send_funds(thread_pool.clone()));
+    }
+
+    async fn task_create_failure_send_funds<S>(spawner: S)
+    where
+        S: Spawn,
+    {
+        // We are an intermediate node on the route, reporting the failure:
+        let (mut identity_client, local_public_key) = dummy_identity_client(1, spawner);
+        let dest_public_key = PublicKey::from(&[0xcc; PUBLIC_KEY_LEN]);
+        let pending_request = dummy_pending_request(&local_public_key, &dest_public_key);
+        let rand_nonce = RandValue::from(&[5; RAND_VALUE_LEN]);
+
+        let failure_send_funds = await!(create_failure_send_funds(
+            &pending_request,
+            &local_public_key,
+            FailureReason::FriendNotReady,
+            rand_nonce.clone(),
+            &mut identity_client
+        ));
+        assert_eq!(failure_send_funds.request_id, pending_request.request_id);
+        assert_eq!(failure_send_funds.reporting_public_key, local_public_key);
+        assert_eq!(failure_send_funds.reason, FailureReason::FriendNotReady);
+        assert_eq!(failure_send_funds.rand_nonce, rand_nonce);
+
+        assert!(verify_failure_signature(&failure_send_funds, &pending_request).is_some());
+
+        // Claiming that someone else reported the failure must invalidate the signature:
+        let mut forged_failure_send_funds = failure_send_funds.clone();
+        forged_failure_send_funds.reporting_public_key = dest_public_key.clone();
+        assert!(verify_failure_signature(&forged_failure_send_funds, &pending_request).is_none());
+    }
+
+    #[test]
+    fn test_create_failure_send_funds() {
+        let mut thread_pool = ThreadPool::new().unwrap();
+        thread_pool.run(task_create_failure_send_funds(thread_pool.clone()));
+    }
+}
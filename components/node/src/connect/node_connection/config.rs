@@ -9,7 +9,8 @@ use crypto::uid::Uid;
 
 use proto::app_server::messages::{AppRequest, AppToAppServer, NamedRelayAddress, RelayAddress};
 use proto::funder::messages::{
-    AddFriend, ResetFriendChannel, SetFriendRelays, SetFriendRemoteMaxDebt,
+    AddFriend, RemoveFriend, ResetFriendChannel, SetFriendRelays, SetFriendRemoteMaxDebt,
+    TokenChannelCounters, TokenCountersQueryResult,
 };
 use proto::index_server::messages::NamedIndexServerAddress;
 
@@ -20,6 +21,7 @@ pub struct AppConfigError;
 pub struct AppConfig<R = OffstSystemRandom> {
     sender: mpsc::Sender<AppToAppServer>,
     done_app_requests_mc: MultiConsumerClient<Uid>,
+    token_counters_mc: MultiConsumerClient<TokenCountersQueryResult>,
     rng: R,
 }
 
@@ -30,11 +32,13 @@ where
     pub(super) fn new(
         sender: mpsc::Sender<AppToAppServer>,
         done_app_requests_mc: MultiConsumerClient<Uid>,
+        token_counters_mc: MultiConsumerClient<TokenCountersQueryResult>,
         rng: R,
     ) -> Self {
         AppConfig {
             sender,
             done_app_requests_mc,
+            token_counters_mc,
             rng,
         }
     }
@@ -102,11 +106,18 @@ where
         await!(self.send_request(AppRequest::SetFriendRelays(set_friend_relays)))
     }
 
+    /// Remove a friend. Unless `force` is set, the node refuses to remove a friend with a
+    /// nonzero balance or with pending requests.
     pub async fn remove_friend(
         &mut self,
         friend_public_key: PublicKey,
+        force: bool,
     ) -> Result<(), AppConfigError> {
-        await!(self.send_request(AppRequest::RemoveFriend(friend_public_key)))
+        let remove_friend = RemoveFriend {
+            friend_public_key,
+            force,
+        };
+        await!(self.send_request(AppRequest::RemoveFriend(remove_friend)))
     }
 
     pub async fn enable_friend(
@@ -180,4 +191,29 @@ where
     ) -> Result<(), AppConfigError> {
         await!(self.send_request(AppRequest::RemoveIndexServer(index_public_key)))
     }
+
+    /// Query the move token counters of the token channel with a friend (For debugging).
+    /// Returns None if the friend does not exist, or if the channel with the friend is
+    /// inconsistent.
+    pub async fn query_token_counters(
+        &mut self,
+        friend_public_key: PublicKey,
+    ) -> Result<Option<TokenChannelCounters>, AppConfigError> {
+        let app_request = AppRequest::QueryTokenCounters(friend_public_key.clone());
+        let to_app_server = AppToAppServer::new(Uid::new(&self.rng), app_request);
+
+        // Start listening for incoming token counters query results:
+        let mut incoming_token_counters =
+            await!(self.token_counters_mc.request_stream()).map_err(|_| AppConfigError)?;
+
+        // Send our request to offst node:
+        await!(self.sender.send(to_app_server)).map_err(|_| AppConfigError)?;
+
+        while let Some(token_counters_query_result) = await!(incoming_token_counters.next()) {
+            if token_counters_query_result.friend_public_key == friend_public_key {
+                return Ok(token_counters_query_result.opt_token_channel_counters);
+            }
+        }
+        Err(AppConfigError)
+    }
 }
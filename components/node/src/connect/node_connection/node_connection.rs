@@ -92,6 +92,16 @@ where
             .spawn(done_app_requests_fut)
             .map_err(|_| NodeConnectionError::SpawnError)?;
 
+        let (mut incoming_token_counters_sender, incoming_token_counters) = mpsc::channel(0);
+        let (requests_sender, incoming_requests) = mpsc::channel(0);
+        let token_counters_mc = MultiConsumerClient::new(requests_sender);
+        let token_counters_fut = multi_consumer_service(incoming_token_counters, incoming_requests)
+            .map_err(|e| error!("TokenCounters multi_consumer_service() error: {:?}", e))
+            .map(|_| ());
+        spawner
+            .spawn(token_counters_fut)
+            .map_err(|_| NodeConnectionError::SpawnError)?;
+
         spawner
             .spawn(async move {
                 while let Some(message) = await!(receiver.next()) {
@@ -117,6 +127,11 @@ where
                         AppServerToApp::ResponseRoutes(client_response_routes) => {
                             let _ = await!(incoming_routes_sender.send(client_response_routes));
                         }
+                        AppServerToApp::TokenCountersQueryResult(token_counters_query_result) => {
+                            let _ =
+                                await!(incoming_token_counters_sender
+                                    .send(token_counters_query_result));
+                        }
                     }
                 }
             })
@@ -126,6 +141,7 @@ where
             Some(AppConfig::new(
                 sender.clone(),
                 done_app_requests_mc.clone(),
+                token_counters_mc.clone(),
                 rng.clone(),
             ))
         } else {
@@ -10,14 +10,14 @@ use crypto::identity::PublicKey;
 
 use database::DatabaseClient;
 use identity::IdentityClient;
-use timer::TimerClient;
+use timer::{TimerClient, TimerTick};
 
 use app_server::{app_server_loop, AppServerError, IncomingAppConnection};
 use channeler::{spawn_channeler, ChannelerError};
 use funder::types::{
     ChannelerConfig, FunderIncomingComm, FunderOutgoingComm, IncomingLivenessMessage,
 };
-use funder::{funder_loop, FunderError, FunderState};
+use funder::{funder_loop, FunderConfig, FunderError, FunderState};
 use keepalive::KeepAliveChannel;
 use secure_channel::SecureChannel;
 
@@ -38,6 +38,7 @@ use crate::types::{create_node_report, NodeConfig, NodeMutation, NodeState};
 #[derive(Debug, From)]
 pub enum NodeError {
     RequestPublicKeyError,
+    RequestTimerStreamError,
     SpawnError,
     ChannelerError(ChannelerError),
     FunderError(FunderError),
@@ -107,6 +108,7 @@ fn node_spawn_funder<R, S>(
     mut to_channeler: mpsc::Sender<FunderToChanneler<RelayAddress>>,
     from_app_server: mpsc::Receiver<FunderIncomingControl<NetAddress>>,
     to_app_server: mpsc::Sender<FunderOutgoingControl<NetAddress>>,
+    timer_stream: mpsc::Receiver<TimerTick>,
     rng: R,
     mut spawner: S,
 ) -> Result<impl Future<Output = Result<(), FunderError>>, NodeError>
@@ -204,16 +206,29 @@ where
         .spawn(funder_to_channeler_adapter)
         .map_err(|_| NodeError::SpawnError)?;
 
+    let funder_config = FunderConfig {
+        max_operations_in_batch: node_config.max_operations_in_batch,
+        max_node_relays: node_config.max_node_relays,
+        max_pending_user_requests: node_config.max_pending_user_requests,
+        offline_grace_ticks: node_config.offline_grace_ticks,
+        inconsistency_error_interval_ticks: node_config.inconsistency_error_interval_ticks,
+        max_ready_receipts: node_config.max_ready_receipts,
+        max_pending_requests: node_config.max_pending_requests,
+        flush_idle_ticks: node_config.flush_idle_ticks,
+        token_stall_ticks: node_config.token_stall_ticks,
+        request_timeout_ticks: node_config.request_timeout_ticks,
+        pending_queue_threshold: node_config.pending_queue_threshold,
+    };
+
     let funder_fut = funder_loop(
         identity_client.clone(),
         rng.clone(),
         from_app_server,
         incoming_comm,
+        timer_stream,
         to_app_server,
         outgoing_comm_sender,
-        node_config.max_node_relays,
-        node_config.max_operations_in_batch,
-        node_config.max_pending_user_requests,
+        funder_config,
         funder_state,
         funder_db_client,
     );
@@ -245,7 +260,7 @@ where
     R: CryptoRandom + Clone + 'static,
     S: Spawn + Clone + Send + Sync + 'static,
 {
-    let initial_node_report = create_node_report(&node_state);
+    let initial_node_report = create_node_report(&node_state, &node_config);
 
     // Database adapter:
     let (request_sender, mut request_receiver) = mpsc::channel(0);
@@ -319,7 +334,7 @@ where
 pub async fn node<C, IA, R, S>(
     node_config: NodeConfig,
     identity_client: IdentityClient,
-    timer_client: TimerClient,
+    mut timer_client: TimerClient,
     node_state: NodeState<NetAddress>,
     database_client: DatabaseClient<NodeMutation<NetAddress>>,
     version_connector: C,
@@ -341,7 +356,7 @@ where
     let local_public_key = await!(identity_client.request_public_key())
         .map_err(|_| NodeError::RequestPublicKeyError)?;
 
-    let initial_node_report = create_node_report(&node_state);
+    let initial_node_report = create_node_report(&node_state, &node_config);
 
     // Channeler <--> Funder
     let (channeler_to_funder_sender, channeler_to_funder_receiver) =
@@ -367,6 +382,9 @@ where
     let (funder_to_app_server_sender, funder_to_app_server_receiver) =
         mpsc::channel(node_config.channel_len);
 
+    let funder_timer_stream = await!(timer_client.request_timer_stream())
+        .map_err(|_| NodeError::RequestTimerStreamError)?;
+
     let funder_handle = node_spawn_funder(
         &node_config,
         identity_client.clone(),
@@ -376,6 +394,7 @@ where
         funder_to_channeler_sender,
         app_server_to_funder_receiver,
         funder_to_app_server_sender,
+        funder_timer_stream,
         rng.clone(),
         spawner.clone(),
     )?;
@@ -70,12 +70,15 @@ where
 }
 
 /// Create an initial NodeReport, based on a NodeState
-pub fn create_node_report<B>(node_state: &NodeState<B>) -> NodeReport<B>
+pub fn create_node_report<B>(node_state: &NodeState<B>, node_config: &NodeConfig) -> NodeReport<B>
 where
     B: Clone + CanonicalSerialize,
 {
     NodeReport {
-        funder_report: create_initial_report(&node_state.funder_state),
+        funder_report: create_initial_report(
+            &node_state.funder_state,
+            node_config.pending_queue_threshold,
+        ),
         index_client_report: create_index_client_report(&node_state.index_client_config),
     }
 }
@@ -99,6 +102,32 @@ pub struct NodeConfig {
     pub max_operations_in_batch: usize,
     /// The size we allocate for the user send funds requests queue.
     pub max_pending_user_requests: usize,
+    /// The amount of ticks we wait before declaring a friend that was reported as offline to be
+    /// offline. Until then, requests pending for this friend are not canceled.
+    pub offline_grace_ticks: usize,
+    /// Minimal amount of ticks between two processed inconsistency errors from the same friend.
+    /// Excess inconsistency errors are ignored. A value of 0 disables the limit.
+    pub inconsistency_error_interval_ticks: usize,
+    /// Maximum amount of ready receipts (Receipts that were not yet acknowledged) kept by the
+    /// funder. When exceeded, the oldest ready receipts are evicted.
+    pub max_ready_receipts: usize,
+    /// Maximum amount of requests waiting to be forwarded to a single friend. Requests
+    /// forwarded to a friend beyond this limit fail with a congestion failure.
+    pub max_pending_requests: usize,
+    /// The amount of ticks a friend may have pending outgoing operations before we flush them,
+    /// even if they did not fill a batch. A value of 0 disables flushing on timer.
+    pub flush_idle_ticks: usize,
+    /// The amount of ticks we may wait for the token from a friend while having pending outgoing
+    /// operations, before requesting the token again. A value of 0 disables stall detection.
+    pub token_stall_ticks: usize,
+    /// The amount of ticks a request may be pending at our node (Holding frozen credits) before we
+    /// cancel it. A request that was already forwarded is canceled along the rest of the route.
+    /// Must be longer than the longest legitimate round trip of a request. A value of 0 disables
+    /// the timeout.
+    pub request_timeout_ticks: usize,
+    /// The length of a friend's pending queue above which the friend is reported as having its
+    /// pending queues over threshold. A value of 0 disables the threshold.
+    pub pending_queue_threshold: usize,
     /// Maximum amount of concurrent index client requests:
     pub max_open_index_client_requests: usize,
     /// Maximum amount of relays a node may use.
@@ -4,8 +4,8 @@ use crypto::identity::PublicKey;
 use crypto::uid::Uid;
 
 use crate::funder::messages::{
-    AddFriend, ReceiptAck, ResetFriendChannel, ResponseReceived, SetFriendName, SetFriendRelays,
-    SetFriendRemoteMaxDebt, UserRequestSendFunds,
+    AddFriend, ReceiptAck, RemoveFriend, ResetFriendChannel, ResponseReceived, SetFriendName,
+    SetFriendRelays, SetFriendRemoteMaxDebt, TokenCountersQueryResult, UserRequestSendFunds,
 };
 use crate::index_client::messages::{
     ClientResponseRoutes, IndexClientReport, IndexClientReportMutation,
@@ -87,6 +87,8 @@ where
     Report(NodeReport<B>),
     ReportMutations(ReportMutations<B>),
     ResponseRoutes(ClientResponseRoutes),
+    /// Debugging:
+    TokenCountersQueryResult(TokenCountersQueryResult),
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -107,7 +109,7 @@ pub enum AppRequest<B = NetAddress> {
     AddFriend(AddFriend<B>),
     SetFriendRelays(SetFriendRelays<B>),
     SetFriendName(SetFriendName),
-    RemoveFriend(PublicKey),
+    RemoveFriend(RemoveFriend),
     EnableFriend(PublicKey),
     DisableFriend(PublicKey),
     OpenFriend(PublicKey),
@@ -119,6 +121,8 @@ pub enum AppRequest<B = NetAddress> {
     /// Manage index servers:
     AddIndexServer(NamedIndexServerAddress<B>),
     RemoveIndexServer(PublicKey),
+    /// Query the move token counters of the token channel with a friend (For debugging):
+    QueryTokenCounters(PublicKey),
 }
 #[derive(Debug, PartialEq, Eq)]
 pub struct AppToAppServer<B = NetAddress> {
@@ -24,10 +24,13 @@ use index_server::serialize::{
 };
 
 use crate::funder::messages::{
-    AddFriend, ReceiptAck, ResetFriendChannel, ResponseReceived, ResponseSendFundsResult,
-    SetFriendName, SetFriendRelays, SetFriendRemoteMaxDebt, UserRequestSendFunds,
+    AddFriend, MoveTokenCounters, ReceiptAck, RemoveFriend, ResetFriendChannel, ResponseReceived,
+    ResponseSendFundsResult, SetFriendName, SetFriendRelays, SetFriendRemoteMaxDebt,
+    TokenChannelCounters, TokenCountersQueryResult, UserRequestSendFunds,
+};
+use crate::funder::serialize::{
+    deser_failure_reason, deser_friends_route, ser_failure_reason, ser_friends_route,
 };
-use crate::funder::serialize::{deser_friends_route, ser_friends_route};
 
 use crate::app_server::messages::{
     AppPermissions, AppRequest, AppServerToApp, AppToAppServer, ReportMutations,
@@ -89,6 +92,25 @@ fn ser_response_received(
             write_public_key(public_key, &mut failure_builder);
         }
     };
+
+    let mut opt_elapsed_ticks_builder = response_received_builder
+        .reborrow()
+        .init_opt_elapsed_ticks();
+    match response_received.opt_elapsed_ticks {
+        Some(elapsed_ticks) => opt_elapsed_ticks_builder.set_elapsed_ticks(elapsed_ticks),
+        None => opt_elapsed_ticks_builder.set_empty(()),
+    };
+
+    let mut opt_failure_reason_builder = response_received_builder
+        .reborrow()
+        .init_opt_failure_reason();
+    match &response_received.opt_failure_reason {
+        Some(failure_reason) => ser_failure_reason(
+            failure_reason,
+            &mut opt_failure_reason_builder.reborrow().init_failure_reason(),
+        ),
+        None => opt_failure_reason_builder.set_empty(()),
+    };
 }
 
 fn deser_response_received(
@@ -105,9 +127,25 @@ fn deser_response_received(
         }
     };
 
+    let opt_elapsed_ticks = match response_received_reader.get_opt_elapsed_ticks().which()? {
+        app_server_capnp::response_received::opt_elapsed_ticks::ElapsedTicks(elapsed_ticks) => {
+            Some(elapsed_ticks)
+        }
+        app_server_capnp::response_received::opt_elapsed_ticks::Empty(()) => None,
+    };
+
+    let opt_failure_reason = match response_received_reader.get_opt_failure_reason().which()? {
+        app_server_capnp::response_received::opt_failure_reason::FailureReason(
+            failure_reason_reader,
+        ) => Some(deser_failure_reason(&failure_reason_reader?)?),
+        app_server_capnp::response_received::opt_failure_reason::Empty(()) => None,
+    };
+
     Ok(ResponseReceived {
         request_id: read_uid(&response_received_reader.get_request_id()?)?,
         result,
+        opt_elapsed_ticks,
+        opt_failure_reason,
     })
 }
 
@@ -195,6 +233,27 @@ fn deser_set_friend_name(
     })
 }
 
+fn ser_remove_friend(
+    remove_friend: &RemoveFriend,
+    remove_friend_builder: &mut app_server_capnp::remove_friend::Builder,
+) {
+    write_public_key(
+        &remove_friend.friend_public_key,
+        &mut remove_friend_builder.reborrow().init_friend_public_key(),
+    );
+
+    remove_friend_builder.set_force(remove_friend.force);
+}
+
+fn deser_remove_friend(
+    remove_friend_reader: &app_server_capnp::remove_friend::Reader,
+) -> Result<RemoveFriend, SerializeError> {
+    Ok(RemoveFriend {
+        friend_public_key: read_public_key(&remove_friend_reader.get_friend_public_key()?)?,
+        force: remove_friend_reader.get_force(),
+    })
+}
+
 fn ser_set_friend_relays(
     set_friend_relays: &SetFriendRelays,
     set_friend_relays_builder: &mut app_server_capnp::set_friend_relays::Builder,
@@ -347,6 +406,134 @@ fn deser_client_response_routes(
 }
 
 /*
+fn ser_move_token_counters(
+    move_token_counters: &MoveTokenCounters,
+    move_token_counters_builder: &mut app_server_capnp::move_token_counters::Builder,
+) {
+    write_custom_u_int128(
+        move_token_counters.move_token_counter,
+        &mut move_token_counters_builder
+            .reborrow()
+            .init_move_token_counter(),
+    );
+    move_token_counters_builder
+        .reborrow()
+        .set_inconsistency_counter(move_token_counters.inconsistency_counter);
+}
+
+fn deser_move_token_counters(
+    move_token_counters_reader: &app_server_capnp::move_token_counters::Reader,
+) -> Result<MoveTokenCounters, SerializeError> {
+    Ok(MoveTokenCounters {
+        move_token_counter: read_custom_u_int128(
+            &move_token_counters_reader.get_move_token_counter()?,
+        )?,
+        inconsistency_counter: move_token_counters_reader.get_inconsistency_counter(),
+    })
+}
+
+fn ser_token_channel_counters(
+    token_channel_counters: &TokenChannelCounters,
+    token_channel_counters_builder: &mut app_server_capnp::token_channel_counters::Builder,
+) {
+    let mut opt_local_outgoing_builder = token_channel_counters_builder
+        .reborrow()
+        .init_opt_local_outgoing();
+    match &token_channel_counters.opt_local_outgoing {
+        Some(local_outgoing) => ser_move_token_counters(
+            local_outgoing,
+            &mut opt_local_outgoing_builder.reborrow().init_local_outgoing(),
+        ),
+        None => opt_local_outgoing_builder.set_empty(()),
+    };
+
+    let mut opt_last_incoming_builder = token_channel_counters_builder
+        .reborrow()
+        .init_opt_last_incoming();
+    match &token_channel_counters.opt_last_incoming {
+        Some(last_incoming) => ser_move_token_counters(
+            last_incoming,
+            &mut opt_last_incoming_builder.reborrow().init_last_incoming(),
+        ),
+        None => opt_last_incoming_builder.set_empty(()),
+    };
+}
+
+fn deser_token_channel_counters(
+    token_channel_counters_reader: &app_server_capnp::token_channel_counters::Reader,
+) -> Result<TokenChannelCounters, SerializeError> {
+    let opt_local_outgoing = match token_channel_counters_reader
+        .get_opt_local_outgoing()
+        .which()?
+    {
+        app_server_capnp::token_channel_counters::opt_local_outgoing::LocalOutgoing(
+            local_outgoing_reader,
+        ) => Some(deser_move_token_counters(&local_outgoing_reader?)?),
+        app_server_capnp::token_channel_counters::opt_local_outgoing::Empty(()) => None,
+    };
+
+    let opt_last_incoming = match token_channel_counters_reader
+        .get_opt_last_incoming()
+        .which()?
+    {
+        app_server_capnp::token_channel_counters::opt_last_incoming::LastIncoming(
+            last_incoming_reader,
+        ) => Some(deser_move_token_counters(&last_incoming_reader?)?),
+        app_server_capnp::token_channel_counters::opt_last_incoming::Empty(()) => None,
+    };
+
+    Ok(TokenChannelCounters {
+        opt_local_outgoing,
+        opt_last_incoming,
+    })
+}
+
+fn ser_token_counters_query_result(
+    token_counters_query_result: &TokenCountersQueryResult,
+    query_result_builder: &mut app_server_capnp::token_counters_query_result::Builder,
+) {
+    write_public_key(
+        &token_counters_query_result.friend_public_key,
+        &mut query_result_builder.reborrow().init_friend_public_key(),
+    );
+
+    let mut opt_token_channel_counters_builder = query_result_builder
+        .reborrow()
+        .init_opt_token_channel_counters();
+    match &token_counters_query_result.opt_token_channel_counters {
+        Some(token_channel_counters) => ser_token_channel_counters(
+            token_channel_counters,
+            &mut opt_token_channel_counters_builder
+                .reborrow()
+                .init_token_channel_counters(),
+        ),
+        None => opt_token_channel_counters_builder.set_empty(()),
+    };
+}
+
+fn deser_token_counters_query_result(
+    token_counters_query_result_reader: &app_server_capnp::token_counters_query_result::Reader,
+) -> Result<TokenCountersQueryResult, SerializeError> {
+    use app_server_capnp::token_counters_query_result::opt_token_channel_counters;
+
+    let opt_token_channel_counters = match token_counters_query_result_reader
+        .get_opt_token_channel_counters()
+        .which()?
+    {
+        opt_token_channel_counters::TokenChannelCounters(token_channel_counters_reader) => {
+            Some(deser_token_channel_counters(&token_channel_counters_reader?)?)
+        }
+        opt_token_channel_counters::Empty(()) => None,
+    };
+
+    Ok(TokenCountersQueryResult {
+        friend_public_key: read_public_key(
+            &token_counters_query_result_reader.get_friend_public_key()?,
+        )?,
+        opt_token_channel_counters,
+    })
+}
+
 fn ser_add_index_server(add_index_server: &AddIndexServer<NetAddress>,
                             add_index_server_builder: &mut app_server_capnp::add_index_server::Builder) {
 
@@ -464,6 +651,14 @@ fn ser_app_server_to_app(
             response_routes,
             &mut app_server_to_app_builder.reborrow().init_response_routes(),
         ),
+        AppServerToApp::TokenCountersQueryResult(token_counters_query_result) => {
+            ser_token_counters_query_result(
+                token_counters_query_result,
+                &mut app_server_to_app_builder
+                    .reborrow()
+                    .init_token_counters_query_result(),
+            )
+        }
     }
 }
 
@@ -485,6 +680,11 @@ fn deser_app_server_to_app(
                 &client_response_routes_reader?,
             )?)
         }
+        app_server_capnp::app_server_to_app::TokenCountersQueryResult(
+            token_counters_query_result_reader,
+        ) => AppServerToApp::TokenCountersQueryResult(deser_token_counters_query_result(
+            &token_counters_query_result_reader?,
+        )?),
     })
 }
 
@@ -521,8 +721,8 @@ fn ser_app_request(
             set_friend_name,
             &mut app_request_builder.reborrow().init_set_friend_name(),
         ),
-        AppRequest::RemoveFriend(friend_public_key) => write_public_key(
-            friend_public_key,
+        AppRequest::RemoveFriend(remove_friend) => ser_remove_friend(
+            remove_friend,
             &mut app_request_builder.reborrow().init_remove_friend(),
         ),
         AppRequest::EnableFriend(friend_public_key) => write_public_key(
@@ -567,6 +767,10 @@ fn ser_app_request(
             public_key,
             &mut app_request_builder.reborrow().init_remove_index_server(),
         ),
+        AppRequest::QueryTokenCounters(friend_public_key) => write_public_key(
+            friend_public_key,
+            &mut app_request_builder.reborrow().init_query_token_counters(),
+        ),
     }
 }
 
@@ -597,8 +801,8 @@ fn deser_app_request(
         app_server_capnp::app_request::SetFriendName(set_friend_name) => {
             AppRequest::SetFriendName(deser_set_friend_name(&set_friend_name?)?)
         }
-        app_server_capnp::app_request::RemoveFriend(public_key_reader) => {
-            AppRequest::RemoveFriend(read_public_key(&public_key_reader?)?)
+        app_server_capnp::app_request::RemoveFriend(remove_friend_reader) => {
+            AppRequest::RemoveFriend(deser_remove_friend(&remove_friend_reader?)?)
         }
         app_server_capnp::app_request::EnableFriend(public_key_reader) => {
             AppRequest::EnableFriend(read_public_key(&public_key_reader?)?)
@@ -631,6 +835,9 @@ fn deser_app_request(
         app_server_capnp::app_request::RemoveIndexServer(public_key_reader) => {
             AppRequest::RemoveIndexServer(read_public_key(&public_key_reader?)?)
         }
+        app_server_capnp::app_request::QueryTokenCounters(public_key_reader) => {
+            AppRequest::QueryTokenCounters(read_public_key(&public_key_reader?)?)
+        }
     })
 }
 
@@ -767,6 +974,26 @@ mod tests {
         let data = serialize_app_server_to_app(&app_server_to_app);
         let app_server_to_app2 = deserialize_app_server_to_app(&data).unwrap();
         assert_eq!(app_server_to_app, app_server_to_app2);
+
+        let token_channel_counters = TokenChannelCounters {
+            opt_local_outgoing: None,
+            opt_last_incoming: Some(MoveTokenCounters {
+                move_token_counter: u128::max_value(),
+                inconsistency_counter: 3,
+            }),
+        };
+        for opt_token_channel_counters in &[None, Some(token_channel_counters)] {
+            let token_counters_query_result = TokenCountersQueryResult {
+                friend_public_key: PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]),
+                opt_token_channel_counters: opt_token_channel_counters.clone(),
+            };
+            let app_server_to_app =
+                AppServerToApp::TokenCountersQueryResult(token_counters_query_result);
+
+            let data = serialize_app_server_to_app(&app_server_to_app);
+            let app_server_to_app2 = deserialize_app_server_to_app(&data).unwrap();
+            assert_eq!(app_server_to_app, app_server_to_app2);
+        }
     }
 
     #[test]
@@ -795,6 +1022,30 @@ mod tests {
         let data = serialize_app_to_app_server(&app_to_app_server);
         let app_to_app_server2 = deserialize_app_to_app_server(&data).unwrap();
         assert_eq!(app_to_app_server, app_to_app_server2);
+
+        for force in &[false, true] {
+            let remove_friend = RemoveFriend {
+                friend_public_key: PublicKey::from(&[0xee; PUBLIC_KEY_LEN]),
+                force: *force,
+            };
+            let app_to_app_server = AppToAppServer {
+                app_request_id: Uid::from(&[2; UID_LEN]),
+                app_request: AppRequest::RemoveFriend(remove_friend),
+            };
+
+            let data = serialize_app_to_app_server(&app_to_app_server);
+            let app_to_app_server2 = deserialize_app_to_app_server(&data).unwrap();
+            assert_eq!(app_to_app_server, app_to_app_server2);
+        }
+
+        let app_to_app_server = AppToAppServer {
+            app_request_id: Uid::from(&[3; UID_LEN]),
+            app_request: AppRequest::QueryTokenCounters(PublicKey::from(&[0xee; PUBLIC_KEY_LEN])),
+        };
+
+        let data = serialize_app_to_app_server(&app_to_app_server);
+        let app_to_app_server2 = deserialize_app_to_app_server(&data).unwrap();
+        assert_eq!(app_to_app_server, app_to_app_server2);
     }
 
     // TODO: More tests are required here
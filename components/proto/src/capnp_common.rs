@@ -12,7 +12,7 @@ use crate::app_server::messages::{NamedRelayAddress, RelayAddress};
 use crate::funder::messages::Receipt;
 use crate::index_server::messages::NamedIndexServerAddress;
 use crate::net::messages::NetAddress;
-use crate::serialize::SerializeError;
+use crate::serialize::{InvalidLength, SerializeError};
 
 use crypto::crypto_rand::RandValue;
 use crypto::dh::{DhPublicKey, Salt};
@@ -88,7 +88,12 @@ macro_rules! type_capnp_serde {
         pub fn $read_func(from: &$capnp_type::Reader) -> Result<$native_type, SerializeError> {
             let inner = from.get_inner()?;
             let data_bytes = &$inner_read_func(&inner);
-            Ok($native_type::try_from(&data_bytes[..]).unwrap())
+            Ok(
+                $native_type::try_from(&data_bytes[..]).map_err(|_| InvalidLength {
+                    type_name: stringify!($native_type),
+                    found: data_bytes.len(),
+                })?,
+            )
         }
 
         pub fn $write_func(from: &$native_type, to: &mut $capnp_type::Builder) {
@@ -1,6 +1,14 @@
 /// The current protocol version
 pub const PROTOCOL_VERSION: u32 = 0;
 
+/// The version of the funder wire format.
+/// Every serialized FriendMessage begins with this byte.
+/// Bump it on every change to the wire format of friend messages.
+///
+/// Version 1: Added the cancelRequest operation and the requestsClosed, friendCongested and
+/// timedOut failure reasons.
+pub const FUNDER_PROTOCOL_VERSION: u8 = 1;
+
 /// Maximum amount of friend operations sent in one move token message.
 pub const MAX_OPERATIONS_IN_BATCH: usize = 16;
 
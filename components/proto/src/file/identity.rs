@@ -1,16 +1,34 @@
+use std::env;
 use std::fs::{self, File};
 use std::io::{self, Write};
 use std::path::Path;
 
+use base64::{self, URL_SAFE_NO_PAD};
 use toml;
 
+use crypto::crypto_rand::CryptoRandom;
+use crypto::dh::Salt;
 use crypto::identity::{Identity, SoftwareEd25519Identity};
+use crypto::sym_encrypt::{derive_symmetric_key_from_password, Decryptor, Encryptor};
 
-use crate::file::ser_string::{private_key_to_string, string_to_private_key, SerStringError};
+use crate::file::ser_string::{salt_to_string, string_to_salt, SerStringError};
 use crate::net::messages::NetAddressError;
 
+/// Identity file version: The private key is stored in the clear.
+/// Identity files without a version field are of this version.
+const IDENTITY_FILE_VERSION_PLAIN: u32 = 0;
+/// Identity file version: The private key is encrypted using a key derived from a passphrase.
+const IDENTITY_FILE_VERSION_ENCRYPTED: u32 = 1;
+
+/// The environment variable holding the passphrase of encrypted identity files.
+/// The passphrase is not taken as a command line argument, where other users of the machine could
+/// see it.
+pub const IDENTITY_PASSPHRASE_ENV: &str = "OFFST_IDENTITY_PASSPHRASE";
+
 #[derive(Debug, From)]
 pub enum IdentityFileError {
+    /// The identity file does not exist
+    FileNotFound,
     IoError(io::Error),
     TomlDeError(toml::de::Error),
     TomlSeError(toml::ser::Error),
@@ -18,12 +36,28 @@ pub enum IdentityFileError {
     ParseSocketAddrError,
     InvalidPublicKey,
     NetAddressError(NetAddressError),
+    /// The private key or salt are not properly encoded
+    MalformedEncoding,
+    /// The decoded private key is of the wrong length
+    InvalidKeyLength,
     Pkcs8ParseError,
+    UnsupportedVersion(u32),
+    /// The identity file is encrypted, but no passphrase was provided
+    PassphraseRequired,
+    EncryptionError,
+    /// Wrong passphrase, or corrupted encrypted private key
+    DecryptionError,
 }
 
 /// A helper structure for serialize and deserializing IdentityAddress.
 #[derive(Serialize, Deserialize)]
 pub struct IdentityFile {
+    #[serde(default)]
+    pub version: u32,
+    /// Salt used for deriving the encryption key from the passphrase.
+    /// Only present for encrypted identity files.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub salt: Option<String>,
     pub private_key: String,
 }
 
@@ -33,39 +67,161 @@ impl From<SerStringError> for IdentityFileError {
     }
 }
 
+/// Decrypt an encrypted private key, using a key derived from the given passphrase
+fn decrypt_private_key(
+    encrypted_private_key: &[u8],
+    opt_salt: Option<&str>,
+    passphrase: &str,
+) -> Result<Vec<u8>, IdentityFileError> {
+    let salt_str = opt_salt.ok_or(IdentityFileError::MalformedEncoding)?;
+    let salt = string_to_salt(salt_str).map_err(|_| IdentityFileError::MalformedEncoding)?;
+
+    let symmetric_key = derive_symmetric_key_from_password(passphrase.as_bytes(), &salt);
+    let mut decryptor =
+        Decryptor::new(&symmetric_key).map_err(|_| IdentityFileError::DecryptionError)?;
+    decryptor
+        .decrypt(encrypted_private_key)
+        .map_err(|_| IdentityFileError::DecryptionError)
+}
+
 /// Load Identity from a file
-pub fn load_raw_identity_from_file(path: &Path) -> Result<[u8; 85], IdentityFileError> {
-    let data = fs::read_to_string(&path)?;
+/// A passphrase is required if the private key inside the file is encrypted.
+pub fn load_raw_identity_from_file(
+    path: &Path,
+    opt_passphrase: Option<&str>,
+) -> Result<[u8; 85], IdentityFileError> {
+    let data = fs::read_to_string(&path).map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => IdentityFileError::FileNotFound,
+        _ => IdentityFileError::IoError(e),
+    })?;
     let identity_file: IdentityFile = toml::from_str(&data)?;
 
-    // Decode public key:
-    let private_key = string_to_private_key(&identity_file.private_key)?;
+    // Decode private key:
+    let private_key_data = base64::decode_config(&identity_file.private_key, URL_SAFE_NO_PAD)
+        .map_err(|_| IdentityFileError::MalformedEncoding)?;
+
+    let private_key_vec = match identity_file.version {
+        IDENTITY_FILE_VERSION_PLAIN => private_key_data,
+        IDENTITY_FILE_VERSION_ENCRYPTED => {
+            let passphrase = opt_passphrase.ok_or(IdentityFileError::PassphraseRequired)?;
+            decrypt_private_key(
+                &private_key_data,
+                identity_file.salt.as_ref().map(String::as_str),
+                passphrase,
+            )?
+        }
+        version => return Err(IdentityFileError::UnsupportedVersion(version)),
+    };
+
+    if private_key_vec.len() != 85 {
+        return Err(IdentityFileError::InvalidKeyLength);
+    }
+    let mut private_key = [0u8; 85];
+    private_key.copy_from_slice(&private_key_vec[0..85]);
     Ok(private_key)
 }
 
+fn store_identity_file(identity_file: &IdentityFile, path: &Path) -> Result<(), IdentityFileError> {
+    let data = toml::to_string(identity_file)?;
+
+    let mut file = File::create(path)?;
+    file.write_all(&data.as_bytes())?;
+
+    Ok(())
+}
+
 /// Store Identity to file
+/// The private key is stored in the clear.
 pub fn store_raw_identity_to_file(
     identity: &[u8; 85],
     path: &Path,
 ) -> Result<(), IdentityFileError> {
     let identity_file = IdentityFile {
-        private_key: private_key_to_string(&identity),
+        version: IDENTITY_FILE_VERSION_PLAIN,
+        salt: None,
+        private_key: base64::encode_config(&identity[0..85], URL_SAFE_NO_PAD),
     };
+    store_identity_file(&identity_file, path)
+}
 
-    let data = toml::to_string(&identity_file)?;
+/// Store Identity to file
+/// The private key is encrypted using a key derived from the given passphrase.
+pub fn store_encrypted_raw_identity_to_file<R>(
+    identity: &[u8; 85],
+    path: &Path,
+    passphrase: &str,
+    rng: &R,
+) -> Result<(), IdentityFileError>
+where
+    R: CryptoRandom,
+{
+    let salt = Salt::new(rng).map_err(|_| IdentityFileError::EncryptionError)?;
+    let symmetric_key = derive_symmetric_key_from_password(passphrase.as_bytes(), &salt);
+    let mut encryptor =
+        Encryptor::new(&symmetric_key).map_err(|_| IdentityFileError::EncryptionError)?;
+    let encrypted_private_key = encryptor
+        .encrypt(&identity[0..85])
+        .map_err(|_| IdentityFileError::EncryptionError)?;
 
-    let mut file = File::create(path)?;
-    file.write_all(&data.as_bytes())?;
+    let identity_file = IdentityFile {
+        version: IDENTITY_FILE_VERSION_ENCRYPTED,
+        salt: Some(salt_to_string(&salt)),
+        private_key: base64::encode_config(&encrypted_private_key, URL_SAFE_NO_PAD),
+    };
+    store_identity_file(&identity_file, path)
+}
 
-    Ok(())
+fn load_software_identity_from_file(
+    path: &Path,
+    opt_passphrase: Option<&str>,
+) -> Result<SoftwareEd25519Identity, IdentityFileError> {
+    let raw_identity = load_raw_identity_from_file(path, opt_passphrase)?;
+    SoftwareEd25519Identity::from_pkcs8(&raw_identity)
+        .map_err(|_| IdentityFileError::Pkcs8ParseError)
+}
+
+/// Read the passphrase of encrypted identity files from the environment
+/// (See `IDENTITY_PASSPHRASE_ENV`). Returns None if no passphrase was set.
+pub fn identity_passphrase_from_env() -> Option<String> {
+    env::var(IDENTITY_PASSPHRASE_ENV).ok()
 }
 
 /// Load an identity from a file
 /// The file stores the private key according to PKCS#8.
-pub fn load_identity_from_file(path: &Path) -> Result<impl Identity, IdentityFileError> {
-    let raw_identity = load_raw_identity_from_file(path)?;
-    SoftwareEd25519Identity::from_pkcs8(&raw_identity)
-        .map_err(|_| IdentityFileError::Pkcs8ParseError)
+/// A passphrase is required if the private key inside the file is encrypted.
+pub fn load_identity_from_file(
+    path: &Path,
+    opt_passphrase: Option<&str>,
+) -> Result<impl Identity, IdentityFileError> {
+    load_software_identity_from_file(path, opt_passphrase)
+}
+
+/// Load all identity files (Files with the `.ident` extension) from a directory.
+/// Every identity is named after the stem of its file name.
+///
+/// Loading does not stop at the first invalid file: The result of loading each file is returned
+/// separately, sorted by name. An error is returned only if the directory itself can not be read.
+pub fn load_identities_from_dir(
+    dir_path: &Path,
+    opt_passphrase: Option<&str>,
+) -> Result<Vec<(String, Result<SoftwareEd25519Identity, IdentityFileError>)>, IdentityFileError> {
+    let mut res_identities = Vec::new();
+    for entry in fs::read_dir(dir_path)? {
+        let path = entry?.path();
+        if path.is_dir() || path.extension().and_then(|ext| ext.to_str()) != Some("ident") {
+            continue;
+        }
+        let name = match path.file_stem().and_then(|stem| stem.to_str()) {
+            Some(name) => name.to_owned(),
+            None => continue,
+        };
+        res_identities.push((
+            name,
+            load_software_identity_from_file(&path, opt_passphrase),
+        ));
+    }
+    res_identities.sort_by(|(name_a, _), (name_b, _)| name_a.cmp(name_b));
+    Ok(res_identities)
 }
 
 #[cfg(test)]
@@ -73,6 +229,9 @@ mod tests {
     use super::*;
     use tempfile::tempdir;
 
+    use crypto::identity::generate_pkcs8_key_pair;
+    use crypto::test_utils::DummyRandom;
+
     #[test]
     fn test_identity_file_basic() {
         let identity_file: IdentityFile = toml::from_str(
@@ -82,6 +241,8 @@ mod tests {
         )
         .unwrap();
 
+        assert_eq!(identity_file.version, IDENTITY_FILE_VERSION_PLAIN);
+        assert_eq!(identity_file.salt, None);
         assert_eq!(identity_file.private_key, "private_key_string");
     }
 
@@ -94,9 +255,159 @@ mod tests {
         let identity = [33u8; 85];
 
         store_raw_identity_to_file(&identity, &file_path).unwrap();
-        let identity2 = load_raw_identity_from_file(&file_path).unwrap();
+        let identity2 = load_raw_identity_from_file(&file_path, None).unwrap();
 
         // We convert to vec here because [u8; 85] doesn't implement PartialEq
         assert_eq!(identity.to_vec(), identity2.to_vec());
     }
+
+    #[test]
+    fn test_load_identity_file_not_found() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("missing_identity_file");
+
+        match load_raw_identity_from_file(&file_path, None) {
+            Err(IdentityFileError::FileNotFound) => {}
+            _ => unreachable!(),
+        };
+    }
+
+    #[test]
+    fn test_load_identity_malformed_encoding() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("identity_file");
+
+        let mut file = File::create(&file_path).unwrap();
+        file.write_all(b"private_key = '#not*base64!'\n").unwrap();
+
+        match load_raw_identity_from_file(&file_path, None) {
+            Err(IdentityFileError::MalformedEncoding) => {}
+            _ => unreachable!(),
+        };
+    }
+
+    #[test]
+    fn test_load_identity_invalid_key_length() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("identity_file");
+
+        let identity_file = IdentityFile {
+            version: IDENTITY_FILE_VERSION_PLAIN,
+            salt: None,
+            private_key: base64::encode_config(&[33u8; 84][..], URL_SAFE_NO_PAD),
+        };
+        let mut file = File::create(&file_path).unwrap();
+        file.write_all(toml::to_string(&identity_file).unwrap().as_bytes())
+            .unwrap();
+
+        match load_raw_identity_from_file(&file_path, None) {
+            Err(IdentityFileError::InvalidKeyLength) => {}
+            _ => unreachable!(),
+        };
+    }
+
+    #[test]
+    fn test_load_identity_invalid_pkcs8() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("identity_file");
+
+        // Correct length, but not a valid PKCS#8 document:
+        store_raw_identity_to_file(&[33u8; 85], &file_path).unwrap();
+
+        match load_identity_from_file(&file_path, None) {
+            Err(IdentityFileError::Pkcs8ParseError) => {}
+            _ => unreachable!(),
+        };
+    }
+
+    #[test]
+    fn test_store_load_encrypted_identity() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("identity_file");
+
+        let identity = [33u8; 85];
+        let rng = DummyRandom::new(&[1u8]);
+
+        store_encrypted_raw_identity_to_file(&identity, &file_path, "passphrase", &rng).unwrap();
+        let identity2 = load_raw_identity_from_file(&file_path, Some("passphrase")).unwrap();
+        assert_eq!(identity.to_vec(), identity2.to_vec());
+
+        match load_raw_identity_from_file(&file_path, Some("wrong_passphrase")) {
+            Err(IdentityFileError::DecryptionError) => {}
+            _ => unreachable!(),
+        };
+
+        match load_raw_identity_from_file(&file_path, None) {
+            Err(IdentityFileError::PassphraseRequired) => {}
+            _ => unreachable!(),
+        };
+    }
+
+    #[test]
+    fn test_load_plain_identity_with_passphrase() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("identity_file");
+
+        // A passphrase is ignored for identity files that are not encrypted:
+        let identity = [33u8; 85];
+        store_raw_identity_to_file(&identity, &file_path).unwrap();
+        let identity2 = load_raw_identity_from_file(&file_path, Some("passphrase")).unwrap();
+        assert_eq!(identity.to_vec(), identity2.to_vec());
+    }
+
+    #[test]
+    fn test_load_identity_unsupported_version() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("identity_file");
+
+        let mut file = File::create(&file_path).unwrap();
+        file.write_all(b"version = 7\nprivate_key = 'AAAA'\n")
+            .unwrap();
+
+        match load_raw_identity_from_file(&file_path, None) {
+            Err(IdentityFileError::UnsupportedVersion(7)) => {}
+            _ => unreachable!(),
+        };
+    }
+
+    #[test]
+    fn test_load_identities_from_dir() {
+        let dir = tempdir().unwrap();
+        let rng = DummyRandom::new(&[1u8]);
+
+        let pkcs8_a = generate_pkcs8_key_pair(&rng);
+        store_raw_identity_to_file(&pkcs8_a, &dir.path().join("node_a.ident")).unwrap();
+        let pkcs8_b = generate_pkcs8_key_pair(&rng);
+        store_raw_identity_to_file(&pkcs8_b, &dir.path().join("node_b.ident")).unwrap();
+
+        // Invalid identity file:
+        let mut file = File::create(&dir.path().join("node_c.ident")).unwrap();
+        file.write_all(b"private_key = '#not*base64!'\n").unwrap();
+
+        // Not an identity file, should be ignored:
+        let mut file = File::create(&dir.path().join("readme.txt")).unwrap();
+        file.write_all(b"Hello world!").unwrap();
+
+        let identities = load_identities_from_dir(&dir.path(), None).unwrap();
+        let names = identities
+            .iter()
+            .map(|(name, _)| name.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["node_a", "node_b", "node_c"]);
+
+        let identity_a = SoftwareEd25519Identity::from_pkcs8(&pkcs8_a).unwrap();
+        let identity_b = SoftwareEd25519Identity::from_pkcs8(&pkcs8_b).unwrap();
+        assert_eq!(
+            identities[0].1.as_ref().unwrap().get_public_key(),
+            identity_a.get_public_key()
+        );
+        assert_eq!(
+            identities[1].1.as_ref().unwrap().get_public_key(),
+            identity_b.get_public_key()
+        );
+        match identities[2].1 {
+            Err(IdentityFileError::MalformedEncoding) => {}
+            _ => unreachable!(),
+        };
+    }
 }
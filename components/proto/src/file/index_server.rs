@@ -1,3 +1,4 @@
+use std::collections::HashSet;
 use std::convert::TryInto;
 use std::fs::{self, File};
 use std::io::{self, Write};
@@ -5,6 +6,8 @@ use std::path::{Path, PathBuf};
 
 use toml;
 
+use crypto::identity::PublicKey;
+
 use crate::file::ser_string::{public_key_to_string, string_to_public_key, SerStringError};
 
 use crate::index_server::messages::IndexServerAddress;
@@ -41,7 +44,8 @@ pub fn load_index_server_from_file(
     let data = fs::read_to_string(&path)?;
     let index_server_file: IndexServerFile = toml::from_str(&data)?;
 
-    let public_key = string_to_public_key(&index_server_file.public_key)?;
+    let public_key = string_to_public_key(&index_server_file.public_key)
+        .map_err(|_| IndexServerFileError::InvalidPublicKey)?;
 
     Ok(IndexServerAddress {
         public_key,
@@ -77,6 +81,8 @@ pub enum IndexServerDirectoryError {
     IoError(io::Error),
     InvalidDirectory(io::Error),
     InvalidFile(PathBuf, IndexServerFileError),
+    /// The file contains an index server public key that already appeared in another file
+    DuplicatePublicKey(PathBuf, PublicKey),
 }
 
 impl From<io::Error> for IndexServerDirectoryError {
@@ -86,21 +92,28 @@ impl From<io::Error> for IndexServerDirectoryError {
 }
 
 /// Load a directory of index server address files, and return a map representing
-/// the information from all files
+/// the information from all files.
+/// Two files describing index servers with the same public key are considered an error.
 pub fn load_trusted_servers(
     dir_path: &Path,
 ) -> Result<Vec<IndexServerAddress<NetAddress>>, IndexServerDirectoryError> {
     let mut res_trusted = Vec::new();
+    let mut seen_public_keys = HashSet::new();
     for entry in fs::read_dir(dir_path).map_err(IndexServerDirectoryError::InvalidDirectory)? {
         let entry = entry?;
         let path = entry.path();
         if path.is_dir() {
             continue;
         }
-        res_trusted.push(
-            load_index_server_from_file(&path)
-                .map_err(|e| IndexServerDirectoryError::InvalidFile(path, e))?,
-        );
+        let index_server_address = load_index_server_from_file(&path)
+            .map_err(|e| IndexServerDirectoryError::InvalidFile(path.clone(), e))?;
+        if !seen_public_keys.insert(index_server_address.public_key.clone()) {
+            return Err(IndexServerDirectoryError::DuplicatePublicKey(
+                path,
+                index_server_address.public_key,
+            ));
+        }
+        res_trusted.push(index_server_address);
     }
     Ok(res_trusted)
 }
@@ -110,7 +123,7 @@ mod tests {
     use super::*;
     use tempfile::tempdir;
 
-    use crypto::identity::{PublicKey, PUBLIC_KEY_LEN};
+    use crypto::identity::PUBLIC_KEY_LEN;
 
     #[test]
     fn test_index_server_file_basic() {
@@ -187,4 +200,53 @@ mod tests {
             ]
         );
     }
+
+    #[test]
+    fn test_load_trusted_index_servers_duplicate_public_key() {
+        // Create a temporary directory:
+        let dir = tempdir().unwrap();
+
+        let file_path = dir.path().join("index_server_address_file_a");
+        let index_server_address = IndexServerAddress {
+            public_key: PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]),
+            address: "127.0.0.1:1000".to_owned().try_into().unwrap(),
+        };
+        store_index_server_to_file(&index_server_address, &file_path).unwrap();
+
+        // Same public key, different address:
+        let file_path = dir.path().join("index_server_address_file_b");
+        let index_server_address = IndexServerAddress {
+            public_key: PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]),
+            address: "127.0.0.1:1001".to_owned().try_into().unwrap(),
+        };
+        store_index_server_to_file(&index_server_address, &file_path).unwrap();
+
+        match load_trusted_servers(&dir.path()) {
+            Err(IndexServerDirectoryError::DuplicatePublicKey(_path, public_key)) => {
+                assert_eq!(public_key, PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]))
+            }
+            _ => unreachable!(),
+        };
+    }
+
+    #[test]
+    fn test_load_index_server_malformed() {
+        // Create a temporary directory:
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("index_server_address_file");
+
+        let mut file = File::create(&file_path).unwrap();
+        file.write_all(b"public_key = 'public_key_string'\naddress = 'localhost:1337'\n")
+            .unwrap();
+
+        match load_index_server_from_file(&file_path) {
+            Err(IndexServerFileError::InvalidPublicKey) => {}
+            _ => unreachable!(),
+        };
+
+        match load_trusted_servers(&dir.path()) {
+            Err(IndexServerDirectoryError::InvalidFile(path, _)) => assert_eq!(path, file_path),
+            _ => unreachable!(),
+        };
+    }
 }
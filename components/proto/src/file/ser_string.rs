@@ -1,5 +1,6 @@
 use base64::{self, URL_SAFE_NO_PAD};
 use crypto::crypto_rand::{RandValue, RAND_VALUE_LEN};
+use crypto::dh::{Salt, SALT_LEN};
 use crypto::hash::{HashResult, HASH_RESULT_LEN};
 use crypto::identity::{PublicKey, Signature, PUBLIC_KEY_LEN, SIGNATURE_LEN};
 use crypto::invoice_id::{InvoiceId, INVOICE_ID_LEN};
@@ -102,6 +103,22 @@ pub fn string_to_rand_value(rand_value_str: &str) -> Result<RandValue, SerString
     Ok(RandValue::from(&rand_value_array))
 }
 
+/// Convert a Salt into a string
+pub fn salt_to_string(salt: &Salt) -> String {
+    base64::encode_config(&salt, URL_SAFE_NO_PAD)
+}
+
+/// Convert a string into a Salt
+pub fn string_to_salt(salt_str: &str) -> Result<Salt, SerStringError> {
+    let salt_vec = base64::decode_config(salt_str, URL_SAFE_NO_PAD).map_err(|_| SerStringError)?;
+    if salt_vec.len() != SALT_LEN {
+        return Err(SerStringError);
+    }
+    let mut salt_array = [0u8; SALT_LEN];
+    salt_array.copy_from_slice(&salt_vec[0..SALT_LEN]);
+    Ok(Salt::from(&salt_array))
+}
+
 // TODO: Find a better way to represent private key.
 // We currently use [u8; 85] directly because of ring limitations.
 
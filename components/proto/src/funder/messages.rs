@@ -10,7 +10,7 @@ use crypto::uid::Uid;
 use crate::app_server::messages::{NamedRelayAddress, RelayAddress};
 use crate::consts::MAX_ROUTE_LEN;
 use crate::net::messages::NetAddress;
-use crate::report::messages::FunderReportMutations;
+use crate::report::messages::{FriendReport, FunderReportMutations};
 use common::canonical_serialize::CanonicalSerialize;
 use common::int_convert::usize_to_u64;
 
@@ -74,10 +74,36 @@ pub struct ResponseSendFunds<S = Signature> {
     pub signature: S,
 }
 
+/// The reason a request was failed by the reporting node.
+#[derive(Eq, PartialEq, Debug, Clone, Serialize, Deserialize)]
+pub enum FailureReason {
+    /// The route is invalid, or the reporting node could not find itself on the route.
+    InvalidRoute,
+    /// The next node on the route is not a friend of the reporting node, or is not ready to
+    /// receive requests (For example, it is offline).
+    FriendNotReady,
+    /// There is not enough trust to forward the request to the next node on the route.
+    InsufficientTrust,
+    /// The request was canceled by the reporting node (For example, because the token channel
+    /// with the next node was reset, or the next node was removed).
+    Canceled,
+    /// The next node on the route closed its requests while the request was waiting to be
+    /// forwarded. Unlike Canceled, the next node is still online.
+    RequestsClosed,
+    /// The next node on the route has too many requests waiting to be forwarded to it.
+    FriendCongested,
+    /// The request was pending at the reporting node for too long without a response.
+    TimedOut,
+}
+
 #[derive(Eq, PartialEq, Debug, Clone, Serialize, Deserialize)]
 pub struct FailureSendFunds<S = Signature> {
     pub request_id: Uid,
     pub reporting_public_key: PublicKey,
+    /// Note that the reason is part of the signed portion of the message. It can only be set by
+    /// the reporting node, and intermediate nodes can not alter it without invalidating the
+    /// signature.
+    pub reason: FailureReason,
     pub rand_nonce: RandValue,
     pub signature: S,
 }
@@ -90,6 +116,10 @@ pub enum FriendTcOp {
     RequestSendFunds(RequestSendFunds),
     ResponseSendFunds(ResponseSendFunds),
     FailureSendFunds(FailureSendFunds),
+    /// Ask the remote side to cancel a request (by request_id) we have sent to it earlier.
+    /// The remote side replies with a failure once it knows the request will not be fulfilled,
+    /// releasing the credits frozen for the request.
+    CancelRequest(Uid),
 }
 
 #[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
@@ -179,11 +209,27 @@ impl CanonicalSerialize for ResponseSendFunds {
     }
 }
 
+impl CanonicalSerialize for FailureReason {
+    fn canonical_serialize(&self) -> Vec<u8> {
+        let reason_byte = match self {
+            FailureReason::InvalidRoute => 0u8,
+            FailureReason::FriendNotReady => 1u8,
+            FailureReason::InsufficientTrust => 2u8,
+            FailureReason::Canceled => 3u8,
+            FailureReason::RequestsClosed => 4u8,
+            FailureReason::FriendCongested => 5u8,
+            FailureReason::TimedOut => 6u8,
+        };
+        vec![reason_byte]
+    }
+}
+
 impl CanonicalSerialize for FailureSendFunds {
     fn canonical_serialize(&self) -> Vec<u8> {
         let mut res_bytes = Vec::new();
         res_bytes.extend_from_slice(&self.request_id);
         res_bytes.extend_from_slice(&self.reporting_public_key);
+        res_bytes.extend_from_slice(&self.reason.canonical_serialize());
         res_bytes.extend_from_slice(&self.signature);
         res_bytes
     }
@@ -215,6 +261,10 @@ impl CanonicalSerialize for FriendTcOp {
                 res_bytes.push(5u8);
                 res_bytes.append(&mut failure_send_funds.canonical_serialize())
             }
+            FriendTcOp::CancelRequest(request_id) => {
+                res_bytes.push(6u8);
+                res_bytes.extend_from_slice(request_id)
+            }
         }
         res_bytes
     }
@@ -299,6 +349,30 @@ impl FriendsRoute {
     pub fn index_to_pk(&self, index: usize) -> Option<&PublicKey> {
         self.public_keys.get(index)
     }
+
+    /// Create a new route that skips the given intermediate node.
+    /// Returns None if the node is not on the route, or if it is the source or destination of the
+    /// route (In which case it can not be avoided).
+    ///
+    /// Note that the resulting route is only usable if the two nodes adjacent to the removed node
+    /// are friends.
+    pub fn without_node(&self, public_key: &PublicKey) -> Option<FriendsRoute> {
+        let index = self.pk_to_index(public_key)?;
+        if index == 0 || index >= self.len().checked_sub(1)? {
+            return None;
+        }
+        let mut public_keys = self.public_keys.clone();
+        public_keys.remove(index);
+        Some(FriendsRoute { public_keys })
+    }
+
+    /// Check if the route does not pass through any of the given nodes.
+    pub fn avoids_nodes(&self, public_keys: &[PublicKey]) -> bool {
+        !self
+            .public_keys
+            .iter()
+            .any(|route_public_key| public_keys.contains(route_public_key))
+    }
 }
 
 impl CanonicalSerialize for Receipt {
@@ -329,6 +403,40 @@ pub enum RequestsStatus {
     Closed,
 }
 
+#[derive(Debug, PartialEq, Eq)]
+pub enum ResetTermsError {
+    /// The inconsistency counter of the reset terms is not newer than the one of the channel.
+    StaleInconsistencyCounter,
+}
+
+impl ResetTerms {
+    pub fn new(
+        reset_token: Signature,
+        inconsistency_counter: u64,
+        balance_for_reset: i128,
+    ) -> Self {
+        ResetTerms {
+            reset_token,
+            inconsistency_counter,
+            balance_for_reset,
+        }
+    }
+
+    /// Make sure that these reset terms were created after the channel reached its current
+    /// inconsistency counter, `channel_inconsistency_counter`. Reset terms with an older (or
+    /// equal) counter are stale, possibly replayed.
+    pub fn validate_against(
+        &self,
+        channel_inconsistency_counter: u64,
+    ) -> Result<(), ResetTermsError> {
+        if self.inconsistency_counter > channel_inconsistency_counter {
+            Ok(())
+        } else {
+            Err(ResetTermsError::StaleInconsistencyCounter)
+        }
+    }
+}
+
 impl RequestsStatus {
     pub fn is_open(&self) -> bool {
         if let RequestsStatus::Open = self {
@@ -347,9 +455,18 @@ pub struct AddFriend<B = NetAddress> {
     pub balance: i128, // Initial balance
 }
 
+/// Add a friend, and set the maximum debt we allow it to have (Initial trust) in the same step.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AddFriendWithTrust<B = NetAddress> {
+    pub add_friend: AddFriend<B>,
+    pub initial_remote_max_debt: u128,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct RemoveFriend {
     pub friend_public_key: PublicKey,
+    /// Remove the friend even if the balance is not zero or there are pending requests.
+    pub force: bool,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -376,6 +493,14 @@ pub struct SetFriendName {
     pub name: String,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SetFriendLivenessInterval {
+    pub friend_public_key: PublicKey,
+    /// Amount of timer ticks the friend may be suspect before it is declared offline.
+    /// Overrides the node's default offline grace period.
+    pub interval_ticks: u64,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct SetFriendRelays<B = NetAddress> {
     pub friend_public_key: PublicKey,
@@ -408,15 +533,37 @@ pub enum FunderControl<B> {
     AddRelay(NamedRelayAddress<B>),
     RemoveRelay(PublicKey),
     AddFriend(AddFriend<B>),
+    /// Add a friend with an initial remote max debt.
+    AddFriendWithTrust(AddFriendWithTrust<B>),
     RemoveFriend(RemoveFriend),
     SetRequestsStatus(SetRequestsStatus),
     SetFriendStatus(SetFriendStatus),
     SetFriendRemoteMaxDebt(SetFriendRemoteMaxDebt),
     SetFriendRelays(SetFriendRelays<B>),
     SetFriendName(SetFriendName),
+    /// Set the offline grace period of a single friend.
+    SetFriendLivenessInterval(SetFriendLivenessInterval),
     ResetFriendChannel(ResetFriendChannel),
     RequestSendFunds(UserRequestSendFunds),
+    /// Cancel a request (by request_id) that we have originated or forwarded.
+    /// If the request was already sent to the next node on the route, the next node is asked to
+    /// cancel it, and the failure arrives back through the usual path.
+    /// Requests that were already sent used to be rejected as not cancellable. They are now
+    /// canceled as well, and only an unknown request_id fails.
+    CancelRequest(Uid),
+    /// Resend our last outgoing move token to a friend (If we hold an outgoing move token).
+    ResendToken(PublicKey),
+    /// Query the full state of a single friend (For debugging).
+    QueryFriend(PublicKey),
     ReceiptAck(ReceiptAck),
+    /// Fetch a ready receipt (by request_id) that was not yet acknowledged.
+    /// The receipt is returned as a successful ResponseReceived.
+    FetchReceipt(Uid),
+    /// Query the funder's event counters.
+    QueryMetrics,
+    /// Query the move token counters of the token channel with a friend.
+    /// Useful for diagnosing channel stalls (For debugging).
+    QueryTokenCounters(PublicKey),
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -464,10 +611,126 @@ pub enum ResponseSendFundsResult {
 pub struct ResponseReceived {
     pub request_id: Uid,
     pub result: ResponseSendFundsResult,
+    /// Amount of timer ticks passed between the time the request was received from the user and
+    /// the time the response arrived. None if not known (For example, if the node was restarted
+    /// while the request was in flight).
+    pub opt_elapsed_ticks: Option<u64>,
+    /// The reason for a failure, if known. None for a successful response.
+    pub opt_failure_reason: Option<FailureReason>,
+}
+
+/// A response to a QueryFriend control command.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FriendQueryResult<B: Clone> {
+    pub friend_public_key: PublicKey,
+    /// None if the friend does not exist.
+    pub opt_friend_report: Option<FriendReport<B>>,
+}
+
+/// A ready receipt that was removed from the funder's state to make room for newer receipts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EvictedReceipt {
+    pub request_id: Uid,
+    pub receipt: Receipt,
+}
+
+/// Counters of events processed by the funder since it was started.
+/// Returned as a response to a QueryMetrics control command.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FunderMetrics {
+    /// Requests queued to be forwarded to the next node on the route.
+    pub requests_forwarded: u64,
+    /// Responses queued to be sent back along the route, or delivered to the user.
+    pub responses_delivered: u64,
+    /// Failures that originated at this node.
+    pub failures_generated: u64,
+    /// Amount of times a token channel became inconsistent.
+    pub inconsistencies_entered: u64,
+    pub move_tokens_sent: u64,
+    pub move_tokens_received: u64,
+}
+
+/// Counters of a single move token.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MoveTokenCounters {
+    pub move_token_counter: u128,
+    pub inconsistency_counter: u64,
+}
+
+/// Move token counters of a consistent token channel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TokenChannelCounters {
+    /// Our outgoing move token. None if we don't hold the token.
+    pub opt_local_outgoing: Option<MoveTokenCounters>,
+    /// The last move token received from the remote side.
+    /// None if we have never received a move token from the remote side.
+    pub opt_last_incoming: Option<MoveTokenCounters>,
+}
+
+/// A response to a QueryTokenCounters control command.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TokenCountersQueryResult {
+    pub friend_public_key: PublicKey,
+    /// None if the friend does not exist, or if the channel with the friend is inconsistent.
+    pub opt_token_channel_counters: Option<TokenChannelCounters>,
 }
 
 #[derive(Debug)]
 pub enum FunderOutgoingControl<B: Clone> {
     ResponseReceived(ResponseReceived),
     ReportMutations(FunderReportMutations<B>),
+    FriendQueryResult(FriendQueryResult<B>),
+    ReceiptEvicted(EvictedReceipt),
+    MetricsQueryResult(FunderMetrics),
+    TokenCountersQueryResult(TokenCountersQueryResult),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crypto::identity::{PUBLIC_KEY_LEN, SIGNATURE_LEN};
+
+    #[test]
+    fn test_reset_terms_validate_against() {
+        let reset_terms = ResetTerms::new(Signature::from(&[1; SIGNATURE_LEN]), 8, 0);
+        assert_eq!(reset_terms.validate_against(7), Ok(()));
+        assert_eq!(
+            reset_terms.validate_against(8),
+            Err(ResetTermsError::StaleInconsistencyCounter)
+        );
+        assert_eq!(
+            reset_terms.validate_against(9),
+            Err(ResetTermsError::StaleInconsistencyCounter)
+        );
+    }
+
+    #[test]
+    fn test_friends_route_without_node() {
+        let pk_a = PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]);
+        let pk_b = PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]);
+        let pk_c = PublicKey::from(&[0xcc; PUBLIC_KEY_LEN]);
+        let pk_d = PublicKey::from(&[0xdd; PUBLIC_KEY_LEN]);
+        let pk_e = PublicKey::from(&[0xee; PUBLIC_KEY_LEN]);
+
+        let route = FriendsRoute {
+            public_keys: vec![pk_a.clone(), pk_b.clone(), pk_c.clone(), pk_d.clone()],
+        };
+
+        // Removing an interior node:
+        let new_route = route.without_node(&pk_c).unwrap();
+        assert_eq!(
+            new_route.public_keys,
+            vec![pk_a.clone(), pk_b.clone(), pk_d.clone()]
+        );
+        assert!(new_route.is_valid());
+        assert!(new_route.avoids_nodes(&[pk_c.clone()]));
+        assert!(!route.avoids_nodes(&[pk_e.clone(), pk_c.clone()]));
+
+        // Endpoints can not be removed:
+        assert!(route.without_node(&pk_a).is_none());
+        assert!(route.without_node(&pk_d).is_none());
+
+        // Node not on the route:
+        assert!(route.without_node(&pk_e).is_none());
+    }
 }
@@ -6,17 +6,35 @@ use crate::capnp_common::{
 };
 use capnp;
 use capnp::serialize_packed;
-use common::int_convert::usize_to_u32;
+use common::int_convert::{u32_to_usize, usize_to_u32};
 use std::io;
 
 use funder_capnp;
 
 use super::messages::{
-    FailureSendFunds, FriendMessage, FriendTcOp, FriendsRoute, MoveToken, MoveTokenRequest,
-    RequestSendFunds, ResetTerms, ResponseSendFunds,
+    FailureReason, FailureSendFunds, FriendMessage, FriendTcOp, FriendsRoute, MoveToken,
+    MoveTokenRequest, RequestSendFunds, ResetTerms, ResponseSendFunds,
 };
 
-use crate::serialize::SerializeError;
+use crate::consts::{
+    FUNDER_PROTOCOL_VERSION, MAX_NODE_RELAYS, MAX_OPERATIONS_IN_BATCH, MAX_ROUTE_LEN,
+};
+use crate::serialize::{BoundExceeded, SerializeError};
+
+#[derive(Debug)]
+pub enum FunderDeserializeError {
+    /// The message does not contain a version byte.
+    EmptyMessage,
+    /// The message was serialized using a funder wire format version we do not support.
+    UnsupportedVersion(u8),
+    SerializeError(SerializeError),
+}
+
+impl From<SerializeError> for FunderDeserializeError {
+    fn from(e: SerializeError) -> Self {
+        FunderDeserializeError::SerializeError(e)
+    }
+}
 
 pub fn ser_friends_route(
     friends_route: &FriendsRoute,
@@ -76,6 +94,21 @@ fn ser_response_send_funds_op(
     );
 }
 
+pub fn ser_failure_reason(
+    failure_reason: &FailureReason,
+    failure_reason_builder: &mut funder_capnp::failure_reason::Builder,
+) {
+    match failure_reason {
+        FailureReason::InvalidRoute => failure_reason_builder.set_invalid_route(()),
+        FailureReason::FriendNotReady => failure_reason_builder.set_friend_not_ready(()),
+        FailureReason::InsufficientTrust => failure_reason_builder.set_insufficient_trust(()),
+        FailureReason::Canceled => failure_reason_builder.set_canceled(()),
+        FailureReason::RequestsClosed => failure_reason_builder.set_requests_closed(()),
+        FailureReason::FriendCongested => failure_reason_builder.set_friend_congested(()),
+        FailureReason::TimedOut => failure_reason_builder.set_timed_out(()),
+    }
+}
+
 fn ser_failure_send_funds_op(
     failure_send_funds: &FailureSendFunds,
     failure_send_funds_op_builder: &mut funder_capnp::failure_send_funds_op::Builder,
@@ -98,6 +131,10 @@ fn ser_failure_send_funds_op(
         &failure_send_funds.signature,
         &mut failure_send_funds_op_builder.reborrow().init_signature(),
     );
+    ser_failure_reason(
+        &failure_send_funds.reason,
+        &mut failure_send_funds_op_builder.reborrow().init_reason(),
+    );
 }
 
 fn ser_friend_operation(
@@ -127,6 +164,12 @@ fn ser_friend_operation(
                 operation_builder.reborrow().init_failure_send_funds();
             ser_failure_send_funds_op(failure_send_funds, &mut failure_send_funds_builder);
         }
+        FriendTcOp::CancelRequest(request_id) => {
+            write_uid(
+                request_id,
+                &mut operation_builder.reborrow().init_cancel_request(),
+            );
+        }
     };
 }
 
@@ -249,13 +292,28 @@ fn ser_friend_message(
     };
 }
 
-/// Serialize a FriendMessage into a vector of bytes
+/// Serialize a FriendMessage into a vector of bytes.
+/// The serialized message is prefixed by a version byte (FUNDER_PROTOCOL_VERSION).
 pub fn serialize_friend_message(friend_message: &FriendMessage) -> Vec<u8> {
     let mut builder = capnp::message::Builder::new_default();
     let mut friend_message_builder = builder.init_root::<funder_capnp::friend_message::Builder>();
 
     ser_friend_message(friend_message, &mut friend_message_builder);
 
+    let mut ser_buff = vec![FUNDER_PROTOCOL_VERSION];
+    serialize_packed::write_message(&mut ser_buff, &builder).unwrap();
+    ser_buff
+}
+
+/// Serialize ResetTerms on its own into a vector of bytes.
+/// Uses the same layout as an InconsistencyError message.
+pub fn serialize_reset_terms(reset_terms: &ResetTerms) -> Vec<u8> {
+    let mut builder = capnp::message::Builder::new_default();
+    let mut inconsistency_error_builder =
+        builder.init_root::<funder_capnp::inconsistency_error::Builder>();
+
+    ser_inconsistency_error(reset_terms, &mut inconsistency_error_builder);
+
     let mut ser_buff = Vec::new();
     serialize_packed::write_message(&mut ser_buff, &builder).unwrap();
     ser_buff
@@ -263,12 +321,31 @@ pub fn serialize_friend_message(friend_message: &FriendMessage) -> Vec<u8> {
 
 // ------------ Deserialization -----------------------
 // ----------------------------------------------------
+// Deserialization handles untrusted input from remote friends, and should never panic.
+// Lists are checked against their bounds before they are read:
+// - MoveToken operations: MAX_OPERATIONS_IN_BATCH
+// - MoveToken local relays: MAX_NODE_RELAYS
+// - FriendsRoute public keys: MAX_ROUTE_LEN
+// Fixed size values (Signatures, public keys, nonces) are read from fixed size capnp structs, and
+// their length is verified before copying (See capnp_common).
+
+/// Make sure that a list read from a message does not exceed its bound.
+fn check_bound(field: &'static str, len: u32, max: usize) -> Result<(), SerializeError> {
+    let found = u32_to_usize(len).unwrap_or(usize::max_value());
+    if found > max {
+        return Err(BoundExceeded { field, max, found }.into());
+    }
+    Ok(())
+}
 
 pub fn deser_friends_route(
     friends_route_reader: &funder_capnp::friends_route::Reader,
 ) -> Result<FriendsRoute, SerializeError> {
+    let public_keys_reader = friends_route_reader.get_public_keys()?;
+    check_bound("route.public_keys", public_keys_reader.len(), MAX_ROUTE_LEN)?;
+
     let mut public_keys = Vec::new();
-    for public_key_reader in friends_route_reader.get_public_keys()? {
+    for public_key_reader in public_keys_reader {
         public_keys.push(read_public_key(&public_key_reader)?);
     }
 
@@ -296,6 +373,20 @@ fn deser_response_send_funds_op(
     })
 }
 
+pub fn deser_failure_reason(
+    failure_reason_reader: &funder_capnp::failure_reason::Reader,
+) -> Result<FailureReason, SerializeError> {
+    Ok(match failure_reason_reader.which()? {
+        funder_capnp::failure_reason::InvalidRoute(()) => FailureReason::InvalidRoute,
+        funder_capnp::failure_reason::FriendNotReady(()) => FailureReason::FriendNotReady,
+        funder_capnp::failure_reason::InsufficientTrust(()) => FailureReason::InsufficientTrust,
+        funder_capnp::failure_reason::Canceled(()) => FailureReason::Canceled,
+        funder_capnp::failure_reason::RequestsClosed(()) => FailureReason::RequestsClosed,
+        funder_capnp::failure_reason::FriendCongested(()) => FailureReason::FriendCongested,
+        funder_capnp::failure_reason::TimedOut(()) => FailureReason::TimedOut,
+    })
+}
+
 fn deser_failure_send_funds_op(
     failure_send_funds_op_reader: &funder_capnp::failure_send_funds_op::Reader,
 ) -> Result<FailureSendFunds, SerializeError> {
@@ -304,6 +395,7 @@ fn deser_failure_send_funds_op(
         reporting_public_key: read_public_key(
             &failure_send_funds_op_reader.get_reporting_public_key()?,
         )?,
+        reason: deser_failure_reason(&failure_send_funds_op_reader.get_reason()?)?,
         rand_nonce: read_rand_nonce(&failure_send_funds_op_reader.get_rand_nonce()?)?,
         signature: read_signature(&failure_send_funds_op_reader.get_signature()?)?,
     })
@@ -329,14 +421,24 @@ fn deser_friend_operation(
         funder_capnp::friend_operation::FailureSendFunds(failure_send_funds_reader) => {
             FriendTcOp::FailureSendFunds(deser_failure_send_funds_op(&failure_send_funds_reader?)?)
         }
+        funder_capnp::friend_operation::CancelRequest(cancel_request_reader) => {
+            FriendTcOp::CancelRequest(read_uid(&cancel_request_reader?)?)
+        }
     })
 }
 
 fn deser_move_token(
     move_token_reader: &funder_capnp::move_token::Reader,
 ) -> Result<MoveToken, SerializeError> {
+    let operations_reader = move_token_reader.get_operations()?;
+    check_bound(
+        "move_token.operations",
+        operations_reader.len(),
+        MAX_OPERATIONS_IN_BATCH,
+    )?;
+
     let mut operations: Vec<FriendTcOp> = Vec::new();
-    for operation_reader in move_token_reader.get_operations()? {
+    for operation_reader in operations_reader {
         operations.push(deser_friend_operation(&operation_reader)?);
     }
 
@@ -344,8 +446,15 @@ fn deser_move_token(
     let opt_local_relays = match opt_local_relays_reader.which()? {
         funder_capnp::move_token::opt_local_relays::Empty(()) => None,
         funder_capnp::move_token::opt_local_relays::Relays(relay_address_reader) => {
+            let relay_address_reader = relay_address_reader?;
+            check_bound(
+                "move_token.opt_local_relays",
+                relay_address_reader.len(),
+                MAX_NODE_RELAYS,
+            )?;
+
             let mut addresses = Vec::new();
-            for address in relay_address_reader? {
+            for address in relay_address_reader {
                 addresses.push(read_relay_address(&address)?);
             }
             Some(addresses)
@@ -407,14 +516,33 @@ fn deser_friend_message(
     })
 }
 
-/// Deserialize FriendMessage from an array of bytes
-pub fn deserialize_friend_message(data: &[u8]) -> Result<FriendMessage, SerializeError> {
-    let mut cursor = io::Cursor::new(data);
+/// Deserialize FriendMessage from an array of bytes.
+/// Messages with a version byte other than FUNDER_PROTOCOL_VERSION are rejected.
+pub fn deserialize_friend_message(data: &[u8]) -> Result<FriendMessage, FunderDeserializeError> {
+    let (version, payload) = data
+        .split_first()
+        .ok_or(FunderDeserializeError::EmptyMessage)?;
+    if *version != FUNDER_PROTOCOL_VERSION {
+        return Err(FunderDeserializeError::UnsupportedVersion(*version));
+    }
+
+    let mut cursor = io::Cursor::new(payload);
     let reader =
         serialize_packed::read_message(&mut cursor, ::capnp::message::ReaderOptions::new())?;
     let friend_message_reader = reader.get_root::<funder_capnp::friend_message::Reader>()?;
 
-    deser_friend_message(&friend_message_reader)
+    Ok(deser_friend_message(&friend_message_reader)?)
+}
+
+/// Deserialize ResetTerms from an array of bytes
+pub fn deserialize_reset_terms(data: &[u8]) -> Result<ResetTerms, SerializeError> {
+    let mut cursor = io::Cursor::new(data);
+    let reader =
+        serialize_packed::read_message(&mut cursor, ::capnp::message::ReaderOptions::new())?;
+    let inconsistency_error_reader =
+        reader.get_root::<funder_capnp::inconsistency_error::Reader>()?;
+
+    deser_inconsistency_error(&inconsistency_error_reader)
 }
 
 #[cfg(test)]
@@ -453,6 +581,7 @@ mod tests {
         let failure_send_funds = FailureSendFunds {
             request_id: Uid::from(&[10; UID_LEN]),
             reporting_public_key: PublicKey::from(&[0x11; PUBLIC_KEY_LEN]),
+            reason: FailureReason::InsufficientTrust,
             rand_nonce: RandValue::from(&[0xbb; RAND_VALUE_LEN]),
             signature: Signature::from(&[3; SIGNATURE_LEN]),
         };
@@ -464,6 +593,7 @@ mod tests {
             FriendTcOp::RequestSendFunds(request_send_funds),
             FriendTcOp::ResponseSendFunds(response_send_funds),
             FriendTcOp::FailureSendFunds(failure_send_funds),
+            FriendTcOp::CancelRequest(Uid::from(&[10; UID_LEN])),
         ];
 
         let relay_address4 = RelayAddress {
@@ -523,4 +653,119 @@ mod tests {
         let friend_message2 = deserialize_friend_message(&ser_buff).unwrap();
         assert_eq!(friend_message, friend_message2);
     }
+
+    #[test]
+    fn test_serialize_reset_terms() {
+        let reset_terms = ResetTerms {
+            reset_token: Signature::from(&[7; SIGNATURE_LEN]),
+            inconsistency_counter: 3,
+            balance_for_reset: -1234,
+        };
+        let ser_buff = serialize_reset_terms(&reset_terms);
+        let reset_terms2 = deserialize_reset_terms(&ser_buff).unwrap();
+        assert_eq!(reset_terms, reset_terms2);
+    }
+
+    #[test]
+    fn test_deserialize_friend_message_version() {
+        let friend_message = create_inconsistency_error();
+        let mut ser_buff = serialize_friend_message(&friend_message);
+        assert_eq!(ser_buff[0], FUNDER_PROTOCOL_VERSION);
+
+        ser_buff[0] = FUNDER_PROTOCOL_VERSION.wrapping_add(1);
+        match deserialize_friend_message(&ser_buff) {
+            Err(FunderDeserializeError::UnsupportedVersion(version)) => {
+                assert_eq!(version, FUNDER_PROTOCOL_VERSION.wrapping_add(1))
+            }
+            _ => unreachable!(),
+        };
+
+        match deserialize_friend_message(&[]) {
+            Err(FunderDeserializeError::EmptyMessage) => {}
+            _ => unreachable!(),
+        };
+    }
+
+    #[test]
+    fn test_deserialize_friend_message_bounds() {
+        let mut friend_message = create_move_token_request();
+        if let FriendMessage::MoveTokenRequest(move_token_request) = &mut friend_message {
+            let move_token = &mut move_token_request.friend_move_token;
+            while move_token.operations.len() <= MAX_OPERATIONS_IN_BATCH {
+                move_token.operations.push(FriendTcOp::EnableRequests);
+            }
+        }
+        let ser_buff = serialize_friend_message(&friend_message);
+        match deserialize_friend_message(&ser_buff) {
+            Err(FunderDeserializeError::SerializeError(SerializeError::BoundExceeded(
+                bound_exceeded,
+            ))) => {
+                assert_eq!(bound_exceeded.field, "move_token.operations");
+                assert_eq!(bound_exceeded.max, MAX_OPERATIONS_IN_BATCH);
+                assert_eq!(bound_exceeded.found, MAX_OPERATIONS_IN_BATCH + 1);
+            }
+            _ => unreachable!(),
+        };
+
+        let mut friend_message = create_move_token_request();
+        if let FriendMessage::MoveTokenRequest(move_token_request) = &mut friend_message {
+            let move_token = &mut move_token_request.friend_move_token;
+            let relays = move_token.opt_local_relays.as_mut().unwrap();
+            while relays.len() <= MAX_NODE_RELAYS {
+                let relay_address = relays[0].clone();
+                relays.push(relay_address);
+            }
+        }
+        let ser_buff = serialize_friend_message(&friend_message);
+        assert!(deserialize_friend_message(&ser_buff).is_err());
+
+        let mut friend_message = create_move_token_request();
+        if let FriendMessage::MoveTokenRequest(move_token_request) = &mut friend_message {
+            let move_token = &mut move_token_request.friend_move_token;
+            if let FriendTcOp::RequestSendFunds(request_send_funds) = &mut move_token.operations[3]
+            {
+                while request_send_funds.route.public_keys.len() <= MAX_ROUTE_LEN {
+                    let public_key = request_send_funds.route.public_keys[0].clone();
+                    request_send_funds.route.public_keys.push(public_key);
+                }
+            } else {
+                unreachable!();
+            }
+        }
+        let ser_buff = serialize_friend_message(&friend_message);
+        assert!(deserialize_friend_message(&ser_buff).is_err());
+    }
+
+    #[test]
+    fn test_deserialize_friend_message_malformed() {
+        let ser_buff = serialize_friend_message(&create_move_token_request());
+
+        // Malformed buffers. Deserialization should return an error (or a valid message) without
+        // panicking:
+        let mut malformed_buffers: Vec<Vec<u8>> = vec![
+            vec![FUNDER_PROTOCOL_VERSION],
+            vec![FUNDER_PROTOCOL_VERSION, 0x00],
+            vec![FUNDER_PROTOCOL_VERSION; 64],
+            vec![0xff; 256],
+        ];
+        // Truncated messages:
+        for len in 0..ser_buff.len() {
+            malformed_buffers.push(ser_buff[..len].to_vec());
+        }
+        // Messages with a single corrupted byte:
+        for i in 1..ser_buff.len() {
+            let mut corrupted = ser_buff.clone();
+            corrupted[i] ^= 0xff;
+            malformed_buffers.push(corrupted);
+        }
+
+        for malformed_buffer in &malformed_buffers {
+            let _ = deserialize_friend_message(malformed_buffer);
+        }
+
+        // Truncated messages must not deserialize successfully:
+        for len in 0..ser_buff.len() {
+            assert!(deserialize_friend_message(&ser_buff[..len]).is_err());
+        }
+    }
 }
@@ -1,9 +1,8 @@
-use byteorder::{BigEndian, WriteBytesExt};
 use crypto::hash::{self, sha_512_256, HashResult};
 use crypto::identity::{verify_signature, PublicKey};
 
 use common::canonical_serialize::CanonicalSerialize;
-use common::int_convert::usize_to_u64;
+use common::int_convert::{i128_to_be_bytes, u128_to_be_bytes, u64_to_be_bytes, usize_to_u64};
 
 use super::messages::{FailureSendFunds, MoveToken, PendingRequest, Receipt, ResponseSendFunds};
 
@@ -13,6 +12,15 @@ pub const FUND_FAILURE_PREFIX: &[u8] = b"FUND_FAILURE";
 /// Create the buffer we sign over at the Response funds.
 /// Note that the signature is not just over the Response funds bytes. The signed buffer also
 /// contains information from the Request funds.
+///
+/// These are the exact bytes the destination node signs. Integers are big endian:
+///
+/// ```text
+/// sha512/256("FUND_SUCCESS")                                  (32 bytes)
+/// sha512/256(requestId || sha512/256(route) || randNonce)    (32 bytes)
+/// invoiceId                                                   (32 bytes)
+/// destPayment                                                 (16 bytes, u128)
+/// ```
 pub fn create_response_signature_buffer<S>(
     response_send_funds: &ResponseSendFunds<S>,
     pending_request: &PendingRequest,
@@ -28,9 +36,7 @@ pub fn create_response_signature_buffer<S>(
 
     sbuffer.extend_from_slice(&hash::sha_512_256(&inner_blob));
     sbuffer.extend_from_slice(&pending_request.invoice_id);
-    sbuffer
-        .write_u128::<BigEndian>(pending_request.dest_payment)
-        .unwrap();
+    sbuffer.extend_from_slice(&u128_to_be_bytes(pending_request.dest_payment));
 
     sbuffer
 }
@@ -40,6 +46,19 @@ pub fn create_response_signature_buffer<S>(
 /// Create the buffer we sign over at the Failure funds.
 /// Note that the signature is not just over the Response funds bytes. The signed buffer also
 /// contains information from the Request funds.
+///
+/// These are the exact bytes the reporting node signs. Integers are big endian:
+///
+/// ```text
+/// sha512/256("FUND_FAILURE")    (32 bytes)
+/// requestId                     (16 bytes)
+/// sha512/256(route)             (32 bytes)
+/// destPayment                   (16 bytes, u128)
+/// invoiceId                     (32 bytes)
+/// reportingPublicKey            (32 bytes)
+/// reason                        (1 byte)
+/// randNonce                     (16 bytes)
+/// ```
 pub fn create_failure_signature_buffer<S>(
     failure_send_funds: &FailureSendFunds<S>,
     pending_request: &PendingRequest,
@@ -50,11 +69,10 @@ pub fn create_failure_signature_buffer<S>(
     sbuffer.extend_from_slice(&pending_request.request_id);
     sbuffer.extend_from_slice(&pending_request.route.hash());
 
-    sbuffer
-        .write_u128::<BigEndian>(pending_request.dest_payment)
-        .unwrap();
+    sbuffer.extend_from_slice(&u128_to_be_bytes(pending_request.dest_payment));
     sbuffer.extend_from_slice(&pending_request.invoice_id);
     sbuffer.extend_from_slice(&failure_send_funds.reporting_public_key);
+    sbuffer.extend_from_slice(&failure_send_funds.reason.canonical_serialize());
     sbuffer.extend_from_slice(&failure_send_funds.rand_nonce);
 
     sbuffer
@@ -107,7 +125,7 @@ pub fn verify_receipt(receipt: &Receipt, public_key: &PublicKey) -> bool {
     data.extend_from_slice(&hash::sha_512_256(FUND_SUCCESS_PREFIX));
     data.extend(receipt.response_hash.as_ref());
     data.extend(receipt.invoice_id.as_ref());
-    data.write_u128::<BigEndian>(receipt.dest_payment).unwrap();
+    data.extend_from_slice(&u128_to_be_bytes(receipt.dest_payment));
     verify_signature(&data, public_key, &receipt.signature)
 }
 
@@ -118,9 +136,9 @@ pub const TOKEN_NEXT: &[u8] = b"NEXT";
 /// Combine all operations into one hash value.
 pub fn operations_hash<B>(move_token: &MoveToken<B>) -> HashResult {
     let mut operations_data = Vec::new();
-    operations_data
-        .write_u64::<BigEndian>(usize_to_u64(move_token.operations.len()).unwrap())
-        .unwrap();
+    operations_data.extend_from_slice(&u64_to_be_bytes(
+        usize_to_u64(move_token.operations.len()).unwrap(),
+    ));
     for op in &move_token.operations {
         operations_data.extend_from_slice(&op.canonical_serialize());
     }
@@ -145,9 +163,9 @@ where
     hash_buff.extend_from_slice(&move_token.old_token);
 
     // TODO: Use CanonicalSerialize instead here:
-    hash_buff
-        .write_u64::<BigEndian>(usize_to_u64(move_token.operations.len()).unwrap())
-        .unwrap();
+    hash_buff.extend_from_slice(&u64_to_be_bytes(
+        usize_to_u64(move_token.operations.len()).unwrap(),
+    ));
     for op in &move_token.operations {
         hash_buff.extend_from_slice(&op.canonical_serialize());
     }
@@ -156,6 +174,26 @@ where
     sha_512_256(&hash_buff)
 }
 
+/// Create the buffer we sign over when sending a move token.
+/// The signature over this buffer is the new_token of the move token.
+///
+/// These are the exact bytes the sender of the move token signs. Integers are big endian:
+///
+/// ```text
+/// sha512/256("NEXT")            (32 bytes)
+/// prefix_hash                   (32 bytes, see `prefix_hash()`)
+/// localPublicKey                (32 bytes)
+/// remotePublicKey               (32 bytes)
+/// inconsistencyCounter          (8 bytes, u64)
+/// moveTokenCounter              (16 bytes, u128)
+/// balance                       (16 bytes, i128)
+/// localPendingDebt              (16 bytes, u128)
+/// remotePendingDebt             (16 bytes, u128)
+/// randNonce                     (16 bytes)
+/// ```
+///
+/// where `prefix_hash = sha512/256(oldToken || numOperations (u64) || operations ||
+/// optLocalRelays)`, with every operation and the relays in their canonical serialization.
 pub fn move_token_signature_buff<B, S>(move_token: &MoveToken<B, S>) -> Vec<u8>
 where
     B: CanonicalSerialize,
@@ -165,21 +203,11 @@ where
     sig_buffer.extend_from_slice(&prefix_hash(move_token));
     sig_buffer.extend_from_slice(&move_token.local_public_key);
     sig_buffer.extend_from_slice(&move_token.remote_public_key);
-    sig_buffer
-        .write_u64::<BigEndian>(move_token.inconsistency_counter)
-        .unwrap();
-    sig_buffer
-        .write_u128::<BigEndian>(move_token.move_token_counter)
-        .unwrap();
-    sig_buffer
-        .write_i128::<BigEndian>(move_token.balance)
-        .unwrap();
-    sig_buffer
-        .write_u128::<BigEndian>(move_token.local_pending_debt)
-        .unwrap();
-    sig_buffer
-        .write_u128::<BigEndian>(move_token.remote_pending_debt)
-        .unwrap();
+    sig_buffer.extend_from_slice(&u64_to_be_bytes(move_token.inconsistency_counter));
+    sig_buffer.extend_from_slice(&u128_to_be_bytes(move_token.move_token_counter));
+    sig_buffer.extend_from_slice(&i128_to_be_bytes(move_token.balance));
+    sig_buffer.extend_from_slice(&u128_to_be_bytes(move_token.local_pending_debt));
+    sig_buffer.extend_from_slice(&u128_to_be_bytes(move_token.remote_pending_debt));
     sig_buffer.extend_from_slice(&move_token.rand_nonce);
 
     sig_buffer
@@ -194,4 +222,275 @@ where
     verify_signature(&sig_buffer, public_key, &move_token.new_token)
 }
 
-// TODO: How to test this?
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crypto::crypto_rand::{RandValue, RAND_VALUE_LEN};
+    use crypto::hash::HASH_RESULT_LEN;
+    use crypto::identity::{Signature, PUBLIC_KEY_LEN, SIGNATURE_LEN};
+    use crypto::invoice_id::{InvoiceId, INVOICE_ID_LEN};
+    use crypto::uid::{Uid, UID_LEN};
+
+    use crate::funder::messages::{FailureReason, FriendTcOp, FriendsRoute};
+
+    fn dummy_pending_request() -> PendingRequest {
+        PendingRequest {
+            request_id: Uid::from(&[1; UID_LEN]),
+            route: FriendsRoute {
+                public_keys: vec![
+                    PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]),
+                    PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]),
+                ],
+            },
+            dest_payment: 0x1234,
+            invoice_id: InvoiceId::from(&[2; INVOICE_ID_LEN]),
+        }
+    }
+
+    #[test]
+    fn test_response_signature_buffer_layout() {
+        let pending_request = dummy_pending_request();
+        let response_send_funds = ResponseSendFunds {
+            request_id: pending_request.request_id.clone(),
+            rand_nonce: RandValue::from(&[3; RAND_VALUE_LEN]),
+            signature: (),
+        };
+        let sbuffer = create_response_signature_buffer(&response_send_funds, &pending_request);
+        assert_eq!(sbuffer.len(), 32 + 32 + 32 + 16);
+
+        let mut inner_blob = Vec::new();
+        inner_blob.extend_from_slice(&[1; UID_LEN]);
+        inner_blob.extend_from_slice(&pending_request.route.hash());
+        inner_blob.extend_from_slice(&[3; RAND_VALUE_LEN]);
+
+        assert_eq!(&sbuffer[0..32], &sha_512_256(b"FUND_SUCCESS")[..]);
+        assert_eq!(&sbuffer[32..64], &sha_512_256(&inner_blob)[..]);
+        assert_eq!(&sbuffer[64..96], &[2; INVOICE_ID_LEN][..]);
+        assert_eq!(&sbuffer[96..112], &u128_to_be_bytes(0x1234)[..]);
+    }
+
+    #[test]
+    fn test_failure_signature_buffer_layout() {
+        let pending_request = dummy_pending_request();
+        let failure_send_funds = FailureSendFunds {
+            request_id: pending_request.request_id.clone(),
+            reporting_public_key: PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]),
+            reason: FailureReason::InsufficientTrust,
+            rand_nonce: RandValue::from(&[3; RAND_VALUE_LEN]),
+            signature: (),
+        };
+        let sbuffer = create_failure_signature_buffer(&failure_send_funds, &pending_request);
+        assert_eq!(sbuffer.len(), 32 + 16 + 32 + 16 + 32 + 32 + 1 + 16);
+
+        assert_eq!(&sbuffer[0..32], &sha_512_256(b"FUND_FAILURE")[..]);
+        assert_eq!(&sbuffer[32..48], &[1; UID_LEN][..]);
+        assert_eq!(&sbuffer[48..80], &pending_request.route.hash()[..]);
+        assert_eq!(&sbuffer[80..96], &u128_to_be_bytes(0x1234)[..]);
+        assert_eq!(&sbuffer[96..128], &[2; INVOICE_ID_LEN][..]);
+        assert_eq!(&sbuffer[128..160], &[0xbb; PUBLIC_KEY_LEN][..]);
+        assert_eq!(sbuffer[160], 2u8);
+        assert_eq!(&sbuffer[161..177], &[3; RAND_VALUE_LEN][..]);
+    }
+
+    #[test]
+    fn test_move_token_signature_buff_layout() {
+        let move_token = MoveToken::<u32, ()> {
+            operations: vec![FriendTcOp::EnableRequests],
+            opt_local_relays: None,
+            old_token: Signature::from(&[4; SIGNATURE_LEN]),
+            local_public_key: PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]),
+            remote_public_key: PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]),
+            inconsistency_counter: 5,
+            move_token_counter: 6,
+            balance: -7,
+            local_pending_debt: 8,
+            remote_pending_debt: 9,
+            rand_nonce: RandValue::from(&[3; RAND_VALUE_LEN]),
+            new_token: (),
+        };
+        let sig_buffer = move_token_signature_buff(&move_token);
+        assert_eq!(
+            sig_buffer.len(),
+            HASH_RESULT_LEN * 2 + PUBLIC_KEY_LEN * 2 + 8 + 16 * 4 + RAND_VALUE_LEN
+        );
+
+        let mut prefix = Vec::new();
+        prefix.extend_from_slice(&[4; SIGNATURE_LEN]);
+        prefix.extend_from_slice(&u64_to_be_bytes(1));
+        prefix.extend_from_slice(&FriendTcOp::EnableRequests.canonical_serialize());
+        prefix.extend_from_slice(&move_token.opt_local_relays.canonical_serialize());
+
+        assert_eq!(&sig_buffer[0..32], &sha_512_256(b"NEXT")[..]);
+        assert_eq!(&sig_buffer[32..64], &sha_512_256(&prefix)[..]);
+        assert_eq!(&sig_buffer[64..96], &[0xaa; PUBLIC_KEY_LEN][..]);
+        assert_eq!(&sig_buffer[96..128], &[0xbb; PUBLIC_KEY_LEN][..]);
+        assert_eq!(&sig_buffer[128..136], &u64_to_be_bytes(5)[..]);
+        assert_eq!(&sig_buffer[136..152], &u128_to_be_bytes(6)[..]);
+        assert_eq!(&sig_buffer[152..168], &i128_to_be_bytes(-7)[..]);
+        assert_eq!(&sig_buffer[168..184], &u128_to_be_bytes(8)[..]);
+        assert_eq!(&sig_buffer[184..200], &u128_to_be_bytes(9)[..]);
+        assert_eq!(&sig_buffer[200..216], &[3; RAND_VALUE_LEN][..]);
+    }
+
+    /// Hex encode a buffer, for comparing against golden vectors.
+    fn to_hex(buffer: &[u8]) -> String {
+        buffer.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    fn golden_pending_request(dest_payment: u128, public_keys: Vec<PublicKey>) -> PendingRequest {
+        PendingRequest {
+            request_id: Uid::from(&[1; UID_LEN]),
+            route: FriendsRoute { public_keys },
+            dest_payment,
+            invoice_id: InvoiceId::from(&[2; INVOICE_ID_LEN]),
+        }
+    }
+
+    // The golden vectors below lock the signed format. If any of these tests fails, the
+    // signatures of this node are no longer compatible with other implementations.
+
+    #[test]
+    fn test_response_signature_buffer_golden() {
+        let pk_a = PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]);
+        let pk_b = PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]);
+        let pk_c = PublicKey::from(&[0xcc; PUBLIC_KEY_LEN]);
+
+        let pending_request = golden_pending_request(0x1234, vec![pk_a.clone(), pk_b.clone()]);
+        let response_send_funds = ResponseSendFunds {
+            request_id: pending_request.request_id.clone(),
+            rand_nonce: RandValue::from(&[3; RAND_VALUE_LEN]),
+            signature: (),
+        };
+        let expected = "63d48fb26e2cedc4ce46baa51c78ca3ac5e7da6818d57b6f5afdc22c3bd2de43\
+                        f18388c63e86b67d477258b669b14016d0af17933bdc80d9512b8c03c43f3098\
+                        0202020202020202020202020202020202020202020202020202020202020202\
+                        00000000000000000000000000001234";
+        assert_eq!(
+            to_hex(&create_response_signature_buffer(
+                &response_send_funds,
+                &pending_request
+            )),
+            expected
+        );
+
+        // Maximal payment, longer route:
+        let pending_request = golden_pending_request(u128::max_value(), vec![pk_a, pk_c, pk_b]);
+        let response_send_funds = ResponseSendFunds {
+            request_id: pending_request.request_id.clone(),
+            rand_nonce: RandValue::from(&[4; RAND_VALUE_LEN]),
+            signature: (),
+        };
+        let expected = "63d48fb26e2cedc4ce46baa51c78ca3ac5e7da6818d57b6f5afdc22c3bd2de43\
+                        c15498bf5a54ca8a67c263f604690dc5cd23f0161af821ddd68e46890e05b4fe\
+                        0202020202020202020202020202020202020202020202020202020202020202\
+                        ffffffffffffffffffffffffffffffff";
+        assert_eq!(
+            to_hex(&create_response_signature_buffer(
+                &response_send_funds,
+                &pending_request
+            )),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_failure_signature_buffer_golden() {
+        let pk_a = PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]);
+        let pk_b = PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]);
+        let pk_c = PublicKey::from(&[0xcc; PUBLIC_KEY_LEN]);
+
+        let pending_request = golden_pending_request(0x1234, vec![pk_a.clone(), pk_b.clone()]);
+        let failure_send_funds = FailureSendFunds {
+            request_id: pending_request.request_id.clone(),
+            reporting_public_key: pk_b.clone(),
+            reason: FailureReason::InsufficientTrust,
+            rand_nonce: RandValue::from(&[3; RAND_VALUE_LEN]),
+            signature: (),
+        };
+        let expected = "849aa34b086fcd6f0ab5229117d88f4849888dc4d50d591090ceb07976a3fd76\
+                        010101010101010101010101010101014aad7f3c315b959ed07e472424d17353\
+                        ba4b73726a017b18b1c009ed3041953e00000000000000000000000000001234\
+                        0202020202020202020202020202020202020202020202020202020202020202\
+                        bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb\
+                        0203030303030303030303030303030303";
+        assert_eq!(
+            to_hex(&create_failure_signature_buffer(
+                &failure_send_funds,
+                &pending_request
+            )),
+            expected
+        );
+
+        // Maximal payment, longer route:
+        let pending_request =
+            golden_pending_request(u128::max_value(), vec![pk_a, pk_c.clone(), pk_b]);
+        let failure_send_funds = FailureSendFunds {
+            request_id: pending_request.request_id.clone(),
+            reporting_public_key: pk_c,
+            reason: FailureReason::FriendCongested,
+            rand_nonce: RandValue::from(&[5; RAND_VALUE_LEN]),
+            signature: (),
+        };
+        let expected = "849aa34b086fcd6f0ab5229117d88f4849888dc4d50d591090ceb07976a3fd76\
+                        010101010101010101010101010101019a02e4258554c321bfded01466ea6717\
+                        ca8de6346060ffbf640236b06ccfc551ffffffffffffffffffffffffffffffff\
+                        0202020202020202020202020202020202020202020202020202020202020202\
+                        cccccccccccccccccccccccccccccccccccccccccccccccccccccccccccccccc\
+                        0505050505050505050505050505050505";
+        assert_eq!(
+            to_hex(&create_failure_signature_buffer(
+                &failure_send_funds,
+                &pending_request
+            )),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_move_token_signature_buff_golden() {
+        // Negative balance:
+        let mut move_token = MoveToken::<u32, ()> {
+            operations: vec![
+                FriendTcOp::EnableRequests,
+                FriendTcOp::SetRemoteMaxDebt(100),
+            ],
+            opt_local_relays: None,
+            old_token: Signature::from(&[4; SIGNATURE_LEN]),
+            local_public_key: PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]),
+            remote_public_key: PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]),
+            inconsistency_counter: 5,
+            move_token_counter: 6,
+            balance: -7,
+            local_pending_debt: 8,
+            remote_pending_debt: 9,
+            rand_nonce: RandValue::from(&[3; RAND_VALUE_LEN]),
+            new_token: (),
+        };
+        let expected = "ff4a64e1b7c7879bc14b09501f67a989c15d15a93a917e1eaa32a2787a4eea8b\
+                        34b04b006618f52fbdc228cd6747f5778d48ad190c623028bfc398abf6e5cf88\
+                        aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa\
+                        bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb\
+                        000000000000000500000000000000000000000000000006ffffffffffffffff\
+                        fffffffffffffff9000000000000000000000000000000080000000000000000\
+                        000000000000000903030303030303030303030303030303";
+        assert_eq!(to_hex(&move_token_signature_buff(&move_token)), expected);
+
+        // Extreme values: Minimal balance, maximal counters and pending debts:
+        move_token.operations = Vec::new();
+        move_token.inconsistency_counter = u64::max_value();
+        move_token.move_token_counter = u128::max_value();
+        move_token.balance = i128::min_value();
+        move_token.local_pending_debt = u128::max_value();
+        move_token.remote_pending_debt = u128::max_value();
+        move_token.rand_nonce = RandValue::from(&[0xff; RAND_VALUE_LEN]);
+        let expected = "ff4a64e1b7c7879bc14b09501f67a989c15d15a93a917e1eaa32a2787a4eea8b\
+                        9144958bc69ee6a43511c3353b12f3cd9941dc7bf58025f30f54c6a3dd043575\
+                        aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa\
+                        bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb\
+                        ffffffffffffffffffffffffffffffffffffffffffffffff8000000000000000\
+                        0000000000000000ffffffffffffffffffffffffffffffffffffffffffffffff\
+                        ffffffffffffffffffffffffffffffffffffffffffffffff";
+        assert_eq!(to_hex(&move_token_signature_buff(&move_token)), expected);
+    }
+}
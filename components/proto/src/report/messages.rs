@@ -162,6 +162,11 @@ where
     pub num_pending_user_requests: u64,
     // Request that the user has sent to this neighbor,
     // but have not been processed yet. Bounded in size.
+    pub acked_remote_max_debt: u128,
+    // The remote_max_debt the remote side has acknowledged receiving.
+    pub pending_queues_over_threshold: bool,
+    // Is any of the pending queues (requests, responses, user requests) of this friend longer than
+    // the configured threshold?
 }
 
 /// A FunderReport is a summary of a FunderState.
@@ -196,6 +201,8 @@ where
     SetNumPendingUserRequests(u64),
     SetOptLastIncomingMoveToken(Option<MoveTokenHashedReport>),
     SetLiveness(FriendLivenessReport),
+    SetAckedRemoteMaxDebt(u128),
+    SetPendingQueuesOverThreshold(bool),
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -297,6 +304,12 @@ where
             FriendReportMutation::SetLiveness(friend_liveness_report) => {
                 self.liveness = friend_liveness_report.clone();
             }
+            FriendReportMutation::SetAckedRemoteMaxDebt(acked_remote_max_debt) => {
+                self.acked_remote_max_debt = *acked_remote_max_debt;
+            }
+            FriendReportMutation::SetPendingQueuesOverThreshold(pending_queues_over_threshold) => {
+                self.pending_queues_over_threshold = *pending_queues_over_threshold;
+            }
         };
         Ok(())
     }
@@ -344,6 +357,8 @@ where
                     num_pending_requests: 0,
                     status: FriendStatusReport::from(&FriendStatus::Disabled),
                     num_pending_user_requests: 0,
+                    acked_remote_max_debt: 0,
+                    pending_queues_over_threshold: false,
                 };
                 if self
                     .friends
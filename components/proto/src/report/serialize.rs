@@ -603,6 +603,16 @@ fn ser_friend_report(
     );
 
     friend_report_builder.set_num_pending_user_requests(friend_report.num_pending_user_requests);
+
+    write_custom_u_int128(
+        friend_report.acked_remote_max_debt,
+        &mut friend_report_builder
+            .reborrow()
+            .init_acked_remote_max_debt(),
+    );
+
+    friend_report_builder
+        .set_pending_queues_over_threshold(friend_report.pending_queues_over_threshold);
 }
 
 fn deser_friend_report(
@@ -634,6 +644,10 @@ fn deser_friend_report(
         num_pending_responses: friend_report_reader.get_num_pending_responses(),
         status: deser_friend_status_report(&friend_report_reader.get_status()?)?,
         num_pending_user_requests: friend_report_reader.get_num_pending_user_requests(),
+        acked_remote_max_debt: read_custom_u_int128(
+            &friend_report_reader.get_acked_remote_max_debt()?,
+        )?,
+        pending_queues_over_threshold: friend_report_reader.get_pending_queues_over_threshold(),
     })
 }
 
@@ -852,6 +866,18 @@ fn ser_friend_report_mutation(
                 .reborrow()
                 .init_set_liveness(),
         ),
+        FriendReportMutation::SetAckedRemoteMaxDebt(acked_remote_max_debt) => {
+            write_custom_u_int128(
+                *acked_remote_max_debt,
+                &mut friend_report_mutation_builder
+                    .reborrow()
+                    .init_set_acked_remote_max_debt(),
+            )
+        }
+        FriendReportMutation::SetPendingQueuesOverThreshold(pending_queues_over_threshold) => {
+            friend_report_mutation_builder
+                .set_set_pending_queues_over_threshold(*pending_queues_over_threshold)
+        }
     };
 }
 
@@ -913,6 +939,14 @@ fn deser_friend_report_mutation(
                 &friend_liveness_report_reader?,
             )?)
         }
+        report_capnp::friend_report_mutation::SetAckedRemoteMaxDebt(
+            acked_remote_max_debt_reader,
+        ) => FriendReportMutation::SetAckedRemoteMaxDebt(read_custom_u_int128(
+            &acked_remote_max_debt_reader?,
+        )?),
+        report_capnp::friend_report_mutation::SetPendingQueuesOverThreshold(
+            pending_queues_over_threshold,
+        ) => FriendReportMutation::SetPendingQueuesOverThreshold(pending_queues_over_threshold),
     })
 }
 
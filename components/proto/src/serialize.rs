@@ -2,10 +2,27 @@ use crate::net::messages::NetAddressError;
 use capnp;
 use std::io;
 
+/// A list inside a deserialized message contains more items than allowed.
+#[derive(Debug)]
+pub struct BoundExceeded {
+    pub field: &'static str,
+    pub max: usize,
+    pub found: usize,
+}
+
+/// A fixed size value inside a deserialized message has the wrong amount of bytes.
+#[derive(Debug)]
+pub struct InvalidLength {
+    pub type_name: &'static str,
+    pub found: usize,
+}
+
 #[derive(Debug, From)]
 pub enum SerializeError {
     CapnpError(capnp::Error),
     NotInSchema(capnp::NotInSchema),
     IoError(io::Error),
     NetAddressError(NetAddressError),
+    BoundExceeded(BoundExceeded),
+    InvalidLength(InvalidLength),
 }
@@ -92,6 +92,9 @@ mod tests {
     use common::conn::FuncFutTransform;
     use common::dummy_connector::DummyConnector;
 
+    use keepalive::KeepAliveChannel;
+    use timer::{dummy_timer_multi_sender, TimerTick};
+
     async fn task_client_connector_basic(mut spawner: impl Spawn + Clone + Sync + Send + 'static) {
         let (local_sender, mut relay_receiver) = mpsc::channel::<Vec<u8>>(0);
         let (mut relay_sender, local_receiver) = mpsc::channel::<Vec<u8>>(0);
@@ -139,4 +142,55 @@ mod tests {
         let mut thread_pool = ThreadPool::new().unwrap();
         thread_pool.run(task_client_connector_basic(thread_pool.clone()));
     }
+
+    async fn task_client_connector_silent_relay(
+        mut spawner: impl Spawn + Clone + Sync + Send + 'static,
+    ) {
+        let (local_sender, mut relay_receiver) = mpsc::channel::<Vec<u8>>(0);
+        let (relay_sender, local_receiver) = mpsc::channel::<Vec<u8>>(0);
+
+        let conn_pair = (local_sender, local_receiver);
+        let (req_sender, mut req_receiver) = mpsc::channel(0);
+        let connector = DummyConnector::new(req_sender);
+
+        let keepalive_ticks = 8;
+        let (mut tick_sender_receiver, timer_client) = dummy_timer_multi_sender(spawner.clone());
+        let keepalive_transform =
+            KeepAliveChannel::new(timer_client, keepalive_ticks, spawner.clone());
+
+        let mut client_connector = ClientConnector::new(connector, keepalive_transform);
+
+        let address: u32 = 15;
+        let public_key = PublicKey::from(&[0x77; PUBLIC_KEY_LEN]);
+        let fut_conn_pair = spawner
+            .spawn_with_handle(async move {
+                await!(client_connector.transform((address, public_key))).unwrap()
+            })
+            .unwrap();
+
+        let req = await!(req_receiver.next()).unwrap();
+        req.reply(Some(conn_pair));
+        let (_sender, mut receiver) = await!(fut_conn_pair);
+
+        let vec = await!(relay_receiver.next()).unwrap();
+        match deserialize_init_connection(&vec).unwrap() {
+            InitConnection::Connect(_) => {}
+            _ => unreachable!(),
+        };
+
+        // The relay keeps the connection open, but never sends anything.
+        // We expect the connection to be closed after keepalive_ticks time ticks:
+        let mut tick_sender = await!(tick_sender_receiver.next()).unwrap();
+        for _ in 0..keepalive_ticks {
+            await!(tick_sender.send(TimerTick)).unwrap();
+        }
+        assert!(await!(receiver.next()).is_none());
+        drop(relay_sender);
+    }
+
+    #[test]
+    fn test_client_connector_silent_relay() {
+        let mut thread_pool = ThreadPool::new().unwrap();
+        thread_pool.run(task_client_connector_silent_relay(thread_pool.clone()));
+    }
 }
@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use std::marker::Unpin;
 
 use futures::channel::mpsc;
@@ -14,19 +15,41 @@ use proto::relay::serialize::{
 
 use common::access_control::{AccessControl, AccessControlOp};
 use common::select_streams::{select_streams, BoxStream};
+use timer::utils::sleep_ticks;
 use timer::{TimerClient, TimerTick};
 
 type AccessControlPk = AccessControl<PublicKey>;
 type AccessControlOpPk = AccessControlOp<PublicKey>;
 
+/// Default maximum amount of simultaneous connections accepted from a single remote public key.
+pub const DEFAULT_MAX_CONNS_PER_KEY: usize = 4;
+
+/// Default maximum amount of simultaneous connections accepted from all remote public keys.
+pub const DEFAULT_MAX_CONNS: usize = 0x100;
+
 #[derive(Debug)]
 pub enum ClientListenerError {
     SendInitConnectionError,
     ConnectionFailure,
     // AccessControlClosed,
     SendToServerError,
-    // ServerClosed,
+    ServerClosed,
     SpawnError,
+    TimerClosed,
+}
+
+impl ClientListenerError {
+    /// Is this error fatal?
+    /// Returns true if we can not recover from this error by reconnecting to the relay.
+    fn is_fatal(&self) -> bool {
+        match self {
+            ClientListenerError::SendInitConnectionError
+            | ClientListenerError::ConnectionFailure
+            | ClientListenerError::SendToServerError
+            | ClientListenerError::ServerClosed => false,
+            ClientListenerError::SpawnError | ClientListenerError::TimerClosed => true,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -36,6 +59,7 @@ enum ClientListenerEvent {
     ServerMessage(IncomingConnection),
     ServerClosed,
     PendingReject(PublicKey),
+    ConnectionClosed(PublicKey),
 }
 
 #[derive(Debug)]
@@ -45,6 +69,8 @@ enum AcceptConnectionError {
     SendInitConnectionError,
     SendConnPairError,
     RequestTimerStreamError,
+    SpawnError,
+    ClosedSenderError,
 }
 
 /*
@@ -104,22 +130,66 @@ where
     }
 }
 
-async fn accept_connection<C, CS, CSE, FT>(
+/// Forward incoming messages of an accepted connection to `user_sender`.
+/// The public key of the remote side is sent to `closed_sender` when the connection is closed,
+/// either by the remote side or by the user.
+fn track_connection(
+    public_key: PublicKey,
+    mut from_tunnel_receiver: mpsc::Receiver<Vec<u8>>,
+    mut user_sender: mpsc::Sender<Vec<u8>>,
+    mut closed_sender: mpsc::Sender<PublicKey>,
+    mut spawner: impl Spawn,
+) -> Result<(), AcceptConnectionError> {
+    let fut_track = async move {
+        let _ = await!(user_sender.send_all(&mut from_tunnel_receiver));
+        let _ = await!(closed_sender.send(public_key));
+    };
+    spawner
+        .spawn(fut_track)
+        .map_err(|_| AcceptConnectionError::SpawnError)
+}
+
+/// Give up on accepting a connection: Release its connection slot, and ask the relay to reject
+/// it. The slot is released through `closed_sender`, which outlives reconnections to the relay.
+async fn reject_connection(
     public_key: PublicKey,
-    connector: C,
     mut pending_reject_sender: mpsc::Sender<PublicKey>,
+    mut closed_sender: mpsc::Sender<PublicKey>,
+) -> Result<(), AcceptConnectionError> {
+    await!(closed_sender.send(public_key.clone()))
+        .map_err(|_| AcceptConnectionError::ClosedSenderError)?;
+    await!(pending_reject_sender.send(public_key))
+        .map_err(|_| AcceptConnectionError::PendingRejectSenderError)
+}
+
+async fn accept_connection<C, CS, CSE, FT, S>(
+    public_key: PublicKey,
+    connector: C,
+    pending_reject_sender: mpsc::Sender<PublicKey>,
+    mut closed_sender: mpsc::Sender<PublicKey>,
     mut connections_sender: CS,
     mut keepalive_transform: FT,
     conn_timeout_ticks: usize,
     mut timer_client: TimerClient,
+    spawner: S,
 ) -> Result<(), AcceptConnectionError>
 where
     C: FutTransform<Input = (), Output = Option<ConnPairVec>> + Send,
     CS: Sink<(PublicKey, ConnPairVec), SinkError = CSE> + Unpin + 'static,
     FT: FutTransform<Input = ConnPairVec, Output = ConnPairVec>,
+    S: Spawn,
 {
-    let timer_stream = await!(timer_client.request_timer_stream())
-        .map_err(|_| AcceptConnectionError::RequestTimerStreamError)?;
+    let timer_stream = match await!(timer_client.request_timer_stream()) {
+        Ok(timer_stream) => timer_stream,
+        Err(_) => {
+            await!(reject_connection(
+                public_key,
+                pending_reject_sender,
+                closed_sender
+            ))?;
+            return Err(AcceptConnectionError::RequestTimerStreamError);
+        }
+    };
     let opt_conn_pair = await!(connect_with_timeout(
         connector,
         conn_timeout_ticks,
@@ -128,8 +198,11 @@ where
     let conn_pair = match opt_conn_pair {
         Some(conn_pair) => Ok(conn_pair),
         None => {
-            await!(pending_reject_sender.send(public_key.clone()))
-                .map_err(|_| AcceptConnectionError::PendingRejectSenderError)?;
+            await!(reject_connection(
+                public_key.clone(),
+                pending_reject_sender,
+                closed_sender.clone()
+            ))?;
             Err(AcceptConnectionError::ConnectionFailed)
         }
     }?;
@@ -141,32 +214,118 @@ where
         serialize_init_connection(&InitConnection::Accept(public_key.clone()));
     let send_res = await!(sender.send(ser_init_connection));
     if send_res.is_err() {
-        await!(pending_reject_sender.send(public_key))
-            .map_err(|_| AcceptConnectionError::PendingRejectSenderError)?;
+        await!(reject_connection(
+            public_key,
+            pending_reject_sender,
+            closed_sender
+        ))?;
         return Err(AcceptConnectionError::SendInitConnectionError);
     }
 
     let to_tunnel_sender = sender;
     let from_tunnel_receiver = receiver;
 
-    let (user_to_tunnel_sender, user_from_tunnel_receiver) =
+    let (user_to_tunnel_sender, keepalive_receiver) =
         await!(keepalive_transform.transform((to_tunnel_sender, from_tunnel_receiver)));
 
-    await!(connections_sender.send((
-        public_key,
+    // The connection is accepted. Until we start tracking it, we report failures to
+    // `closed_sender` ourselves, so that the connection slot is released:
+    let (user_sender, user_from_tunnel_receiver) = mpsc::channel(0);
+    let send_res = await!(connections_sender.send((
+        public_key.clone(),
         (user_to_tunnel_sender, user_from_tunnel_receiver)
-    )))
-    .map_err(|_| AcceptConnectionError::SendConnPairError)?;
+    )));
+    if send_res.is_err() {
+        await!(closed_sender.send(public_key))
+            .map_err(|_| AcceptConnectionError::ClosedSenderError)?;
+        return Err(AcceptConnectionError::SendConnPairError);
+    }
+
+    let track_res = track_connection(
+        public_key.clone(),
+        keepalive_receiver,
+        user_sender,
+        closed_sender.clone(),
+        spawner,
+    );
+    if let Err(e) = track_res {
+        await!(closed_sender.send(public_key))
+            .map_err(|_| AcceptConnectionError::ClosedSenderError)?;
+        return Err(e);
+    }
     Ok(())
 }
 
+/// Amount of connections (accepted or in the process of being accepted), per remote public key
+/// and in total.
+struct ConnCounter {
+    conns_per_key: HashMap<PublicKey, usize>,
+    total: usize,
+}
+
+impl ConnCounter {
+    fn new() -> Self {
+        ConnCounter {
+            conns_per_key: HashMap::new(),
+            total: 0,
+        }
+    }
+
+    fn num_conns(&self, public_key: &PublicKey) -> usize {
+        self.conns_per_key.get(public_key).cloned().unwrap_or(0)
+    }
+
+    fn acquire(&mut self, public_key: &PublicKey) {
+        *self.conns_per_key.entry(public_key.clone()).or_insert(0) += 1;
+        self.total = self.total.saturating_add(1);
+    }
+
+    fn release(&mut self, public_key: &PublicKey) {
+        let remove = match self.conns_per_key.get_mut(public_key) {
+            Some(num_conns) => {
+                *num_conns = num_conns.saturating_sub(1);
+                self.total = self.total.saturating_sub(1);
+                *num_conns == 0
+            }
+            None => false,
+        };
+        if remove {
+            self.conns_per_key.remove(public_key);
+        }
+    }
+}
+
+/// Connections accepted through the relay. Kept across reconnections to the relay: Connections
+/// accepted before a reconnection keep running, and must keep counting towards the limits.
+struct ConnTracker {
+    conn_counter: ConnCounter,
+    /// The public key of the remote side of an accepted connection is sent through closed_sender
+    /// when the connection is closed, or when it could not be accepted.
+    closed_sender: mpsc::Sender<PublicKey>,
+    closed_receiver: mpsc::Receiver<PublicKey>,
+}
+
+impl ConnTracker {
+    fn new() -> Self {
+        let (closed_sender, closed_receiver) = mpsc::channel::<PublicKey>(0);
+        ConnTracker {
+            conn_counter: ConnCounter::new(),
+            closed_sender,
+            closed_receiver,
+        }
+    }
+}
+
 async fn inner_client_listener<'a, C, IAC, CS, CSE, FT>(
     mut connector: C,
     access_control: &'a mut AccessControlPk,
     incoming_access_control: &'a mut IAC,
+    conn_tracker: &'a mut ConnTracker,
     connections_sender: CS,
     mut keepalive_transform: FT,
     conn_timeout_ticks: usize,
+    max_conns_per_key: usize,
+    max_conns: usize,
     timer_client: TimerClient,
     mut spawner: impl Spawn + Clone + Send + 'static,
     mut opt_event_sender: Option<mpsc::Sender<ClientListenerEvent>>,
@@ -185,9 +344,15 @@ where
 
     // A channel used by the accept_connection.
     // In case of failure to accept a connection, the public key of the rejected remote host will
-    // be received at pending_reject_receiver
+    // be received at pending_reject_receiver, so that we can reject it through the relay.
     let (pending_reject_sender, pending_reject_receiver) = mpsc::channel::<PublicKey>(0);
 
+    let ConnTracker {
+        conn_counter,
+        closed_sender,
+        closed_receiver,
+    } = conn_tracker;
+
     let (mut sender, receiver) = conn_pair;
     let ser_init_connection = serialize_init_connection(&InitConnection::Listen);
 
@@ -231,11 +396,13 @@ where
         )));
 
     let pending_reject_receiver = pending_reject_receiver.map(ClientListenerEvent::PendingReject);
+    let closed_receiver = closed_receiver.map(ClientListenerEvent::ConnectionClosed);
 
     let mut events = select_streams![
         incoming_access_control,
         server_receiver,
-        pending_reject_receiver
+        pending_reject_receiver,
+        closed_receiver
     ];
 
     while let Some(event) = await!(events.next()) {
@@ -251,19 +418,40 @@ where
                 if !access_control.is_allowed(&public_key) {
                     await!(sender.send(RejectConnection { public_key }))
                         .map_err(|_| ClientListenerError::SendToServerError)?;
+                } else if conn_counter.total >= max_conns {
+                    warn!(
+                        "inner_client_listener(): Connection budget exhausted. Rejecting {}.",
+                        public_key
+                    );
+                    await!(sender.send(RejectConnection { public_key }))
+                        .map_err(|_| ClientListenerError::SendToServerError)?;
+                } else if conn_counter.num_conns(&public_key) >= max_conns_per_key {
+                    warn!(
+                        "inner_client_listener(): Too many connections from {}. Rejecting.",
+                        public_key
+                    );
+                    await!(sender.send(RejectConnection { public_key }))
+                        .map_err(|_| ClientListenerError::SendToServerError)?;
                 } else {
                     // We will attempt to accept the connection
+                    conn_counter.acquire(&public_key);
+                    let peer_public_key = public_key.clone();
                     let fut_accept = accept_connection(
                         public_key,
                         connector.clone(),
                         pending_reject_sender.clone(),
+                        closed_sender.clone(),
                         connections_sender.clone(),
                         keepalive_transform.clone(),
                         conn_timeout_ticks,
                         timer_client.clone(),
+                        spawner.clone(),
                     )
                     .map_err(|e| {
-                        error!("Error in accept_connection: {:?}", e);
+                        error!(
+                            "Error in accept_connection with peer {}: {:?}",
+                            peer_public_key, e
+                        );
                     })
                     .map(|_| ());
                     spawner
@@ -272,21 +460,83 @@ where
                 }
             }
             ClientListenerEvent::PendingReject(public_key) => {
+                // The connection slot is released through closed_receiver:
                 await!(sender.send(RejectConnection { public_key }))
                     .map_err(|_| ClientListenerError::SendToServerError)?;
             }
-            ClientListenerEvent::ServerClosed => break,
+            ClientListenerEvent::ConnectionClosed(public_key) => {
+                conn_counter.release(&public_key);
+            }
+            ClientListenerEvent::ServerClosed => return Err(ClientListenerError::ServerClosed),
             ClientListenerEvent::AccessControlClosed => break,
         }
     }
     Ok(())
 }
 
+/// Listen for incoming connections through a relay.
+/// Reconnects to the relay (waiting `backoff_ticks` between attempts) after transient errors.
+/// Returns Ok(()) when `incoming_access_control` is closed, or an error if a fatal error occurred.
+pub async fn client_listener<'a, C, IAC, CS, CSE, FT>(
+    connector: C,
+    access_control: &'a mut AccessControl<PublicKey>,
+    incoming_access_control: &'a mut IAC,
+    connections_sender: CS,
+    keepalive_transform: FT,
+    conn_timeout_ticks: usize,
+    max_conns_per_key: usize,
+    max_conns: usize,
+    backoff_ticks: usize,
+    timer_client: TimerClient,
+    spawner: impl Spawn + Clone + Send + 'static,
+) -> Result<(), ClientListenerError>
+where
+    C: FutTransform<Input = (), Output = Option<ConnPairVec>> + Send + Sync + Clone + 'static,
+    IAC: Stream<Item = AccessControlOp<PublicKey>> + Unpin + Send + 'static,
+    CS: Sink<(PublicKey, ConnPairVec), SinkError = CSE> + Unpin + Clone + Send + 'static,
+    CSE: 'static,
+    FT: FutTransform<Input = ConnPairVec, Output = ConnPairVec> + Clone + Send + 'static,
+{
+    let mut conn_tracker = ConnTracker::new();
+    loop {
+        let res = await!(inner_client_listener(
+            connector.clone(),
+            access_control,
+            incoming_access_control,
+            &mut conn_tracker,
+            connections_sender.clone(),
+            keepalive_transform.clone(),
+            conn_timeout_ticks,
+            max_conns_per_key,
+            max_conns,
+            timer_client.clone(),
+            spawner.clone(),
+            None
+        ));
+        match res {
+            Ok(()) => return Ok(()),
+            Err(e) => {
+                if e.is_fatal() {
+                    return Err(e);
+                }
+                warn!("client_listener(): inner_client_listener() error: {:?}", e);
+            }
+        }
+
+        // Wait before we attempt to reconnect:
+        await!(sleep_ticks(backoff_ticks, timer_client.clone()))
+            .map_err(|_| ClientListenerError::TimerClosed)?;
+    }
+}
+
 #[derive(Clone)]
 pub struct ClientListener<C, FT, S> {
     connector: C,
     keepalive_transform: FT,
     conn_timeout_ticks: usize,
+    max_conns_per_key: usize,
+    max_conns: usize,
+    backoff_ticks: usize,
     timer_client: TimerClient,
     spawner: S,
 }
@@ -296,6 +546,9 @@ impl<C, FT, S> ClientListener<C, FT, S> {
         connector: C,
         keepalive_transform: FT,
         conn_timeout_ticks: usize,
+        max_conns_per_key: usize,
+        max_conns: usize,
+        backoff_ticks: usize,
         timer_client: TimerClient,
         spawner: S,
     ) -> ClientListener<C, FT, S> {
@@ -303,6 +556,9 @@ impl<C, FT, S> ClientListener<C, FT, S> {
             connector,
             keepalive_transform,
             conn_timeout_ticks,
+            max_conns_per_key,
+            max_conns,
+            backoff_ticks,
             timer_client,
             spawner,
         }
@@ -336,18 +592,20 @@ where
         let const_connector = ConstFutTransform::new(self.connector.clone(), relay_address);
 
         let fut = async move {
-            await!(inner_client_listener(
+            await!(client_listener(
                 const_connector,
                 &mut access_control,
                 &mut access_control_receiver,
                 connections_sender,
                 self.keepalive_transform,
                 self.conn_timeout_ticks,
+                self.max_conns_per_key,
+                self.max_conns,
+                self.backoff_ticks,
                 self.timer_client,
-                self.spawner,
-                None
+                self.spawner
             )
-            .map_err(|e| warn!("inner_client_listener() error: {:?}", e))
+            .map_err(|e| warn!("client_listener() error: {:?}", e))
             .map(|_| ()))
         };
 
@@ -363,8 +621,11 @@ mod tests {
     use crypto::identity::PUBLIC_KEY_LEN;
     use futures::channel::oneshot;
     use futures::executor::ThreadPool;
+    use futures::future::FutureObj;
+    use futures::task::SpawnError;
     use proto::relay::serialize::deserialize_init_connection;
-    use timer::create_timer_incoming;
+    use timer::utils::dummy_connector_with_latency;
+    use timer::{create_timer_incoming, dummy_timer_multi_sender};
 
     use proto::relay::serialize::{deserialize_reject_connection, serialize_incoming_connection};
 
@@ -429,11 +690,52 @@ mod tests {
         thread_pool.run(task_connect_with_timeout_timeout(thread_pool.clone()));
     }
 
+    async fn task_connect_with_timeout_latency(mut spawner: impl Spawn + Clone) {
+        let conn_timeout_ticks = 8;
+        let (mut timer_sender, timer_stream) = mpsc::channel::<TimerTick>(0);
+
+        // The connector's latency exceeds the connection timeout:
+        let (_latency_tick_sender_receiver, latency_timer_client) =
+            dummy_timer_multi_sender(spawner.clone());
+        let (req_sender, _req_receiver) = mpsc::channel(0);
+        let connector = dummy_connector_with_latency::<(), Option<ConnPairVec>>(
+            req_sender,
+            conn_timeout_ticks + 8,
+            latency_timer_client,
+        );
+
+        let (res_sender, res_receiver) = oneshot::channel();
+
+        spawner
+            .spawn(async move {
+                let res = await!(connect_with_timeout(
+                    connector,
+                    conn_timeout_ticks,
+                    timer_stream
+                ));
+                res_sender.send(res).unwrap();
+            })
+            .unwrap();
+
+        for _ in 0..conn_timeout_ticks {
+            await!(timer_sender.send(TimerTick)).unwrap();
+        }
+
+        assert!(await!(res_receiver).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_connect_with_timeout_latency() {
+        let mut thread_pool = ThreadPool::new().unwrap();
+        thread_pool.run(task_connect_with_timeout_latency(thread_pool.clone()));
+    }
+
     async fn task_accept_connection_basic(mut spawner: impl Spawn + Clone + Send + 'static) {
         let public_key = PublicKey::from(&[0x77; PUBLIC_KEY_LEN]);
         let (req_sender, mut req_receiver) = mpsc::channel(0);
         let connector = DummyConnector::new(req_sender);
         let (pending_reject_sender, _pending_reject_receiver) = mpsc::channel(0);
+        let (closed_sender, _closed_receiver) = mpsc::channel(0);
         let (connections_sender, mut connections_receiver) = mpsc::channel(0);
         let conn_timeout_ticks = 8;
         let (_tick_sender, tick_receiver) = mpsc::channel(0);
@@ -446,10 +748,12 @@ mod tests {
             public_key.clone(),
             connector,
             pending_reject_sender,
+            closed_sender,
             connections_sender,
             keepalive_transform,
             conn_timeout_ticks,
             timer_client,
+            spawner.clone(),
         )
         .map_err(|e| error!("accept_connection error: {:?}", e))
         .map(|_| ());
@@ -496,6 +800,67 @@ mod tests {
         thread_pool.run(task_accept_connection_basic(thread_pool.clone()));
     }
 
+    async fn task_accept_connection_send_conn_pair_error(
+        mut spawner: impl Spawn + Clone + Send + 'static,
+    ) {
+        let public_key = PublicKey::from(&[0x77; PUBLIC_KEY_LEN]);
+        let (req_sender, mut req_receiver) = mpsc::channel(0);
+        let connector = DummyConnector::new(req_sender);
+        let (pending_reject_sender, _pending_reject_receiver) = mpsc::channel(0);
+        let (closed_sender, mut closed_receiver) = mpsc::channel(0);
+        // Nobody is going to receive the accepted connection:
+        let (connections_sender, _) = mpsc::channel::<(PublicKey, ConnPairVec)>(0);
+        let conn_timeout_ticks = 8;
+        let (_tick_sender, tick_receiver) = mpsc::channel(0);
+        let timer_client = create_timer_incoming(tick_receiver, spawner.clone()).unwrap();
+
+        // We don't need a real keepalive transform for this test:
+        let keepalive_transform = FuncFutTransform::new(|x| Box::pin(future::ready(x)));
+
+        let fut_accept = accept_connection(
+            public_key.clone(),
+            connector,
+            pending_reject_sender,
+            closed_sender,
+            connections_sender,
+            keepalive_transform,
+            conn_timeout_ticks,
+            timer_client,
+            spawner.clone(),
+        )
+        .map_err(|e| error!("accept_connection error: {:?}", e))
+        .map(|_| ());
+
+        spawner.spawn(fut_accept).unwrap();
+
+        let (local_sender, mut remote_receiver) = mpsc::channel(0);
+        let (_remote_sender, local_receiver) = mpsc::channel(0);
+
+        let conn_pair = (local_sender, local_receiver);
+
+        let req = await!(req_receiver.next()).unwrap();
+        req.reply(Some(conn_pair));
+
+        let vec_init_connection = await!(remote_receiver.next()).unwrap();
+        let init_connection = deserialize_init_connection(&vec_init_connection).unwrap();
+        if let InitConnection::Accept(accept_public_key) = init_connection {
+            assert_eq!(accept_public_key, public_key);
+        } else {
+            unreachable!();
+        }
+
+        // The connection could not be handed over, so it is reported as closed:
+        assert_eq!(await!(closed_receiver.next()).unwrap(), public_key);
+    }
+
+    #[test]
+    fn test_accept_connection_send_conn_pair_error() {
+        let mut thread_pool = ThreadPool::new().unwrap();
+        thread_pool.run(task_accept_connection_send_conn_pair_error(
+            thread_pool.clone(),
+        ));
+    }
+
     async fn task_client_listener_basic(mut spawner: impl Spawn + Clone + Send + 'static) {
         let (req_sender, mut req_receiver) = mpsc::channel(0);
         let connector = DummyConnector::new(req_sender);
@@ -511,13 +876,17 @@ mod tests {
         let c_spawner = spawner.clone();
         let fut_listener = async move {
             let mut access_control = AccessControlPk::new();
+            let mut conn_tracker = ConnTracker::new();
             await!(inner_client_listener(
                 connector,
                 &mut access_control,
                 &mut incoming_access_control,
+                &mut conn_tracker,
                 connections_sender,
                 keepalive_transform,
                 conn_timeout_ticks,
+                DEFAULT_MAX_CONNS_PER_KEY,
+                DEFAULT_MAX_CONNS,
                 timer_client,
                 c_spawner,
                 Some(event_sender)
@@ -596,6 +965,578 @@ mod tests {
         thread_pool.run(task_client_listener_basic(thread_pool.clone()));
     }
 
+    async fn task_client_listener_accepted_public_key(
+        mut spawner: impl Spawn + Clone + Send + 'static,
+    ) {
+        let (req_sender, mut req_receiver) = mpsc::channel(0);
+        let connector = DummyConnector::new(req_sender);
+        let (connections_sender, mut connections_receiver) = mpsc::channel(0);
+        let conn_timeout_ticks = 8;
+        let (_tick_sender, tick_receiver) = mpsc::channel(0);
+        let timer_client = create_timer_incoming(tick_receiver, spawner.clone()).unwrap();
+
+        let (mut acl_sender, mut incoming_access_control) = mpsc::channel(0);
+        let (event_sender, mut event_receiver) = mpsc::channel(0);
+        let keepalive_transform = FuncFutTransform::new(|x| Box::pin(future::ready(x)));
+
+        let c_spawner = spawner.clone();
+        let fut_listener = async move {
+            let mut access_control = AccessControlPk::new();
+            let mut conn_tracker = ConnTracker::new();
+            await!(inner_client_listener(
+                connector,
+                &mut access_control,
+                &mut incoming_access_control,
+                &mut conn_tracker,
+                connections_sender,
+                keepalive_transform,
+                conn_timeout_ticks,
+                DEFAULT_MAX_CONNS_PER_KEY,
+                DEFAULT_MAX_CONNS,
+                timer_client,
+                c_spawner,
+                Some(event_sender)
+            ))
+        }
+            .map_err(|e| warn!("inner_client_listener error: {:?}", e))
+            .map(|_| ());
+
+        spawner.spawn(fut_listener).unwrap();
+
+        // listener will attempt to start a main connection to the relay:
+        let (mut relay_sender, local_receiver) = mpsc::channel(0);
+        let (local_sender, mut relay_receiver) = mpsc::channel(0);
+        let conn_pair = (local_sender, local_receiver);
+        let req = await!(req_receiver.next()).unwrap();
+        req.reply(Some(conn_pair));
+
+        // Open access for a certain public key:
+        let public_key_a = PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]);
+        await!(acl_sender.send(AccessControlOp::Add(public_key_a.clone()))).unwrap();
+        await!(event_receiver.next()).unwrap();
+
+        // First message to the relay should be InitConnection::Listen:
+        let vec_init_connection = await!(relay_receiver.next()).unwrap();
+        let init_connection = deserialize_init_connection(&vec_init_connection).unwrap();
+        if let InitConnection::Listen = init_connection {
+        } else {
+            unreachable!();
+        }
+
+        // Relay will now send a message about incoming connection from an allowed public key:
+        let incoming_connection = IncomingConnection {
+            public_key: public_key_a.clone(),
+        };
+        let vec_incoming_connection = serialize_incoming_connection(&incoming_connection);
+        await!(relay_sender.send(vec_incoming_connection)).unwrap();
+        await!(event_receiver.next()).unwrap();
+
+        // Listener will open a connection to the relay and accept:
+        let (mut remote_sender, local_receiver) = mpsc::channel(0);
+        let (local_sender, mut remote_receiver) = mpsc::channel(0);
+        let conn_pair = (local_sender, local_receiver);
+
+        let req = await!(req_receiver.next()).unwrap();
+        req.reply(Some(conn_pair));
+
+        let vec_init_connection = await!(remote_receiver.next()).unwrap();
+        let init_connection = deserialize_init_connection(&vec_init_connection).unwrap();
+        if let InitConnection::Accept(accepted_public_key) = init_connection {
+            assert_eq!(accepted_public_key, public_key_a);
+        } else {
+            unreachable!();
+        }
+
+        // The accepted connection is handed over together with the remote public key:
+        let (accepted_public_key, (_sender, mut receiver)) =
+            await!(connections_receiver.next()).unwrap();
+        assert_eq!(accepted_public_key, public_key_a);
+
+        await!(remote_sender.send(vec![1, 2, 3])).unwrap();
+        assert_eq!(await!(receiver.next()).unwrap(), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_client_listener_accepted_public_key() {
+        let mut thread_pool = ThreadPool::new().unwrap();
+        thread_pool.run(task_client_listener_accepted_public_key(
+            thread_pool.clone(),
+        ));
+    }
+
+    async fn task_client_listener_max_conns_per_key(
+        mut spawner: impl Spawn + Clone + Send + 'static,
+    ) {
+        let (req_sender, mut req_receiver) = mpsc::channel(0);
+        let connector = DummyConnector::new(req_sender);
+        let (connections_sender, mut connections_receiver) = mpsc::channel(0);
+        let conn_timeout_ticks = 8;
+        let max_conns_per_key = 2;
+        let (_tick_sender, tick_receiver) = mpsc::channel(0);
+        let timer_client = create_timer_incoming(tick_receiver, spawner.clone()).unwrap();
+
+        let (mut acl_sender, mut incoming_access_control) = mpsc::channel(0);
+        let (event_sender, mut event_receiver) = mpsc::channel(0);
+        let keepalive_transform = FuncFutTransform::new(|x| Box::pin(future::ready(x)));
+
+        let c_spawner = spawner.clone();
+        let fut_listener = async move {
+            let mut access_control = AccessControlPk::new();
+            let mut conn_tracker = ConnTracker::new();
+            await!(inner_client_listener(
+                connector,
+                &mut access_control,
+                &mut incoming_access_control,
+                &mut conn_tracker,
+                connections_sender,
+                keepalive_transform,
+                conn_timeout_ticks,
+                max_conns_per_key,
+                DEFAULT_MAX_CONNS,
+                timer_client,
+                c_spawner,
+                Some(event_sender)
+            ))
+        }
+            .map_err(|e| warn!("inner_client_listener error: {:?}", e))
+            .map(|_| ());
+
+        spawner.spawn(fut_listener).unwrap();
+
+        // listener will attempt to start a main connection to the relay:
+        let (mut relay_sender, local_receiver) = mpsc::channel(0);
+        let (local_sender, mut relay_receiver) = mpsc::channel(0);
+        let conn_pair = (local_sender, local_receiver);
+        let req = await!(req_receiver.next()).unwrap();
+        req.reply(Some(conn_pair));
+
+        let public_key_a = PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]);
+        await!(acl_sender.send(AccessControlOp::Add(public_key_a.clone()))).unwrap();
+        await!(event_receiver.next()).unwrap();
+
+        let vec_init_connection = await!(relay_receiver.next()).unwrap();
+        let init_connection = deserialize_init_connection(&vec_init_connection).unwrap();
+        if let InitConnection::Listen = init_connection {
+        } else {
+            unreachable!();
+        }
+
+        let incoming_connection = IncomingConnection {
+            public_key: public_key_a.clone(),
+        };
+
+        // The first max_conns_per_key connections are accepted:
+        let mut conns = Vec::new();
+        for _ in 0..max_conns_per_key {
+            await!(relay_sender.send(serialize_incoming_connection(&incoming_connection))).unwrap();
+            await!(event_receiver.next()).unwrap();
+
+            let (remote_sender, local_receiver) = mpsc::channel(0);
+            let (local_sender, mut remote_receiver) = mpsc::channel(0);
+            let req = await!(req_receiver.next()).unwrap();
+            req.reply(Some((local_sender, local_receiver)));
+
+            let vec_init_connection = await!(remote_receiver.next()).unwrap();
+            let init_connection = deserialize_init_connection(&vec_init_connection).unwrap();
+            if let InitConnection::Accept(accepted_public_key) = init_connection {
+                assert_eq!(accepted_public_key, public_key_a);
+            } else {
+                unreachable!();
+            }
+
+            let (accepted_public_key, conn_pair) = await!(connections_receiver.next()).unwrap();
+            assert_eq!(accepted_public_key, public_key_a);
+            conns.push((remote_sender, remote_receiver, conn_pair));
+        }
+
+        // The next connection from the same public key is rejected:
+        await!(relay_sender.send(serialize_incoming_connection(&incoming_connection))).unwrap();
+        await!(event_receiver.next()).unwrap();
+
+        let vec_reject_connection = await!(relay_receiver.next()).unwrap();
+        let reject_connection = deserialize_reject_connection(&vec_reject_connection).unwrap();
+        assert_eq!(reject_connection.public_key, public_key_a);
+    }
+
+    #[test]
+    fn test_client_listener_max_conns_per_key() {
+        let mut thread_pool = ThreadPool::new().unwrap();
+        thread_pool.run(task_client_listener_max_conns_per_key(thread_pool.clone()));
+    }
+
+    async fn task_client_listener_max_conns(mut spawner: impl Spawn + Clone + Send + 'static) {
+        let (req_sender, mut req_receiver) = mpsc::channel(0);
+        let connector = DummyConnector::new(req_sender);
+        let (connections_sender, mut connections_receiver) = mpsc::channel(0);
+        let conn_timeout_ticks = 8;
+        let max_conns = 1;
+        let (_tick_sender, tick_receiver) = mpsc::channel(0);
+        let timer_client = create_timer_incoming(tick_receiver, spawner.clone()).unwrap();
+
+        let (mut acl_sender, mut incoming_access_control) = mpsc::channel(0);
+        let (event_sender, mut event_receiver) = mpsc::channel(0);
+        let keepalive_transform = FuncFutTransform::new(|x| Box::pin(future::ready(x)));
+
+        let c_spawner = spawner.clone();
+        let fut_listener = async move {
+            let mut access_control = AccessControlPk::new();
+            let mut conn_tracker = ConnTracker::new();
+            await!(inner_client_listener(
+                connector,
+                &mut access_control,
+                &mut incoming_access_control,
+                &mut conn_tracker,
+                connections_sender,
+                keepalive_transform,
+                conn_timeout_ticks,
+                DEFAULT_MAX_CONNS_PER_KEY,
+                max_conns,
+                timer_client,
+                c_spawner,
+                Some(event_sender)
+            ))
+        }
+            .map_err(|e| warn!("inner_client_listener error: {:?}", e))
+            .map(|_| ());
+
+        spawner.spawn(fut_listener).unwrap();
+
+        // listener will attempt to start a main connection to the relay:
+        let (mut relay_sender, local_receiver) = mpsc::channel(0);
+        let (local_sender, mut relay_receiver) = mpsc::channel(0);
+        let conn_pair = (local_sender, local_receiver);
+        let req = await!(req_receiver.next()).unwrap();
+        req.reply(Some(conn_pair));
+
+        let public_key_a = PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]);
+        let public_key_b = PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]);
+        for public_key in &[&public_key_a, &public_key_b] {
+            await!(acl_sender.send(AccessControlOp::Add((*public_key).clone()))).unwrap();
+            await!(event_receiver.next()).unwrap();
+        }
+
+        let vec_init_connection = await!(relay_receiver.next()).unwrap();
+        let init_connection = deserialize_init_connection(&vec_init_connection).unwrap();
+        if let InitConnection::Listen = init_connection {
+        } else {
+            unreachable!();
+        }
+
+        // A connection from public_key_a is accepted:
+        let incoming_connection = IncomingConnection {
+            public_key: public_key_a.clone(),
+        };
+        await!(relay_sender.send(serialize_incoming_connection(&incoming_connection))).unwrap();
+        await!(event_receiver.next()).unwrap();
+
+        let (remote_sender, local_receiver) = mpsc::channel(0);
+        let (local_sender, mut remote_receiver) = mpsc::channel(0);
+        let req = await!(req_receiver.next()).unwrap();
+        req.reply(Some((local_sender, local_receiver)));
+        await!(remote_receiver.next()).unwrap();
+
+        let (accepted_public_key, conn_pair) = await!(connections_receiver.next()).unwrap();
+        assert_eq!(accepted_public_key, public_key_a);
+
+        // The budget is exhausted, so a connection from public_key_b is rejected:
+        let incoming_connection = IncomingConnection {
+            public_key: public_key_b.clone(),
+        };
+        await!(relay_sender.send(serialize_incoming_connection(&incoming_connection))).unwrap();
+        await!(event_receiver.next()).unwrap();
+
+        let vec_reject_connection = await!(relay_receiver.next()).unwrap();
+        let reject_connection = deserialize_reject_connection(&vec_reject_connection).unwrap();
+        assert_eq!(reject_connection.public_key, public_key_b);
+
+        // The connection with public_key_a is closed:
+        drop(remote_sender);
+        drop(remote_receiver);
+        drop(conn_pair);
+        match await!(event_receiver.next()).unwrap() {
+            ClientListenerEvent::ConnectionClosed(public_key) => {
+                assert_eq!(public_key, public_key_a)
+            }
+            _ => unreachable!(),
+        };
+
+        // public_key_b can now connect:
+        await!(relay_sender.send(serialize_incoming_connection(&incoming_connection))).unwrap();
+        await!(event_receiver.next()).unwrap();
+
+        let (_remote_sender, local_receiver) = mpsc::channel(0);
+        let (local_sender, mut remote_receiver) = mpsc::channel(0);
+        let req = await!(req_receiver.next()).unwrap();
+        req.reply(Some((local_sender, local_receiver)));
+
+        let vec_init_connection = await!(remote_receiver.next()).unwrap();
+        let init_connection = deserialize_init_connection(&vec_init_connection).unwrap();
+        if let InitConnection::Accept(accepted_public_key) = init_connection {
+            assert_eq!(accepted_public_key, public_key_b);
+        } else {
+            unreachable!();
+        }
+
+        let (accepted_public_key, _conn_pair) = await!(connections_receiver.next()).unwrap();
+        assert_eq!(accepted_public_key, public_key_b);
+    }
+
+    #[test]
+    fn test_client_listener_max_conns() {
+        let mut thread_pool = ThreadPool::new().unwrap();
+        thread_pool.run(task_client_listener_max_conns(thread_pool.clone()));
+    }
+
+    async fn task_client_listener_reconnect(mut spawner: impl Spawn + Clone + Send + 'static) {
+        let (req_sender, mut req_receiver) = mpsc::channel(0);
+        let connector = DummyConnector::new(req_sender);
+        let (connections_sender, _connections_receiver) = mpsc::channel(0);
+        let conn_timeout_ticks = 8;
+        let backoff_ticks = 2;
+        let (mut tick_sender_receiver, timer_client) = dummy_timer_multi_sender(spawner.clone());
+
+        let (acl_sender, mut incoming_access_control) = mpsc::channel(0);
+        let keepalive_transform = FuncFutTransform::new(|x| Box::pin(future::ready(x)));
+
+        let (res_sender, res_receiver) = oneshot::channel();
+        let c_spawner = spawner.clone();
+        spawner
+            .spawn(async move {
+                let mut access_control = AccessControlPk::new();
+                let res = await!(client_listener(
+                    connector,
+                    &mut access_control,
+                    &mut incoming_access_control,
+                    connections_sender,
+                    keepalive_transform,
+                    conn_timeout_ticks,
+                    DEFAULT_MAX_CONNS_PER_KEY,
+                    DEFAULT_MAX_CONNS,
+                    backoff_ticks,
+                    timer_client,
+                    c_spawner
+                ));
+                res_sender.send(res).unwrap();
+            })
+            .unwrap();
+
+        // The first connection attempt to the relay fails:
+        let req = await!(req_receiver.next()).unwrap();
+        req.reply(None);
+
+        // Wait until backoff_ticks time passes:
+        let mut tick_sender = await!(tick_sender_receiver.next()).unwrap();
+        for _ in 0..backoff_ticks {
+            await!(tick_sender.send(TimerTick)).unwrap();
+        }
+
+        // The listener attempts to reconnect:
+        let (_relay_sender, local_receiver) = mpsc::channel(0);
+        let (local_sender, mut relay_receiver) = mpsc::channel(0);
+        let req = await!(req_receiver.next()).unwrap();
+        req.reply(Some((local_sender, local_receiver)));
+
+        let vec_init_connection = await!(relay_receiver.next()).unwrap();
+        let init_connection = deserialize_init_connection(&vec_init_connection).unwrap();
+        if let InitConnection::Listen = init_connection {
+        } else {
+            unreachable!();
+        }
+
+        // Closing the access control channel shuts down the listener:
+        drop(acl_sender);
+        assert!(await!(res_receiver).unwrap().is_ok());
+    }
+
+    #[test]
+    fn test_client_listener_reconnect() {
+        let mut thread_pool = ThreadPool::new().unwrap();
+        thread_pool.run(task_client_listener_reconnect(thread_pool.clone()));
+    }
+
+    async fn task_client_listener_reconnect_keeps_limits(
+        mut spawner: impl Spawn + Clone + Send + 'static,
+    ) {
+        let (req_sender, mut req_receiver) = mpsc::channel(0);
+        let connector = DummyConnector::new(req_sender);
+        let (connections_sender, mut connections_receiver) = mpsc::channel(0);
+        let conn_timeout_ticks = 8;
+        let max_conns_per_key = 1;
+        let backoff_ticks = 2;
+        let (mut tick_sender_receiver, timer_client) = dummy_timer_multi_sender(spawner.clone());
+
+        let (_acl_sender, mut incoming_access_control) = mpsc::channel(0);
+        let keepalive_transform = FuncFutTransform::new(|x| Box::pin(future::ready(x)));
+
+        let public_key_a = PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]);
+        let mut access_control = AccessControlPk::new();
+        access_control.apply_op(AccessControlOp::Add(public_key_a.clone()));
+
+        let c_spawner = spawner.clone();
+        spawner
+            .spawn(async move {
+                let _ = await!(client_listener(
+                    connector,
+                    &mut access_control,
+                    &mut incoming_access_control,
+                    connections_sender,
+                    keepalive_transform,
+                    conn_timeout_ticks,
+                    max_conns_per_key,
+                    DEFAULT_MAX_CONNS,
+                    backoff_ticks,
+                    timer_client,
+                    c_spawner
+                ));
+            })
+            .unwrap();
+
+        // The listener connects to the relay:
+        let (mut relay_sender, local_receiver) = mpsc::channel(0);
+        let (local_sender, mut relay_receiver) = mpsc::channel(0);
+        let req = await!(req_receiver.next()).unwrap();
+        req.reply(Some((local_sender, local_receiver)));
+
+        let vec_init_connection = await!(relay_receiver.next()).unwrap();
+        let init_connection = deserialize_init_connection(&vec_init_connection).unwrap();
+        if let InitConnection::Listen = init_connection {
+        } else {
+            unreachable!();
+        }
+
+        // A connection from public_key_a is accepted, using up the limit for public_key_a:
+        let incoming_connection = IncomingConnection {
+            public_key: public_key_a.clone(),
+        };
+        await!(relay_sender.send(serialize_incoming_connection(&incoming_connection))).unwrap();
+
+        // Ticks for the connection timeout of accept_connection(). We keep the sender, so that the
+        // timeout does not occur:
+        let _accept_tick_sender = await!(tick_sender_receiver.next()).unwrap();
+
+        let (_remote_sender, local_receiver) = mpsc::channel(0);
+        let (local_sender, mut remote_receiver) = mpsc::channel(0);
+        let req = await!(req_receiver.next()).unwrap();
+        req.reply(Some((local_sender, local_receiver)));
+        await!(remote_receiver.next()).unwrap();
+
+        let (accepted_public_key, _conn_pair) = await!(connections_receiver.next()).unwrap();
+        assert_eq!(accepted_public_key, public_key_a);
+
+        // The relay closes the connection, and the listener reconnects after backoff_ticks:
+        drop(relay_sender);
+        drop(relay_receiver);
+        let mut tick_sender = await!(tick_sender_receiver.next()).unwrap();
+        for _ in 0..backoff_ticks {
+            await!(tick_sender.send(TimerTick)).unwrap();
+        }
+
+        let (mut relay_sender, local_receiver) = mpsc::channel(0);
+        let (local_sender, mut relay_receiver) = mpsc::channel(0);
+        let req = await!(req_receiver.next()).unwrap();
+        req.reply(Some((local_sender, local_receiver)));
+
+        let vec_init_connection = await!(relay_receiver.next()).unwrap();
+        let init_connection = deserialize_init_connection(&vec_init_connection).unwrap();
+        if let InitConnection::Listen = init_connection {
+        } else {
+            unreachable!();
+        }
+
+        // The connection accepted before the reconnection is still open, so another connection
+        // from public_key_a is rejected:
+        await!(relay_sender.send(serialize_incoming_connection(&incoming_connection))).unwrap();
+        let vec_reject_connection = await!(relay_receiver.next()).unwrap();
+        let reject_connection = deserialize_reject_connection(&vec_reject_connection).unwrap();
+        assert_eq!(reject_connection.public_key, public_key_a);
+    }
+
+    #[test]
+    fn test_client_listener_reconnect_keeps_limits() {
+        let mut thread_pool = ThreadPool::new().unwrap();
+        thread_pool.run(task_client_listener_reconnect_keeps_limits(
+            thread_pool.clone(),
+        ));
+    }
+
+    /// A spawner that always fails to spawn.
+    #[derive(Clone)]
+    struct FailSpawner;
+
+    impl Spawn for FailSpawner {
+        fn spawn_obj(&mut self, _future: FutureObj<'static, ()>) -> Result<(), SpawnError> {
+            Err(SpawnError::shutdown())
+        }
+    }
+
+    async fn task_client_listener_fatal_error(mut spawner: impl Spawn + Clone + Send + 'static) {
+        let (req_sender, mut req_receiver) = mpsc::channel(0);
+        let connector = DummyConnector::new(req_sender);
+        let (connections_sender, _connections_receiver) = mpsc::channel(0);
+        let conn_timeout_ticks = 8;
+        let backoff_ticks = 2;
+        let (_tick_sender_receiver, timer_client) = dummy_timer_multi_sender(spawner.clone());
+
+        let (_acl_sender, mut incoming_access_control) = mpsc::channel(0);
+        let keepalive_transform = FuncFutTransform::new(|x| Box::pin(future::ready(x)));
+
+        let public_key_a = PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]);
+        let mut access_control = AccessControlPk::new();
+        access_control.apply_op(AccessControlOp::Add(public_key_a.clone()));
+
+        let (res_sender, res_receiver) = oneshot::channel();
+        spawner
+            .spawn(async move {
+                let res = await!(client_listener(
+                    connector,
+                    &mut access_control,
+                    &mut incoming_access_control,
+                    connections_sender,
+                    keepalive_transform,
+                    conn_timeout_ticks,
+                    DEFAULT_MAX_CONNS_PER_KEY,
+                    DEFAULT_MAX_CONNS,
+                    backoff_ticks,
+                    timer_client,
+                    FailSpawner
+                ));
+                res_sender.send(res).unwrap();
+            })
+            .unwrap();
+
+        let (mut relay_sender, local_receiver) = mpsc::channel(0);
+        let (local_sender, mut relay_receiver) = mpsc::channel(0);
+        let req = await!(req_receiver.next()).unwrap();
+        req.reply(Some((local_sender, local_receiver)));
+
+        let vec_init_connection = await!(relay_receiver.next()).unwrap();
+        let init_connection = deserialize_init_connection(&vec_init_connection).unwrap();
+        if let InitConnection::Listen = init_connection {
+        } else {
+            unreachable!();
+        }
+
+        // Accepting the connection requires spawning, which fails:
+        let incoming_connection = IncomingConnection {
+            public_key: public_key_a.clone(),
+        };
+        await!(relay_sender.send(serialize_incoming_connection(&incoming_connection))).unwrap();
+
+        match await!(res_receiver).unwrap() {
+            Err(ClientListenerError::SpawnError) => {}
+            _ => unreachable!(),
+        };
+
+        // The listener did not attempt to reconnect:
+        assert!(await!(req_receiver.next()).is_none());
+    }
+
+    #[test]
+    fn test_client_listener_fatal_error() {
+        let mut thread_pool = ThreadPool::new().unwrap();
+        thread_pool.run(task_client_listener_fatal_error(thread_pool.clone()));
+    }
+
     // TODO: Add a test for ClientListener.
 
 }
@@ -0,0 +1,224 @@
+use futures::stream::FuturesUnordered;
+use futures::StreamExt;
+
+use common::conn::{BoxFuture, ConnPairVec, FutTransform};
+
+use timer::utils::with_timeout;
+use timer::TimerClient;
+
+/// The order in which MultiConnector attempts to connect to the given relays.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MultiConnectMode {
+    /// Attempt the relays one after the other, in the given order.
+    Sequential,
+    /// Attempt all the relays at the same time. The first successful connection is used.
+    Parallel,
+}
+
+/// Connect to the first available relay out of a list of relays.
+/// Every connection attempt is limited to `conn_timeout_ticks` time ticks.
+#[derive(Clone)]
+pub struct MultiConnector<C> {
+    connector: C,
+    mode: MultiConnectMode,
+    conn_timeout_ticks: usize,
+    timer_client: TimerClient,
+}
+
+impl<A, C> MultiConnector<C>
+where
+    A: Send + 'static,
+    C: FutTransform<Input = A, Output = Option<ConnPairVec>> + Clone + Send + 'static,
+{
+    pub fn new(
+        connector: C,
+        mode: MultiConnectMode,
+        conn_timeout_ticks: usize,
+        timer_client: TimerClient,
+    ) -> MultiConnector<C> {
+        MultiConnector {
+            connector,
+            mode,
+            conn_timeout_ticks,
+            timer_client,
+        }
+    }
+
+    async fn connect_sequential(&mut self, addresses: Vec<A>) -> Option<ConnPairVec> {
+        for address in addresses {
+            let fut_connect = self.connector.transform(address);
+            match await!(with_timeout(
+                fut_connect,
+                self.conn_timeout_ticks,
+                self.timer_client.clone()
+            )) {
+                Ok(Some(conn_pair)) => return Some(conn_pair),
+                Ok(None) => warn!("MultiConnector: Connection attempt failed"),
+                Err(e) => warn!("MultiConnector: Connection attempt error: {:?}", e),
+            }
+        }
+        None
+    }
+
+    async fn connect_parallel(&mut self, addresses: Vec<A>) -> Option<ConnPairVec> {
+        let mut fut_attempts = addresses
+            .into_iter()
+            .map(|address| {
+                let mut connector = self.connector.clone();
+                let conn_timeout_ticks = self.conn_timeout_ticks;
+                let timer_client = self.timer_client.clone();
+                async move {
+                    let fut_connect = connector.transform(address);
+                    match await!(with_timeout(fut_connect, conn_timeout_ticks, timer_client)) {
+                        Ok(opt_conn_pair) => opt_conn_pair,
+                        Err(e) => {
+                            warn!("MultiConnector: Connection attempt error: {:?}", e);
+                            None
+                        }
+                    }
+                }
+            })
+            .collect::<FuturesUnordered<_>>();
+
+        // Remaining attempts are dropped once we have a connection:
+        while let Some(opt_conn_pair) = await!(fut_attempts.next()) {
+            if opt_conn_pair.is_some() {
+                return opt_conn_pair;
+            }
+        }
+        None
+    }
+}
+
+impl<A, C> FutTransform for MultiConnector<C>
+where
+    A: Send + Sync + 'static,
+    C: FutTransform<Input = A, Output = Option<ConnPairVec>> + Clone + Send + Sync + 'static,
+{
+    type Input = Vec<A>;
+    type Output = Option<ConnPairVec>;
+
+    fn transform(&mut self, addresses: Vec<A>) -> BoxFuture<'_, Self::Output> {
+        match self.mode {
+            MultiConnectMode::Sequential => Box::pin(self.connect_sequential(addresses)),
+            MultiConnectMode::Parallel => Box::pin(self.connect_parallel(addresses)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::channel::mpsc;
+    use futures::executor::ThreadPool;
+    use futures::task::{Spawn, SpawnExt};
+    use futures::SinkExt;
+
+    use common::dummy_connector::DummyConnector;
+    use timer::{dummy_timer_multi_sender, TimerTick};
+
+    async fn task_multi_connector_sequential_failover<S>(mut spawner: S)
+    where
+        S: Spawn + Clone + Send + 'static,
+    {
+        let conn_timeout_ticks = 8;
+        let (mut tick_sender_receiver, timer_client) = dummy_timer_multi_sender(spawner.clone());
+        let (req_sender, mut req_receiver) = mpsc::channel(0);
+        let connector = DummyConnector::<u32, Option<ConnPairVec>>::new(req_sender);
+
+        let mut multi_connector = MultiConnector::new(
+            connector,
+            MultiConnectMode::Sequential,
+            conn_timeout_ticks,
+            timer_client,
+        );
+
+        let fut_conn_pair = spawner
+            .spawn_with_handle(async move { await!(multi_connector.transform(vec![0, 1, 2])) })
+            .unwrap();
+
+        // The first relay fails immediately:
+        let _tick_sender0 = await!(tick_sender_receiver.next()).unwrap();
+        let req = await!(req_receiver.next()).unwrap();
+        assert_eq!(req.address, 0);
+        req.reply(None);
+
+        // The second relay does not respond until the attempt times out:
+        let mut tick_sender1 = await!(tick_sender_receiver.next()).unwrap();
+        let req1 = await!(req_receiver.next()).unwrap();
+        assert_eq!(req1.address, 1);
+        for _ in 0..conn_timeout_ticks {
+            await!(tick_sender1.send(TimerTick)).unwrap();
+        }
+
+        // The third relay is available:
+        let _tick_sender2 = await!(tick_sender_receiver.next()).unwrap();
+        let req = await!(req_receiver.next()).unwrap();
+        assert_eq!(req.address, 2);
+        let (local_sender, _remote_receiver) = mpsc::channel::<Vec<u8>>(0);
+        let (_remote_sender, local_receiver) = mpsc::channel::<Vec<u8>>(0);
+        req.reply(Some((local_sender, local_receiver)));
+
+        assert!(await!(fut_conn_pair).is_some());
+        drop(req1);
+    }
+
+    #[test]
+    fn test_multi_connector_sequential_failover() {
+        let mut thread_pool = ThreadPool::new().unwrap();
+        thread_pool.run(task_multi_connector_sequential_failover(
+            thread_pool.clone(),
+        ));
+    }
+
+    async fn task_multi_connector_parallel_failover<S>(mut spawner: S)
+    where
+        S: Spawn + Clone + Send + 'static,
+    {
+        let conn_timeout_ticks = 8;
+        let (mut tick_sender_receiver, timer_client) = dummy_timer_multi_sender(spawner.clone());
+        let (req_sender, mut req_receiver) = mpsc::channel(0);
+        let connector = DummyConnector::<u32, Option<ConnPairVec>>::new(req_sender);
+
+        let mut multi_connector = MultiConnector::new(
+            connector,
+            MultiConnectMode::Parallel,
+            conn_timeout_ticks,
+            timer_client,
+        );
+
+        let fut_conn_pair = spawner
+            .spawn_with_handle(async move { await!(multi_connector.transform(vec![0, 1])) })
+            .unwrap();
+
+        // Both attempts are made at the same time. We keep the tick senders, so that no attempt
+        // times out:
+        let _tick_sender0 = await!(tick_sender_receiver.next()).unwrap();
+        let _tick_sender1 = await!(tick_sender_receiver.next()).unwrap();
+
+        let mut reqs = vec![
+            await!(req_receiver.next()).unwrap(),
+            await!(req_receiver.next()).unwrap(),
+        ];
+        reqs.sort_by_key(|req| req.address);
+        let req1 = reqs.pop().unwrap();
+        let req0 = reqs.pop().unwrap();
+        assert_eq!(req0.address, 0);
+        assert_eq!(req1.address, 1);
+
+        // The first relay always fails:
+        req0.reply(None);
+
+        let (local_sender, _remote_receiver) = mpsc::channel::<Vec<u8>>(0);
+        let (_remote_sender, local_receiver) = mpsc::channel::<Vec<u8>>(0);
+        req1.reply(Some((local_sender, local_receiver)));
+
+        assert!(await!(fut_conn_pair).is_some());
+    }
+
+    #[test]
+    fn test_multi_connector_parallel_failover() {
+        let mut thread_pool = ThreadPool::new().unwrap();
+        thread_pool.run(task_multi_connector_parallel_failover(thread_pool.clone()));
+    }
+}
@@ -23,5 +23,9 @@ mod client;
 mod server;
 
 pub use self::client::client_connector::ClientConnector;
-pub use self::client::client_listener::ClientListener;
+pub use self::client::client_listener::{
+    client_listener, ClientListener, ClientListenerError, DEFAULT_MAX_CONNS,
+    DEFAULT_MAX_CONNS_PER_KEY,
+};
+pub use self::client::multi_connector::{MultiConnectMode, MultiConnector};
 pub use self::server::net_server::{net_relay_server, NetRelayServerError};
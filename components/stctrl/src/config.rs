@@ -79,6 +79,9 @@ pub struct RemoveFriendCmd {
     /// Friend name to remove
     #[structopt(long = "name", short = "n")]
     pub friend_name: String,
+    /// Remove the friend even if the balance is not zero or there are pending requests
+    #[structopt(long = "force", short = "f")]
+    pub force: bool,
 }
 
 /// Enable friend
@@ -371,7 +374,8 @@ async fn config_remove_friend(
         .ok_or(ConfigError::FriendNameNotFound)?
         .clone();
 
-    await!(app_config.remove_friend(friend_public_key)).map_err(|_| ConfigError::AppConfigError)
+    await!(app_config.remove_friend(friend_public_key, remove_friend_cmd.force))
+        .map_err(|_| ConfigError::AppConfigError)
 }
 
 async fn config_enable_friend(
@@ -153,8 +153,8 @@ async fn task_resolve_inconsistency(mut test_executor: TestExecutor) {
 
     await!(advance_time(40, &mut tick_sender, &test_executor));
 
-    // Node0: remove the friend node1:
-    await!(config0.remove_friend(node_public_key(1))).unwrap();
+    // Node0: remove the friend node1 (The balance is not zero, so we have to force):
+    await!(config0.remove_friend(node_public_key(1), true)).unwrap();
 
     // Node0: Add node1 as a friend with a different balance
     // This should cause an inconsistency when the first token
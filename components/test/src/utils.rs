@@ -41,6 +41,23 @@ const BACKOFF_TICKS: usize = 0x8;
 const MAX_CONCURRENT_ENCRYPT: usize = 0x8;
 /// The size we allocate for the user send funds requests queue.
 const MAX_PENDING_USER_REQUESTS: usize = 0x20;
+/// The amount of ticks we wait before declaring a friend that was reported as offline to be
+/// offline.
+const OFFLINE_GRACE_TICKS: usize = 0x8;
+/// Minimal amount of ticks between two processed inconsistency errors from the same friend.
+const INCONSISTENCY_ERROR_INTERVAL_TICKS: usize = 0x4;
+/// Maximum amount of ready receipts kept by the funder.
+const MAX_READY_RECEIPTS: usize = 0x400;
+/// Maximum amount of requests waiting to be forwarded to a single friend.
+const MAX_PENDING_REQUESTS: usize = 0x100;
+/// The amount of ticks a friend may have pending outgoing operations before we flush them.
+const FLUSH_IDLE_TICKS: usize = 0x10;
+/// The amount of ticks we may wait for the token while having pending outgoing operations.
+const TOKEN_STALL_TICKS: usize = 0x20;
+/// The amount of ticks a request may be pending at our node before we fail it.
+const REQUEST_TIMEOUT_TICKS: usize = 0x400;
+/// The length of a friend's pending queue above which it is reported as over threshold.
+const PENDING_QUEUE_THRESHOLD: usize = 0x80;
 /// Maximum amount of concurrent index client requests:
 const MAX_OPEN_INDEX_CLIENT_REQUESTS: usize = 0x8;
 /// The amount of ticks we are willing to wait until a connection is established (Through
@@ -138,6 +155,14 @@ fn default_node_config() -> NodeConfig {
         max_operations_in_batch: MAX_OPERATIONS_IN_BATCH,
         /// The size we allocate for the user send funds requests queue.
         max_pending_user_requests: MAX_PENDING_USER_REQUESTS,
+        offline_grace_ticks: OFFLINE_GRACE_TICKS,
+        inconsistency_error_interval_ticks: INCONSISTENCY_ERROR_INTERVAL_TICKS,
+        max_ready_receipts: MAX_READY_RECEIPTS,
+        max_pending_requests: MAX_PENDING_REQUESTS,
+        flush_idle_ticks: FLUSH_IDLE_TICKS,
+        token_stall_ticks: TOKEN_STALL_TICKS,
+        request_timeout_ticks: REQUEST_TIMEOUT_TICKS,
+        pending_queue_threshold: PENDING_QUEUE_THRESHOLD,
         /// Maximum amount of concurrent index client requests:
         max_open_index_client_requests: MAX_OPEN_INDEX_CLIENT_REQUESTS,
         /// Maximum amount of relays a node may use.
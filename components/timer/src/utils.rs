@@ -1,7 +1,11 @@
 use std::marker::Unpin;
 
 use crate::timer::{TimerClient, TimerTick};
+#[cfg(any(test, feature = "test-utils"))]
+use common::dummy_connector::{ConnRequest, DummyConnector};
 use common::int_convert::usize_to_u64;
+#[cfg(any(test, feature = "test-utils"))]
+use futures::channel::mpsc;
 use futures::select;
 use futures::{future, Future, FutureExt, Stream, StreamExt};
 
@@ -43,12 +47,54 @@ where
     }
 }
 
-// TODO: Add tests.
+#[derive(Debug, Eq, PartialEq)]
+pub enum WithTimeoutError {
+    RequestTimerStreamError,
+    /// The timeout occurred before the future has finished
+    Timeout,
+}
+
+/// Wraps a future with a timeout of `time_ticks` ticks of the given timer.
+/// If the future finishes before the timeout with value v, Ok(v) is returned.
+pub async fn with_timeout<T, F>(
+    fut: F,
+    time_ticks: usize,
+    mut timer_client: TimerClient,
+) -> Result<T, WithTimeoutError>
+where
+    F: Future<Output = T> + Unpin,
+{
+    let timer_stream = await!(timer_client.request_timer_stream())
+        .map_err(|_| WithTimeoutError::RequestTimerStreamError)?;
+    await!(future_timeout(fut, timer_stream, time_ticks)).ok_or(WithTimeoutError::Timeout)
+}
+
+/// Create a DummyConnector that simulates latency: Every connection attempt is forwarded to
+/// `req_sender` only after `ticks` time ticks of the given timer.
+/// Meant for tests. Available to other crates through the `test-utils` feature.
+#[cfg(any(test, feature = "test-utils"))]
+pub fn dummy_connector_with_latency<A, O>(
+    req_sender: mpsc::Sender<ConnRequest<A, O>>,
+    ticks: usize,
+    timer_client: TimerClient,
+) -> DummyConnector<A, O> {
+    DummyConnector::with_delay(req_sender, move || {
+        let c_timer_client = timer_client.clone();
+        Box::pin(async move {
+            if let Err(e) = await!(sleep_ticks(ticks, c_timer_client)) {
+                warn!(
+                    "dummy_connector_with_latency(): sleep_ticks() error: {:?}",
+                    e
+                );
+            }
+        })
+    })
+}
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::timer::create_timer_incoming;
+    use crate::timer::{create_timer_incoming, dummy_timer_multi_sender};
     use futures::channel::{mpsc, oneshot};
     use futures::executor::ThreadPool;
     use futures::task::{Spawn, SpawnExt};
@@ -104,4 +150,86 @@ mod tests {
         thread_pool.run(task_future_timeout_late(thread_pool.clone()));
     }
 
+    async fn task_with_timeout_on_time(mut spawner: impl Spawn + Clone + Send + 'static) {
+        let (mut tick_sender_receiver, timer_client) = dummy_timer_multi_sender(spawner.clone());
+
+        let (sender, receiver) = oneshot::channel::<u32>();
+        let receiver = receiver.map(|res| res.unwrap());
+        let timeout_fut = spawner
+            .spawn_with_handle(with_timeout(receiver, 8, timer_client))
+            .unwrap();
+        // Wait until the timer stream is created:
+        let mut tick_sender = await!(tick_sender_receiver.next()).unwrap();
+
+        for _ in 0..7usize {
+            await!(tick_sender.send(TimerTick)).unwrap();
+        }
+
+        sender.send(0x1337).unwrap();
+        assert_eq!(await!(timeout_fut), Ok(0x1337));
+    }
+
+    #[test]
+    fn test_with_timeout_on_time() {
+        let mut thread_pool = ThreadPool::new().unwrap();
+        thread_pool.run(task_with_timeout_on_time(thread_pool.clone()));
+    }
+
+    async fn task_with_timeout_late(mut spawner: impl Spawn + Clone + Send + 'static) {
+        let (mut tick_sender_receiver, timer_client) = dummy_timer_multi_sender(spawner.clone());
+
+        let (sender, receiver) = oneshot::channel::<u32>();
+        let receiver = receiver.map(|res| res.unwrap());
+        let timeout_fut = spawner
+            .spawn_with_handle(with_timeout(receiver, 8, timer_client))
+            .unwrap();
+        // Wait until the timer stream is created:
+        let mut tick_sender = await!(tick_sender_receiver.next()).unwrap();
+
+        for _ in 0..9usize {
+            let _ = await!(tick_sender.send(TimerTick));
+        }
+
+        let _ = sender.send(0x1337);
+        assert_eq!(await!(timeout_fut), Err(WithTimeoutError::Timeout));
+    }
+
+    #[test]
+    fn test_with_timeout_late() {
+        let mut thread_pool = ThreadPool::new().unwrap();
+        thread_pool.run(task_with_timeout_late(thread_pool.clone()));
+    }
+
+    async fn task_dummy_connector_with_latency(mut spawner: impl Spawn + Clone + Send + 'static) {
+        use common::conn::FutTransform;
+
+        let (mut tick_sender_receiver, timer_client) = dummy_timer_multi_sender(spawner.clone());
+        let (req_sender, mut req_receiver) = mpsc::channel(0);
+        let connector = dummy_connector_with_latency::<u32, u64>(req_sender, 3, timer_client);
+
+        // Latency applies separately to every connection attempt:
+        for i in 0..2u64 {
+            let mut c_connector = connector.clone();
+            let conn_fut = spawner
+                .spawn_with_handle(async move { await!(c_connector.transform(0x10)) })
+                .unwrap();
+
+            // Wait until the timer stream is created:
+            let mut tick_sender = await!(tick_sender_receiver.next()).unwrap();
+            for _ in 0..3usize {
+                await!(tick_sender.send(TimerTick)).unwrap();
+            }
+
+            let conn_request = await!(req_receiver.next()).unwrap();
+            assert_eq!(conn_request.address, 0x10);
+            conn_request.reply(i);
+            assert_eq!(await!(conn_fut), i);
+        }
+    }
+
+    #[test]
+    fn test_dummy_connector_with_latency() {
+        let mut thread_pool = ThreadPool::new().unwrap();
+        thread_pool.run(task_dummy_connector_with_latency(thread_pool.clone()));
+    }
 }